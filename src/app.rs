@@ -1,25 +1,125 @@
+use crate::auth;
 use crate::chat;
-use crate::connection;
+use crate::connection::{
+    self, ChannelSpec, DataChannelHandle, IceSettings, IncomingMessages, SharedDataChannel,
+    MESSAGING_CHANNEL, TYPING_CHANNEL,
+};
+use crate::identity;
 use crate::sdp;
+use crate::signaling::{ConsoleSignaler, Signaler};
+use crate::store::SharedStore;
+use crate::wormhole::{self, WormholeSignaler};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use log::{error, info};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+
+// Options shared by the offerer and answerer roles
+pub struct SessionOptions {
+    pub timeout: Duration,
+    // Session password both peers must know
+    pub password: Option<String>,
+    // Contact the peer must be
+    pub to: Option<String>,
+    // Display name for this session, overriding the configured one
+    pub name: Option<String>,
+    // Channel specs for this session, overriding the configured ones
+    pub channels: Vec<ChannelSpec>,
+    // Rendezvous server to signal through instead of copy/paste
+    pub rendezvous: Option<String>,
+    // Wormhole code of the offer to answer at the rendezvous server
+    pub code: Option<String>,
+    // Print connection stats this often while the session lasts
+    pub stats_interval: Option<Duration>,
+    // Identity the peer announced before connecting, which it has to prove
+    pub announced: Option<String>,
+}
 
 // Application logic for the offerer role
-pub async fn run_offerer(connection_timeout: Duration) -> Result<()> {
+pub async fn run_offerer(options: SessionOptions, store: SharedStore) -> Result<()> {
     info!("Starting as offerer...");
+    run(options, store, true).await
+}
+
+// Application logic for the answerer role
+pub async fn run_answerer(options: SessionOptions, store: SharedStore) -> Result<()> {
+    info!("Starting as answerer...");
+    run(options, store, false).await
+}
+
+// Connect with copy/paste or rendezvous signaling, then chat
+async fn run(options: SessionOptions, store: SharedStore, is_offerer: bool) -> Result<()> {
+    // Use the contact's ICE preferences if there are any
+    let ice = preferred_ice_settings(&options, &store).await?;
+
+    let mut signaler = session_signaler(&options, is_offerer)?;
+    run_with_signaler(options, store, &ice, signaler.as_mut(), is_offerer).await
+}
+
+// The rendezvous server if one was given, otherwise copy/paste
+pub fn session_signaler(options: &SessionOptions, is_offerer: bool) -> Result<Box<dyn Signaler>> {
+    let signaler: Box<dyn Signaler> = match (&options.rendezvous, &options.code) {
+        (Some(server), _) if is_offerer => Box::new(WormholeSignaler::offering(server)),
+        (Some(server), Some(code)) => Box::new(WormholeSignaler::answering(
+            server,
+            wormhole::Code::parse(code)?,
+        )),
+        (Some(_), None) => {
+            return Err(anyhow::anyhow!(
+                "Answering through a rendezvous server needs the offerer's code"
+            ))
+        }
+        (None, _) => Box::new(ConsoleSignaler),
+    };
+    Ok(signaler)
+}
+
+// Connect with any signaler, then chat
+pub async fn run_with_signaler(
+    options: SessionOptions,
+    store: SharedStore,
+    ice: &IceSettings,
+    signaler: &mut dyn Signaler,
+    is_offerer: bool,
+) -> Result<()> {
     println!(
         "Initializing connection with a timeout of {} seconds",
-        connection_timeout.as_secs()
+        options.timeout.as_secs()
     );
 
-    let start_time = Instant::now();
+    let pc = connection::create_peer_connection(ice).await?;
 
-    // Create peer connection with TURN fallback support
-    let pc = connection::create_peer_connection(true).await?;
+    let specs = session_channels(&options, &store).await;
+    let channels = connect(
+        Arc::clone(&pc),
+        specs,
+        signaler,
+        is_offerer,
+        options.timeout,
+    )
+    .await?;
+
+    // Start the chat session
+    run_session(pc, channels, &options, is_offerer, store).await
+}
+
+// Open the channels and run the offer/answer exchange over the signaler until
+// the peer connection is up (or the timeout passes)
+pub async fn connect(
+    pc: Arc<RTCPeerConnection>,
+    specs: Vec<ChannelSpec>,
+    signaler: &mut dyn Signaler,
+    is_offerer: bool,
+    timeout: Duration,
+) -> Result<HashMap<String, DataChannelHandle>> {
+    let start_time = Instant::now();
 
     // Set up ICE candidate handling with improved buffering
     let candidates_mutex = Arc::new(Mutex::new(Vec::new()));
@@ -27,12 +127,12 @@ pub async fn run_offerer(connection_timeout: Duration) -> Result<()> {
 
     pc.on_ice_candidate(Box::new(move |c| {
         if let Some(c) = c {
-            match serde_json::to_string(&c) {
-                Ok(candidate_str) => {
+            match c.to_json() {
+                Ok(candidate) => {
                     // Store the candidate for later use
                     let candidates = Arc::clone(&candidates_clone);
                     tokio::spawn(async move {
-                        candidates.lock().await.push(candidate_str);
+                        candidates.lock().await.push(candidate);
                     });
                 }
                 Err(e) => {
@@ -43,112 +143,247 @@ pub async fn run_offerer(connection_timeout: Duration) -> Result<()> {
         Box::pin(async {})
     }));
 
-    // Set up data channel
-    let dc = connection::setup_data_channel(Arc::clone(&pc), "messaging", true).await?;
+    // Set up data channels
+    let channels = connection::setup_data_channels(Arc::clone(&pc), specs, is_offerer).await?;
 
-    // Generate and display the SDP offer
-    sdp::generate_offer(&pc, &candidates_mutex).await?;
+    if is_offerer {
+        // Generate and send the SDP offer
+        let stamp = sdp::SessionStamp::new();
+        let offer = sdp::generate_offer(&pc, &candidates_mutex, &stamp).await?;
+        signaler.send(&offer).await?;
 
-    // Read the answer from the peer
-    println!("\nSend the above text to the answerer, then paste their response below:");
-    println!("(Waiting for peer response...)\n");
+        // Read the answer from the peer
+        println!("(Waiting for peer response...)");
+        let response = signaler.receive().await?;
 
-    let response = sdp::read_sdp_input().await?;
+        // Parse the answer and the peer's ICE candidates
+        let answer = sdp::parse_answer(&response, &stamp.nonce)?;
+        let description = sdp::attach_candidates(answer.description, &answer.candidates)?;
+        set_remote_description(&pc, description).await?;
+    } else {
+        // Read the offer from the peer
+        let offer_data = signaler.receive().await?;
 
-    // Parse the answer
-    let answer = sdp::parse_answer(&response)?;
+        // Parse the offer and the peer's ICE candidates
+        let offer = sdp::parse_offer(&offer_data)?;
+        let description = sdp::attach_candidates(offer.description, &offer.candidates)?;
+        set_remote_description(&pc, description).await?;
 
-    // Set the remote description
-    match pc.set_remote_description(answer).await {
+        // Generate and send the SDP answer
+        let stamp = sdp::SessionStamp::answering(
+            offer
+                .session
+                .as_ref()
+                .context("Offer has no session stamp")?,
+        );
+        let answer = sdp::generate_answer(&pc, &candidates_mutex, &stamp).await?;
+        signaler.send(&answer).await?;
+    }
+
+    // Monitor connection state
+    connection::monitor_connection_state(Arc::clone(&pc), timeout, start_time).await?;
+
+    Ok(channels)
+}
+
+async fn set_remote_description(
+    pc: &RTCPeerConnection,
+    description: RTCSessionDescription,
+) -> Result<()> {
+    match pc.set_remote_description(description).await {
         Ok(_) => {
             println!("Remote description set successfully");
+            Ok(())
         }
         Err(e) => {
             error!("Error setting remote description: {}", e);
-            return Err(anyhow::anyhow!("Failed to set remote description: {}", e));
+            Err(anyhow::anyhow!("Failed to set remote description: {}", e))
+        }
+    }
+}
+
+// ICE settings for the session, from the selected contact or the defaults
+pub async fn preferred_ice_settings(
+    options: &SessionOptions,
+    store: &SharedStore,
+) -> Result<IceSettings> {
+    let store = store.lock().await;
+    match options.to {
+        Some(ref name) => {
+            let contact = store
+                .contact(name)
+                .ok_or_else(|| anyhow::anyhow!("No contact named '{}'", name))?;
+            Ok(contact.ice.clone().unwrap_or_default())
         }
+        None => Ok(IceSettings::default()),
     }
+}
 
-    // Process ICE candidates from the peer
-    sdp::process_ice_candidates(&response, &pc).await?;
+// Channels to open: the built-in ones, then the configured ones, then --channel
+pub async fn session_channels(options: &SessionOptions, store: &SharedStore) -> Vec<ChannelSpec> {
+    let store = store.lock().await;
+    ChannelSpec::with_overrides(store.config().channels.iter().chain(&options.channels))
+}
 
-    // Monitor connection state
-    connection::monitor_connection_state(Arc::clone(&pc), connection_timeout, start_time).await?;
+// A connected session whose peer has been checked, for modes that carry
+// something other than chat over the messaging channel
+pub struct Session {
+    pub pc: Arc<RTCPeerConnection>,
+    pub dc: Arc<RTCDataChannel>,
+    pub incoming: IncomingMessages,
+    pub peer: chat::Peer,
+}
 
-    // Start the chat session
-    chat::enhanced_message_loop(dc).await?;
+// Connect over the signaler and run the handshake, failing if the peer
+// connection doesn't come up in time
+pub async fn open_session(
+    options: &SessionOptions,
+    store: &SharedStore,
+    signaler: &mut dyn Signaler,
+    is_offerer: bool,
+) -> Result<Session> {
+    let ice = preferred_ice_settings(options, store).await?;
+    let pc = connection::create_peer_connection(&ice).await?;
+    let specs = session_channels(options, store).await;
+    let mut channels = connect(
+        Arc::clone(&pc),
+        specs,
+        signaler,
+        is_offerer,
+        options.timeout,
+    )
+    .await?;
+    if pc.connection_state() != RTCPeerConnectionState::Connected {
+        let _ = pc.close().await;
+        return Err(anyhow::anyhow!(
+            "Couldn't connect to the peer within {} seconds",
+            options.timeout.as_secs()
+        ));
+    }
 
-    Ok(())
+    let DataChannelHandle {
+        channel,
+        mut incoming,
+    } = channels
+        .remove(MESSAGING_CHANNEL)
+        .context("Messaging channel was not set up")?;
+    let peer = handshake(&pc, &channel, &mut incoming, options, is_offerer, store).await?;
+    let dc = connection::wait_for_open(&channel, options.timeout).await?;
+    Ok(Session {
+        pc,
+        dc,
+        incoming,
+        peer,
+    })
 }
 
-// Application logic for the answerer role
-pub async fn run_answerer(connection_timeout: Duration) -> Result<()> {
-    info!("Starting as answerer...");
-    println!(
-        "Initializing connection with a timeout of {} seconds",
-        connection_timeout.as_secs()
-    );
+// Authenticate the peer once connected, then run the chat
+async fn run_session(
+    pc: Arc<RTCPeerConnection>,
+    mut channels: HashMap<String, DataChannelHandle>,
+    options: &SessionOptions,
+    is_offerer: bool,
+    store: SharedStore,
+) -> Result<()> {
+    let DataChannelHandle {
+        channel: dc,
+        mut incoming,
+    } = channels
+        .remove(MESSAGING_CHANNEL)
+        .context("Messaging channel was not set up")?;
+    let typing = channels
+        .remove(TYPING_CHANNEL)
+        .context("Typing channel was not set up")?;
 
-    let start_time = Instant::now();
+    let peer = handshake(&pc, &dc, &mut incoming, options, is_offerer, &store).await?;
 
-    // Create peer connection with TURN fallback support
-    let pc = connection::create_peer_connection(true).await?;
+    // Whatever is left are the extra channels from the config and --channel
+    let messaging = DataChannelHandle {
+        channel: dc,
+        incoming,
+    };
+    chat::enhanced_message_loop(pc, messaging, typing, channels, store, peer, options).await
+}
 
-    // Set up ICE candidate handling with improved buffering
-    let candidates_mutex = Arc::new(Mutex::new(Vec::new()));
-    let candidates_clone = Arc::clone(&candidates_mutex);
+// Check the session password and the peer's identity over the messaging
+// channel, closing the connection if either fails
+pub async fn handshake(
+    pc: &Arc<RTCPeerConnection>,
+    dc: &SharedDataChannel,
+    incoming: &mut IncomingMessages,
+    options: &SessionOptions,
+    is_offerer: bool,
+    store: &SharedStore,
+) -> Result<chat::Peer> {
+    // Verify the session password before any chat traffic
+    if let Some(ref password) = options.password {
+        if let Err(e) = auth::authenticate(dc, incoming, password, is_offerer).await {
+            pc.close().await?;
+            return Err(e);
+        }
+    }
 
-    pc.on_ice_candidate(Box::new(move |c| {
-        if let Some(c) = c {
-            match serde_json::to_string(&c) {
-                Ok(candidate_str) => {
-                    // Store the candidate for later use
-                    let candidates = Arc::clone(&candidates_clone);
-                    tokio::spawn(async move {
-                        candidates.lock().await.push(candidate_str);
-                    });
-                }
-                Err(e) => {
-                    error!("Failed to serialize ICE candidate: {}", e);
-                }
-            }
+    // Exchange identities and check them against the contact book
+    let identity_result = {
+        let store = store.lock().await;
+        identity::exchange(pc, dc, incoming, store.identity()).await
+    };
+    let peer_identity = match identity_result {
+        Ok(peer_identity) => peer_identity,
+        Err(e) => {
+            pc.close().await?;
+            return Err(e);
         }
-        Box::pin(async {})
-    }));
+    };
 
-    // Set up data channel (as answerer)
-    let dc = connection::setup_data_channel(Arc::clone(&pc), "messaging", false).await?;
+    // An announcement is unsigned, so anyone could have sent it
+    if let Some(ref announced) = options.announced {
+        if peer_identity != *announced {
+            pc.close().await?;
+            return Err(anyhow::anyhow!(
+                "Peer identity {} is not the {} it announced, closing the session",
+                identity::short_fingerprint(&peer_identity),
+                identity::short_fingerprint(announced)
+            ));
+        }
+    }
 
-    // Read the offer from the peer
-    let offer_data = sdp::read_sdp_input().await?;
+    let mut store = store.lock().await;
+    let contact = store.contact_by_identity(&peer_identity).cloned();
 
-    // Parse the offer
-    let offer = sdp::parse_offer(&offer_data)?;
+    if let Some(ref expected) = options.to {
+        let matches = contact.as_ref().is_some_and(|c| &c.name == expected);
+        if !matches {
+            drop(store);
+            pc.close().await?;
+            return Err(anyhow::anyhow!(
+                "Peer identity {} is not contact '{}', closing the session",
+                identity::short_fingerprint(&peer_identity),
+                expected
+            ));
+        }
+    }
 
-    // Set the remote description
-    match pc.set_remote_description(offer).await {
-        Ok(_) => {
-            println!("Remote description set successfully");
+    match contact {
+        Some(contact) => {
+            println!("Connected to contact '{}'", contact.name);
+            store.update_contacts(|contacts| {
+                if let Some(c) = contacts.iter_mut().find(|c| c.name == contact.name) {
+                    c.last_seen = Some(chrono::Local::now());
+                }
+            })?;
+            Ok(chat::Peer::new(
+                peer_identity,
+                Some(contact.label().to_string()),
+            ))
         }
-        Err(e) => {
-            error!("Error setting remote description: {}", e);
-            return Err(anyhow::anyhow!("Failed to set remote description: {}", e));
+        None => {
+            println!("Connected to an unknown peer with identity:");
+            println!("  {}", peer_identity);
+            println!("Save it with `contacts add <name> {}`", peer_identity);
+            Ok(chat::Peer::new(peer_identity, None))
         }
     }
-
-    // Process ICE candidates from the peer
-    sdp::process_ice_candidates(&offer_data, &pc).await?;
-
-    // Generate and display the SDP answer
-    sdp::generate_answer(&pc, &candidates_mutex).await?;
-
-    // Monitor connection state
-    connection::monitor_connection_state(Arc::clone(&pc), connection_timeout, start_time).await?;
-
-    // Start the chat session
-    chat::enhanced_message_loop(dc).await?;
-
-    Ok(())
 }
 
 // Placeholder for future group chat functionality
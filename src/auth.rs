@@ -0,0 +1,87 @@
+use crate::connection::{self, IncomingMessages, SharedDataChannel};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::time::Duration;
+
+// Identity both peers bind into the symmetric exchange
+const PAKE_IDENTITY: &[u8] = b"modulate-comms/session-password";
+
+// How long to wait for each step of the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Run a password-authenticated key exchange over a freshly opened data channel.
+// Fails if the peer does not know the same password.
+pub async fn authenticate(
+    dc: &SharedDataChannel,
+    incoming: &mut IncomingMessages,
+    password: &str,
+    is_offerer: bool,
+) -> Result<()> {
+    println!("Verifying session password with peer...");
+    let channel = connection::wait_for_open(dc, HANDSHAKE_TIMEOUT).await?;
+
+    // Exchange SPAKE2 messages, both sides send first so there is no ordering to agree on
+    let (state, outbound) = Spake2::<Ed25519Group>::start_symmetric(
+        &Password::new(password.as_bytes()),
+        &Identity::new(PAKE_IDENTITY),
+    );
+    channel
+        .send(&Bytes::from(outbound))
+        .await
+        .context("Failed to send password exchange message")?;
+
+    let inbound = next_frame(incoming).await?;
+    let key = state
+        .finish(&inbound)
+        .map_err(|e| anyhow::anyhow!("Invalid password exchange message from peer: {}", e))?;
+    debug!("Password exchange completed, confirming derived key");
+
+    // Prove we derived the same key without revealing it. The role label keeps
+    // a peer from simply echoing our confirmation back to us.
+    let (local_role, remote_role) = if is_offerer {
+        ("offerer", "answerer")
+    } else {
+        ("answerer", "offerer")
+    };
+    channel
+        .send(&Bytes::from(confirmation(&key, local_role)))
+        .await
+        .context("Failed to send key confirmation")?;
+
+    let remote_confirmation = next_frame(incoming).await?;
+    if remote_confirmation != confirmation(&key, remote_role) {
+        return Err(anyhow::anyhow!(
+            "Session password mismatch: the peer does not know the password"
+        ));
+    }
+
+    info!("Session password verified");
+    println!("Session password verified");
+    Ok(())
+}
+
+// Wait for the next handshake frame from the peer
+async fn next_frame(incoming: &mut IncomingMessages) -> Result<Vec<u8>> {
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, incoming.recv()).await {
+        Ok(Some(msg)) => Ok(msg.data.to_vec()),
+        Ok(None) => Err(anyhow::anyhow!(
+            "Data channel closed during password verification"
+        )),
+        Err(_) => Err(anyhow::anyhow!(
+            "Timed out waiting for the peer to verify the session password"
+        )),
+    }
+}
+
+// Key confirmation value for one side of the exchange
+fn confirmation(key: &[u8], role: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(b"modulate-comms/key-confirmation/");
+    hasher.update(role.as_bytes());
+    hasher.update(key);
+    hasher.finalize().to_vec()
+}
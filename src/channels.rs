@@ -0,0 +1,46 @@
+use crate::cli::ChannelsAction;
+use crate::connection::ChannelSpec;
+use crate::store::Store;
+
+use anyhow::Result;
+
+// Run a `channels` subcommand against the unlocked store
+pub fn run_channels(action: ChannelsAction, store: &mut Store) -> Result<()> {
+    match action {
+        ChannelsAction::List => {
+            println!("Channels opened for each session:");
+            let configured = &store.config().channels;
+            for spec in ChannelSpec::with_overrides(configured) {
+                let source = if configured.iter().any(|c| c.label == spec.label) {
+                    "configured"
+                } else {
+                    "default"
+                };
+                println!("  {} - {} ({})", spec.label, spec.profile, source);
+            }
+        }
+        ChannelsAction::Set { label, profile } => {
+            store.update_config(|config| {
+                match config.channels.iter_mut().find(|c| c.label == label) {
+                    Some(existing) => existing.profile = profile,
+                    None => config.channels.push(ChannelSpec::new(&label, profile)),
+                }
+            })?;
+            println!("Channel '{}' set to {}", label, profile);
+        }
+        ChannelsAction::Remove { label } => {
+            let mut removed = false;
+            store.update_config(|config| {
+                let before = config.channels.len();
+                config.channels.retain(|c| c.label != label);
+                removed = config.channels.len() != before;
+            })?;
+            if !removed {
+                return Err(anyhow::anyhow!("No configured channel '{}'", label));
+            }
+            println!("Removed channel '{}'", label);
+        }
+    }
+
+    Ok(())
+}
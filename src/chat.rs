@@ -1,16 +1,95 @@
+use crate::app::SessionOptions;
+use crate::connection::{
+    DataChannelHandle, IncomingMessages, ReliabilityProfile, SharedDataChannel, MESSAGING_CHANNEL,
+    TYPING_CHANNEL,
+};
+use crate::console::{Console, ConsoleEvent, ConsolePrinter};
+use crate::identity;
+use crate::ping::{self, Pinger};
+use crate::protocol::{self, WireMessage};
+use crate::stats;
+use crate::store::{Conversation, Direction, SharedStore, Store};
+
 use anyhow::Result;
-use std::io::{self, BufRead, Write};
+use log::{debug, error, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Mutex};
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
 use webrtc::data_channel::data_channel_state::RTCDataChannelState;
 use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::RTCPeerConnection;
+
+// Longest display name we accept, ours or the peer's
+pub const MAX_NICK_LEN: usize = 32;
+
+// Minimum gap between typing indicators we send while the user keeps typing
+const TYPING_SEND_INTERVAL: Duration = Duration::from_secs(2);
+
+// How long the peer's typing indicator stays up without a refresh
+const TYPING_DISPLAY_TIMEOUT: Duration = Duration::from_secs(4);
+
+// Pings sent by /ping without a count, and the most it sends
+const DEFAULT_PING_COUNT: usize = 4;
+const MAX_PING_COUNT: usize = 100;
+
+// What we know about the peer on the other end
+pub struct Peer {
+    pub fingerprint: String,
+    // Name from our contact book, if the peer is a contact
+    pub contact_name: Option<String>,
+    // Name the peer announced for itself
+    pub nick: Option<String>,
+}
+
+impl Peer {
+    pub fn new(fingerprint: String, contact_name: Option<String>) -> Self {
+        Self {
+            fingerprint,
+            contact_name,
+            nick: None,
+        }
+    }
+
+    // Name to show on the peer's messages. Our own contact name wins over
+    // whatever the peer calls itself, and announced names of unknown peers
+    // carry the fingerprint so they can't pass for a contact.
+    pub fn label(&self) -> String {
+        let short = identity::short_fingerprint(&self.fingerprint);
+        match (&self.contact_name, &self.nick) {
+            (Some(contact), _) => contact.clone(),
+            (None, Some(nick)) => format!("{} ({})", nick, short),
+            (None, None) => format!("peer {}", short),
+        }
+    }
+}
 
 // Enhanced message loop with more features
-pub async fn enhanced_message_loop(dc: Arc<Mutex<Option<Arc<RTCDataChannel>>>>) -> Result<()> {
-    let stdin = io::stdin();
-    let mut message_history = Vec::new();
-    let mut _history_position = 0;
+pub async fn enhanced_message_loop(
+    pc: Arc<RTCPeerConnection>,
+    messaging: DataChannelHandle,
+    typing: DataChannelHandle,
+    extra: HashMap<String, DataChannelHandle>,
+    store: SharedStore,
+    peer: Peer,
+    options: &SessionOptions,
+) -> Result<()> {
+    let DataChannelHandle {
+        channel: dc,
+        incoming,
+    } = messaging;
+    let conversation = Conversation::new(&peer.fingerprint);
+    let peer = Arc::new(Mutex::new(peer));
+    let mut last_typing_sent: Option<Instant> = None;
+
+    // Session name wins over the configured one
+    let mut local_name = match options.name {
+        Some(ref name) => Some(name.clone()),
+        None => store.lock().await.config().display_name.clone(),
+    };
 
     println!("Waiting for data channel to be ready...");
     let mut is_ready = false;
@@ -50,20 +129,127 @@ pub async fn enhanced_message_loop(dc: Arc<Mutex<Option<Arc<RTCDataChannel>>>>)
         }
     }
 
+    // Let the peer know what to call us
+    if let Some(ref name) = local_name {
+        let announcement = WireMessage::Nick { name: name.clone() };
+        if let Err(e) = send_control(&dc, &announcement).await {
+            warn!("Failed to announce display name: {}", e);
+        }
+    }
+
     println!("\n===== CHAT SESSION STARTED =====");
     println!("Enter messages (or type '/exit' to quit, '/help' for commands):");
 
+    // Merge the extra channels into one queue for the printer, tagged by label
+    let (extra_tx, extra_incoming) = mpsc::unbounded_channel();
+    let mut extra_channels = BTreeMap::new();
+    for (label, handle) in extra {
+        let DataChannelHandle {
+            channel,
+            mut incoming,
+        } = handle;
+        let extra_tx = extra_tx.clone();
+        let tag = label.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = incoming.recv().await {
+                if extra_tx.send((tag.clone(), msg)).is_err() {
+                    break;
+                }
+            }
+        });
+        extra_channels.insert(label, channel);
+    }
+    drop(extra_tx);
+
+    // Pings are answered and pongs timed before the printer sees anything.
+    // Ours go on the unordered typing channel so they don't queue up behind a
+    // file transfer, older peers still ping on the messaging channel.
+    let open_messaging = dc.lock().await.clone();
+    let open_typing = typing.channel.lock().await.clone();
+    let pinger = open_typing
+        .clone()
+        .or_else(|| open_messaging.clone())
+        .map(|open| Arc::new(Pinger::new(open)));
+    let incoming = match open_messaging {
+        Some(open) => ping::intercept(incoming, open, pinger.clone()),
+        None => incoming,
+    };
+    let typing_incoming = match open_typing {
+        Some(open) => ping::intercept(typing.incoming, open, pinger.clone()),
+        None => typing.incoming,
+    };
+
+    let mut console = Console::new();
+    let collapsed = Arc::new(AtomicBool::new(false));
+    let inbound = Inbound {
+        messaging: incoming,
+        typing: typing_incoming,
+        extra: extra_incoming,
+    };
+    let receiver = spawn_message_printer(
+        inbound,
+        Arc::clone(&dc),
+        console.printer(),
+        Arc::clone(&store),
+        conversation.clone(),
+        Arc::clone(&peer),
+        Arc::clone(&collapsed),
+    );
+    let reporter = match options.stats_interval {
+        Some(interval) => {
+            let printer = console.printer();
+            let channels = open_channels(&dc, &typing.channel, &extra_channels).await;
+            Some(stats::spawn_reporter(
+                Arc::clone(&pc),
+                channels,
+                pinger.clone(),
+                interval,
+                move |line| printer.print_line(line),
+            ))
+        }
+        None => None,
+    };
+    let keepalive = pinger.as_ref().map(|pinger| {
+        let printer = console.printer();
+        ping::spawn_keepalive(Arc::clone(pinger), move |line| {
+            let now = chrono::Local::now().format("%H:%M:%S");
+            printer.print_line(&format!("[{}] {}", now, line))
+        })
+    });
+
     loop {
-        print!("> ");
-        io::stdout().flush()?;
+        let input = match console.next_event().await {
+            ConsoleEvent::Line(line) => line,
+            ConsoleEvent::Edited => {
+                // Tell the peer we're typing, at most every couple of seconds
+                let due = last_typing_sent.is_none_or(|t| t.elapsed() >= TYPING_SEND_INTERVAL);
+                if due {
+                    last_typing_sent = Some(Instant::now());
+                    if let Err(e) = send_control(&typing.channel, &WireMessage::Typing).await {
+                        debug!("Typing indicator not sent: {}", e);
+                    }
+                }
+                continue;
+            }
+            ConsoleEvent::Closed => {
+                println!("Exiting chat...");
+                break;
+            }
+        };
+        last_typing_sent = None;
 
-        let mut input = String::new();
-        stdin.lock().read_line(&mut input)?;
-        let input = input.trim().to_string();
+        // Text to send, and the message it replies to when using /reply
+        let mut text = input.clone();
+        let mut reply_to: Option<String> = None;
 
         // Command handling
         if input.starts_with('/') {
-            match input.as_str() {
+            let (command, args) = match input.split_once(' ') {
+                Some((command, args)) => (command, args.trim()),
+                None => (input.as_str(), ""),
+            };
+
+            match command {
                 "/exit" | "/quit" => {
                     println!("Exiting chat...");
                     break;
@@ -73,8 +259,50 @@ pub async fn enhanced_message_loop(dc: Arc<Mutex<Option<Arc<RTCDataChannel>>>>)
                     println!("  /exit, /quit - Exit the chat");
                     println!("  /help       - Show this help message");
                     println!("  /status     - Show connection status");
+                    println!(
+                        "  /stats      - Show the candidate pair, round trip time and traffic"
+                    );
                     println!("  /clear      - Clear the screen");
                     println!("  /history    - Show message history");
+                    println!("  /nick <name> - Change your display name");
+                    println!("  /edit <id> <text> - Edit one of your messages");
+                    println!("  /delete <id> - Delete one of your messages");
+                    println!("  /react <id> <emoji> - React to a message");
+                    println!("  /reply <id> <text> - Reply to a message");
+                    println!("  /thread <id> - Show the thread a message belongs to");
+                    println!("  /collapse, /expand - Summarise or show replies as they arrive");
+                    println!("  /channels   - List data channels and their reliability");
+                    println!("  /send <channel> <text> - Send text on an extra channel");
+                    continue;
+                }
+                "/nick" => {
+                    if args.is_empty() {
+                        match local_name {
+                            Some(ref name) => println!("Your display name is {}", name),
+                            None => println!("No display name set, use /nick <name>"),
+                        }
+                        continue;
+                    }
+                    if args.chars().count() > MAX_NICK_LEN {
+                        println!("Display names can be at most {} characters", MAX_NICK_LEN);
+                        continue;
+                    }
+
+                    let name = args.to_string();
+                    if let Err(e) = store
+                        .lock()
+                        .await
+                        .update_config(|config| config.display_name = Some(name.clone()))
+                    {
+                        error!("Failed to save display name: {}", e);
+                    }
+
+                    let announcement = WireMessage::Nick { name: name.clone() };
+                    match send_control(&dc, &announcement).await {
+                        Ok(_) => println!("You are now known as {}", name),
+                        Err(e) => println!("Display name saved but not announced: {}", e),
+                    }
+                    local_name = Some(name);
                     continue;
                 }
                 "/status" => {
@@ -86,19 +314,211 @@ pub async fn enhanced_message_loop(dc: Arc<Mutex<Option<Arc<RTCDataChannel>>>>)
                     }
                     continue;
                 }
+                "/stats" => {
+                    let channels = open_channels(&dc, &typing.channel, &extra_channels).await;
+                    for line in stats::collect(&pc, &channels, pinger.as_deref())
+                        .await
+                        .lines()
+                    {
+                        println!("{}", line);
+                    }
+                    continue;
+                }
+                "/ping" => {
+                    let count = match args {
+                        "" => DEFAULT_PING_COUNT,
+                        count => match count.parse() {
+                            Ok(count) if (1..=MAX_PING_COUNT).contains(&count) => count,
+                            _ => {
+                                println!(
+                                    "Usage: /ping [count], with up to {} pings",
+                                    MAX_PING_COUNT
+                                );
+                                continue;
+                            }
+                        },
+                    };
+                    let Some(ref pinger) = pinger else {
+                        println!("The messaging channel is not open");
+                        continue;
+                    };
+
+                    // In the background, so chat goes on while it runs
+                    let pinger = Arc::clone(pinger);
+                    let printer = console.printer();
+                    let label = peer.lock().await.label();
+                    tokio::spawn(async move {
+                        ping::run(&pinger, count, &label, |line| printer.print_line(line)).await
+                    });
+                    continue;
+                }
+                "/channels" => {
+                    println!("Data channels:");
+                    print_channel(MESSAGING_CHANNEL, &dc).await;
+                    print_channel(TYPING_CHANNEL, &typing.channel).await;
+                    for (label, channel) in &extra_channels {
+                        print_channel(label, channel).await;
+                    }
+                    continue;
+                }
+                "/send" => {
+                    let Some((label, text)) = args.split_once(' ') else {
+                        println!("Usage: /send <channel> <text>");
+                        continue;
+                    };
+                    let Some(channel) = extra_channels.get(label) else {
+                        println!("No extra channel '{}' (see /channels)", label);
+                        continue;
+                    };
+
+                    let open = channel.lock().await.clone();
+                    match open {
+                        Some(data_channel)
+                            if data_channel.ready_state() == RTCDataChannelState::Open =>
+                        {
+                            if let Err(e) = data_channel.send_text(text.trim().to_string()).await {
+                                error!("Failed to send on '{}': {}", label, e);
+                            }
+                        }
+                        _ => println!("Channel '{}' is not open", label),
+                    }
+                    continue;
+                }
                 "/clear" => {
                     // Clear screen with ANSI escape codes (might not work on all terminals)
                     print!("\x1B[2J\x1B[1;1H");
                     continue;
                 }
                 "/history" => {
-                    if message_history.is_empty() {
-                        println!("No message history yet");
-                    } else {
-                        println!("Message history:");
-                        for (i, msg) in message_history.iter().enumerate() {
-                            println!("  {}. {}", i + 1, msg);
+                    print_history(&*store.lock().await);
+                    continue;
+                }
+                "/edit" => {
+                    let Some((id, text)) = args.split_once(' ') else {
+                        println!("Usage: /edit <id> <new text>");
+                        continue;
+                    };
+                    let id = id.trim_start_matches('#');
+                    let text = text.trim();
+
+                    let edited =
+                        store
+                            .lock()
+                            .await
+                            .edit_message(&conversation, id, Direction::Sent, text);
+                    match edited {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            println!("No message #{} of yours to edit", id);
+                            continue;
                         }
+                        Err(e) => error!("Failed to save message history: {}", e),
+                    }
+
+                    let edit = WireMessage::Edit {
+                        id: id.to_string(),
+                        text: text.to_string(),
+                    };
+                    match send_control(&dc, &edit).await {
+                        Ok(_) => println!("(Edited #{})", id),
+                        Err(e) => println!("Error sending edit: {}", e),
+                    }
+                    continue;
+                }
+                "/delete" => {
+                    let id = args.trim_start_matches('#');
+                    if id.is_empty() {
+                        println!("Usage: /delete <id>");
+                        continue;
+                    }
+
+                    let deleted =
+                        store
+                            .lock()
+                            .await
+                            .delete_message(&conversation, id, Direction::Sent);
+                    match deleted {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            println!("No message #{} of yours to delete", id);
+                            continue;
+                        }
+                        Err(e) => error!("Failed to save message history: {}", e),
+                    }
+
+                    let delete = WireMessage::Delete { id: id.to_string() };
+                    match send_control(&dc, &delete).await {
+                        Ok(_) => println!("(Deleted #{})", id),
+                        Err(e) => println!("Error sending deletion: {}", e),
+                    }
+                    continue;
+                }
+                "/reply" => {
+                    let Some((id, reply)) = args.split_once(' ') else {
+                        println!("Usage: /reply <id> <text>");
+                        continue;
+                    };
+                    let id = id.trim_start_matches('#');
+                    if store.lock().await.message(&conversation, id).is_none() {
+                        println!("No message #{} in this session to reply to", id);
+                        continue;
+                    }
+
+                    // Fall through and send it like any other message
+                    text = reply.trim().to_string();
+                    reply_to = Some(id.to_string());
+                }
+                "/thread" => {
+                    let id = args.trim_start_matches('#');
+                    if id.is_empty() {
+                        println!("Usage: /thread <id>");
+                        continue;
+                    }
+                    print_thread(&*store.lock().await, &conversation, id);
+                    continue;
+                }
+                "/collapse" => {
+                    collapsed.store(true, Ordering::SeqCst);
+                    println!(
+                        "Threads collapsed, replies are summarised (use /thread <id> to read them)"
+                    );
+                    continue;
+                }
+                "/expand" => {
+                    collapsed.store(false, Ordering::SeqCst);
+                    println!("Threads expanded, replies are shown in full");
+                    continue;
+                }
+                "/react" => {
+                    let Some((id, emoji)) = args.split_once(' ') else {
+                        println!("Usage: /react <id> <emoji>");
+                        continue;
+                    };
+                    let id = id.trim_start_matches('#');
+                    let emoji = emoji.trim();
+
+                    let reacted = store.lock().await.react_to_message(
+                        &conversation,
+                        id,
+                        Direction::Sent,
+                        emoji,
+                    );
+                    match reacted {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            println!("No message #{} to react to", id);
+                            continue;
+                        }
+                        Err(e) => error!("Failed to save message history: {}", e),
+                    }
+
+                    let react = WireMessage::React {
+                        id: id.to_string(),
+                        emoji: emoji.to_string(),
+                    };
+                    match send_control(&dc, &react).await {
+                        Ok(_) => println!("(Reacted {} to #{})", emoji, id),
+                        Err(e) => println!("Error sending reaction: {}", e),
                     }
                     continue;
                 }
@@ -113,25 +533,42 @@ pub async fn enhanced_message_loop(dc: Arc<Mutex<Option<Arc<RTCDataChannel>>>>)
         }
 
         // Skip empty messages
-        if input.is_empty() {
+        if text.is_empty() {
             continue;
         }
 
         // Add to history
-        message_history.push(input.clone());
-        _history_position = message_history.len();
+        let id = protocol::new_message_id();
+        let peer_label = peer.lock().await.label();
+        {
+            let mut store = store.lock().await;
+            if let Some(ref parent) = reply_to {
+                println!("{}", quote_parent(&store, &conversation, parent));
+            }
+            if let Err(e) = store.record(
+                &conversation,
+                Direction::Sent,
+                &peer_label,
+                &id,
+                &text,
+                reply_to.as_deref(),
+            ) {
+                error!("Failed to save message history: {}", e);
+            }
+        }
+        let message = WireMessage::Chat {
+            id: id.clone(),
+            text: text.clone(),
+            reply_to,
+        };
 
         let dc_lock = dc.lock().await;
         if let Some(ref data_channel) = *dc_lock {
             if data_channel.ready_state() == RTCDataChannelState::Open {
-                // Add timestamp to messages
-                let timestamp = chrono::Local::now().format("%H:%M:%S");
-                let formatted_message = format!("[{}] {}", timestamp, input);
-
-                match data_channel.send_text(formatted_message).await {
+                match message.send(data_channel).await {
                     Ok(_) => {
                         // Print confirmation
-                        println!("(Message sent)");
+                        println!("(Message sent as #{})", id);
                     }
                     Err(e) => {
                         println!("Error sending message: {}", e);
@@ -156,10 +593,7 @@ pub async fn enhanced_message_loop(dc: Arc<Mutex<Option<Arc<RTCDataChannel>>>>)
                 let dc_lock = dc.lock().await;
                 if let Some(ref data_channel) = *dc_lock {
                     if data_channel.ready_state() == RTCDataChannelState::Open {
-                        let timestamp = chrono::Local::now().format("%H:%M:%S");
-                        let formatted_message = format!("[{}] {}", timestamp, input);
-
-                        match data_channel.send_text(formatted_message).await {
+                        match message.send(data_channel).await {
                             Ok(_) => println!("Message sent on retry"),
                             Err(e) => println!("Error sending message on retry: {}", e),
                         }
@@ -177,5 +611,374 @@ pub async fn enhanced_message_loop(dc: Arc<Mutex<Option<Arc<RTCDataChannel>>>>)
         }
     }
 
+    receiver.abort();
+    if let Some(reporter) = reporter {
+        reporter.abort();
+    }
+    if let Some(keepalive) = keepalive {
+        keepalive.abort();
+    }
     Ok(())
 }
+
+// Print the history as an audit trail, including edits, deletions and reactions
+fn print_history(store: &Store) {
+    if store.history().is_empty() {
+        println!("No message history yet");
+        return;
+    }
+
+    if store.is_persistent() {
+        println!("Message history (encrypted on disk):");
+    } else {
+        println!("Message history (this session only):");
+    }
+
+    for (i, entry) in store.history().iter().enumerate() {
+        let who = match entry.direction {
+            Direction::Sent => "You",
+            Direction::Received => entry.peer.as_str(),
+        };
+        let id = if entry.id.is_empty() {
+            String::new()
+        } else {
+            format!(" #{}", entry.id)
+        };
+        println!(
+            "  {}. [{}]{} {}: {}",
+            i + 1,
+            entry.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            id,
+            who,
+            entry.text
+        );
+
+        if let Some(ref parent) = entry.reply_to {
+            println!("       reply to #{}", parent);
+        }
+        for revision in &entry.revisions {
+            println!(
+                "       edited [{}]: {}",
+                revision.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                revision.text
+            );
+        }
+        if let Some(deleted_at) = entry.deleted_at {
+            println!(
+                "       deleted [{}]",
+                deleted_at.format("%Y-%m-%d %H:%M:%S")
+            );
+        }
+        if !entry.reactions.is_empty() {
+            let reactions: Vec<String> = entry
+                .reactions
+                .iter()
+                .map(|r| match r.by {
+                    Direction::Sent => format!("{} (you)", r.emoji),
+                    Direction::Received => format!("{} ({})", r.emoji, entry.peer),
+                })
+                .collect();
+            println!("       reactions: {}", reactions.join(", "));
+        }
+    }
+}
+
+// One-line quote of the message being replied to
+fn quote_parent(store: &Store, conversation: &Conversation, parent: &str) -> String {
+    const SNIPPET_LEN: usize = 40;
+
+    match store.message(conversation, parent) {
+        Some(entry) => {
+            let who = match entry.direction {
+                Direction::Sent => "You",
+                Direction::Received => entry.peer.as_str(),
+            };
+            let snippet = if entry.deleted_at.is_some() {
+                "[deleted]".to_string()
+            } else {
+                let text = entry.current_text();
+                let mut snippet: String = text.chars().take(SNIPPET_LEN).collect();
+                if text.chars().count() > SNIPPET_LEN {
+                    snippet.push('…');
+                }
+                format!("\"{}\"", snippet)
+            };
+            format!("  ↪ re #{} {}: {}", parent, who, snippet)
+        }
+        None => format!("  ↪ re #{} (not in this session)", parent),
+    }
+}
+
+// Print a whole thread, indenting replies under the message they answer
+fn print_thread(store: &Store, conversation: &Conversation, id: &str) {
+    if store.message(conversation, id).is_none() {
+        println!("No message #{} in this session", id);
+        return;
+    }
+
+    let root = store.thread_root(conversation, id);
+    println!("Thread #{}:", root);
+    for (depth, entry) in store.thread(conversation, root) {
+        let who = match entry.direction {
+            Direction::Sent => "You",
+            Direction::Received => entry.peer.as_str(),
+        };
+        let text = if entry.deleted_at.is_some() {
+            "[deleted]"
+        } else {
+            entry.current_text()
+        };
+        println!(
+            "{}[{}] #{} {}: {}",
+            "  ".repeat(depth + 1),
+            entry.timestamp.format("%H:%M:%S"),
+            entry.id,
+            who,
+            text
+        );
+    }
+}
+
+// One line of /channels output
+async fn print_channel(label: &str, channel: &SharedDataChannel) {
+    match *channel.lock().await {
+        Some(ref dc) => println!(
+            "  {} - {} - {:?}",
+            label,
+            ReliabilityProfile::of(dc),
+            dc.ready_state()
+        ),
+        None => println!("  {} - not opened by the peer yet", label),
+    }
+}
+
+// The chat's channels that are open, for their stats
+async fn open_channels(
+    dc: &SharedDataChannel,
+    typing: &SharedDataChannel,
+    extra: &BTreeMap<String, SharedDataChannel>,
+) -> Vec<Arc<RTCDataChannel>> {
+    let mut channels = Vec::new();
+    for channel in [dc, typing].into_iter().chain(extra.values()) {
+        if let Some(ref channel) = *channel.lock().await {
+            channels.push(Arc::clone(channel));
+        }
+    }
+    channels
+}
+
+// Send a control message if the data channel is open
+async fn send_control(dc: &SharedDataChannel, message: &WireMessage) -> Result<()> {
+    let dc_lock = dc.lock().await;
+    match *dc_lock {
+        Some(ref data_channel) if data_channel.ready_state() == RTCDataChannelState::Open => {
+            message.send(data_channel).await
+        }
+        _ => Err(anyhow::anyhow!("Data channel is not open")),
+    }
+}
+
+// What the peer sends, by channel
+struct Inbound {
+    messaging: IncomingMessages,
+    typing: IncomingMessages,
+    // Extra channels, with the label each message came in on
+    extra: mpsc::UnboundedReceiver<(String, DataChannelMessage)>,
+}
+
+// Print incoming messages and typing indicators as they arrive
+fn spawn_message_printer(
+    inbound: Inbound,
+    dc: SharedDataChannel,
+    console: ConsolePrinter,
+    store: SharedStore,
+    conversation: Conversation,
+    peer: Arc<Mutex<Peer>>,
+    collapsed: Arc<AtomicBool>,
+) -> tokio::task::JoinHandle<()> {
+    let Inbound {
+        messaging: mut incoming,
+        mut typing,
+        mut extra,
+    } = inbound;
+    tokio::spawn(async move {
+        let mut typing_until: Option<Instant> = None;
+        let mut tick = tokio::time::interval(Duration::from_millis(500));
+
+        loop {
+            tokio::select! {
+                msg = incoming.recv() => {
+                    let Some(msg) = msg else { break };
+                    // File data, which only the daemon saves
+                    if !msg.is_string {
+                        continue;
+                    }
+                    match WireMessage::decode(&msg.data) {
+                        Ok(WireMessage::Chat { id, text, reply_to }) => {
+                            let peer_label = peer.lock().await.label();
+
+                            // A message ends the typing indicator
+                            typing_until = None;
+                            console.set_status(None);
+
+                            let mut store = store.lock().await;
+                            if let Err(e) = store.record(
+                                &conversation,
+                                Direction::Received,
+                                &peer_label,
+                                &id,
+                                &text,
+                                reply_to.as_deref(),
+                            ) {
+                                error!("Failed to save message history: {}", e);
+                            }
+
+                            // Enhanced message display with timestamp and formatting
+                            let now = chrono::Local::now().format("%H:%M:%S");
+                            match reply_to {
+                                Some(_) if collapsed.load(Ordering::SeqCst) => {
+                                    let root = store.thread_root(&conversation, &id);
+                                    console.print_line(&format!(
+                                        "[{}] * {} replied in thread #{} (/thread {})",
+                                        now, peer_label, root, root
+                                    ));
+                                }
+                                Some(ref parent) => {
+                                    console.print_line(&quote_parent(&store, &conversation, parent));
+                                    console.print_line(&format!(
+                                        "[{}] #{} {}: {}",
+                                        now, id, peer_label, text
+                                    ));
+                                }
+                                None => {
+                                    console.print_line(&format!(
+                                        "[{}] #{} {}: {}",
+                                        now, id, peer_label, text
+                                    ));
+                                }
+                            }
+                        }
+                        Ok(WireMessage::Edit { id, text }) => {
+                            let peer_label = peer.lock().await.label();
+                            let edited = store.lock().await.edit_message(&conversation, &id, Direction::Received, &text);
+                            let now = chrono::Local::now().format("%H:%M:%S");
+                            match edited {
+                                Ok(true) => console.print_line(&format!(
+                                    "[{}] * {} edited #{}: {}",
+                                    now, peer_label, id, text
+                                )),
+                                Ok(false) => warn!("Peer edited unknown message #{}", id),
+                                Err(e) => error!("Failed to save message history: {}", e),
+                            }
+                        }
+                        Ok(WireMessage::Delete { id }) => {
+                            let peer_label = peer.lock().await.label();
+                            let deleted = store.lock().await.delete_message(&conversation, &id, Direction::Received);
+                            let now = chrono::Local::now().format("%H:%M:%S");
+                            match deleted {
+                                Ok(true) => console.print_line(&format!(
+                                    "[{}] * {} deleted #{}",
+                                    now, peer_label, id
+                                )),
+                                Ok(false) => warn!("Peer deleted unknown message #{}", id),
+                                Err(e) => error!("Failed to save message history: {}", e),
+                            }
+                        }
+                        Ok(WireMessage::React { id, emoji }) => {
+                            let peer_label = peer.lock().await.label();
+                            let reacted = store
+                                .lock()
+                                .await
+                                .react_to_message(&conversation, &id, Direction::Received, &emoji);
+                            let now = chrono::Local::now().format("%H:%M:%S");
+                            match reacted {
+                                Ok(true) => console.print_line(&format!(
+                                    "[{}] * {} reacted {} to #{}",
+                                    now, peer_label, emoji, id
+                                )),
+                                Ok(false) => warn!("Peer reacted to unknown message #{}", id),
+                                Err(e) => error!("Failed to save message history: {}", e),
+                            }
+                        }
+                        Ok(WireMessage::Nick { name }) => {
+                            let name: String = name.trim().chars().take(MAX_NICK_LEN).collect();
+                            if name.is_empty() {
+                                continue;
+                            }
+
+                            let mut peer = peer.lock().await;
+                            let previous = peer.nick.replace(name.clone());
+                            if previous.as_deref() != Some(name.as_str()) {
+                                let now = chrono::Local::now().format("%H:%M:%S");
+                                match previous {
+                                    Some(previous) => console.print_line(&format!(
+                                        "[{}] * {} is now known as {}",
+                                        now, previous, name
+                                    )),
+                                    None => console.print_line(&format!(
+                                        "[{}] * Peer goes by {}",
+                                        now,
+                                        peer.label()
+                                    )),
+                                }
+                            }
+                        }
+                        Ok(WireMessage::FileStart { id, name, size }) => {
+                            let peer_label = peer.lock().await.label();
+                            let now = chrono::Local::now().format("%H:%M:%S");
+                            console.print_line(&format!(
+                                "[{}] * {} offered {} ({} bytes), but only the daemon can receive files",
+                                now, peer_label, name, size
+                            ));
+                            // Answered at once, so the sender doesn't wait for nothing
+                            let reject = WireMessage::FileReject {
+                                id,
+                                reason: "Only the daemon can receive files".to_string(),
+                            };
+                            if let Some(open) = dc.lock().await.clone() {
+                                if let Err(e) = reject.send(&open).await {
+                                    debug!("File rejection not sent: {}", e);
+                                }
+                            }
+                        }
+                        Ok(WireMessage::FileEnd { .. }) => {}
+                        Ok(other) => {
+                            warn!("Ignoring unexpected message from peer: {:?}", other);
+                        }
+                        Err(e) => {
+                            warn!("{}", e);
+                            console.print_line("Received a message that could not be read");
+                        }
+                    }
+                }
+                // A closed channel disables the branch rather than firing forever
+                Some(msg) = typing.recv() => {
+                    if let Ok(WireMessage::Typing) = WireMessage::decode(&msg.data) {
+                        typing_until = Some(Instant::now() + TYPING_DISPLAY_TIMEOUT);
+                        let label = peer.lock().await.label();
+                        console.set_status(Some(format!("{} is typing…", label)));
+                    }
+                }
+                Some((label, msg)) = extra.recv() => {
+                    // Extra channels carry plain text for whoever configured them
+                    let peer_label = peer.lock().await.label();
+                    let now = chrono::Local::now().format("%H:%M:%S");
+                    console.print_line(&format!(
+                        "[{}] [{}] {}: {}",
+                        now,
+                        label,
+                        peer_label,
+                        String::from_utf8_lossy(&msg.data)
+                    ));
+                }
+                _ = tick.tick() => {
+                    // Drop the indicator once the peer stops typing
+                    if typing_until.is_some_and(|until| Instant::now() >= until) {
+                        typing_until = None;
+                        console.set_status(None);
+                    }
+                }
+            }
+        }
+    })
+}
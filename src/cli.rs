@@ -1,4 +1,9 @@
-use clap::{Parser, Subcommand};
+use crate::connection::{ChannelSpec, ReliabilityProfile};
+use crate::simulate::NatKind;
+use crate::tunnel::AllowRule;
+
+use clap::{ArgGroup, Parser, Subcommand};
+use std::path::PathBuf;
 
 // CLI configuration
 #[derive(Parser)]
@@ -14,14 +19,323 @@ pub struct Cli {
     /// Verbose logging
     #[arg(short, long)]
     pub verbose: bool,
+
+    /// Don't read or write history and config on disk
+    #[arg(long)]
+    pub no_persist: bool,
+
+    /// Directory for the encrypted history and config
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+
+    /// Display name shown to peers (defaults to the one set with /nick)
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Open an extra data channel, or change a built-in one's profile, as
+    /// <label>[=<profile>], reliable if no profile is given (repeatable, see
+    /// `channels --help`)
+    #[arg(long = "channel", value_name = "LABEL[=PROFILE]")]
+    pub channels: Vec<ChannelSpec>,
+
+    /// Print connection stats every this many seconds during a session, as
+    /// /stats does in a chat
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub stats_interval: Option<u64>,
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
     /// Start as offerer (initiates the connection)
-    Offer,
+    Offer {
+        /// Shared session password, verified with the peer before chatting
+        #[arg(long)]
+        password: Option<String>,
+
+        /// Only accept this contact as the peer
+        #[arg(long)]
+        to: Option<String>,
+
+        /// Exchange the offer and answer through this rendezvous server
+        /// (e.g. http://host:7878) instead of copy/paste, and print a short
+        /// code such as 7-crossword-banana for the peer
+        #[arg(long, value_name = "URL")]
+        rendezvous: Option<String>,
+    },
     /// Start as answerer (waits for an offer)
-    Answer,
+    Answer {
+        /// Shared session password, verified with the peer before chatting
+        #[arg(long)]
+        password: Option<String>,
+
+        /// Only accept this contact as the peer
+        #[arg(long)]
+        to: Option<String>,
+
+        /// Exchange the offer and answer through this rendezvous server
+        /// instead of copy/paste
+        #[arg(long, value_name = "URL", requires = "code")]
+        rendezvous: Option<String>,
+
+        /// Code printed by the offerer, such as 7-crossword-banana
+        #[arg(requires = "rendezvous")]
+        code: Option<String>,
+    },
+    /// Send stdin to the peer and write what it sends to stdout, like netcat
+    ///
+    /// Only the peer's data is written to stdout; progress and errors go to
+    /// stderr. The end of stdin closes the peer's stdout, and the session ends
+    /// once both sides are done. Stdin carries the data, so the offer and
+    /// answer go through a rendezvous server.
+    #[command(group(ArgGroup::new("role").required(true).args(["offer", "answer"])))]
+    Pipe {
+        /// Make the offer and print a code for the peer
+        #[arg(long)]
+        offer: bool,
+
+        /// Answer the offer with the code from the peer
+        #[arg(long, requires = "code")]
+        answer: bool,
+
+        /// Shared session password, verified with the peer before any data
+        #[arg(long)]
+        password: Option<String>,
+
+        /// Only accept this contact as the peer
+        #[arg(long)]
+        to: Option<String>,
+
+        /// Rendezvous server to exchange the offer and answer through
+        #[arg(long, value_name = "URL")]
+        rendezvous: String,
+
+        /// Code printed by the offering side, such as 7-crossword-banana
+        #[arg(requires = "answer")]
+        code: Option<String>,
+    },
+    /// Forward local TCP connections or UDP datagrams to a service on the
+    /// peer's side
+    ///
+    /// The entry side listens on --local and has the peer connect to --remote
+    /// for each connection it accepts, each carried on a data channel of its
+    /// own. With --udp, datagrams from every local client share one channel
+    /// that doesn't retransmit, and each client gets its own socket on the
+    /// peer's side. The peer runs with --exit and only connects to addresses
+    /// it allows with --allow.
+    #[command(group(ArgGroup::new("role").required(true).args(["offer", "answer"])))]
+    #[command(group(ArgGroup::new("side").required(true).args(["local", "exit"])))]
+    Tunnel {
+        /// Make the offer
+        #[arg(long)]
+        offer: bool,
+
+        /// Answer the peer's offer
+        #[arg(long)]
+        answer: bool,
+
+        /// Address to accept connections on, e.g. 127.0.0.1:8080
+        #[arg(long, value_name = "ADDR", requires = "remote")]
+        local: Option<std::net::SocketAddr>,
+
+        /// Where the peer connects to for each connection, e.g. 127.0.0.1:80
+        #[arg(long, value_name = "HOST:PORT", requires = "local")]
+        remote: Option<String>,
+
+        /// Carry UDP datagrams sent to --local instead of TCP connections
+        #[arg(long, requires = "local")]
+        udp: bool,
+
+        /// Reliability profile of the UDP tunnel's channel (see `channels
+        /// --help`)
+        #[arg(long, default_value = "retransmits:0", requires = "udp")]
+        profile: ReliabilityProfile,
+
+        /// Connect to services for the peer instead
+        #[arg(long, requires = "allow")]
+        exit: bool,
+
+        /// Destination the peer may have us connect to (repeatable). The host
+        /// can be a name, *.domain, an address, a network such as 10.0.0.0/8
+        /// or [fd00::/8], or *; the port a number, a range such as 8000-8100,
+        /// or *
+        #[arg(long = "allow", value_name = "HOST:PORT", requires = "exit")]
+        allow: Vec<AllowRule>,
+
+        /// Shared session password, verified with the peer before any data
+        #[arg(long)]
+        password: Option<String>,
+
+        /// Only accept this contact as the peer
+        #[arg(long)]
+        to: Option<String>,
+
+        /// Exchange the offer and answer through this rendezvous server
+        /// instead of copy/paste
+        #[arg(long, value_name = "URL")]
+        rendezvous: Option<String>,
+
+        /// Code printed by the offering side, such as 7-crossword-banana
+        #[arg(requires_all = ["answer", "rendezvous"])]
+        code: Option<String>,
+    },
+    /// Run a SOCKS5 proxy whose connections are made from the peer's side
+    ///
+    /// Each CONNECT is carried to the peer, which runs `tunnel --exit` and
+    /// only connects to the destinations it allows with --allow.
+    #[command(group(ArgGroup::new("role").required(true).args(["offer", "answer"])))]
+    Socks {
+        /// Make the offer
+        #[arg(long)]
+        offer: bool,
+
+        /// Answer the peer's offer
+        #[arg(long)]
+        answer: bool,
+
+        /// Address to accept SOCKS clients on
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:1080")]
+        listen: std::net::SocketAddr,
+
+        /// Shared session password, verified with the peer before any data
+        #[arg(long)]
+        password: Option<String>,
+
+        /// Only accept this contact as the peer
+        #[arg(long)]
+        to: Option<String>,
+
+        /// Exchange the offer and answer through this rendezvous server
+        /// instead of copy/paste
+        #[arg(long, value_name = "URL")]
+        rendezvous: Option<String>,
+
+        /// Code printed by the offering side, such as 7-crossword-banana
+        #[arg(requires_all = ["answer", "rendezvous"])]
+        code: Option<String>,
+    },
+    /// Find peers on the local network and connect without copy/paste
+    ///
+    /// Peers announce their name and identity by UDP broadcast and the one
+    /// picked from the list is sent the offer over TCP. Works without an
+    /// internet connection.
+    Discover {
+        /// Shared session password, verified with the peer before chatting
+        #[arg(long)]
+        password: Option<String>,
+
+        /// Only accept this contact as the peer
+        #[arg(long)]
+        to: Option<String>,
+
+        /// UDP port to announce on, the same for every peer
+        #[arg(long, default_value_t = crate::discovery::DISCOVERY_PORT)]
+        port: u16,
+    },
+    /// Run a rendezvous server for `offer --rendezvous`
+    ///
+    /// Peers leave messages for each other in a mailbox named by a small
+    /// number, until the offerer closes it or it expires. Everything but the
+    /// key exchange is encrypted, and nothing is kept on disk.
+    Rendezvous {
+        /// Address to listen on
+        #[arg(long, default_value = "0.0.0.0:7878")]
+        listen: std::net::SocketAddr,
+
+        /// Seconds a mailbox is kept
+        #[arg(long, default_value = "600")]
+        ttl: u64,
+    },
+    /// Keep peer connections in the background, controlled over a local socket
+    ///
+    /// Speaks JSON-RPC 2.0, one request per line, on a Unix socket only you
+    /// can open. Methods: connect, list_sessions, send_message, send_file,
+    /// accept_file, decline_file, subscribe and close. Peers connect through
+    /// a rendezvous server.
+    Daemon {
+        /// Socket to listen on (defaults to modulate-comms.sock in the runtime
+        /// directory)
+        #[arg(long)]
+        socket: Option<PathBuf>,
+
+        /// Directory to save received files in (defaults to the downloads
+        /// directory)
+        #[arg(long)]
+        downloads: Option<PathBuf>,
+
+        /// Refuse files larger than this many MiB without asking
+        #[arg(long, value_name = "MIB", default_value_t = crate::transfer::DEFAULT_MAX_FILE_MIB)]
+        max_file_size: u64,
+    },
+    /// Manage saved peers
+    Contacts {
+        #[command(subcommand)]
+        action: ContactsAction,
+    },
+    /// Manage the data channels opened for each session
+    ///
+    /// Profiles: `reliable` (the default, and what chat uses),
+    /// `reliable-unordered`, `retransmits:<n>` and `lifetime:<ms>`. The last two
+    /// give up on a message after n retransmissions or once it is ms old, and
+    /// are unordered unless followed by `,ordered`. Partial reliability is only
+    /// ever used when asked for.
+    Channels {
+        #[command(subcommand)]
+        action: ChannelsAction,
+    },
+    /// Check the network: interfaces, ICE servers and the NAT in front of you
+    ///
+    /// Gathers candidates against each STUN and TURN server, works out how
+    /// the NAT maps and filters traffic, and says whether peers can connect
+    /// directly or need a TURN relay. Checks the default servers unless told
+    /// otherwise.
+    Doctor {
+        /// Check the ICE servers saved for this contact
+        #[arg(long, conflicts_with_all = ["stun", "turn"])]
+        to: Option<String>,
+        /// STUN server to check instead of the defaults (repeatable)
+        #[arg(long)]
+        stun: Vec<String>,
+        /// TURN server to check (repeatable)
+        #[arg(long)]
+        turn: Vec<String>,
+        /// TURN username
+        #[arg(long)]
+        turn_username: Option<String>,
+        /// TURN credential
+        #[arg(long)]
+        turn_credential: Option<String>,
+    },
+    /// Run two peers over a simulated network and report how they fare
+    #[command(hide = true)] // Development tool for reproducing network problems
+    Simulate {
+        /// One-way latency in milliseconds
+        #[arg(long, default_value = "0")]
+        latency: u64,
+        /// Random extra delay of up to this many milliseconds
+        #[arg(long, default_value = "0")]
+        jitter: u64,
+        /// Percentage of packets to drop
+        #[arg(long, default_value = "0", value_parser = clap::value_parser!(u32).range(0..=100))]
+        loss: u32,
+        /// Link capacity in kbit/s, packets over it are dropped
+        #[arg(long)]
+        bandwidth: Option<u32>,
+        /// NAT in front of both peers
+        #[arg(long, value_enum, default_value = "none")]
+        nat: NatKind,
+        /// Offer a TURN relay on the simulated network
+        #[arg(long)]
+        relay: bool,
+        /// Chat messages to send once connected
+        #[arg(long, default_value = "100")]
+        messages: usize,
+        /// Size of each message in bytes
+        #[arg(long, default_value = "64")]
+        size: usize,
+        /// Reliability profile of the chat channel
+        #[arg(long, default_value = "reliable")]
+        profile: ReliabilityProfile,
+    },
     /// Create a group chat (experimental)
     #[command(hide = true)] // Hide this experimental feature
     Group {
@@ -30,3 +344,55 @@ pub enum Commands {
         max_peers: usize,
     },
 }
+
+#[derive(Subcommand)]
+pub enum ContactsAction {
+    /// Save a peer's identity key under a name
+    Add {
+        /// Name to refer to the contact by
+        name: String,
+        /// The peer's identity key (from `contacts whoami`)
+        identity: String,
+        /// Name shown in the chat instead of the contact name
+        #[arg(long)]
+        display_name: Option<String>,
+        /// STUN server to use with this contact (repeatable)
+        #[arg(long)]
+        stun: Vec<String>,
+        /// TURN server to use with this contact (repeatable)
+        #[arg(long)]
+        turn: Vec<String>,
+        /// TURN username
+        #[arg(long)]
+        turn_username: Option<String>,
+        /// TURN credential
+        #[arg(long)]
+        turn_credential: Option<String>,
+        /// Only connect to this contact through TURN relays
+        #[arg(long)]
+        relay_only: bool,
+    },
+    /// List saved contacts
+    List,
+    /// Remove a contact
+    Remove { name: String },
+    /// Rename a contact
+    Rename { name: String, new_name: String },
+    /// Show your own identity key
+    Whoami,
+}
+
+#[derive(Subcommand)]
+pub enum ChannelsAction {
+    /// Show the channels every session opens
+    List,
+    /// Add a channel, or change the profile of an existing one
+    Set {
+        /// Channel label, the peer must configure the same one
+        label: String,
+        /// Reliability profile, e.g. reliable or lifetime:500
+        profile: ReliabilityProfile,
+    },
+    /// Remove a configured channel, restoring the default for built-in ones
+    Remove { label: String },
+}
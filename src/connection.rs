@@ -1,23 +1,73 @@
 use anyhow::Result;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
 use std::io::{self, Write};
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::MediaEngine;
+use webrtc::api::setting_engine::SettingEngine;
 use webrtc::api::APIBuilder;
 use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
 use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice::candidate::CandidatePairState;
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::interceptor;
 use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
 use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::stats::{ICECandidatePairStats, ICECandidateStats, StatsReportType};
+
+// STUN used when nothing else is configured
+const DEFAULT_STUN_SERVERS: [&str; 5] = [
+    "stun:stun.l.google.com:19302",
+    "stun:stun1.l.google.com:19302",
+    "stun:stun2.l.google.com:19302",
+    "stun:stun3.l.google.com:19302",
+    "stun:stun4.l.google.com:19302",
+];
+
+// ICE servers and policy for a peer connection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IceSettings {
+    pub stun_servers: Vec<String>,
+    pub turn_servers: Vec<String>,
+    pub turn_username: String,
+    pub turn_credential: String,
+    // Only use relayed candidates, hiding our addresses from the peer
+    pub relay_only: bool,
+}
+
+impl Default for IceSettings {
+    fn default() -> Self {
+        Self {
+            stun_servers: DEFAULT_STUN_SERVERS.iter().map(|s| s.to_string()).collect(),
+            turn_servers: Vec::new(),
+            turn_username: String::new(),
+            turn_credential: String::new(),
+            relay_only: false,
+        }
+    }
+}
 
 // Create and configure a new peer connection
-pub async fn create_peer_connection(use_turn: bool) -> Result<Arc<RTCPeerConnection>> {
+pub async fn create_peer_connection(ice: &IceSettings) -> Result<Arc<RTCPeerConnection>> {
+    create_peer_connection_with(ice, SettingEngine::default()).await
+}
+
+// Create a peer connection with custom engine settings, e.g. a virtual network
+pub async fn create_peer_connection_with(
+    ice: &IceSettings,
+    settings: SettingEngine,
+) -> Result<Arc<RTCPeerConnection>> {
     // Create a MediaEngine object to configure the supported codec
     let mut m = MediaEngine::default();
 
@@ -28,32 +78,32 @@ pub async fn create_peer_connection(use_turn: bool) -> Result<Arc<RTCPeerConnect
     let registry = register_default_interceptors(registry, &mut m)?;
 
     // Enhanced ICE server configuration with fallbacks
-    let mut ice_servers = vec![RTCIceServer {
-        urls: vec![
-            "stun:stun.l.google.com:19302".to_owned(),
-            "stun:stun1.l.google.com:19302".to_owned(),
-            "stun:stun2.l.google.com:19302".to_owned(),
-            "stun:stun3.l.google.com:19302".to_owned(),
-            "stun:stun4.l.google.com:19302".to_owned(),
-        ],
-        ..Default::default()
-    }];
+    let mut ice_servers = Vec::new();
+    if !ice.stun_servers.is_empty() {
+        ice_servers.push(RTCIceServer {
+            urls: ice.stun_servers.clone(),
+            ..Default::default()
+        });
+    }
 
     // Add TURN servers for situations where direct connections aren't possible
-    if use_turn {
+    if !ice.turn_servers.is_empty() {
         ice_servers.push(RTCIceServer {
-            urls: vec![
-                // These would need to be replaced with actual TURN servers
-                // "turn:turn.example.org:3478".to_owned(),
-            ],
-            username: "".to_owned(), // Credentials would be needed
-            credential: "".to_owned(),
-            ..Default::default()
+            urls: ice.turn_servers.clone(),
+            username: ice.turn_username.clone(),
+            credential: ice.turn_credential.clone(),
         });
     }
 
+    let ice_transport_policy = if ice.relay_only {
+        RTCIceTransportPolicy::Relay
+    } else {
+        RTCIceTransportPolicy::All
+    };
+
     // Create the API object with more extensive configuration
     let api = APIBuilder::new()
+        .with_setting_engine(settings)
         .with_interceptor_registry(registry)
         .build();
 
@@ -61,7 +111,7 @@ pub async fn create_peer_connection(use_turn: bool) -> Result<Arc<RTCPeerConnect
     let peer_connection = Arc::new(
         api.new_peer_connection(RTCConfiguration {
             ice_servers,
-            ice_transport_policy: RTCIceTransportPolicy::All,
+            ice_transport_policy,
             ..Default::default()
         })
         .await?,
@@ -70,119 +120,271 @@ pub async fn create_peer_connection(use_turn: bool) -> Result<Arc<RTCPeerConnect
     Ok(peer_connection)
 }
 
-// Setup a data channel for messaging
-pub async fn setup_data_channel(
+// Shared handle to a data channel that becomes available once it opens
+pub type SharedDataChannel = Arc<Mutex<Option<Arc<RTCDataChannel>>>>;
+
+// Messages received on a data channel, in arrival order
+pub type IncomingMessages = mpsc::UnboundedReceiver<DataChannelMessage>;
+
+// Label of the reliable channel carrying chat messages
+pub const MESSAGING_CHANNEL: &str = "messaging";
+
+// Label of the lossy channel carrying typing indicators
+pub const TYPING_CHANNEL: &str = "typing";
+
+// How hard a data channel tries to deliver each message
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ReliabilityProfile {
+    // Every message arrives, in the order it was sent
+    #[default]
+    ReliableOrdered,
+    // Every message arrives, but may overtake earlier ones
+    ReliableUnordered,
+    // Give up on a message after this many retransmissions
+    MaxRetransmits {
+        retransmits: u16,
+        ordered: bool,
+    },
+    // Give up on a message once it is this many milliseconds old
+    MaxPacketLifeTime {
+        millis: u16,
+        ordered: bool,
+    },
+}
+
+impl ReliabilityProfile {
+    // Channel options for this profile
+    pub fn init(&self) -> RTCDataChannelInit {
+        let (ordered, max_retransmits, max_packet_life_time) = match *self {
+            ReliabilityProfile::ReliableOrdered => (true, None, None),
+            ReliabilityProfile::ReliableUnordered => (false, None, None),
+            ReliabilityProfile::MaxRetransmits {
+                retransmits,
+                ordered,
+            } => (ordered, Some(retransmits), None),
+            ReliabilityProfile::MaxPacketLifeTime { millis, ordered } => {
+                (ordered, None, Some(millis))
+            }
+        };
+
+        RTCDataChannelInit {
+            ordered: Some(ordered),
+            max_retransmits,
+            max_packet_life_time,
+            ..Default::default()
+        }
+    }
+
+    // Profile an open channel was negotiated with. On the answerer this is
+    // whatever the offerer asked for.
+    pub fn of(dc: &RTCDataChannel) -> Self {
+        match (dc.max_retransmits(), dc.max_packet_lifetime()) {
+            (Some(retransmits), _) => ReliabilityProfile::MaxRetransmits {
+                retransmits,
+                ordered: dc.ordered(),
+            },
+            (None, Some(millis)) => ReliabilityProfile::MaxPacketLifeTime {
+                millis,
+                ordered: dc.ordered(),
+            },
+            (None, None) if dc.ordered() => ReliabilityProfile::ReliableOrdered,
+            (None, None) => ReliabilityProfile::ReliableUnordered,
+        }
+    }
+}
+
+impl fmt::Display for ReliabilityProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (mode, ordered) = match *self {
+            ReliabilityProfile::ReliableOrdered => return write!(f, "reliable"),
+            ReliabilityProfile::ReliableUnordered => return write!(f, "reliable-unordered"),
+            ReliabilityProfile::MaxRetransmits {
+                retransmits,
+                ordered,
+            } => (format!("retransmits:{}", retransmits), ordered),
+            ReliabilityProfile::MaxPacketLifeTime { millis, ordered } => {
+                (format!("lifetime:{}", millis), ordered)
+            }
+        };
+        if ordered {
+            write!(f, "{},ordered", mode)
+        } else {
+            write!(f, "{}", mode)
+        }
+    }
+}
+
+// Parses the same syntax Display produces: `reliable`, `reliable-unordered`,
+// `retransmits:<n>` or `lifetime:<ms>`, the last two optionally `,ordered`
+impl FromStr for ReliabilityProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().to_lowercase();
+        let (mode, ordered) = match s.strip_suffix(",ordered") {
+            Some(mode) => (mode, true),
+            None => (s.as_str(), false),
+        };
+
+        let parse_limit = |value: &str, what: &str| {
+            value
+                .parse::<u16>()
+                .map_err(|_| anyhow::anyhow!("Invalid {} '{}', expected 0-65535", what, value))
+        };
+
+        match mode.split_once(':') {
+            None if mode == "reliable" && !ordered => Ok(ReliabilityProfile::ReliableOrdered),
+            None if mode == "reliable-unordered" && !ordered => {
+                Ok(ReliabilityProfile::ReliableUnordered)
+            }
+            Some(("retransmits", n)) => Ok(ReliabilityProfile::MaxRetransmits {
+                retransmits: parse_limit(n, "retransmit count")?,
+                ordered,
+            }),
+            Some(("lifetime", ms)) => Ok(ReliabilityProfile::MaxPacketLifeTime {
+                millis: parse_limit(ms, "packet lifetime")?,
+                ordered,
+            }),
+            _ => Err(anyhow::anyhow!(
+                "Unknown reliability profile '{}' (expected reliable, reliable-unordered, \
+                 retransmits:<n> or lifetime:<ms>, optionally followed by ,ordered)",
+                s
+            )),
+        }
+    }
+}
+
+// A data channel to open: created by the offerer, matched by label on the answerer.
+// Channels are fully reliable unless a partial profile is asked for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelSpec {
+    pub label: String,
+    #[serde(default)]
+    pub profile: ReliabilityProfile,
+}
+
+impl ChannelSpec {
+    pub fn new(label: &str, profile: ReliabilityProfile) -> Self {
+        Self {
+            label: label.to_string(),
+            profile,
+        }
+    }
+
+    // Reliable, ordered channel for chat messages. A partial profile here
+    // could silently drop messages on a lossy link.
+    pub fn messaging() -> Self {
+        Self::new(MESSAGING_CHANNEL, ReliabilityProfile::ReliableOrdered)
+    }
+
+    // Unordered, fire-and-forget channel for typing indicators, so they never
+    // hold up chat messages behind retransmissions
+    pub fn typing() -> Self {
+        Self::new(
+            TYPING_CHANNEL,
+            ReliabilityProfile::MaxRetransmits {
+                retransmits: 0,
+                ordered: false,
+            },
+        )
+    }
+
+    // The built-in channels, with the given specs replacing them by label or
+    // added after them. Later specs win.
+    pub fn with_overrides<'a>(overrides: impl IntoIterator<Item = &'a ChannelSpec>) -> Vec<Self> {
+        let mut specs = vec![Self::messaging(), Self::typing()];
+        for spec in overrides {
+            match specs.iter_mut().find(|s| s.label == spec.label) {
+                Some(existing) => existing.profile = spec.profile,
+                None => specs.push(spec.clone()),
+            }
+        }
+        specs
+    }
+}
+
+// `label=profile` or just `label` for a reliable channel, as given to --channel
+impl FromStr for ChannelSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (label, profile) = match s.split_once('=') {
+            Some((label, profile)) => (label.trim(), profile.parse()?),
+            None => (s.trim(), ReliabilityProfile::default()),
+        };
+        if label.is_empty() {
+            return Err(anyhow::anyhow!("Channel label can't be empty"));
+        }
+        Ok(Self::new(label, profile))
+    }
+}
+
+// A data channel and the messages received on it
+pub struct DataChannelHandle {
+    pub channel: SharedDataChannel,
+    pub incoming: IncomingMessages,
+}
+
+// Setup data channels, keyed by label
+pub async fn setup_data_channels(
     pc: Arc<RTCPeerConnection>,
-    channel_name: &str,
+    specs: Vec<ChannelSpec>,
     is_offerer: bool,
-) -> Result<Arc<Mutex<Option<Arc<RTCDataChannel>>>>> {
-    let data_channel = Arc::new(Mutex::new(None as Option<Arc<RTCDataChannel>>));
-    let data_channel_clone = Arc::clone(&data_channel);
-
-    if is_offerer {
-        // Create a datachannel with enhanced settings for better reliability
-        let dc = pc
-            .create_data_channel(
-                channel_name,
-                Some(RTCDataChannelInit {
-                    ordered: Some(true),
-                    max_retransmits: Some(3), // Add retry logic
-                    ..Default::default()
-                }),
-            )
-            .await?;
-
-        info!("Created data channel: {}", channel_name);
-
-        let d1 = Arc::clone(&dc);
-        let data_channel_clone3 = Arc::clone(&data_channel_clone);
-        dc.on_open(Box::new(move || {
-            info!("Data channel '{}' opened", d1.label());
-
-            // Update the data channel in the shared state when it opens
-            let d1_clone = Arc::clone(&d1);
-            let data_channel_clone4 = Arc::clone(&data_channel_clone3);
-            tokio::spawn(async move {
-                *data_channel_clone4.lock().await = Some(d1_clone);
-                info!("Data channel state updated");
-            });
+) -> Result<HashMap<String, DataChannelHandle>> {
+    let mut handles = HashMap::new();
+    let mut routes = HashMap::new();
 
-            Box::pin(async {})
-        }));
+    for spec in specs {
+        let data_channel = Arc::new(Mutex::new(None as Option<Arc<RTCDataChannel>>));
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
 
-        // Improved message handling with proper error handling
-        let _d2 = Arc::clone(&dc);
-        dc.on_message(Box::new(move |msg| {
-            match String::from_utf8(msg.data.to_vec()) {
-                Ok(message) => {
-                    // Enhanced message display with timestamp and formatting
-                    let now = chrono::Local::now().format("%H:%M:%S");
-                    println!("[{}] Received: {}", now, message);
-                }
-                Err(e) => {
-                    warn!("Received invalid UTF-8 data: {}", e);
-                    println!("Received message with invalid encoding");
-                }
-            }
-            Box::pin(async {})
-        }));
+        if is_offerer {
+            // Create the channel with the settings from its spec
+            let dc = pc
+                .create_data_channel(&spec.label, Some(spec.profile.init()))
+                .await?;
 
-        // Add error handler for data channel
-        dc.on_error(Box::new(move |err| {
-            error!("Data channel error: {}", err);
-            Box::pin(async {})
-        }));
+            info!("Created data channel: {} ({})", spec.label, spec.profile);
 
-        *data_channel.lock().await = Some(dc);
-    } else {
-        // Register data channel creation handling
+            register_channel_handlers(&dc, &data_channel, incoming_tx);
+
+            *data_channel.lock().await = Some(dc);
+        } else {
+            routes.insert(spec.label.clone(), (Arc::clone(&data_channel), incoming_tx));
+        }
+
+        handles.insert(
+            spec.label,
+            DataChannelHandle {
+                channel: data_channel,
+                incoming: incoming_rx,
+            },
+        );
+    }
+
+    if !is_offerer {
+        // Register data channel creation handling, routing channels by label
         pc.on_data_channel(Box::new(move |dc| {
             info!("New data channel: {}", dc.label());
 
-            let d1 = Arc::clone(&dc);
-            let data_channel_clone3 = Arc::clone(&data_channel_clone);
-            dc.on_open(Box::new(move || {
-                info!("Data channel '{}' opened", d1.label());
-
-                // Update the data channel in the shared state when it opens
-                let d1_clone = Arc::clone(&d1);
-                let data_channel_clone4 = Arc::clone(&data_channel_clone3);
-                tokio::spawn(async move {
-                    *data_channel_clone4.lock().await = Some(d1_clone);
-                    info!("Data channel state updated");
-                });
-
-                Box::pin(async {})
-            }));
-
-            // Improved message handling with proper error handling
-            let _d2 = Arc::clone(&dc);
-            dc.on_message(Box::new(move |msg| {
-                match String::from_utf8(msg.data.to_vec()) {
-                    Ok(message) => {
-                        // Enhanced message display with timestamp and formatting
-                        let now = chrono::Local::now().format("%H:%M:%S");
-                        println!("[{}] Received: {}", now, message);
-                    }
-                    Err(e) => {
-                        warn!("Received invalid UTF-8 data: {}", e);
-                        println!("Received message with invalid encoding");
-                    }
+            match routes.get(dc.label()) {
+                Some((data_channel, incoming_tx)) => {
+                    register_channel_handlers(&dc, data_channel, incoming_tx.clone());
+
+                    let dc_clone = Arc::clone(&dc);
+                    let data_channel = Arc::clone(data_channel);
+                    tokio::spawn(async move {
+                        *data_channel.lock().await = Some(dc_clone);
+                    });
                 }
-                Box::pin(async {})
-            }));
-
-            // Add error handler for data channel
-            dc.on_error(Box::new(move |err| {
-                error!("Data channel error: {}", err);
-                Box::pin(async {})
-            }));
-
-            let dc_clone = Arc::clone(&dc);
-            let data_channel_clone2 = Arc::clone(&data_channel_clone);
-            tokio::spawn(async move {
-                *data_channel_clone2.lock().await = Some(dc_clone);
-            });
+                None => {
+                    warn!(
+                        "Ignoring unexpected data channel '{}', open it on this side too with --channel",
+                        dc.label()
+                    );
+                }
+            }
             Box::pin(async {})
         }));
     }
@@ -208,7 +410,97 @@ pub async fn setup_data_channel(
         Box::pin(async {})
     }));
 
-    Ok(data_channel)
+    Ok(handles)
+}
+
+// Open a channel mid-session that both sides create themselves with the same
+// stream id, so no in-band announcement (and no on_data_channel) is needed.
+// Messages that arrive before the peer has created its end are lost, so
+// whoever picks the id has to wait to hear from the peer before sending.
+pub async fn open_negotiated_channel(
+    pc: &RTCPeerConnection,
+    spec: &ChannelSpec,
+    id: u16,
+) -> Result<(Arc<RTCDataChannel>, IncomingMessages)> {
+    let init = RTCDataChannelInit {
+        negotiated: Some(id),
+        ..spec.profile.init()
+    };
+    let dc = pc.create_data_channel(&spec.label, Some(init)).await?;
+    debug!("Opened negotiated data channel {} ({})", id, spec.label);
+
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+    dc.on_message(Box::new(move |msg| {
+        if incoming_tx.send(msg).is_err() {
+            debug!("Dropping message, no consumer for incoming data");
+        }
+        Box::pin(async {})
+    }));
+    Ok((dc, incoming_rx))
+}
+
+// Attach open, message and error handlers to a data channel
+fn register_channel_handlers(
+    dc: &Arc<RTCDataChannel>,
+    shared: &SharedDataChannel,
+    incoming_tx: mpsc::UnboundedSender<DataChannelMessage>,
+) {
+    let d1 = Arc::clone(dc);
+    let shared = Arc::clone(shared);
+    dc.on_open(Box::new(move || {
+        info!("Data channel '{}' opened", d1.label());
+
+        // Update the data channel in the shared state when it opens
+        let d1_clone = Arc::clone(&d1);
+        let shared = Arc::clone(&shared);
+        tokio::spawn(async move {
+            *shared.lock().await = Some(d1_clone);
+            info!("Data channel state updated");
+        });
+
+        Box::pin(async {})
+    }));
+
+    // Queue incoming messages for whoever is consuming the session
+    dc.on_message(Box::new(move |msg| {
+        if incoming_tx.send(msg).is_err() {
+            debug!("Dropping message, no consumer for incoming data");
+        }
+        Box::pin(async {})
+    }));
+
+    // Add error handler for data channel
+    dc.on_error(Box::new(move |err| {
+        error!("Data channel error: {}", err);
+        Box::pin(async {})
+    }));
+}
+
+// Wait until the shared data channel exists and is open
+pub async fn wait_for_open(
+    dc: &SharedDataChannel,
+    timeout: Duration,
+) -> Result<Arc<RTCDataChannel>> {
+    let start_time = std::time::Instant::now();
+
+    loop {
+        {
+            let dc_lock = dc.lock().await;
+            if let Some(ref data_channel) = *dc_lock {
+                if data_channel.ready_state() == RTCDataChannelState::Open {
+                    return Ok(Arc::clone(data_channel));
+                }
+            }
+        }
+
+        if start_time.elapsed() > timeout {
+            return Err(anyhow::anyhow!(
+                "Timed out waiting for the data channel to open"
+            ));
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
 }
 
 // Monitor connection state with timeout
@@ -253,12 +545,16 @@ pub async fn monitor_connection_state(
                 || state == RTCPeerConnectionState::Closed
             {
                 println!("\nConnection failed or closed              ");
+                if state == RTCPeerConnectionState::Failed {
+                    println!("Run `modulate-comms doctor` to check the network");
+                }
                 break;
             }
 
             // Check for timeout
             if start_time.elapsed() > timeout {
                 println!("\nConnection attempt timed out             ");
+                println!("Run `modulate-comms doctor` to check the network");
                 break;
             }
         }
@@ -276,3 +572,53 @@ pub async fn monitor_connection_state(
         }
     }
 }
+
+// The candidate pair ICE settled on, with both candidates
+pub struct SelectedCandidatePair {
+    pub pair: ICECandidatePairStats,
+    pub local: ICECandidateStats,
+    pub remote: ICECandidateStats,
+}
+
+// Look up the selected candidate pair in the connection's stats
+pub async fn selected_candidate_pair(pc: &RTCPeerConnection) -> Option<SelectedCandidatePair> {
+    let mut reports = pc.get_stats().await.reports;
+
+    // Prefer the nominated pair, then any pair that completed its checks
+    let pair_id = reports
+        .values()
+        .filter_map(|report| match report {
+            StatsReportType::CandidatePair(pair) => Some(pair),
+            _ => None,
+        })
+        .filter(|pair| pair.state == CandidatePairState::Succeeded)
+        .max_by_key(|pair| (pair.nominated, pair.bytes_sent + pair.bytes_received))?
+        .id
+        .clone();
+
+    let Some(StatsReportType::CandidatePair(pair)) = reports.remove(&pair_id) else {
+        return None;
+    };
+    let Some(StatsReportType::LocalCandidate(local)) = reports.remove(&pair.local_candidate_id)
+    else {
+        return None;
+    };
+    let Some(StatsReportType::RemoteCandidate(remote)) = reports.remove(&pair.remote_candidate_id)
+    else {
+        return None;
+    };
+
+    Some(SelectedCandidatePair {
+        pair,
+        local,
+        remote,
+    })
+}
+
+// Candidate type and address, e.g. "srflx 203.0.113.7:50123"
+pub fn describe_candidate(candidate: &ICECandidateStats) -> String {
+    format!(
+        "{} {}:{}",
+        candidate.candidate_type, candidate.ip, candidate.port
+    )
+}
@@ -0,0 +1,283 @@
+use std::io::{self, BufRead, Read, Write};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+// What happened at the prompt
+pub enum ConsoleEvent {
+    // The input line changed but was not submitted yet
+    Edited,
+    // The user pressed enter
+    Line(String),
+    // Input was closed (EOF, Ctrl-C or Ctrl-D)
+    Closed,
+}
+
+// Prompt contents shared between the input thread and anyone printing
+struct PromptState {
+    buffer: String,
+    status: Option<String>,
+    // Whether the prompt is currently on screen
+    active: bool,
+}
+
+// Chat console: reads input on a background thread and keeps the prompt
+// intact while incoming messages are printed above it.
+//
+// On a terminal, input is read key by key so we can tell when the user is
+// typing. Otherwise input is read line by line, as from a pipe.
+pub struct Console {
+    state: Arc<Mutex<PromptState>>,
+    events: mpsc::UnboundedReceiver<ConsoleEvent>,
+    interactive: bool,
+    _raw_guard: Option<RawModeGuard>,
+}
+
+impl Console {
+    // Not Default: creating a console takes over stdin and the terminal mode
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let state = Arc::new(Mutex::new(PromptState {
+            buffer: String::new(),
+            status: None,
+            active: false,
+        }));
+        let (tx, events) = mpsc::unbounded_channel();
+
+        let raw_guard = RawModeGuard::enable();
+        let interactive = raw_guard.is_some();
+
+        let thread_state = Arc::clone(&state);
+        std::thread::spawn(move || {
+            if interactive {
+                read_keys(thread_state, tx);
+            } else {
+                read_lines(tx);
+            }
+        });
+
+        Self {
+            state,
+            events,
+            interactive,
+            _raw_guard: raw_guard,
+        }
+    }
+
+    // Handle for printing from other tasks
+    pub fn printer(&self) -> ConsolePrinter {
+        ConsolePrinter {
+            state: Arc::clone(&self.state),
+            interactive: self.interactive,
+        }
+    }
+
+    // Show the prompt and wait for the next input event
+    pub async fn next_event(&mut self) -> ConsoleEvent {
+        {
+            let mut state = self.state.lock().unwrap();
+            if !state.active {
+                state.active = true;
+                if self.interactive {
+                    redraw(&state);
+                } else {
+                    print!("> ");
+                    let _ = io::stdout().flush();
+                }
+            }
+        }
+
+        self.events.recv().await.unwrap_or(ConsoleEvent::Closed)
+    }
+}
+
+// Prints above the prompt without mangling what the user is typing
+#[derive(Clone)]
+pub struct ConsolePrinter {
+    state: Arc<Mutex<PromptState>>,
+    interactive: bool,
+}
+
+impl ConsolePrinter {
+    // Print a line, keeping the prompt and any partial input below it
+    pub fn print_line(&self, line: &str) {
+        let state = self.state.lock().unwrap();
+        if self.interactive && state.active {
+            print!("\r\x1B[2K{}\n", line);
+            redraw(&state);
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    // Set or clear the status shown in front of the prompt
+    pub fn set_status(&self, status: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        if state.status == status {
+            return;
+        }
+        state.status = status;
+        if self.interactive && state.active {
+            redraw(&state);
+        }
+    }
+}
+
+// Redraw the prompt line with its status and the current input
+fn redraw(state: &PromptState) {
+    match state.status {
+        Some(ref status) => print!("\r\x1B[2K[{}] > {}", status, state.buffer),
+        None => print!("\r\x1B[2K> {}", state.buffer),
+    }
+    let _ = io::stdout().flush();
+}
+
+// Read input a line at a time, for pipes and non-unix platforms
+fn read_lines(tx: mpsc::UnboundedSender<ConsoleEvent>) {
+    let stdin = io::stdin();
+    loop {
+        let mut line = String::new();
+        let event = match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => ConsoleEvent::Closed,
+            Ok(_) => ConsoleEvent::Line(line.trim().to_string()),
+        };
+        let closed = matches!(event, ConsoleEvent::Closed);
+        if tx.send(event).is_err() || closed {
+            break;
+        }
+    }
+}
+
+// Read input key by key, doing our own line editing and echo
+fn read_keys(state: Arc<Mutex<PromptState>>, tx: mpsc::UnboundedSender<ConsoleEvent>) {
+    let mut stdin = io::stdin();
+    let mut pending_utf8 = Vec::new();
+    let mut byte = [0u8; 1];
+
+    loop {
+        let event = match stdin.read(&mut byte) {
+            Ok(0) | Err(_) => Some(ConsoleEvent::Closed),
+            Ok(_) => {
+                let mut state = state.lock().unwrap();
+                let event = match byte[0] {
+                    b'\r' | b'\n' => {
+                        let line = std::mem::take(&mut state.buffer);
+                        state.active = false;
+                        println!();
+                        Some(ConsoleEvent::Line(line.trim().to_string()))
+                    }
+                    // Ctrl-C, or Ctrl-D on an empty line
+                    0x03 => Some(ConsoleEvent::Closed),
+                    0x04 if state.buffer.is_empty() => Some(ConsoleEvent::Closed),
+                    // Backspace
+                    0x7F | 0x08 => state.buffer.pop().map(|_| ConsoleEvent::Edited),
+                    // Ctrl-U clears the line
+                    0x15 => {
+                        state.buffer.clear();
+                        Some(ConsoleEvent::Edited)
+                    }
+                    // Escape sequences (arrow keys etc.) are not supported, skip them
+                    0x1B => {
+                        skip_escape_sequence(&mut stdin);
+                        None
+                    }
+                    b if b < 0x20 => None,
+                    b => {
+                        pending_utf8.push(b);
+                        match std::str::from_utf8(&pending_utf8) {
+                            Ok(text) => {
+                                state.buffer.push_str(text);
+                                pending_utf8.clear();
+                                Some(ConsoleEvent::Edited)
+                            }
+                            Err(e) if e.error_len().is_some() => {
+                                pending_utf8.clear();
+                                None
+                            }
+                            // Incomplete character, wait for the rest
+                            Err(_) => None,
+                        }
+                    }
+                };
+                if matches!(event, Some(ConsoleEvent::Edited)) {
+                    redraw(&state);
+                }
+                event
+            }
+        };
+
+        if let Some(event) = event {
+            let closed = matches!(event, ConsoleEvent::Closed);
+            if tx.send(event).is_err() || closed {
+                break;
+            }
+        }
+    }
+}
+
+// Consume the rest of a CSI escape sequence
+fn skip_escape_sequence(stdin: &mut io::Stdin) {
+    let mut byte = [0u8; 1];
+    if stdin.read(&mut byte).is_err() || byte[0] != b'[' {
+        return;
+    }
+    while stdin.read(&mut byte).is_ok() {
+        if (0x40..=0x7E).contains(&byte[0]) {
+            break;
+        }
+    }
+}
+
+// Puts the terminal in non-canonical mode without echo and restores it on drop.
+// Output processing stays on, so ordinary println! output still renders correctly.
+#[cfg(unix)]
+struct RawModeGuard {
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawModeGuard {
+    fn enable() -> Option<Self> {
+        let fd = libc::STDIN_FILENO;
+        // SAFETY: isatty only inspects the file descriptor
+        if unsafe { libc::isatty(fd) } != 1 {
+            return None;
+        }
+
+        // SAFETY: termios is plain data and tcgetattr fills it in
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut original) } != 0 {
+            return None;
+        }
+
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        // SAFETY: raw is a valid termios derived from the current settings
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            return None;
+        }
+
+        Some(Self { original })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings read in enable
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct RawModeGuard;
+
+#[cfg(not(unix))]
+impl RawModeGuard {
+    fn enable() -> Option<Self> {
+        None
+    }
+}
@@ -0,0 +1,148 @@
+use crate::cli::ContactsAction;
+use crate::connection::IceSettings;
+use crate::identity;
+use crate::store::Store;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+// A saved peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Contact {
+    // Name used to refer to the contact on the command line
+    pub name: String,
+    // Peer's identity key
+    pub identity: String,
+    // Name shown in the chat, defaults to the contact name
+    pub display_name: Option<String>,
+    pub last_seen: Option<chrono::DateTime<chrono::Local>>,
+    // ICE servers to use when connecting to this peer
+    pub ice: Option<IceSettings>,
+}
+
+impl Contact {
+    // Name to show for this contact in the chat
+    pub fn label(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.name)
+    }
+}
+
+// Run a `contacts` subcommand against the unlocked store
+pub fn run_contacts(action: ContactsAction, store: &mut Store) -> Result<()> {
+    match action {
+        ContactsAction::Add {
+            name,
+            identity,
+            display_name,
+            stun,
+            turn,
+            turn_username,
+            turn_credential,
+            relay_only,
+        } => {
+            identity::parse_fingerprint(&identity)?;
+            let identity = identity.trim().to_lowercase();
+            if store.contact(&name).is_some() {
+                return Err(anyhow::anyhow!("A contact named '{}' already exists", name));
+            }
+            if let Some(existing) = store.contact_by_identity(&identity) {
+                return Err(anyhow::anyhow!(
+                    "This identity is already saved as '{}'",
+                    existing.name
+                ));
+            }
+
+            // Only store ICE preferences if any were given
+            let ice = if stun.is_empty() && turn.is_empty() && !relay_only {
+                None
+            } else {
+                let defaults = IceSettings::default();
+                Some(IceSettings {
+                    stun_servers: if stun.is_empty() {
+                        defaults.stun_servers
+                    } else {
+                        stun
+                    },
+                    turn_servers: turn,
+                    turn_username: turn_username.unwrap_or_default(),
+                    turn_credential: turn_credential.unwrap_or_default(),
+                    relay_only,
+                })
+            };
+
+            store.update_contacts(|contacts| {
+                contacts.push(Contact {
+                    name: name.clone(),
+                    identity,
+                    display_name,
+                    last_seen: None,
+                    ice,
+                })
+            })?;
+            println!("Saved contact '{}'", name);
+        }
+        ContactsAction::List => {
+            if store.contacts().is_empty() {
+                println!("No contacts saved yet");
+            } else {
+                println!("Contacts:");
+                for contact in store.contacts() {
+                    let last_seen = contact
+                        .last_seen
+                        .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+                        .unwrap_or_else(|| "never".to_string());
+                    println!(
+                        "  {} ({}) - {} - last seen {}{}",
+                        contact.name,
+                        contact.label(),
+                        identity::short_fingerprint(&contact.identity),
+                        last_seen,
+                        if contact.ice.is_some() {
+                            " - custom ICE"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+            }
+        }
+        ContactsAction::Remove { name } => {
+            let removed = store.update_contacts(|contacts| {
+                let before = contacts.len();
+                contacts.retain(|c| c.name != name);
+                contacts.len() != before
+            })?;
+            if !removed {
+                return Err(anyhow::anyhow!("No contact named '{}'", name));
+            }
+            println!("Removed contact '{}'", name);
+        }
+        ContactsAction::Rename { name, new_name } => {
+            if store.contact(&new_name).is_some() {
+                return Err(anyhow::anyhow!(
+                    "A contact named '{}' already exists",
+                    new_name
+                ));
+            }
+            let renamed = store.update_contacts(|contacts| {
+                match contacts.iter_mut().find(|c| c.name == name) {
+                    Some(contact) => {
+                        contact.name = new_name.clone();
+                        true
+                    }
+                    None => false,
+                }
+            })?;
+            if !renamed {
+                return Err(anyhow::anyhow!("No contact named '{}'", name));
+            }
+            println!("Renamed contact '{}' to '{}'", name, new_name);
+        }
+        ContactsAction::Whoami => {
+            println!("Your identity key (share it with peers so they can add you):");
+            println!("{}", store.identity().fingerprint());
+        }
+    }
+
+    Ok(())
+}
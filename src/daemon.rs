@@ -0,0 +1,1152 @@
+use crate::app::{self, SessionOptions};
+use crate::chat::{self, Peer};
+use crate::connection::{self, DataChannelHandle, IncomingMessages, SharedDataChannel};
+use crate::protocol::{self, WireMessage};
+use crate::signaling::Signaler;
+use crate::store::{Conversation, Direction, SharedStore};
+use crate::transfer::{IncomingFiles, OutgoingFile};
+use crate::wormhole::{self, WormholeSignaler};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+use tokio::task::AbortHandle;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::RTCPeerConnection;
+
+// Events kept for a subscriber that falls behind before it starts missing them
+const EVENT_BACKLOG: usize = 256;
+
+// Longest request line a client can send
+const MAX_REQUEST_LEN: usize = 1024 * 1024;
+
+// How often a connected session checks the peer is still there
+const STATE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long a file we offer waits for the peer to accept it
+const FILE_ANSWER_TIMEOUT: Duration = Duration::from_secs(300);
+
+// JSON-RPC 2.0 error codes, the last for requests that were valid but failed
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const REQUEST_FAILED: i64 = -32000;
+
+// Which side of the offer/answer exchange a session is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Offer,
+    Answer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionState {
+    // Signaling, connecting or checking the peer's identity
+    Connecting,
+    // Ready for messages and files
+    Connected,
+}
+
+// A session as shown by list_sessions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub session: u64,
+    pub role: Role,
+    pub state: SessionState,
+    // Wormhole code the session was set up with, if any
+    pub code: Option<String>,
+    // The peer's name and identity, once connected
+    pub peer: Option<String>,
+    pub fingerprint: Option<String>,
+}
+
+// Something that happened in one of the sessions, sent to subscribers as an
+// `event` notification
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Connected {
+        session: u64,
+        peer: String,
+        fingerprint: String,
+    },
+    Closed {
+        session: u64,
+        reason: String,
+    },
+    Message {
+        session: u64,
+        id: String,
+        from: String,
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<String>,
+    },
+    Edited {
+        session: u64,
+        id: String,
+        text: String,
+    },
+    Deleted {
+        session: u64,
+        id: String,
+    },
+    Reacted {
+        session: u64,
+        id: String,
+        emoji: String,
+    },
+    Nick {
+        session: u64,
+        name: String,
+    },
+    // The peer offered a file; accept_file or decline_file answers it
+    FileOffered {
+        session: u64,
+        id: String,
+        name: String,
+        size: u64,
+    },
+    FileReceived {
+        session: u64,
+        id: String,
+        name: String,
+        path: PathBuf,
+        size: u64,
+    },
+    FileFailed {
+        session: u64,
+        id: String,
+        reason: String,
+    },
+}
+
+struct Session {
+    role: Role,
+    code: Option<String>,
+    pc: Arc<RTCPeerConnection>,
+    // Set once the peer has been authenticated
+    connected: Option<Connected>,
+    task: AbortHandle,
+}
+
+// A peer's answer to a file we offered, the reason if it declined
+type FileAnswer = Result<(), String>;
+
+#[derive(Clone)]
+struct Connected {
+    dc: SharedDataChannel,
+    peer: Arc<Mutex<Peer>>,
+    conversation: Conversation,
+    files: Arc<Mutex<IncomingFiles>>,
+    // Files we offered, waiting for the peer to answer
+    answers: Arc<Mutex<HashMap<String, oneshot::Sender<FileAnswer>>>>,
+}
+
+// Peer connections kept alive in the background, shared by every client of
+// the control socket
+pub struct Daemon {
+    store: SharedStore,
+    downloads: PathBuf,
+    max_file_size: u64,
+    timeout: Duration,
+    sessions: Mutex<BTreeMap<u64, Session>>,
+    next_session: AtomicU64,
+    events: broadcast::Sender<Event>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConnectParams {
+    role: Role,
+    rendezvous: String,
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    to: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MessageParams {
+    session: u64,
+    text: String,
+    #[serde(default)]
+    reply_to: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileParams {
+    session: u64,
+    path: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TransferParams {
+    session: u64,
+    id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SessionParams {
+    session: u64,
+}
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    // Absent for notifications, which get no response
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+// Errors from carrying out a request, with their causes
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(REQUEST_FAILED, format!("{:#}", e))
+    }
+}
+
+impl Daemon {
+    // Received files go to `downloads`, and files over `max_file_size` bytes
+    // are refused; sessions that aren't connected after `timeout`, signaling
+    // included, are dropped
+    pub fn new(
+        store: SharedStore,
+        downloads: PathBuf,
+        max_file_size: u64,
+        timeout: Duration,
+    ) -> Arc<Self> {
+        let (events, _) = broadcast::channel(EVENT_BACKLOG);
+        Arc::new(Self {
+            store,
+            downloads,
+            max_file_size,
+            timeout,
+            sessions: Mutex::new(BTreeMap::new()),
+            next_session: AtomicU64::new(1),
+            events,
+        })
+    }
+
+    // Every event from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: Event) {
+        // Nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    // Connect `pc` in the background with any signaler, returning the new
+    // session's number. `connect` requests come through here with a wormhole.
+    pub async fn attach(
+        self: &Arc<Self>,
+        pc: Arc<RTCPeerConnection>,
+        mut signaler: Box<dyn Signaler>,
+        options: SessionOptions,
+        is_offerer: bool,
+    ) -> u64 {
+        let id = self.next_session.fetch_add(1, Ordering::SeqCst);
+        let code = options.code.clone();
+
+        // Held until the session is listed, which the task needs first
+        let mut sessions = self.sessions.lock().await;
+        let daemon = Arc::clone(self);
+        let session_pc = Arc::clone(&pc);
+        let task = tokio::spawn(async move {
+            let result = daemon
+                .run_session(id, session_pc, signaler.as_mut(), &options, is_offerer)
+                .await;
+            let reason = match result {
+                Ok(()) => "Connection closed".to_string(),
+                Err(e) => format!("{:#}", e),
+            };
+            daemon.end_session(id, reason).await;
+        });
+
+        sessions.insert(
+            id,
+            Session {
+                role: if is_offerer {
+                    Role::Offer
+                } else {
+                    Role::Answer
+                },
+                code,
+                pc,
+                connected: None,
+                task: task.abort_handle(),
+            },
+        );
+        id
+    }
+
+    async fn run_session(
+        &self,
+        id: u64,
+        pc: Arc<RTCPeerConnection>,
+        signaler: &mut dyn Signaler,
+        options: &SessionOptions,
+        is_offerer: bool,
+    ) -> Result<()> {
+        let specs = app::session_channels(options, &self.store).await;
+        // The signaler can wait for a peer that never shows up, so the whole
+        // exchange is bounded and not just the ICE connection
+        let connecting = app::connect(
+            Arc::clone(&pc),
+            specs,
+            signaler,
+            is_offerer,
+            options.timeout,
+        );
+        let mut channels = match tokio::time::timeout(options.timeout, connecting).await {
+            Ok(channels) => channels?,
+            Err(_) => {
+                let _ = pc.close().await;
+                bail!(
+                    "Couldn't reach the peer within {} seconds",
+                    options.timeout.as_secs()
+                );
+            }
+        };
+        if pc.connection_state() != RTCPeerConnectionState::Connected {
+            let _ = pc.close().await;
+            bail!(
+                "Couldn't connect to the peer within {} seconds",
+                options.timeout.as_secs()
+            );
+        }
+
+        let DataChannelHandle {
+            channel: dc,
+            mut incoming,
+        } = channels
+            .remove(connection::MESSAGING_CHANNEL)
+            .context("Messaging channel was not set up")?;
+        let typing = channels
+            .remove(connection::TYPING_CHANNEL)
+            .context("Typing channel was not set up")?;
+        let peer =
+            app::handshake(&pc, &dc, &mut incoming, options, is_offerer, &self.store).await?;
+
+        // Let the peer know what to call us
+        let name = match options.name {
+            Some(ref name) => Some(name.clone()),
+            None => self.store.lock().await.config().display_name.clone(),
+        };
+        if let Some(name) = name {
+            if let Err(e) = send(&dc, &WireMessage::Nick { name }).await {
+                warn!("Failed to announce display name: {}", e);
+            }
+        }
+
+        let label = peer.label();
+        let fingerprint = peer.fingerprint.clone();
+        let connected = Connected {
+            dc: Arc::clone(&dc),
+            peer: Arc::new(Mutex::new(peer)),
+            conversation: Conversation::new(&fingerprint),
+            files: Arc::new(Mutex::new(IncomingFiles::new(
+                self.downloads.clone(),
+                self.max_file_size,
+            ))),
+            answers: Arc::default(),
+        };
+        {
+            let mut sessions = self.sessions.lock().await;
+            let session = sessions.get_mut(&id).context("Session was closed")?;
+            session.connected = Some(connected.clone());
+        }
+        info!("Session {} connected to {}", id, label);
+        self.emit(Event::Connected {
+            session: id,
+            peer: label,
+            fingerprint,
+        });
+
+        self.receive(id, &pc, incoming, typing, &connected).await
+    }
+
+    // Handle what the peer sends until the connection goes away
+    async fn receive(
+        &self,
+        id: u64,
+        pc: &RTCPeerConnection,
+        mut incoming: IncomingMessages,
+        mut typing: DataChannelHandle,
+        connected: &Connected,
+    ) -> Result<()> {
+        let mut check = tokio::time::interval(STATE_CHECK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                msg = incoming.recv() => {
+                    let Some(msg) = msg else { return Ok(()) };
+                    if msg.is_string {
+                        self.handle_message(id, &msg.data, connected).await;
+                    } else if let Err(e) = connected.files.lock().await.chunk(&msg.data).await {
+                        warn!("Session {}: {}", id, e);
+                    }
+                }
+                // Keepalives come on the typing channel, clear of file transfers
+                Some(msg) = typing.incoming.recv() => {
+                    if let Ok(WireMessage::Ping { seq, sent_at }) = WireMessage::decode(&msg.data) {
+                        let pong = WireMessage::Pong { seq, sent_at };
+                        if let Err(e) = send(&typing.channel, &pong).await {
+                            debug!("Session {}: pong not sent: {}", id, e);
+                        }
+                    }
+                }
+                _ = check.tick() => {
+                    if pc.connection_state() == RTCPeerConnectionState::Failed {
+                        bail!("Lost the connection to the peer");
+                    }
+                    // A peer hanging up closes the channel well before ICE notices
+                    if pc.connection_state() == RTCPeerConnectionState::Closed
+                        || open_channel(&connected.dc).await.is_err()
+                    {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_message(&self, id: u64, data: &[u8], connected: &Connected) {
+        let Connected {
+            dc,
+            peer,
+            conversation,
+            files,
+            answers,
+        } = connected;
+        let message = match WireMessage::decode(data) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Session {}: {}", id, e);
+                return;
+            }
+        };
+
+        match message {
+            WireMessage::Chat {
+                id: message_id,
+                text,
+                reply_to,
+            } => {
+                let from = peer.lock().await.label();
+                let recorded = self.store.lock().await.record(
+                    conversation,
+                    Direction::Received,
+                    &from,
+                    &message_id,
+                    &text,
+                    reply_to.as_deref(),
+                );
+                if let Err(e) = recorded {
+                    error!("Failed to save message history: {}", e);
+                }
+                self.emit(Event::Message {
+                    session: id,
+                    id: message_id,
+                    from,
+                    text,
+                    reply_to,
+                });
+            }
+            WireMessage::Edit {
+                id: message_id,
+                text,
+            } => {
+                let edited = self.store.lock().await.edit_message(
+                    conversation,
+                    &message_id,
+                    Direction::Received,
+                    &text,
+                );
+                match edited {
+                    Ok(true) => self.emit(Event::Edited {
+                        session: id,
+                        id: message_id,
+                        text,
+                    }),
+                    Ok(false) => warn!("Peer edited unknown message #{}", message_id),
+                    Err(e) => error!("Failed to save message history: {}", e),
+                }
+            }
+            WireMessage::Delete { id: message_id } => {
+                let deleted = self.store.lock().await.delete_message(
+                    conversation,
+                    &message_id,
+                    Direction::Received,
+                );
+                match deleted {
+                    Ok(true) => self.emit(Event::Deleted {
+                        session: id,
+                        id: message_id,
+                    }),
+                    Ok(false) => warn!("Peer deleted unknown message #{}", message_id),
+                    Err(e) => error!("Failed to save message history: {}", e),
+                }
+            }
+            WireMessage::React {
+                id: message_id,
+                emoji,
+            } => {
+                let reacted = self.store.lock().await.react_to_message(
+                    conversation,
+                    &message_id,
+                    Direction::Received,
+                    &emoji,
+                );
+                match reacted {
+                    Ok(true) => self.emit(Event::Reacted {
+                        session: id,
+                        id: message_id,
+                        emoji,
+                    }),
+                    Ok(false) => warn!("Peer reacted to unknown message #{}", message_id),
+                    Err(e) => error!("Failed to save message history: {}", e),
+                }
+            }
+            WireMessage::Nick { name } => {
+                let name: String = name.trim().chars().take(chat::MAX_NICK_LEN).collect();
+                if !name.is_empty() {
+                    peer.lock().await.nick = Some(name.clone());
+                    self.emit(Event::Nick { session: id, name });
+                }
+            }
+            // Nothing is written until a client accepts the file
+            WireMessage::FileStart {
+                id: transfer,
+                name,
+                size,
+            } => match files.lock().await.offer(&transfer, &name, size) {
+                Ok(offered) => self.emit(Event::FileOffered {
+                    session: id,
+                    id: transfer,
+                    name: offered.name,
+                    size: offered.size,
+                }),
+                Err(e) => {
+                    let reason = format!("{:#}", e);
+                    let reject = WireMessage::FileReject {
+                        id: transfer.clone(),
+                        reason: reason.clone(),
+                    };
+                    if let Err(e) = send(dc, &reject).await {
+                        debug!("Session {}: file rejection not sent: {}", id, e);
+                    }
+                    self.emit(Event::FileFailed {
+                        session: id,
+                        id: transfer,
+                        reason,
+                    });
+                }
+            },
+            WireMessage::FileAccept { id: transfer } => {
+                match answers.lock().await.remove(&transfer) {
+                    Some(answer) => {
+                        let _ = answer.send(Ok(()));
+                    }
+                    None => warn!("Session {}: peer accepted unknown file {}", id, transfer),
+                }
+            }
+            WireMessage::FileReject {
+                id: transfer,
+                reason,
+            } => match answers.lock().await.remove(&transfer) {
+                Some(answer) => {
+                    let _ = answer.send(Err(reason));
+                }
+                None => warn!("Session {}: peer rejected unknown file {}", id, transfer),
+            },
+            WireMessage::FileEnd {
+                id: transfer,
+                sha256,
+            } => match files.lock().await.finish(&transfer, &sha256).await {
+                Ok(file) => {
+                    info!("Session {}: saved {}", id, file.path.display());
+                    self.emit(Event::FileReceived {
+                        session: id,
+                        id: file.id,
+                        name: file.name,
+                        path: file.path,
+                        size: file.size,
+                    });
+                }
+                Err(e) => self.emit(Event::FileFailed {
+                    session: id,
+                    id: transfer,
+                    reason: format!("{:#}", e),
+                }),
+            },
+            // Pings from older chat peers, which send them here rather than
+            // on the typing channel
+            WireMessage::Ping { seq, sent_at } => {
+                if let Err(e) = send(dc, &WireMessage::Pong { seq, sent_at }).await {
+                    debug!("Session {}: pong not sent: {}", id, e);
+                }
+            }
+            WireMessage::Pong { .. } => {}
+            other => warn!("Session {}: ignoring unexpected {:?}", id, other),
+        }
+    }
+
+    // Forget a session whose task has finished
+    async fn end_session(&self, id: u64, reason: String) {
+        let Some(session) = self.sessions.lock().await.remove(&id) else {
+            return;
+        };
+        let _ = session.pc.close().await;
+        info!("Session {} ended: {}", id, reason);
+        self.emit(Event::Closed {
+            session: id,
+            reason,
+        });
+    }
+
+    // Start a session through a rendezvous server. The offerer gets the
+    // wormhole code back at once, to hand to the other peer.
+    async fn connect(self: &Arc<Self>, params: ConnectParams) -> Result<Value> {
+        let mut options = SessionOptions {
+            timeout: self.timeout,
+            password: params.password,
+            to: params.to,
+            name: None,
+            channels: Vec::new(),
+            rendezvous: Some(params.rendezvous.clone()),
+            code: None,
+            stats_interval: None,
+            announced: None,
+        };
+        let ice = app::preferred_ice_settings(&options, &self.store).await?;
+
+        let (signaler, is_offerer) = match params.role {
+            Role::Offer => {
+                let mut signaler = WormholeSignaler::offering(&params.rendezvous);
+                options.code = Some(signaler.open().await?.to_string());
+                (signaler, true)
+            }
+            Role::Answer => {
+                let code = params
+                    .code
+                    .context("Answering needs the code the offerer was given")?;
+                let code = wormhole::Code::parse(&code)?;
+                options.code = Some(code.to_string());
+                (WormholeSignaler::answering(&params.rendezvous, code), false)
+            }
+        };
+
+        let code = options.code.clone();
+        let pc = connection::create_peer_connection(&ice).await?;
+        let session = self
+            .attach(pc, Box::new(signaler), options, is_offerer)
+            .await;
+        Ok(json!({ "session": session, "code": code }))
+    }
+
+    pub async fn sessions(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().await;
+        let mut list = Vec::new();
+        for (&id, session) in sessions.iter() {
+            let (state, peer, fingerprint) = match session.connected {
+                Some(ref connected) => {
+                    let peer = connected.peer.lock().await;
+                    (
+                        SessionState::Connected,
+                        Some(peer.label()),
+                        Some(peer.fingerprint.clone()),
+                    )
+                }
+                None => (SessionState::Connecting, None, None),
+            };
+            list.push(SessionInfo {
+                session: id,
+                role: session.role,
+                state,
+                code: session.code.clone(),
+                peer,
+                fingerprint,
+            });
+        }
+        list
+    }
+
+    // The open messaging channel and the rest of a connected session
+    async fn connected(&self, id: u64) -> Result<(Arc<RTCDataChannel>, Connected)> {
+        let sessions = self.sessions.lock().await;
+        let session = sessions
+            .get(&id)
+            .with_context(|| format!("No session {}", id))?;
+        let connected = session
+            .connected
+            .as_ref()
+            .with_context(|| format!("Session {} isn't connected yet", id))?;
+        let dc = open_channel(&connected.dc).await?;
+        Ok((dc, connected.clone()))
+    }
+
+    // Send a chat message, returning its id
+    pub async fn send_message(
+        &self,
+        session: u64,
+        text: &str,
+        reply_to: Option<&str>,
+    ) -> Result<String> {
+        let text = text.trim();
+        if text.is_empty() {
+            bail!("Message is empty");
+        }
+        let (dc, connected) = self.connected(session).await?;
+        let conversation = connected.conversation;
+        let peer_label = connected.peer.lock().await.label();
+
+        let id = protocol::new_message_id();
+        {
+            let mut store = self.store.lock().await;
+            if let Some(parent) = reply_to {
+                if store.message(&conversation, parent).is_none() {
+                    bail!("No message #{} in this session to reply to", parent);
+                }
+            }
+            if let Err(e) = store.record(
+                &conversation,
+                Direction::Sent,
+                &peer_label,
+                &id,
+                text,
+                reply_to,
+            ) {
+                error!("Failed to save message history: {}", e);
+            }
+        }
+
+        WireMessage::Chat {
+            id: id.clone(),
+            text: text.to_string(),
+            reply_to: reply_to.map(|id| id.to_string()),
+        }
+        .send(&dc)
+        .await?;
+        Ok(id)
+    }
+
+    // Offer a file and send it once the peer accepts, returning the transfer
+    // id and size once it is all queued
+    pub async fn send_file(&self, session: u64, path: &Path) -> Result<(String, u64)> {
+        // The daemon's working directory is not the client's
+        if !path.is_absolute() {
+            bail!("File paths must be absolute, not {}", path.display());
+        }
+        let (dc, connected) = self.connected(session).await?;
+        let file = OutgoingFile::open(path).await?;
+
+        // Listening before the offer goes out, so a quick answer isn't missed
+        let (answer_tx, answer_rx) = oneshot::channel();
+        connected
+            .answers
+            .lock()
+            .await
+            .insert(file.id.clone(), answer_tx);
+        let answer = match file.offer(&dc).await {
+            Ok(()) => tokio::time::timeout(FILE_ANSWER_TIMEOUT, answer_rx).await,
+            Err(e) => {
+                connected.answers.lock().await.remove(&file.id);
+                return Err(e);
+            }
+        };
+        match answer {
+            Ok(Ok(Ok(()))) => {}
+            Ok(Ok(Err(reason))) => bail!("The peer declined {}: {}", file.name, reason),
+            Ok(Err(_)) => bail!("The session ended before the peer answered"),
+            Err(_) => {
+                connected.answers.lock().await.remove(&file.id);
+                bail!(
+                    "The peer didn't accept {} within {} seconds",
+                    file.name,
+                    FILE_ANSWER_TIMEOUT.as_secs()
+                );
+            }
+        }
+
+        let id = file.id.clone();
+        let size = file.send(&dc).await?;
+        Ok((id, size))
+    }
+
+    // Take a file the peer offered, writing it to the downloads directory as
+    // it arrives
+    pub async fn accept_file(&self, session: u64, transfer: &str) -> Result<()> {
+        let (dc, connected) = self.connected(session).await?;
+        connected.files.lock().await.accept(transfer).await?;
+        WireMessage::FileAccept {
+            id: transfer.to_string(),
+        }
+        .send(&dc)
+        .await
+    }
+
+    // Turn down a file the peer offered
+    pub async fn decline_file(&self, session: u64, transfer: &str) -> Result<()> {
+        let (dc, connected) = self.connected(session).await?;
+        connected.files.lock().await.decline(transfer)?;
+        WireMessage::FileReject {
+            id: transfer.to_string(),
+            reason: "Declined".to_string(),
+        }
+        .send(&dc)
+        .await
+    }
+
+    // Hang up on a session, connected or not
+    pub async fn close(&self, id: u64) -> Result<()> {
+        let session = self
+            .sessions
+            .lock()
+            .await
+            .remove(&id)
+            .with_context(|| format!("No session {}", id))?;
+        session.task.abort();
+        let _ = session.pc.close().await;
+        info!("Session {} closed by request", id);
+        self.emit(Event::Closed {
+            session: id,
+            reason: "Closed by request".to_string(),
+        });
+        Ok(())
+    }
+
+    // Accept clients until the listener fails
+    pub async fn serve(self: Arc<Self>, listener: UnixListener) -> Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let daemon = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = daemon.serve_client(stream).await {
+                    debug!("Control connection ended: {}", e);
+                }
+            });
+        }
+    }
+
+    // One JSON-RPC request per line in, responses and event notifications
+    // out, one per line. Requests are handled concurrently, so responses may
+    // come back in any order.
+    async fn serve_client(self: Arc<Self>, stream: UnixStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<Value>();
+        let writing = tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                let mut line = message.to_string();
+                line.push('\n');
+                if writer.write_all(line.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut subscription = None;
+        let mut line = Vec::new();
+        let result = loop {
+            line.clear();
+            let read = (&mut reader)
+                .take(MAX_REQUEST_LEN as u64)
+                .read_until(b'\n', &mut line)
+                .await;
+            match read {
+                Ok(0) => break Ok(()),
+                Ok(n) if n == MAX_REQUEST_LEN && !line.ends_with(b"\n") => {
+                    let _ = tx.send(error_response(
+                        Value::Null,
+                        RpcError::new(INVALID_REQUEST, "Request is too long"),
+                    ));
+                    break Ok(());
+                }
+                Ok(_) => {}
+                Err(e) => break Err(e.into()),
+            }
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+
+            let request = match parse_request(&line) {
+                Ok(request) => request,
+                Err((id, e)) => {
+                    let _ = tx.send(error_response(id, e));
+                    continue;
+                }
+            };
+
+            // Subscriptions belong to the connection, everything else to the daemon
+            if request.method == "subscribe" {
+                if subscription.is_none() {
+                    subscription = Some(forward_events(self.subscribe(), tx.clone()));
+                }
+                if let Some(id) = request.id {
+                    let _ = tx.send(result_response(id, json!({ "subscribed": true })));
+                }
+                continue;
+            }
+
+            let daemon = Arc::clone(&self);
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let result = daemon.call(&request.method, request.params).await;
+                if let Some(id) = request.id {
+                    let response = match result {
+                        Ok(result) => result_response(id, result),
+                        Err(e) => error_response(id, e),
+                    };
+                    let _ = tx.send(response);
+                }
+            });
+        };
+
+        if let Some(subscription) = subscription {
+            subscription.abort();
+        }
+        drop(tx);
+        let _ = writing.await;
+        result
+    }
+
+    async fn call(self: &Arc<Self>, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "connect" => Ok(self.connect(parse_params(params)?).await?),
+            "list_sessions" => Ok(json!(self.sessions().await)),
+            "send_message" => {
+                let params: MessageParams = parse_params(params)?;
+                let id = self
+                    .send_message(params.session, &params.text, params.reply_to.as_deref())
+                    .await?;
+                Ok(json!({ "id": id }))
+            }
+            "send_file" => {
+                let params: FileParams = parse_params(params)?;
+                let (id, size) = self.send_file(params.session, &params.path).await?;
+                Ok(json!({ "id": id, "size": size }))
+            }
+            "accept_file" => {
+                let params: TransferParams = parse_params(params)?;
+                self.accept_file(params.session, &params.id).await?;
+                Ok(Value::Null)
+            }
+            "decline_file" => {
+                let params: TransferParams = parse_params(params)?;
+                self.decline_file(params.session, &params.id).await?;
+                Ok(Value::Null)
+            }
+            "close" => {
+                let params: SessionParams = parse_params(params)?;
+                self.close(params.session).await?;
+                Ok(Value::Null)
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method '{}'", method),
+            )),
+        }
+    }
+}
+
+// The request on a line, or the id to report why it isn't one
+fn parse_request(line: &[u8]) -> Result<Request, (Value, RpcError)> {
+    let value: Value = serde_json::from_slice(line)
+        .map_err(|e| (Value::Null, RpcError::new(PARSE_ERROR, e.to_string())))?;
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    let request: Request = serde_json::from_value(value)
+        .map_err(|e| (id.clone(), RpcError::new(INVALID_REQUEST, e.to_string())))?;
+    if request.jsonrpc != "2.0" {
+        return Err((
+            id,
+            RpcError::new(INVALID_REQUEST, "Only JSON-RPC 2.0 is spoken"),
+        ));
+    }
+    Ok(request)
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn result_response(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": error.code, "message": error.message },
+    })
+}
+
+// Send every event to one client as a notification
+fn forward_events(
+    mut events: broadcast::Receiver<Event>,
+    tx: mpsc::UnboundedSender<Value>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("A subscriber fell behind and missed {} events", missed);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let notification = json!({ "jsonrpc": "2.0", "method": "event", "params": event });
+            if tx.send(notification).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+async fn open_channel(dc: &SharedDataChannel) -> Result<Arc<RTCDataChannel>> {
+    match *dc.lock().await {
+        Some(ref channel) if channel.ready_state() == RTCDataChannelState::Open => {
+            Ok(Arc::clone(channel))
+        }
+        _ => bail!("Data channel is not open"),
+    }
+}
+
+async fn send(dc: &SharedDataChannel, message: &WireMessage) -> Result<()> {
+    message.send(&*open_channel(dc).await?).await
+}
+
+// Where the control socket goes unless --socket says otherwise. Without a
+// runtime directory it goes in a directory of this user's own under the
+// shared temporary one.
+pub fn default_socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(|| {
+            let uid = unsafe { libc::getuid() };
+            std::env::temp_dir().join(format!("modulate-comms-{}", uid))
+        })
+        .join("modulate-comms.sock")
+}
+
+// Where received files go unless --downloads says otherwise
+pub fn default_downloads_dir() -> PathBuf {
+    dirs::download_dir()
+        .or_else(dirs::home_dir)
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+// Listen on `socket` so only this user can connect, replacing a socket left
+// behind by a daemon that didn't shut down cleanly. The directory it goes in
+// is created private if it's missing, and must not be writable by anyone
+// else, so nobody can swap the socket out from under the daemon.
+pub fn bind(socket: &Path) -> Result<UnixListener> {
+    let parent = match socket.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(parent)
+        .with_context(|| format!("Failed to create {}", parent.display()))?;
+    let metadata = std::fs::metadata(parent)
+        .with_context(|| format!("Failed to read {}", parent.display()))?;
+    if metadata.uid() != unsafe { libc::getuid() } || metadata.mode() & 0o022 != 0 {
+        bail!(
+            "{} can be written by other users; put the socket in a directory only you can write to",
+            parent.display()
+        );
+    }
+
+    match std::fs::symlink_metadata(socket) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(socket).is_ok() {
+                bail!(
+                    "Another daemon is already listening on {}",
+                    socket.display()
+                );
+            }
+            std::fs::remove_file(socket)
+                .with_context(|| format!("Failed to remove stale socket {}", socket.display()))?;
+        }
+        Ok(_) => bail!(
+            "{} already exists and isn't a socket, so it was left alone",
+            socket.display()
+        ),
+        Err(_) => {}
+    }
+
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict access to {}", socket.display()))?;
+    Ok(listener)
+}
+
+// Run the daemon until Ctrl-C
+pub async fn run_daemon(
+    socket: PathBuf,
+    downloads: PathBuf,
+    max_file_size: u64,
+    store: SharedStore,
+    timeout: Duration,
+) -> Result<()> {
+    let listener = bind(&socket)?;
+    println!("Daemon listening on {}", socket.display());
+    println!("Received files are saved in {}", downloads.display());
+
+    let daemon = Daemon::new(store, downloads, max_file_size, timeout);
+    let result = tokio::select! {
+        result = daemon.serve(listener) => result,
+        _ = tokio::signal::ctrl_c() => {
+            println!("Shutting down");
+            Ok(())
+        }
+    };
+    let _ = std::fs::remove_file(&socket);
+    result
+}
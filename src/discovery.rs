@@ -0,0 +1,422 @@
+use crate::app::{self, SessionOptions};
+use crate::connection::IceSettings;
+use crate::identity;
+use crate::signaling::{Signaler, TcpSignaler};
+use crate::store::SharedStore;
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+use webrtc::util::ifaces::{self, Kind, NextHop};
+
+// UDP port peers announce themselves on
+pub const DISCOVERY_PORT: u16 = 47474;
+
+// Tells our announcements apart from anything else sent to the port
+const APP_TAG: &str = "modulate-comms";
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+// Peers that haven't been heard from for this long have gone away
+const PEER_EXPIRY: Duration = Duration::from_secs(5);
+// How long a peer has to say who it is after connecting
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What a peer broadcasts about itself, and sends again when connecting
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Announcement {
+    pub app: String,
+    // Random per run, so we can ignore our own announcements
+    pub instance: String,
+    pub name: String,
+    pub fingerprint: String,
+    // TCP port the peer takes signaling connections on
+    pub port: u16,
+}
+
+impl Announcement {
+    pub fn new(name: String, fingerprint: String, port: u16) -> Self {
+        let mut instance = [0u8; 8];
+        rand::rng().fill_bytes(&mut instance);
+        Self {
+            app: APP_TAG.to_string(),
+            instance: hex::encode(instance),
+            name,
+            fingerprint,
+            port,
+        }
+    }
+
+    pub fn encode(&self) -> Result<String> {
+        serde_json::to_string(self).context("Failed to serialize announcement")
+    }
+
+    // Parse an announcement, rejecting anything not sent by this app
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let announcement: Self = serde_json::from_slice(data).context("Not a peer announcement")?;
+        if announcement.app != APP_TAG {
+            bail!("Announcement from another application");
+        }
+        identity::parse_fingerprint(&announcement.fingerprint)?;
+        Ok(announcement)
+    }
+
+    // Name to show in the peer list
+    pub fn label(&self) -> String {
+        let name = if self.name.is_empty() {
+            "(no name)"
+        } else {
+            &self.name
+        };
+        format!(
+            "{} ({})",
+            name,
+            identity::short_fingerprint(&self.fingerprint)
+        )
+    }
+}
+
+// A peer heard on the local network
+#[derive(Debug, Clone)]
+pub struct DiscoveredPeer {
+    pub announcement: Announcement,
+    // Where to connect for signaling
+    pub address: SocketAddr,
+    last_seen: Instant,
+}
+
+// Announces us on the local network and collects the peers heard there,
+// until dropped
+pub struct Discovery {
+    peers: Arc<Mutex<HashMap<String, DiscoveredPeer>>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Discovery {
+    pub async fn start(announcement: Announcement, port: u16) -> Result<Self> {
+        let socket = Arc::new(bind_broadcast(port)?);
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+        let datagram = announcement.encode()?;
+
+        let announcer = {
+            let socket = Arc::clone(&socket);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    for target in broadcast_addresses(port) {
+                        if let Err(e) = socket.send_to(datagram.as_bytes(), target).await {
+                            debug!("Failed to announce to {}: {}", target, e);
+                        }
+                    }
+                }
+            })
+        };
+
+        let listener = {
+            let peers = Arc::clone(&peers);
+            tokio::spawn(async move {
+                let mut buf = [0u8; 2048];
+                loop {
+                    let (len, from) = match socket.recv_from(&mut buf).await {
+                        Ok(received) => received,
+                        Err(e) => {
+                            debug!("Failed to receive announcement: {}", e);
+                            continue;
+                        }
+                    };
+                    let heard = match Announcement::decode(&buf[..len]) {
+                        Ok(heard) => heard,
+                        Err(e) => {
+                            debug!("Ignoring datagram from {}: {:#}", from, e);
+                            continue;
+                        }
+                    };
+                    if heard.instance == announcement.instance {
+                        continue;
+                    }
+                    let peer = DiscoveredPeer {
+                        address: SocketAddr::new(from.ip(), heard.port),
+                        announcement: heard,
+                        last_seen: Instant::now(),
+                    };
+                    peers
+                        .lock()
+                        .await
+                        .insert(peer.announcement.instance.clone(), peer);
+                }
+            })
+        };
+
+        Ok(Self {
+            peers,
+            tasks: vec![announcer, listener],
+        })
+    }
+
+    // Peers heard recently, in a stable order
+    pub async fn peers(&self) -> Vec<DiscoveredPeer> {
+        let mut peers = self.peers.lock().await;
+        peers.retain(|_, peer| peer.last_seen.elapsed() < PEER_EXPIRY);
+
+        let mut peers = peers.values().cloned().collect::<Vec<_>>();
+        peers.sort_by(|a, b| {
+            (&a.announcement.name, &a.announcement.instance)
+                .cmp(&(&b.announcement.name, &b.announcement.instance))
+        });
+        peers
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+// A UDP socket on the discovery port that can send broadcasts. Several
+// instances on one machine can share the port, and each gets every broadcast.
+fn bind_broadcast(port: u16) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())
+        .with_context(|| format!("Failed to listen for peers on UDP port {}", port))?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+// The broadcast address of every IPv4 interface, plus loopback so that
+// peers on this machine are found even with no network at all
+pub fn broadcast_addresses(port: u16) -> Vec<SocketAddr> {
+    let mut targets = vec![SocketAddr::from((Ipv4Addr::new(127, 255, 255, 255), port))];
+    match ifaces::ifaces() {
+        Ok(interfaces) => {
+            for interface in interfaces {
+                if let (Kind::Ipv4, Some(NextHop::Broadcast(broadcast))) =
+                    (&interface.kind, &interface.hop)
+                {
+                    let target = SocketAddr::new(broadcast.ip(), port);
+                    if !targets.contains(&target) {
+                        targets.push(target);
+                    }
+                }
+            }
+        }
+        Err(e) => debug!("Failed to list network interfaces: {}", e),
+    }
+    targets
+}
+
+// Open a signaling connection to a discovered peer and wait for them to accept
+pub async fn connect_to(
+    peer: &DiscoveredPeer,
+    us: &Announcement,
+    timeout: Duration,
+) -> Result<TcpSignaler> {
+    let stream = tokio::time::timeout(HELLO_TIMEOUT, TcpStream::connect(peer.address))
+        .await
+        .context("Timed out connecting")??;
+    let mut signaler = TcpSignaler::new(stream);
+    signaler.send(&us.encode()?).await?;
+
+    let reply = tokio::time::timeout(timeout, signaler.receive())
+        .await
+        .context("Timed out waiting for them to accept")?
+        .context("They declined")?;
+    let them = Announcement::decode(reply.as_bytes())?;
+    if them.instance != peer.announcement.instance {
+        bail!("A different peer answered at {}", peer.address);
+    }
+    Ok(signaler)
+}
+
+// Read who is connecting to us
+pub async fn receive_hello(signaler: &mut TcpSignaler) -> Result<Announcement> {
+    let hello = tokio::time::timeout(HELLO_TIMEOUT, signaler.receive())
+        .await
+        .context("Timed out waiting for the peer to say who it is")??;
+    Announcement::decode(hello.as_bytes())
+}
+
+// Read one line from stdin on its own thread. It's only ever abandoned on the
+// way out, so the chat never loses a line to it.
+fn read_line() -> oneshot::Receiver<io::Result<Option<String>>> {
+    let (tx, rx) = oneshot::channel();
+    std::thread::spawn(move || {
+        let mut line = String::new();
+        let result = match io::stdin().lock().read_line(&mut line) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(line)),
+            Err(e) => Err(e),
+        };
+        let _ = tx.send(result);
+    });
+    rx
+}
+
+async fn next_line(input: &mut oneshot::Receiver<io::Result<Option<String>>>) -> Result<String> {
+    match input.await.context("Input reader stopped")?? {
+        Some(line) => Ok(line),
+        None => bail!("Input ended"),
+    }
+}
+
+// Find peers on the local network, connect to the one picked (or accept one
+// that picks us), exchange the offer and answer over TCP, then chat. Uses
+// host candidates only, so it works with no internet connection.
+pub async fn run_discover(
+    mut options: SessionOptions,
+    store: SharedStore,
+    port: u16,
+) -> Result<()> {
+    let (fingerprint, name) = {
+        let store = store.lock().await;
+        (
+            store.identity().fingerprint(),
+            options
+                .name
+                .clone()
+                .or_else(|| store.config().display_name.clone()),
+        )
+    };
+
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .context("Failed to listen for signaling connections")?;
+    let us = Announcement::new(
+        name.unwrap_or_default(),
+        fingerprint,
+        listener.local_addr()?.port(),
+    );
+    let discovery = Discovery::start(us.clone(), port).await?;
+
+    let ice = IceSettings {
+        stun_servers: Vec::new(),
+        ..Default::default()
+    };
+
+    println!(
+        "Looking for peers on the local network as {}...",
+        us.label()
+    );
+    println!("Type a peer's number to connect, or wait for one to connect to you.");
+
+    let mut shown: Vec<DiscoveredPeer> = Vec::new();
+    let mut input = read_line();
+    let mut refresh = tokio::time::interval(ANNOUNCE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = refresh.tick() => {
+                let peers = discovery.peers().await;
+                let instances = |peers: &[DiscoveredPeer]| {
+                    peers
+                        .iter()
+                        .map(|p| p.announcement.instance.clone())
+                        .collect::<Vec<_>>()
+                };
+                if instances(&peers) != instances(&shown) {
+                    print_peers(&peers, &store).await;
+                }
+                shown = peers;
+            }
+            line = next_line(&mut input) => {
+                let line = line?;
+                let picked = line
+                    .trim()
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| n.checked_sub(1))
+                    .and_then(|i| shown.get(i));
+                let Some(peer) = picked else {
+                    print_peers(&shown, &store).await;
+                    input = read_line();
+                    continue;
+                };
+
+                println!(
+                    "Connecting to {}, waiting for them to accept...",
+                    peer.announcement.label()
+                );
+                match connect_to(peer, &us, options.timeout).await {
+                    Ok(mut signaler) => {
+                        drop(discovery);
+                        options.announced = Some(peer.announcement.fingerprint.clone());
+                        return app::run_with_signaler(options, store, &ice, &mut signaler, true)
+                            .await;
+                    }
+                    Err(e) => {
+                        println!("Couldn't connect to {}: {:#}", peer.announcement.label(), e);
+                        input = read_line();
+                    }
+                }
+            }
+            accepted = listener.accept() => {
+                let (stream, from) = accepted.context("Failed to accept a connection")?;
+                let mut signaler = TcpSignaler::new(stream);
+                let them = match receive_hello(&mut signaler).await {
+                    Ok(them) => them,
+                    Err(e) => {
+                        debug!("Ignoring connection from {}: {:#}", from, e);
+                        continue;
+                    }
+                };
+
+                println!(
+                    "{} at {} wants to connect, claiming identity {} (checked once connected). \
+                     Accept? [y/N]",
+                    them.label(),
+                    from.ip(),
+                    identity::short_fingerprint(&them.fingerprint)
+                );
+                let line = next_line(&mut input).await?;
+                if !line.trim().eq_ignore_ascii_case("y") {
+                    println!("Declined");
+                    input = read_line();
+                    continue;
+                }
+
+                signaler.send(&us.encode()?).await?;
+                drop(discovery);
+                options.announced = Some(them.fingerprint.clone());
+                return app::run_with_signaler(options, store, &ice, &mut signaler, false).await;
+            }
+        }
+    }
+}
+
+async fn print_peers(peers: &[DiscoveredPeer], store: &SharedStore) {
+    if peers.is_empty() {
+        println!("No peers found yet");
+        return;
+    }
+
+    // Names and identities are only as announced until the peer proves its
+    // identity after connecting
+    let store = store.lock().await;
+    println!("Peers on the local network, unverified until connected:");
+    for (i, peer) in peers.iter().enumerate() {
+        let contact = store
+            .contact_by_identity(&peer.announcement.fingerprint)
+            .map(|c| format!(" [claims to be contact {}]", c.name))
+            .unwrap_or_default();
+        println!(
+            "  {}. {}{} at {}",
+            i + 1,
+            peer.announcement.label(),
+            contact,
+            peer.address.ip()
+        );
+    }
+}
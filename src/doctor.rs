@@ -0,0 +1,605 @@
+use crate::connection::{self, IceSettings};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use webrtc::ice::url::{SchemeType, Url};
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
+use webrtc::stun::addr::MappedAddress;
+use webrtc::stun::agent::TransactionId;
+use webrtc::stun::attributes::{ATTR_CHANGE_REQUEST, ATTR_OTHER_ADDRESS};
+use webrtc::stun::message::{Getter, Message, BINDING_REQUEST, BINDING_SUCCESS};
+use webrtc::stun::xoraddr::XorMappedAddress;
+use webrtc::util::ifaces::{self, Kind};
+
+// How long to gather candidates against one server. The ICE agent gives up
+// on a STUN server after 5 seconds of its own.
+const GATHER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to wait for the answer to one binding request, resending it
+// every STUN_RESEND_INTERVAL meanwhile
+const STUN_TIMEOUT: Duration = Duration::from_secs(2);
+const STUN_RESEND_INTERVAL: Duration = Duration::from_millis(500);
+
+// CHANGE-REQUEST flags (RFC 5780)
+const CHANGE_IP: u8 = 0x04;
+const CHANGE_PORT: u8 = 0x02;
+
+// An address on one of this machine's interfaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalAddress {
+    pub interface: String,
+    pub ip: IpAddr,
+}
+
+// The IPv4 and IPv6 addresses of every interface
+pub fn local_addresses() -> Vec<LocalAddress> {
+    let interfaces = match ifaces::ifaces() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            debug!("Failed to list network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+    interfaces
+        .into_iter()
+        .filter(|interface| matches!(interface.kind, Kind::Ipv4 | Kind::Ipv6))
+        .filter_map(|interface| {
+            Some(LocalAddress {
+                ip: interface.addr?.ip(),
+                interface: interface.name,
+            })
+        })
+        .collect()
+}
+
+// Candidates one ICE server gave us
+#[derive(Debug, Clone)]
+pub struct ServerCheck {
+    pub url: String,
+    pub is_turn: bool,
+    // Where the server's name resolved to, for the NAT tests
+    pub address: Option<SocketAddr>,
+    pub outcome: ServerOutcome,
+}
+
+#[derive(Debug, Clone)]
+pub enum ServerOutcome {
+    // The URL didn't parse or the name didn't resolve
+    Unresolved(String),
+    // Nothing came back before the ICE agent gave up
+    NoResponse,
+    // Server reflexive or relayed addresses, with how long each took
+    Candidates(Vec<(String, Duration)>),
+}
+
+impl ServerCheck {
+    pub fn is_reachable(&self) -> bool {
+        matches!(self.outcome, ServerOutcome::Candidates(_))
+    }
+}
+
+// Gather candidates against this one server, as a session would, and time
+// them. TURN servers are checked for relayed candidates only.
+pub async fn check_server(url: &str, ice: &IceSettings) -> ServerCheck {
+    let is_turn = url.starts_with("turn");
+    let mut check = ServerCheck {
+        url: url.to_string(),
+        is_turn,
+        address: None,
+        outcome: ServerOutcome::NoResponse,
+    };
+
+    match resolve(url).await {
+        Ok(address) => check.address = Some(address),
+        Err(e) => {
+            check.outcome = ServerOutcome::Unresolved(format!("{:#}", e));
+            return check;
+        }
+    }
+
+    let settings = IceSettings {
+        stun_servers: if is_turn {
+            Vec::new()
+        } else {
+            vec![url.to_string()]
+        },
+        turn_servers: if is_turn {
+            vec![url.to_string()]
+        } else {
+            Vec::new()
+        },
+        relay_only: is_turn,
+        ..ice.clone()
+    };
+    let wanted = if is_turn {
+        RTCIceCandidateType::Relay
+    } else {
+        RTCIceCandidateType::Srflx
+    };
+    match gather(&settings).await {
+        Ok(candidates) => {
+            let found: Vec<_> = candidates
+                .into_iter()
+                .filter(|(typ, _, _)| *typ == wanted)
+                .map(|(_, address, after)| (address, after))
+                .collect();
+            if !found.is_empty() {
+                check.outcome = ServerOutcome::Candidates(found);
+            }
+        }
+        Err(e) => check.outcome = ServerOutcome::Unresolved(format!("{:#}", e)),
+    }
+    check
+}
+
+// Where an ICE server URL points, IPv4 first as the NAT tests use IPv4
+async fn resolve(url: &str) -> Result<SocketAddr> {
+    let parsed = Url::parse_url(url).with_context(|| format!("Not an ICE server URL: {}", url))?;
+    if !matches!(parsed.scheme, SchemeType::Stun | SchemeType::Turn) {
+        bail!("Only stun: and turn: servers can be checked");
+    }
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((parsed.host.as_str(), parsed.port))
+        .await
+        .with_context(|| format!("{} doesn't resolve", parsed.host))?
+        .collect();
+    addresses
+        .iter()
+        .find(|address| address.is_ipv4())
+        .or(addresses.first())
+        .copied()
+        .with_context(|| format!("{} has no addresses", parsed.host))
+}
+
+// Candidates of a fresh peer connection, with their type and how long after
+// the start of gathering each one came in
+async fn gather(ice: &IceSettings) -> Result<Vec<(RTCIceCandidateType, String, Duration)>> {
+    let pc = connection::create_peer_connection(ice).await?;
+    let start = Instant::now();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    pc.on_ice_candidate(Box::new(move |candidate| {
+        if let Some(candidate) = candidate {
+            let address = format!("{}:{}", candidate.address, candidate.port);
+            let _ = tx.send((candidate.typ, address, start.elapsed()));
+        }
+        Box::pin(async {})
+    }));
+
+    // Something to negotiate, or there's nothing to gather for
+    pc.create_data_channel("doctor", None).await?;
+    let offer = pc.create_offer(None).await?;
+    let mut gathered = pc.gathering_complete_promise().await;
+    pc.set_local_description(offer).await?;
+    let _ = tokio::time::timeout(GATHER_TIMEOUT, gathered.recv()).await;
+    let _ = pc.close().await;
+
+    let mut candidates = Vec::new();
+    while let Ok(candidate) = rx.try_recv() {
+        candidates.push(candidate);
+    }
+    Ok(candidates)
+}
+
+// What a STUN server said to one binding request
+#[derive(Debug, Clone, Copy)]
+pub struct BindingResponse {
+    // Our address as the server saw it
+    pub mapped: SocketAddr,
+    // The server's alternate address, if it can do the RFC 5780 tests
+    pub other_address: Option<SocketAddr>,
+    pub rtt: Duration,
+}
+
+// Send a binding request from `socket` and wait for the answer, None if
+// there was none. `change` holds CHANGE-REQUEST flags, asking the server to
+// answer from its alternate address or port.
+pub async fn binding_request(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    change: u8,
+) -> Result<Option<BindingResponse>> {
+    let mut request = Message::new();
+    request.build(&[Box::new(TransactionId::new()), Box::new(BINDING_REQUEST)])?;
+    if change != 0 {
+        request.add(ATTR_CHANGE_REQUEST, &[0, 0, 0, change]);
+    }
+
+    let start = Instant::now();
+    let mut buffer = [0u8; 1500];
+    while start.elapsed() < STUN_TIMEOUT {
+        socket.send_to(&request.raw, server).await?;
+        let deadline = Instant::now() + STUN_RESEND_INTERVAL;
+        while let Ok(received) =
+            tokio::time::timeout_at(deadline.into(), socket.recv_from(&mut buffer)).await
+        {
+            // Unreachable servers show up as errors on some platforms
+            let Ok((n, _)) = received else { break };
+            let mut response = Message::new();
+            response.raw = buffer[..n].to_vec();
+            if response.decode().is_err()
+                || response.transaction_id != request.transaction_id
+                || response.typ != BINDING_SUCCESS
+            {
+                continue;
+            }
+            return Ok(Some(parse_response(&response, start.elapsed())?));
+        }
+    }
+    Ok(None)
+}
+
+fn parse_response(response: &Message, rtt: Duration) -> Result<BindingResponse> {
+    let mut xor_mapped = XorMappedAddress::default();
+    let mapped = match xor_mapped.get_from(response) {
+        Ok(()) => SocketAddr::new(xor_mapped.ip, xor_mapped.port),
+        Err(_) => {
+            // Servers from before RFC 5389 only send MAPPED-ADDRESS
+            let mut mapped = MappedAddress::default();
+            mapped
+                .get_from(response)
+                .context("STUN response has no mapped address")?;
+            SocketAddr::new(mapped.ip, mapped.port)
+        }
+    };
+    let mut other = MappedAddress::default();
+    let other_address = other
+        .get_from_as(response, ATTR_OTHER_ADDRESS)
+        .ok()
+        .map(|()| SocketAddr::new(other.ip, other.port));
+    Ok(BindingResponse {
+        mapped,
+        other_address,
+        rtt,
+    })
+}
+
+// How the NAT picks our public address for each destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mapping {
+    // Our own address is the public one
+    NoNat,
+    // The same public address and port whatever the destination
+    EndpointIndependent,
+    // A new public address or port for each destination, a symmetric NAT
+    EndpointDependent,
+    // Only one destination answered, so there's nothing to compare
+    Unknown,
+}
+
+// Which packets the NAT lets in to a public address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filtering {
+    // From anywhere once we've sent something out
+    EndpointIndependent,
+    // Only from addresses we've sent to
+    AddressDependent,
+    // Only from the exact address and port we've sent to
+    AddressAndPortDependent,
+    // No STUN server could do the RFC 5780 tests
+    Unknown,
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Mapping::NoNat => "none, this machine has a public address",
+            Mapping::EndpointIndependent => {
+                "endpoint-independent, the same public address for every destination"
+            }
+            Mapping::EndpointDependent => {
+                "endpoint-dependent (symmetric NAT), a new public address for each destination"
+            }
+            Mapping::Unknown => "unknown, it takes two STUN servers that answer to tell",
+        })
+    }
+}
+
+impl fmt::Display for Filtering {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Filtering::EndpointIndependent => "endpoint-independent, anyone can send to us",
+            Filtering::AddressDependent => "address-dependent, only hosts we've sent to",
+            Filtering::AddressAndPortDependent => {
+                "address and port-dependent, only the exact addresses we've sent to"
+            }
+            Filtering::Unknown => "unknown, none of the STUN servers support the RFC 5780 tests",
+        })
+    }
+}
+
+// What the STUN servers told us about the NAT in front of this machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NatReport {
+    // Our public addresses as the servers saw them
+    pub public: Vec<SocketAddr>,
+    pub mapping: Mapping,
+    pub filtering: Filtering,
+}
+
+// Work out the NAT's behaviour by sending binding requests from one socket
+// to each STUN server, and to the alternate addresses of a server that has
+// them, then testing its filtering against that server. None if no server
+// answered at all.
+pub async fn probe_nat(
+    servers: &[SocketAddr],
+    local: &[LocalAddress],
+) -> Result<Option<NatReport>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let local_port = socket.local_addr()?.port();
+
+    // Where we sent to, and the public address each destination saw
+    let mut observed: Vec<(SocketAddr, SocketAddr)> = Vec::new();
+    let mut rfc5780_server = None;
+    for &server in servers.iter().filter(|server| server.is_ipv4()) {
+        if observed.iter().any(|(to, _)| *to == server) {
+            continue;
+        }
+        if let Some(response) = binding_request(&socket, server, 0).await? {
+            observed.push((server, response.mapped));
+            if rfc5780_server.is_none() {
+                if let Some(other) = response.other_address {
+                    rfc5780_server = Some((server, other));
+                }
+            }
+        }
+    }
+    if observed.is_empty() {
+        return Ok(None);
+    }
+
+    // The alternate address of an RFC 5780 server is another destination
+    // to compare the mapping against
+    let mut filtering = Filtering::Unknown;
+    if let Some((server, other)) = rfc5780_server {
+        for to in [
+            SocketAddr::new(other.ip(), server.port()),
+            SocketAddr::new(other.ip(), other.port()),
+        ] {
+            if let Some(response) = binding_request(&socket, to, 0).await? {
+                observed.push((to, response.mapped));
+            }
+        }
+        filtering = probe_filtering(server).await?;
+    }
+
+    let mut public: Vec<SocketAddr> = Vec::new();
+    for (_, mapped) in &observed {
+        if !public.contains(mapped) {
+            public.push(*mapped);
+        }
+    }
+    let first = observed[0].1;
+    let mapping =
+        if local.iter().any(|address| address.ip == first.ip()) && first.port() == local_port {
+            Mapping::NoNat
+        } else if public.len() > 1 {
+            Mapping::EndpointDependent
+        } else if observed.len() > 1 {
+            Mapping::EndpointIndependent
+        } else {
+            Mapping::Unknown
+        };
+
+    Ok(Some(NatReport {
+        public,
+        mapping,
+        filtering,
+    }))
+}
+
+// Ask an RFC 5780 server to answer from addresses we haven't sent to. This
+// takes a socket of its own: one that had already sent to the alternate
+// address would have opened the NAT to it.
+async fn probe_filtering(server: SocketAddr) -> Result<Filtering> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    if binding_request(&socket, server, 0).await?.is_none() {
+        return Ok(Filtering::Unknown);
+    }
+    Ok(
+        if binding_request(&socket, server, CHANGE_IP | CHANGE_PORT)
+            .await?
+            .is_some()
+        {
+            Filtering::EndpointIndependent
+        } else if binding_request(&socket, server, CHANGE_PORT)
+            .await?
+            .is_some()
+        {
+            Filtering::AddressDependent
+        } else {
+            Filtering::AddressAndPortDependent
+        },
+    )
+}
+
+// Whether peers will be able to connect directly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outlook {
+    // With any peer
+    Direct,
+    // With most peers, those behind symmetric NATs need a relay
+    MostPeers,
+    // Only through a TURN relay, except on the local network
+    Relay,
+}
+
+impl NatReport {
+    pub fn outlook(&self) -> Outlook {
+        match (self.mapping, self.filtering) {
+            (Mapping::NoNat, _) | (_, Filtering::EndpointIndependent) => Outlook::Direct,
+            (Mapping::EndpointDependent, _) => Outlook::Relay,
+            _ => Outlook::MostPeers,
+        }
+    }
+}
+
+// Everything `doctor` found out
+#[derive(Debug, Clone)]
+pub struct DoctorReport {
+    pub local: Vec<LocalAddress>,
+    pub servers: Vec<ServerCheck>,
+    pub nat: Option<NatReport>,
+}
+
+// Check the interfaces, every STUN and TURN server in `ice`, and the NAT
+pub async fn diagnose(ice: &IceSettings) -> Result<DoctorReport> {
+    let local = local_addresses();
+    let mut servers = Vec::new();
+    for url in ice.stun_servers.iter().chain(&ice.turn_servers) {
+        servers.push(check_server(url, ice).await);
+    }
+    let stun_addresses: Vec<SocketAddr> = servers
+        .iter()
+        .filter(|check| !check.is_turn)
+        .filter_map(|check| check.address)
+        .collect();
+    let nat = probe_nat(&stun_addresses, &local).await?;
+    Ok(DoctorReport {
+        local,
+        servers,
+        nat,
+    })
+}
+
+impl DoctorReport {
+    // What to do about it, one suggestion per line
+    pub fn advice(&self) -> Vec<String> {
+        let turn_servers: Vec<_> = self.servers.iter().filter(|check| check.is_turn).collect();
+        let relay = turn_servers.iter().find(|check| check.is_reachable());
+        let mut advice = Vec::new();
+
+        let outlook = match self.nat {
+            Some(ref nat) => nat.outlook(),
+            None => {
+                let resolved = self
+                    .servers
+                    .iter()
+                    .any(|check| !check.is_turn && check.address.is_some());
+                advice.push(if resolved {
+                    "No STUN server answered, so UDP to the internet looks blocked. Only peers \
+                     on the local network can connect directly (try `discover`)."
+                        .to_string()
+                } else {
+                    "No STUN server resolved, check DNS or pass servers by address with \
+                     `--stun stun:<ip>:<port>`. Peers on the local network can still connect \
+                     directly (try `discover`)."
+                        .to_string()
+                });
+                Outlook::Relay
+            }
+        };
+        match outlook {
+            Outlook::Direct => advice.push(
+                "Direct connections should work, peers can reach this machine's public address."
+                    .to_string(),
+            ),
+            Outlook::MostPeers => advice.push(
+                "Direct connections should work with most peers. A peer behind a symmetric NAT \
+                 will need a TURN relay."
+                    .to_string(),
+            ),
+            Outlook::Relay if self.nat.is_some() => advice.push(
+                "This NAT gives every destination a different public address, so direct \
+                 connections only work with peers that have a public address or a full cone NAT. \
+                 Expect to need a TURN relay."
+                    .to_string(),
+            ),
+            Outlook::Relay => {}
+        }
+
+        for check in &turn_servers {
+            if !check.is_reachable() {
+                advice.push(format!(
+                    "TURN server {} gave no relay, check its URL, username and credential.",
+                    check.url
+                ));
+            }
+        }
+        match (outlook, relay) {
+            (Outlook::Direct, _) => {}
+            (_, Some(relay)) => advice.push(format!(
+                "TURN server {} works and will carry the connection when a direct one fails.",
+                relay.url
+            )),
+            (_, None) => advice.push(
+                "No working TURN server is configured. Add one for the contacts you talk to with \
+                 `contacts add <name> <identity> --turn turn:host:3478 --turn-username <user> \
+                 --turn-credential <secret>`."
+                    .to_string(),
+            ),
+        }
+        advice
+    }
+
+    pub fn print(&self) {
+        println!("Local interfaces:");
+        if self.local.is_empty() {
+            println!("  none found");
+        }
+        for address in &self.local {
+            let note = if address.ip.is_loopback() {
+                " (loopback)"
+            } else {
+                match address.ip {
+                    IpAddr::V6(ip) if ip.segments()[0] & 0xffc0 == 0xfe80 => " (link-local)",
+                    _ => "",
+                }
+            };
+            println!("  {} {}{}", address.interface, address.ip, note);
+        }
+
+        println!("\nICE servers:");
+        if self.servers.is_empty() {
+            println!("  none configured");
+        }
+        for check in &self.servers {
+            match check.outcome {
+                ServerOutcome::Unresolved(ref reason) => println!("  {} - {}", check.url, reason),
+                ServerOutcome::NoResponse => println!(
+                    "  {} - no {} candidate, the server didn't answer",
+                    check.url,
+                    if check.is_turn { "relay" } else { "srflx" }
+                ),
+                ServerOutcome::Candidates(ref candidates) => {
+                    for (address, after) in candidates {
+                        println!(
+                            "  {} - {} {} after {} ms",
+                            check.url,
+                            if check.is_turn { "relay" } else { "srflx" },
+                            address,
+                            after.as_millis()
+                        );
+                    }
+                }
+            }
+        }
+
+        println!("\nNAT:");
+        match self.nat {
+            Some(ref nat) => {
+                let public: Vec<String> = nat.public.iter().map(|a| a.to_string()).collect();
+                println!("  Public address: {}", public.join(", "));
+                println!("  Mapping: {}", nat.mapping);
+                println!("  Filtering: {}", nat.filtering);
+            }
+            None => println!("  unknown, no STUN server answered"),
+        }
+
+        println!("\nAdvice:");
+        for line in self.advice() {
+            println!("  {}", line);
+        }
+    }
+}
+
+// Run the checks and print what they found
+pub async fn run_doctor(ice: IceSettings) -> Result<()> {
+    println!("Checking the network, this takes a few seconds...\n");
+    let report = diagnose(&ice).await?;
+    report.print();
+    Ok(())
+}
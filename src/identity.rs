@@ -0,0 +1,148 @@
+use crate::connection::{self, IncomingMessages, SharedDataChannel};
+use crate::protocol::WireMessage;
+
+use anyhow::{Context, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use log::debug;
+use rand::RngCore;
+use std::sync::Arc;
+use std::time::Duration;
+use webrtc::peer_connection::RTCPeerConnection;
+
+// How long to wait for the peer to announce its identity
+const HELLO_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Our long-term identity key pair
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    // Create a new random identity
+    pub fn generate() -> Self {
+        let mut seed = [0u8; 32];
+        rand::rng().fill_bytes(&mut seed);
+        Self {
+            signing_key: SigningKey::from_bytes(&seed),
+        }
+    }
+
+    // Restore an identity from its stored seed
+    pub fn from_seed_hex(seed: &str) -> Result<Self> {
+        let seed: [u8; 32] = hex::decode(seed)
+            .context("Corrupt identity key")?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Identity key has the wrong length"))?;
+        Ok(Self {
+            signing_key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    // Secret seed for storage
+    pub fn seed_hex(&self) -> String {
+        hex::encode(self.signing_key.to_bytes())
+    }
+
+    // Public key, which is what peers save in their contact book
+    pub fn fingerprint(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().to_bytes())
+    }
+}
+
+// Shortened fingerprint for display
+pub fn short_fingerprint(fingerprint: &str) -> &str {
+    &fingerprint[..fingerprint.len().min(16)]
+}
+
+// Check that a string is a well-formed identity key
+pub fn parse_fingerprint(fingerprint: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(fingerprint.trim())
+        .context("Identity key must be hex encoded")?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Identity key must be 32 bytes (64 hex characters)"))?;
+    VerifyingKey::from_bytes(&bytes).context("Invalid identity key")
+}
+
+// Announce our identity and verify the peer's, returning the peer's fingerprint.
+// Signatures cover both DTLS fingerprints, so they can't be replayed into another session.
+pub async fn exchange(
+    pc: &Arc<RTCPeerConnection>,
+    dc: &SharedDataChannel,
+    incoming: &mut IncomingMessages,
+    identity: &Identity,
+) -> Result<String> {
+    let channel = connection::wait_for_open(dc, HELLO_TIMEOUT).await?;
+
+    let local_sdp = pc
+        .local_description()
+        .await
+        .context("No local description")?
+        .sdp;
+    let remote_sdp = pc
+        .remote_description()
+        .await
+        .context("No remote description")?
+        .sdp;
+    let local_dtls = dtls_fingerprint(&local_sdp)?;
+    let remote_dtls = dtls_fingerprint(&remote_sdp)?;
+
+    let signature = identity
+        .signing_key
+        .sign(&transcript(&local_dtls, &remote_dtls));
+    WireMessage::Hello {
+        identity: identity.fingerprint(),
+        signature: hex::encode(signature.to_bytes()),
+    }
+    .send(&channel)
+    .await?;
+
+    let msg = match tokio::time::timeout(HELLO_TIMEOUT, incoming.recv()).await {
+        Ok(Some(msg)) => msg,
+        Ok(None) => {
+            return Err(anyhow::anyhow!(
+                "Data channel closed before peer identified"
+            ))
+        }
+        Err(_) => return Err(anyhow::anyhow!("Timed out waiting for peer identity")),
+    };
+
+    let (peer_identity, peer_signature) = match WireMessage::decode(&msg.data)? {
+        WireMessage::Hello {
+            identity,
+            signature,
+        } => (identity, signature),
+        other => {
+            return Err(anyhow::anyhow!(
+                "Expected identity announcement from peer, got {:?}",
+                other
+            ))
+        }
+    };
+
+    let key = parse_fingerprint(&peer_identity)?;
+    let signature: [u8; 64] = hex::decode(&peer_signature)
+        .context("Malformed identity signature")?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Identity signature has the wrong length"))?;
+    key.verify(
+        &transcript(&remote_dtls, &local_dtls),
+        &Signature::from_bytes(&signature),
+    )
+    .map_err(|_| anyhow::anyhow!("Peer identity signature does not match this session"))?;
+
+    debug!("Verified peer identity {}", peer_identity);
+    Ok(peer_identity)
+}
+
+// Bytes signed by the sender: its own DTLS fingerprint followed by the receiver's
+fn transcript(sender_dtls: &str, receiver_dtls: &str) -> Vec<u8> {
+    format!("modulate-comms/hello\n{}\n{}", sender_dtls, receiver_dtls).into_bytes()
+}
+
+// Extract the DTLS certificate fingerprint from an SDP
+fn dtls_fingerprint(sdp: &str) -> Result<String> {
+    sdp.lines()
+        .find_map(|line| line.trim().strip_prefix("a=fingerprint:"))
+        .map(|fingerprint| fingerprint.to_lowercase())
+        .context("Session description has no DTLS fingerprint")
+}
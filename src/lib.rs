@@ -0,0 +1,27 @@
+pub mod app;
+pub mod auth;
+pub mod channels;
+pub mod chat;
+pub mod cli;
+pub mod connection;
+pub mod console;
+pub mod contacts;
+#[cfg(unix)]
+pub mod daemon;
+pub mod discovery;
+pub mod doctor;
+pub mod identity;
+pub mod ping;
+pub mod pipe;
+pub mod protocol;
+pub mod rendezvous;
+pub mod sdp;
+pub mod signaling;
+pub mod simulate;
+pub mod socks;
+pub mod stats;
+pub mod store;
+pub mod transfer;
+pub mod tunnel;
+pub mod utils;
+pub mod wormhole;
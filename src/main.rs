@@ -1,12 +1,16 @@
-mod app;
-mod chat;
-mod cli;
-mod connection;
-mod sdp;
+use modulate_comms::{
+    app, channels, cli, connection, contacts, discovery, doctor, rendezvous, simulate, store,
+    tunnel,
+};
+#[cfg(unix)]
+use modulate_comms::{daemon, pipe};
 
 use anyhow::Result;
 use clap::Parser;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::Mutex;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -23,11 +27,274 @@ async fn main() -> Result<()> {
 
     // Set up connection timeout from CLI
     let connection_timeout = Duration::from_secs(cli.timeout);
+    let stats_interval = cli.stats_interval.map(Duration::from_secs);
 
     // Execute the appropriate command
     match cli.command {
-        cli::Commands::Offer => app::run_offerer(connection_timeout).await,
-        cli::Commands::Answer => app::run_answerer(connection_timeout).await,
+        cli::Commands::Offer {
+            password,
+            to,
+            rendezvous,
+        } => {
+            let store = open_store(&cli.data_dir, cli.no_persist)?;
+            let options = app::SessionOptions {
+                timeout: connection_timeout,
+                password,
+                to,
+                name: cli.name.clone(),
+                channels: cli.channels.clone(),
+                rendezvous,
+                code: None,
+                stats_interval,
+                announced: None,
+            };
+            app::run_offerer(options, store).await
+        }
+        cli::Commands::Answer {
+            password,
+            to,
+            rendezvous,
+            code,
+        } => {
+            let store = open_store(&cli.data_dir, cli.no_persist)?;
+            let options = app::SessionOptions {
+                timeout: connection_timeout,
+                password,
+                to,
+                name: cli.name.clone(),
+                channels: cli.channels.clone(),
+                rendezvous,
+                code,
+                stats_interval,
+                announced: None,
+            };
+            app::run_answerer(options, store).await
+        }
+        #[cfg(unix)]
+        cli::Commands::Pipe {
+            offer,
+            answer: _,
+            password,
+            to,
+            rendezvous,
+            code,
+        } => {
+            // Before anything is printed, so stdout only ever carries data
+            let output = pipe::take_stdout()?;
+            let store = open_store(&cli.data_dir, cli.no_persist)?;
+            let options = app::SessionOptions {
+                timeout: connection_timeout,
+                password,
+                to,
+                name: cli.name.clone(),
+                channels: cli.channels.clone(),
+                rendezvous: Some(rendezvous),
+                code,
+                stats_interval,
+                announced: None,
+            };
+            pipe::run_pipe(options, store, offer, output).await
+        }
+        #[cfg(not(unix))]
+        cli::Commands::Pipe { .. } => Err(anyhow::anyhow!(
+            "Pipe mode isn't available on this platform yet"
+        )),
+        cli::Commands::Tunnel {
+            offer,
+            answer: _,
+            local,
+            remote,
+            udp,
+            profile,
+            exit: _,
+            allow,
+            password,
+            to,
+            rendezvous,
+            code,
+        } => {
+            let role = match (local, remote) {
+                (Some(local), Some(remote)) if udp => tunnel::TunnelRole::UdpEntry {
+                    local,
+                    remote,
+                    profile,
+                },
+                (Some(local), Some(remote)) => tunnel::TunnelRole::Entry {
+                    local,
+                    forward: tunnel::Forward::To(remote),
+                },
+                _ => tunnel::TunnelRole::Exit {
+                    allow: tunnel::Allowlist(allow),
+                },
+            };
+            let store = open_store(&cli.data_dir, cli.no_persist)?;
+            let options = app::SessionOptions {
+                timeout: connection_timeout,
+                password,
+                to,
+                name: cli.name.clone(),
+                channels: cli.channels.clone(),
+                rendezvous,
+                code,
+                stats_interval,
+                announced: None,
+            };
+            tunnel::run_tunnel(options, store, offer, role).await
+        }
+        cli::Commands::Socks {
+            offer,
+            answer: _,
+            listen,
+            password,
+            to,
+            rendezvous,
+            code,
+        } => {
+            let store = open_store(&cli.data_dir, cli.no_persist)?;
+            let options = app::SessionOptions {
+                timeout: connection_timeout,
+                password,
+                to,
+                name: cli.name.clone(),
+                channels: cli.channels.clone(),
+                rendezvous,
+                code,
+                stats_interval,
+                announced: None,
+            };
+            let role = tunnel::TunnelRole::Entry {
+                local: listen,
+                forward: tunnel::Forward::Socks,
+            };
+            tunnel::run_tunnel(options, store, offer, role).await
+        }
+        cli::Commands::Discover { password, to, port } => {
+            let store = open_store(&cli.data_dir, cli.no_persist)?;
+            let options = app::SessionOptions {
+                timeout: connection_timeout,
+                password,
+                to,
+                name: cli.name.clone(),
+                channels: cli.channels.clone(),
+                rendezvous: None,
+                code: None,
+                stats_interval,
+                announced: None,
+            };
+            discovery::run_discover(options, store, port).await
+        }
+        cli::Commands::Rendezvous { listen, ttl } => {
+            rendezvous::run_server(listen, Duration::from_secs(ttl)).await
+        }
+        #[cfg(unix)]
+        cli::Commands::Daemon {
+            socket,
+            downloads,
+            max_file_size,
+        } => {
+            let store = open_store(&cli.data_dir, cli.no_persist)?;
+            daemon::run_daemon(
+                socket.unwrap_or_else(daemon::default_socket_path),
+                downloads.unwrap_or_else(daemon::default_downloads_dir),
+                max_file_size.saturating_mul(1024 * 1024),
+                store,
+                connection_timeout,
+            )
+            .await
+        }
+        #[cfg(not(unix))]
+        cli::Commands::Daemon { .. } => Err(anyhow::anyhow!(
+            "The daemon needs Unix domain sockets, which this platform doesn't have"
+        )),
+        cli::Commands::Contacts { action } => {
+            if cli.no_persist {
+                return Err(anyhow::anyhow!(
+                    "Contacts need the store, drop --no-persist"
+                ));
+            }
+            let mut store = store::Store::open(cli.data_dir.clone())?;
+            contacts::run_contacts(action, &mut store)
+        }
+        cli::Commands::Channels { action } => {
+            if cli.no_persist {
+                return Err(anyhow::anyhow!(
+                    "Channel settings need the store, drop --no-persist (or use --channel)"
+                ));
+            }
+            let mut store = store::Store::open(cli.data_dir.clone())?;
+            channels::run_channels(action, &mut store)
+        }
+        cli::Commands::Doctor {
+            to,
+            stun,
+            turn,
+            turn_username,
+            turn_credential,
+        } => {
+            let ice = match to {
+                Some(name) => {
+                    if cli.no_persist {
+                        return Err(anyhow::anyhow!(
+                            "Contacts need the store, drop --no-persist"
+                        ));
+                    }
+                    let store = store::Store::open(cli.data_dir.clone())?;
+                    let contact = store
+                        .contact(&name)
+                        .ok_or_else(|| anyhow::anyhow!("No contact named '{}'", name))?;
+                    contact.ice.clone().unwrap_or_default()
+                }
+                None => {
+                    let defaults = connection::IceSettings::default();
+                    connection::IceSettings {
+                        stun_servers: if stun.is_empty() {
+                            defaults.stun_servers
+                        } else {
+                            stun
+                        },
+                        turn_servers: turn,
+                        turn_username: turn_username.unwrap_or_default(),
+                        turn_credential: turn_credential.unwrap_or_default(),
+                        relay_only: false,
+                    }
+                }
+            };
+            doctor::run_doctor(ice).await
+        }
+        cli::Commands::Simulate {
+            latency,
+            jitter,
+            loss,
+            bandwidth,
+            nat,
+            relay,
+            messages,
+            size,
+            profile,
+        } => {
+            let config = simulate::SimulationConfig {
+                latency: Duration::from_millis(latency),
+                jitter: Duration::from_millis(jitter),
+                loss_percent: loss,
+                bandwidth_kbps: bandwidth,
+                nat,
+                relay,
+                messages,
+                message_size: size,
+                profile,
+            };
+            simulate::run_simulation(config, connection_timeout).await
+        }
         cli::Commands::Group { max_peers } => app::run_group_chat(max_peers).await,
     }
 }
+
+// Unlock the encrypted store, or use an in-memory one in ephemeral mode
+fn open_store(data_dir: &Option<PathBuf>, no_persist: bool) -> Result<store::SharedStore> {
+    let store = if no_persist {
+        store::Store::ephemeral()
+    } else {
+        store::Store::open(data_dir.clone())?
+    };
+    Ok(Arc::new(Mutex::new(store)))
+}
@@ -0,0 +1,300 @@
+use crate::connection::IncomingMessages;
+use crate::protocol::WireMessage;
+
+use anyhow::Result;
+use log::debug;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use webrtc::data_channel::RTCDataChannel;
+
+// Gap between the pings of /ping, as the ping tool does
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long /ping waits for each pong
+pub const PONG_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How often the keepalive pings the peer
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Silence after which the peer counts as gone. ICE only calls the connection
+// disconnected after 5 seconds without consent and failed after 25.
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Sends pings on a data channel and matches up the pongs
+pub struct Pinger {
+    dc: Arc<RTCDataChannel>,
+    // Ping timestamps count from here
+    epoch: Instant,
+    next_seq: AtomicU32,
+    // Pings someone is waiting on, by sequence number
+    pending: Mutex<HashMap<u32, oneshot::Sender<Duration>>>,
+    last_pong: Mutex<Option<Instant>>,
+    last_rtt: Mutex<Option<Duration>>,
+}
+
+impl Pinger {
+    pub fn new(dc: Arc<RTCDataChannel>) -> Self {
+        Self {
+            dc,
+            epoch: Instant::now(),
+            next_seq: AtomicU32::new(1),
+            pending: Mutex::new(HashMap::new()),
+            last_pong: Mutex::new(None),
+            last_rtt: Mutex::new(None),
+        }
+    }
+
+    // Send a ping nobody waits on
+    async fn send(&self) -> Result<()> {
+        self.send_seq(self.next_seq.fetch_add(1, Ordering::SeqCst))
+            .await
+    }
+
+    async fn send_seq(&self, seq: u32) -> Result<()> {
+        let sent_at = self.epoch.elapsed().as_micros() as u64;
+        WireMessage::Ping { seq, sent_at }.send(&self.dc).await
+    }
+
+    // Ping the peer and wait up to `timeout` for the round trip time, None if
+    // no pong came back in time
+    pub async fn ping(&self, timeout: Duration) -> Result<Option<Duration>> {
+        let (tx, rx) = oneshot::channel();
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        self.pending.lock().unwrap().insert(seq, tx);
+        let sent = self.send_seq(seq).await;
+        let rtt = match sent {
+            Ok(_) => tokio::time::timeout(timeout, rx)
+                .await
+                .ok()
+                .and_then(Result::ok),
+            Err(_) => None,
+        };
+        self.pending.lock().unwrap().remove(&seq);
+        sent?;
+        Ok(rtt)
+    }
+
+    // A pong came back for one of our pings
+    fn pong(&self, seq: u32, sent_at: u64) {
+        let Some(rtt) = self
+            .epoch
+            .elapsed()
+            .checked_sub(Duration::from_micros(sent_at))
+        else {
+            debug!("Ignoring a pong from the future");
+            return;
+        };
+        *self.last_pong.lock().unwrap() = Some(Instant::now());
+        *self.last_rtt.lock().unwrap() = Some(rtt);
+        if let Some(waiting) = self.pending.lock().unwrap().remove(&seq) {
+            let _ = waiting.send(rtt);
+        }
+    }
+
+    // Round trip time of the latest pong
+    pub fn last_rtt(&self) -> Option<Duration> {
+        *self.last_rtt.lock().unwrap()
+    }
+
+    // Time since the latest pong, None before the first
+    pub fn silence(&self) -> Option<Duration> {
+        self.last_pong.lock().unwrap().map(|pong| pong.elapsed())
+    }
+}
+
+// Answer the peer's pings and hand pongs to `pinger`, passing everything
+// else on in the returned queue
+pub fn intercept(
+    mut incoming: IncomingMessages,
+    dc: Arc<RTCDataChannel>,
+    pinger: Option<Arc<Pinger>>,
+) -> IncomingMessages {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(msg) = incoming.recv().await {
+            if msg.is_string {
+                match WireMessage::decode(&msg.data) {
+                    Ok(WireMessage::Ping { seq, sent_at }) => {
+                        let pong = WireMessage::Pong { seq, sent_at };
+                        if let Err(e) = pong.send(&dc).await {
+                            debug!("Pong not sent: {}", e);
+                        }
+                        continue;
+                    }
+                    Ok(WireMessage::Pong { seq, sent_at }) => {
+                        if let Some(ref pinger) = pinger {
+                            pinger.pong(seq, sent_at);
+                        }
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+            if tx.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// Ping the peer every second and call `notify` when it stops answering for
+// longer than PEER_TIMEOUT, and again when it's back. Nothing is reported
+// before the first pong, as older peers don't answer pings at all.
+pub fn spawn_keepalive<F>(pinger: Arc<Pinger>, notify: F) -> JoinHandle<()>
+where
+    F: Fn(&str) + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(KEEPALIVE_INTERVAL);
+        let mut lost = false;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = pinger.send().await {
+                debug!("Keepalive ping not sent: {}", e);
+            }
+            match pinger.silence() {
+                Some(silence) if silence > PEER_TIMEOUT && !lost => {
+                    lost = true;
+                    notify(&format!(
+                        "* The peer hasn't answered for {} seconds, the connection looks lost",
+                        silence.as_secs()
+                    ));
+                }
+                Some(silence) if silence <= PEER_TIMEOUT && lost => {
+                    lost = false;
+                    notify("* The peer is answering again");
+                }
+                _ => {}
+            }
+        }
+    })
+}
+
+// Ping the peer `count` times a second apart, printing each round trip as
+// the ping tool does and the summary at the end
+pub async fn run<F>(pinger: &Pinger, count: usize, peer: &str, print: F) -> PingSummary
+where
+    F: Fn(&str),
+{
+    print(&format!(
+        "PING {}: {} pings on the {} channel",
+        peer,
+        count,
+        pinger.dc.label()
+    ));
+    let mut rtts = Vec::new();
+    let mut sent = 0;
+    let mut ticker = tokio::time::interval(PING_INTERVAL);
+    // Numbered from 1 for this run, the keepalive's pings are in between
+    for seq in 1..=count {
+        ticker.tick().await;
+        match pinger.ping(PONG_TIMEOUT).await {
+            Ok(Some(rtt)) => {
+                sent += 1;
+                rtts.push(rtt);
+                print(&format!(
+                    "pong from {}: seq={} time={} ms",
+                    peer,
+                    seq,
+                    millis(rtt)
+                ));
+            }
+            Ok(None) => {
+                sent += 1;
+                print(&format!("no pong from {}: seq={}", peer, seq));
+            }
+            Err(e) => {
+                print(&format!("Ping not sent: {}", e));
+                break;
+            }
+        }
+    }
+
+    let summary = PingSummary::new(sent, &rtts);
+    print(&format!("--- {} ping statistics ---", peer));
+    for line in summary.lines() {
+        print(&line);
+    }
+    summary
+}
+
+// Summary of a run of pings, like the last lines of the ping tool
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PingSummary {
+    pub sent: usize,
+    pub received: usize,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    // Mean difference between consecutive round trip times
+    pub jitter: Duration,
+}
+
+impl PingSummary {
+    // Sum up the round trip times of the pongs for `sent` pings, in the order
+    // they came back
+    pub fn new(sent: usize, rtts: &[Duration]) -> Self {
+        let received = rtts.len();
+        let min = rtts.iter().min().copied().unwrap_or_default();
+        let max = rtts.iter().max().copied().unwrap_or_default();
+        let avg = match received {
+            0 => Duration::ZERO,
+            n => rtts.iter().sum::<Duration>() / n as u32,
+        };
+        let jitter = match received {
+            0 | 1 => Duration::ZERO,
+            n => {
+                rtts.windows(2)
+                    .map(|pair| pair[0].abs_diff(pair[1]))
+                    .sum::<Duration>()
+                    / (n - 1) as u32
+            }
+        };
+        Self {
+            sent,
+            received,
+            min,
+            avg,
+            max,
+            jitter,
+        }
+    }
+
+    // Share of the pings that got no pong, in percent
+    pub fn loss_percent(&self) -> f64 {
+        match self.sent {
+            0 => 0.0,
+            sent => (sent - self.received) as f64 * 100.0 / sent as f64,
+        }
+    }
+
+    // The closing lines of the ping tool's output
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "{} pings sent, {} pongs received, {:.0}% loss",
+            self.sent,
+            self.received,
+            self.loss_percent()
+        )];
+        if self.received > 0 {
+            lines.push(format!(
+                "rtt min/avg/max/jitter = {}/{}/{}/{} ms",
+                millis(self.min),
+                millis(self.avg),
+                millis(self.max),
+                millis(self.jitter)
+            ));
+        }
+        lines
+    }
+}
+
+// Milliseconds with three decimals, as the ping tool prints them
+pub fn millis(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64() * 1000.0)
+}
@@ -0,0 +1,187 @@
+use crate::app::{self, SessionOptions};
+use crate::connection::IncomingMessages;
+use crate::protocol::WireMessage;
+use crate::stats;
+use crate::store::SharedStore;
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use log::{debug, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
+
+// Bytes of input in each binary frame
+const CHUNK_LEN: usize = 16 * 1024;
+
+// Stop reading input while this much is still waiting to go out
+const MAX_BUFFERED: usize = 1024 * 1024;
+
+// How often to check the peer is still there while nothing is arriving
+const STATE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Bytes moved each way by one pipe session
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PipeTotals {
+    pub sent: u64,
+    pub received: u64,
+}
+
+// Keep the real stdout for the peer's data and point fd 1 at stderr, so
+// anything printed from here on (connection progress, the wormhole code)
+// can't end up mixed into the data
+#[cfg(unix)]
+pub fn take_stdout() -> Result<std::fs::File> {
+    use std::io::{self, Write};
+    use std::os::unix::io::FromRawFd;
+
+    io::stdout().flush()?;
+
+    // SAFETY: dup only duplicates a file descriptor we were started with
+    let data = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if data < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to duplicate stdout");
+    }
+    // SAFETY: dup2 replaces fd 1 with a copy of stderr, both stay valid
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to redirect stdout");
+    }
+    // SAFETY: `data` is a fresh descriptor that nothing else owns
+    Ok(unsafe { std::fs::File::from_raw_fd(data) })
+}
+
+// Connect through a rendezvous server, then pipe stdin to the peer and the
+// peer's data to `output`
+pub async fn run_pipe(
+    options: SessionOptions,
+    store: SharedStore,
+    is_offerer: bool,
+    output: std::fs::File,
+) -> Result<()> {
+    if options.rendezvous.is_none() {
+        bail!("Pipe mode reads data from stdin, so it needs --rendezvous to signal");
+    }
+    let mut signaler = app::session_signaler(&options, is_offerer)?;
+    let app::Session {
+        pc, dc, incoming, ..
+    } = app::open_session(&options, &store, signaler.as_mut(), is_offerer).await?;
+
+    eprintln!("Piping data, the session ends once both sides reach end of input");
+    // On stderr like everything else, stdout only carries the peer's data
+    let reporter = options.stats_interval.map(|interval| {
+        stats::spawn_reporter(
+            Arc::clone(&pc),
+            vec![Arc::clone(&dc)],
+            None,
+            interval,
+            |line| eprintln!("{}", line),
+        )
+    });
+    let result = pump(
+        &dc,
+        incoming,
+        tokio::io::stdin(),
+        tokio::fs::File::from_std(output),
+    )
+    .await;
+    if let Some(reporter) = reporter {
+        reporter.abort();
+    }
+    let _ = pc.close().await;
+
+    let totals = result?;
+    eprintln!(
+        "Done: sent {} bytes, received {} bytes",
+        totals.sent, totals.received
+    );
+    Ok(())
+}
+
+// Send `input` to the peer in binary frames and write the peer's frames to
+// `output`, until both sides have reached end of input. Each side's end is
+// passed on as an Eof message, which closes `output` on the other side.
+pub async fn pump<R, W>(
+    dc: &RTCDataChannel,
+    mut incoming: IncomingMessages,
+    mut input: R,
+    output: W,
+) -> Result<PipeTotals>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut totals = PipeTotals::default();
+    let mut reading = true;
+    let mut receiving = true;
+    let mut buffer = vec![0u8; CHUNK_LEN];
+    let mut check = tokio::time::interval(STATE_CHECK_INTERVAL);
+    // Dropped at the peer's Eof, so whatever reads it sees the end right away
+    let mut output = Some(output);
+
+    while reading || receiving {
+        tokio::select! {
+            read = input.read(&mut buffer), if reading => {
+                let n = read.context("Failed to read input")?;
+                if n == 0 {
+                    WireMessage::Eof.send(dc).await?;
+                    reading = false;
+                    debug!("End of input after {} bytes", totals.sent);
+                    continue;
+                }
+
+                while dc.buffered_amount().await > MAX_BUFFERED {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                dc.send(&Bytes::copy_from_slice(&buffer[..n]))
+                    .await
+                    .context("Failed to send data to the peer")?;
+                totals.sent += n as u64;
+            }
+            msg = incoming.recv(), if receiving => {
+                let Some(msg) = msg else {
+                    bail!("The connection closed before the peer finished sending");
+                };
+                if !msg.is_string {
+                    let Some(ref mut writer) = output else {
+                        bail!("Peer sent data after the end of its input");
+                    };
+                    writer
+                        .write_all(&msg.data)
+                        .await
+                        .context("Failed to write output")?;
+                    totals.received += msg.data.len() as u64;
+                    continue;
+                }
+
+                match WireMessage::decode(&msg.data) {
+                    Ok(WireMessage::Eof) => {
+                        if let Some(mut writer) = output.take() {
+                            writer.shutdown().await.context("Failed to close output")?;
+                        }
+                        receiving = false;
+                        debug!("Peer's input ended after {} bytes", totals.received);
+                    }
+                    // Whatever else a peer sends isn't part of the data
+                    Ok(WireMessage::Nick { .. }) => {}
+                    Ok(other) => warn!("Ignoring unexpected message from peer: {:?}", other),
+                    Err(e) => warn!("{}", e),
+                }
+            }
+            _ = check.tick() => {
+                if dc.ready_state() != RTCDataChannelState::Open {
+                    bail!("Lost the connection to the peer");
+                }
+            }
+        }
+    }
+
+    // Everything we sent has to get there before the connection is closed.
+    // The peer only hangs up once it has read our Eof, which comes after all
+    // the data, so it closing the channel first means the same.
+    while dc.buffered_amount().await > 0 && dc.ready_state() == RTCDataChannelState::Open {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    Ok(totals)
+}
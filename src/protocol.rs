@@ -0,0 +1,155 @@
+use crate::connection::ReliabilityProfile;
+
+use anyhow::{Context, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use webrtc::data_channel::RTCDataChannel;
+
+// Messages exchanged over the chat data channel
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WireMessage {
+    // Identity announcement, signed over both DTLS fingerprints of the session
+    Hello {
+        identity: String,
+        signature: String,
+    },
+    // A chat message typed by the user, possibly a reply to an earlier one
+    Chat {
+        id: String,
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<String>,
+    },
+    // Replace the text of one of the sender's messages
+    Edit {
+        id: String,
+        text: String,
+    },
+    // Withdraw one of the sender's messages
+    Delete {
+        id: String,
+    },
+    // React to a message from either side
+    React {
+        id: String,
+        emoji: String,
+    },
+    // The sender's display name, announced on connect and on every change
+    Nick {
+        name: String,
+    },
+    // The sender is editing a message, sent on the lossy typing channel
+    Typing,
+    // Offer a file. Once the peer accepts it follows as binary frames tagged
+    // with this id.
+    FileStart {
+        id: String,
+        name: String,
+        size: u64,
+    },
+    // The peer wants the file, so its frames can follow
+    FileAccept {
+        id: String,
+    },
+    // The peer turned the file down, or it's larger than the peer takes
+    FileReject {
+        id: String,
+        reason: String,
+    },
+    // Every frame of the file has been sent, with the SHA-256 of its contents
+    FileEnd {
+        id: String,
+        sha256: String,
+    },
+    // In pipe mode or on a tunnelled connection, the sender's input has ended
+    // and no more data follows
+    Eof,
+    // Ask the peer to connect to `target` (host:port) and carry the connection
+    // on the negotiated data channel with this stream id. UDP datagrams go
+    // with the channel profile given, TCP is always reliable.
+    TunnelOpen {
+        stream: u16,
+        target: String,
+        #[serde(default)]
+        protocol: TunnelProtocol,
+        #[serde(default)]
+        profile: ReliabilityProfile,
+    },
+    // The peer connected and is reading the stream's channel
+    TunnelOpened {
+        stream: u16,
+    },
+    // The peer didn't connect, and the stream's channel is closed again
+    TunnelFailed {
+        stream: u16,
+        #[serde(default)]
+        error: TunnelError,
+        reason: String,
+    },
+    // Ask the peer for a Pong with the same fields. `sent_at` is microseconds
+    // on the sender's own clock, only the sender makes sense of it.
+    Ping {
+        seq: u32,
+        sent_at: u64,
+    },
+    // Answer to a Ping
+    Pong {
+        seq: u32,
+        sent_at: u64,
+    },
+}
+
+// What a tunnel carries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelProtocol {
+    // One connection per stream
+    #[default]
+    Tcp,
+    // Datagrams of any number of flows on one stream, each tagged with its
+    // flow id
+    Udp,
+}
+
+// Why the peer couldn't open a tunnelled connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelError {
+    // The target isn't on the peer's allowlist
+    NotAllowed,
+    // The target turned the connection down
+    Refused,
+    // The target's name didn't resolve, or there's no route to it
+    Unreachable,
+    TimedOut,
+    #[default]
+    Failed,
+}
+
+impl WireMessage {
+    // Serialize for sending as a text frame
+    pub fn encode(&self) -> Result<String> {
+        serde_json::to_string(self).context("Failed to encode message")
+    }
+
+    // Parse a frame received from the peer
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        serde_json::from_slice(data).context("Failed to decode message from peer")
+    }
+
+    // Send this message over a data channel
+    pub async fn send(&self, dc: &RTCDataChannel) -> Result<()> {
+        dc.send_text(self.encode()?)
+            .await
+            .context("Failed to send message")?;
+        Ok(())
+    }
+}
+
+// Random 64-bit id for a new chat message, used to refer to it in edits and reactions
+pub fn new_message_id() -> String {
+    let mut bytes = [0u8; 8];
+    rand::rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
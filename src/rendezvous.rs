@@ -0,0 +1,341 @@
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{debug, info};
+use reqwest::Client;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+
+// Largest message the server stores; blobs are a few kilobytes
+const MAX_BODY_LEN: usize = 64 * 1024;
+// Messages each side can leave in one mailbox
+const MAX_MESSAGES: usize = 8;
+// Mailboxes open at once; more are refused until some close or expire
+pub const MAX_MAILBOXES: usize = 1024;
+// How often a client asks whether a message has arrived
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Which peer left a message in a mailbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Offerer,
+    Answerer,
+}
+
+impl Side {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Side::Offerer => "offer",
+            Side::Answerer => "answer",
+        }
+    }
+
+    fn parse(side: &str) -> Option<Self> {
+        match side {
+            "offer" => Some(Side::Offerer),
+            "answer" => Some(Side::Answerer),
+            _ => None,
+        }
+    }
+}
+
+// A mailbox on a rendezvous server, named by a short number (the
+// nameplate). Each side leaves numbered messages there for the other.
+pub struct Mailbox {
+    client: Client,
+    server: String,
+    nameplate: String,
+}
+
+impl Mailbox {
+    // Open a new mailbox on `server`
+    pub async fn allocate(server: &str) -> Result<Self> {
+        let mut mailbox = Self::open(server, "");
+        let response = mailbox
+            .request(mailbox.client.post(format!("{}/mailboxes", mailbox.server)))
+            .await?;
+        if response.status() == reqwest::StatusCode::SERVICE_UNAVAILABLE {
+            bail!(
+                "Rendezvous server {} has too many open mailboxes, try again later",
+                mailbox.server
+            );
+        }
+        if response.status() != reqwest::StatusCode::CREATED {
+            bail!(
+                "Rendezvous server refused to open a mailbox: {}",
+                response.status()
+            );
+        }
+        mailbox.nameplate = response.text().await?.trim().to_string();
+        Ok(mailbox)
+    }
+
+    // Use the mailbox the other peer opened
+    pub fn open(server: &str, nameplate: &str) -> Self {
+        Self {
+            client: Client::new(),
+            server: server.trim_end_matches('/').to_string(),
+            nameplate: nameplate.to_string(),
+        }
+    }
+
+    pub fn nameplate(&self) -> &str {
+        &self.nameplate
+    }
+
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    fn url(&self, side: Side, index: usize) -> String {
+        format!(
+            "{}/mailboxes/{}/{}/{}",
+            self.server,
+            self.nameplate,
+            side.as_str(),
+            index
+        )
+    }
+
+    async fn request(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        request
+            .send()
+            .await
+            .with_context(|| format!("Failed to reach the rendezvous server at {}", self.server))
+    }
+
+    fn gone(&self) -> anyhow::Error {
+        anyhow::anyhow!(
+            "Nothing is waiting under {} at {}; it may have expired, or the code is mistyped",
+            self.nameplate,
+            self.server
+        )
+    }
+
+    // Leave message `index` from `side`. Each can only be left once.
+    pub async fn put(&self, side: Side, index: usize, message: &str) -> Result<()> {
+        let response = self
+            .request(
+                self.client
+                    .post(self.url(side, index))
+                    .body(message.to_string()),
+            )
+            .await?;
+        match response.status() {
+            reqwest::StatusCode::NO_CONTENT => Ok(()),
+            reqwest::StatusCode::NOT_FOUND => Err(self.gone()),
+            reqwest::StatusCode::CONFLICT => bail!(
+                "Someone else has already used {}; start again with a new code",
+                self.nameplate
+            ),
+            status => bail!("Rendezvous server refused the message: {}", status),
+        }
+    }
+
+    // Message `index` from `side`, if it has been left yet
+    pub async fn get(&self, side: Side, index: usize) -> Result<Option<String>> {
+        let response = self.request(self.client.get(self.url(side, index))).await?;
+        match response.status() {
+            reqwest::StatusCode::OK => Ok(Some(response.text().await?)),
+            reqwest::StatusCode::NO_CONTENT => Ok(None),
+            reqwest::StatusCode::NOT_FOUND => Err(self.gone()),
+            status => bail!("Rendezvous server failed to return a message: {}", status),
+        }
+    }
+
+    // Wait for message `index` from `side`, for as long as the mailbox lasts
+    pub async fn wait(&self, side: Side, index: usize) -> Result<String> {
+        loop {
+            if let Some(message) = self.get(side, index).await? {
+                return Ok(message);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    // Free the nameplate for someone else
+    pub async fn close(&self) -> Result<()> {
+        let url = format!("{}/mailboxes/{}", self.server, self.nameplate);
+        self.request(self.client.delete(url)).await?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Messages {
+    offerer: Vec<Option<String>>,
+    answerer: Vec<Option<String>>,
+}
+
+impl Messages {
+    fn side(&mut self, side: Side) -> &mut Vec<Option<String>> {
+        match side {
+            Side::Offerer => &mut self.offerer,
+            Side::Answerer => &mut self.answerer,
+        }
+    }
+}
+
+struct Entry {
+    messages: Messages,
+    created: Instant,
+}
+
+struct Rendezvous {
+    mailboxes: HashMap<String, Entry>,
+    ttl: Duration,
+}
+
+impl Rendezvous {
+    fn expire(&mut self) {
+        let ttl = self.ttl;
+        self.mailboxes
+            .retain(|_, entry| entry.created.elapsed() < ttl);
+    }
+
+    // The lowest number not in use, so nameplates stay short. With at most
+    // MAX_MAILBOXES open the scan stays short too.
+    fn new_nameplate(&self) -> String {
+        (1..)
+            .map(|n: u64| n.to_string())
+            .find(|nameplate| !self.mailboxes.contains_key(nameplate))
+            .unwrap()
+    }
+}
+
+// Run the rendezvous server until it fails
+pub async fn run_server(listen: SocketAddr, ttl: Duration) -> Result<()> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", listen))?;
+    println!(
+        "Rendezvous server listening on http://{}, mailboxes expire after {} seconds",
+        listener.local_addr()?,
+        ttl.as_secs()
+    );
+    serve(listener, ttl).await
+}
+
+// Serve the rendezvous API on an already bound listener:
+//   POST   /mailboxes                        open a mailbox, returns its nameplate (201),
+//                                            or 503 while MAX_MAILBOXES are open
+//   POST   /mailboxes/<nameplate>/<side>/<n> leave message n from side (204), once only
+//   GET    /mailboxes/<nameplate>/<side>/<n> message n (200), or 204 until it is left
+//   DELETE /mailboxes/<nameplate>            close the mailbox
+// where side is `offer` or `answer`. Mailboxes are forgotten after `ttl`.
+pub async fn serve(listener: TcpListener, ttl: Duration) -> Result<()> {
+    let state = Arc::new(Mutex::new(Rendezvous {
+        mailboxes: HashMap::new(),
+        ttl,
+    }));
+
+    loop {
+        let (stream, from) = listener.accept().await?;
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let service = service_fn(move |request| handle(Arc::clone(&state), request));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("Connection from {} failed: {}", from, e);
+            }
+        });
+    }
+}
+
+async fn handle(
+    state: Arc<Mutex<Rendezvous>>,
+    request: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+
+    let body = match Limited::new(request.into_body(), MAX_BODY_LEN)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return Ok(respond(StatusCode::PAYLOAD_TOO_LARGE, "Too large")),
+    };
+    let Ok(body) = String::from_utf8(body.to_vec()) else {
+        return Ok(respond(StatusCode::BAD_REQUEST, "Not text"));
+    };
+
+    let mut state = state.lock().await;
+    state.expire();
+
+    let response = match (&method, segments.as_slice()) {
+        (&Method::POST, ["mailboxes"]) if state.mailboxes.len() >= MAX_MAILBOXES => {
+            respond(StatusCode::SERVICE_UNAVAILABLE, "Too many open mailboxes")
+        }
+        (&Method::POST, ["mailboxes"]) => {
+            let nameplate = state.new_nameplate();
+            info!("Opened mailbox {}", nameplate);
+            state.mailboxes.insert(
+                nameplate.clone(),
+                Entry {
+                    messages: Messages::default(),
+                    created: Instant::now(),
+                },
+            );
+            respond(StatusCode::CREATED, &nameplate)
+        }
+        (&Method::DELETE, ["mailboxes", nameplate]) => match state.mailboxes.remove(*nameplate) {
+            Some(_) => {
+                info!("Closed mailbox {}", nameplate);
+                respond(StatusCode::NO_CONTENT, "")
+            }
+            None => respond(StatusCode::NOT_FOUND, "No such mailbox"),
+        },
+        (method, ["mailboxes", nameplate, side, index]) => {
+            let (Some(side), Ok(index)) = (Side::parse(side), index.parse::<usize>()) else {
+                return Ok(respond(StatusCode::NOT_FOUND, "Not found"));
+            };
+            if index >= MAX_MESSAGES {
+                return Ok(respond(StatusCode::NOT_FOUND, "Not found"));
+            }
+            let Some(entry) = state.mailboxes.get_mut(*nameplate) else {
+                return Ok(respond(StatusCode::NOT_FOUND, "No such mailbox"));
+            };
+            let messages = entry.messages.side(side);
+            match *method {
+                Method::GET => match messages.get(index) {
+                    Some(Some(message)) => respond(StatusCode::OK, message),
+                    _ => respond(StatusCode::NO_CONTENT, ""),
+                },
+                Method::POST => {
+                    if messages.len() <= index {
+                        messages.resize(index + 1, None);
+                    }
+                    if messages[index].is_some() {
+                        respond(StatusCode::CONFLICT, "Already left")
+                    } else {
+                        messages[index] = Some(body);
+                        respond(StatusCode::NO_CONTENT, "")
+                    }
+                }
+                _ => respond(StatusCode::NOT_FOUND, "Not found"),
+            }
+        }
+        _ => respond(StatusCode::NOT_FOUND, "Not found"),
+    };
+    Ok(response)
+}
+
+fn respond(status: StatusCode, body: &str) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+    *response.status_mut() = status;
+    response
+}
@@ -1,17 +1,103 @@
-use anyhow::{Context, Result};
-use log::{debug, error, warn};
-use serde_json;
+use anyhow::{bail, Context, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
 use std::io::{self, BufRead};
 use std::sync::Arc;
+use webrtc::ice::candidate::candidate_base::unmarshal_candidate;
 use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::sdp::description::common::Attribute;
+use webrtc::sdp::description::session::SessionDescription;
+
+// How long a blob is accepted after it was made. Long enough to get it to
+// the peer by hand, short enough that an old one pasted by mistake is caught.
+pub const BLOB_VALIDITY_SECS: u64 = 10 * 60;
+
+// Allowance for the two computers' clocks disagreeing
+const CLOCK_SKEW_SECS: i64 = 2 * 60;
+
+// When and for which session a blob was made. The offerer picks a random
+// nonce and the answer carries it back, so an answer from an earlier session
+// is recognised as such.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionStamp {
+    // Unix time the blob was made, in seconds
+    pub created: i64,
+    pub nonce: String,
+    pub valid_for: u64,
+}
+
+impl SessionStamp {
+    // A stamp for a new offer, with a fresh nonce
+    pub fn new() -> Self {
+        let mut nonce = [0u8; 16];
+        rand::rng().fill_bytes(&mut nonce);
+        Self {
+            created: chrono::Utc::now().timestamp(),
+            nonce: hex::encode(nonce),
+            valid_for: BLOB_VALIDITY_SECS,
+        }
+    }
+
+    // A stamp for the answer to an offer, carrying its nonce back
+    pub fn answering(offer: &SessionStamp) -> Self {
+        Self {
+            nonce: offer.nonce.clone(),
+            ..Self::new()
+        }
+    }
 
-// Generate and display SDP offer
+    // Reject a blob made too long ago, or in the future by more than the
+    // clocks could plausibly disagree
+    pub fn check(&self, kind: RTCSdpType, now: i64) -> Result<()> {
+        // Saturating, since the stamp came from the peer and could be anything
+        let age = now.saturating_sub(self.created);
+        if age < -CLOCK_SKEW_SECS {
+            bail!(
+                "This {} was made {} in the future. Check that the clocks on both computers \
+                 are right.",
+                kind,
+                describe_secs(age.unsigned_abs())
+            );
+        }
+        let expired_for = age
+            .saturating_sub(CLOCK_SKEW_SECS)
+            .saturating_sub(i64::try_from(self.valid_for).unwrap_or(i64::MAX));
+        if expired_for > 0 {
+            bail!(
+                "This {} expired {} ago; it was only valid for {}. It may be from an earlier \
+                 session. Ask the other peer for a fresh one.",
+                kind,
+                describe_secs(expired_for as u64),
+                describe_secs(self.valid_for)
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Default for SessionStamp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn describe_secs(secs: u64) -> String {
+    match secs {
+        0..=119 => format!("{} seconds", secs),
+        120..=7199 => format!("{} minutes", secs / 60),
+        _ => format!("{} hours", secs / 3600),
+    }
+}
+
+// Generate the SDP offer and return it as text for the other peer
 pub async fn generate_offer(
     pc: &Arc<RTCPeerConnection>,
-    candidates_mutex: &Arc<tokio::sync::Mutex<Vec<String>>>,
-) -> Result<()> {
+    candidates_mutex: &Arc<tokio::sync::Mutex<Vec<RTCIceCandidateInit>>>,
+    stamp: &SessionStamp,
+) -> Result<String> {
     // Create an offer with additional configuration for better compatibility
     let offer = pc
         .create_offer(None)
@@ -25,30 +111,16 @@ pub async fn generate_offer(
     wait_for_ice_gathering(candidates_mutex).await;
 
     // Generate offer with improved formatting for better copy/paste experience
-    let offer_json = serde_json::to_string(&offer).context("Failed to serialize offer")?;
-
-    // Color coded output for better visibility (if the terminal supports it)
-    println!("\n==== COPY EVERYTHING BETWEEN THESE LINES ====");
-    println!("OFFER:{}", offer_json);
-
-    // Collect ICE candidates in a single string
-    let pending_candidates = candidates_mutex.lock().await;
-    println!("ICE_CANDIDATES:");
-    println!(
-        "{}",
-        serde_json::to_string(&*pending_candidates)
-            .context("Failed to serialize ICE candidates")?
-    );
-    println!("==== END OF SECTION TO COPY ====\n");
-
-    Ok(())
+    println!("The offer is valid for {}", describe_secs(stamp.valid_for));
+    encode_blob(&offer, &candidates_mutex.lock().await, stamp)
 }
 
-// Generate and display SDP answer
+// Generate the SDP answer and return it as text for the other peer
 pub async fn generate_answer(
     pc: &Arc<RTCPeerConnection>,
-    candidates_mutex: &Arc<tokio::sync::Mutex<Vec<String>>>,
-) -> Result<()> {
+    candidates_mutex: &Arc<tokio::sync::Mutex<Vec<RTCIceCandidateInit>>>,
+    stamp: &SessionStamp,
+) -> Result<String> {
     // Create answer with additional configuration for better compatibility
     let answer = pc
         .create_answer(None)
@@ -64,27 +136,86 @@ pub async fn generate_answer(
     wait_for_ice_gathering(candidates_mutex).await;
 
     // Generate answer with improved formatting for better copy/paste experience
-    let answer_json = serde_json::to_string(&answer).context("Failed to serialize answer")?;
-
-    // Color coded output for better visibility (if the terminal supports it)
-    println!("\n==== COPY EVERYTHING BETWEEN THESE LINES ====");
-    println!("ANSWER:{}", answer_json);
-
-    // Collect ICE candidates in a single string
-    let pending_candidates = candidates_mutex.lock().await;
-    println!("ICE_CANDIDATES:");
-    println!(
-        "{}",
-        serde_json::to_string(&*pending_candidates)
-            .context("Failed to serialize ICE candidates")?
+    encode_blob(&answer, &candidates_mutex.lock().await, stamp)
+}
+
+// Serialize candidates as RTCIceCandidateInit objects, as RTCIceCandidate::to_json produces them
+pub fn encode_candidates(candidates: &[RTCIceCandidateInit]) -> Result<String> {
+    serde_json::to_string(candidates).context("Failed to serialize ICE candidates")
+}
+
+// Terminals in canonical mode accept at most 4095 bytes per line (Linux's
+// N_TTY_BUF_SIZE is 4096 including the newline) and silently drop the rest,
+// while an offer is longer than that. The blob is printed wrapped instead and
+// the parser joins the lines back together.
+const BLOB_LINE_WIDTH: usize = 76;
+
+const START_MARKER: &str = "==== COPY EVERYTHING BETWEEN THESE LINES ====";
+const END_MARKER: &str = "==== END OF SECTION TO COPY ====";
+const SESSION_MARKER: &str = "SESSION:";
+const OFFER_MARKER: &str = "OFFER:";
+const ANSWER_MARKER: &str = "ANSWER:";
+const CANDIDATES_MARKER: &str = "ICE_CANDIDATES:";
+
+// Wrap a description, its candidates and the session stamp in the
+// copy/paste markers
+pub fn encode_blob(
+    description: &RTCSessionDescription,
+    candidates: &[RTCIceCandidateInit],
+    stamp: &SessionStamp,
+) -> Result<String> {
+    let marker = match description.sdp_type {
+        RTCSdpType::Offer => OFFER_MARKER,
+        RTCSdpType::Answer => ANSWER_MARKER,
+        other => bail!("Can't send a {} description", other),
+    };
+    let description_json =
+        serde_json::to_string(description).context("Failed to serialize description")?;
+
+    let stamp_json = serde_json::to_string(stamp).context("Failed to serialize session stamp")?;
+
+    let mut blob = format!("{}\n", START_MARKER);
+    wrap_line(&format!("{}{}", SESSION_MARKER, stamp_json), &mut blob);
+    wrap_line(&format!("{}{}", marker, description_json), &mut blob);
+    blob.push_str(CANDIDATES_MARKER);
+    blob.push('\n');
+    wrap_line(
+        &encode_candidates(&label_candidates(description, candidates))?,
+        &mut blob,
     );
-    println!("==== END OF SECTION TO COPY ====\n");
+    blob.push_str(END_MARKER);
+    Ok(blob)
+}
 
-    Ok(())
+// Split a line at BLOB_LINE_WIDTH, only ever between two non-space
+// characters so the parser can trim the pasted lines without losing anything
+fn wrap_line(line: &str, out: &mut String) {
+    let chars = line.chars().collect::<Vec<_>>();
+    let splits_cleanly =
+        |end: usize| !chars[end - 1].is_whitespace() && !chars[end].is_whitespace();
+
+    let mut start = 0;
+    while chars.len() - start > BLOB_LINE_WIDTH {
+        // The nearest clean split before the limit, or failing that after it
+        let end = (start + 1..=start + BLOB_LINE_WIDTH)
+            .rev()
+            .chain(start + BLOB_LINE_WIDTH + 1..chars.len())
+            .find(|&end| splits_cleanly(end));
+        let Some(end) = end else {
+            break;
+        };
+        out.extend(&chars[start..end]);
+        out.push('\n');
+        start = end;
+    }
+    out.extend(&chars[start..]);
+    out.push('\n');
 }
 
 // Wait for ICE gathering to complete
-async fn wait_for_ice_gathering(candidates_mutex: &Arc<tokio::sync::Mutex<Vec<String>>>) -> usize {
+async fn wait_for_ice_gathering(
+    candidates_mutex: &Arc<tokio::sync::Mutex<Vec<RTCIceCandidateInit>>>,
+) -> usize {
     println!("Gathering ICE candidates (this may take a few seconds)...");
 
     // Wait for ICE gathering to complete or timeout
@@ -112,171 +243,389 @@ pub async fn read_sdp_input() -> Result<String> {
     println!("\nPaste everything from the other peer between the lines (including the lines):");
     println!("(Waiting for SDP data...)\n");
 
-    // Improved input handling with better error recovery
+    read_blob(&mut io::stdin().lock())
+}
+
+// Read a pasted blob. A paste that starts with the copy marker is read up to
+// the end marker, so that line isn't left behind for the chat; one without
+// markers is read until it holds a description and its candidates. Either
+// way reading stops at EOF, and the parser reports anything missing.
+pub fn read_blob(reader: &mut impl BufRead) -> Result<String> {
     let mut sdp_data = String::new();
-    let stdin = io::stdin();
 
     loop {
         let mut line = String::new();
-        match stdin.lock().read_line(&mut line) {
-            Ok(_) => {}
-            Err(e) => {
-                error!("Error reading input: {}", e);
-                continue;
-            }
-        }
-
-        if line.contains("END OF SECTION") {
-            sdp_data.push_str(&line);
+        if reader
+            .read_line(&mut line)
+            .context("Failed to read input")?
+            == 0
+        {
             break;
         }
 
         if !line.trim().is_empty() {
             sdp_data.push_str(&line);
         }
+
+        if line.contains("END OF SECTION") {
+            break;
+        }
+        if !sdp_data.contains("COPY EVERYTHING") && matches!(parse_blob(&sdp_data), Ok((_, true))) {
+            break;
+        }
     }
 
+    if sdp_data.trim().is_empty() {
+        bail!("Input ended before anything was pasted");
+    }
     Ok(sdp_data)
 }
 
-// Parse SDP offer from input text
-pub fn parse_offer(offer_data: &str) -> Result<RTCSessionDescription> {
-    let offer_line = offer_data
-        .lines()
-        .find(|line| line.starts_with("OFFER:"))
-        .context("No OFFER: found in the data")?;
+// An offer or answer from the peer, with the ICE candidates sent alongside it
+#[derive(Debug, Clone)]
+pub struct SignalingBlob {
+    pub description: RTCSessionDescription,
+    pub candidates: Vec<RTCIceCandidateInit>,
+    // None for a bare description, which parse_offer and parse_answer refuse
+    pub session: Option<SessionStamp>,
+}
+
+impl SignalingBlob {
+    // Parse pasted text. Lines may be indented or wrapped, and the copy
+    // markers and even the OFFER:/ANSWER: prefix may be missing.
+    pub fn parse(data: &str) -> Result<Self> {
+        parse_blob(data).map(|(blob, _)| blob)
+    }
+}
 
-    let offer_json = offer_line.trim_start_matches("OFFER:");
-    let offer = serde_json::from_str::<RTCSessionDescription>(offer_json)
-        .context("Failed to parse offer")?;
+// Parse SDP offer from input text, rejecting one that has expired
+pub fn parse_offer(offer_data: &str) -> Result<SignalingBlob> {
+    let blob = SignalingBlob::parse(offer_data)?;
+    match blob.description.sdp_type {
+        RTCSdpType::Offer => {
+            session_stamp(&blob)?.check(RTCSdpType::Offer, chrono::Utc::now().timestamp())?;
+            println!("Successfully parsed offer");
+            Ok(blob)
+        }
+        RTCSdpType::Answer => bail!(
+            "This is an answer, but an offer is needed here. Paste the text printed by the \
+             peer who ran `offer`; answers go back to the offerer."
+        ),
+        other => bail!("Expected an offer but this is a {} description", other),
+    }
+}
 
-    println!("Successfully parsed offer");
-    Ok(offer)
+// Parse SDP answer from input text, rejecting one that has expired or
+// answers some other offer than the one with `nonce`
+pub fn parse_answer(response: &str, nonce: &str) -> Result<SignalingBlob> {
+    let blob = SignalingBlob::parse(response)?;
+    match blob.description.sdp_type {
+        RTCSdpType::Answer => {
+            let stamp = session_stamp(&blob)?;
+            if stamp.nonce != nonce {
+                bail!(
+                    "This answer is for a different offer. Paste the answer to the offer \
+                     printed above; answers from earlier sessions can't be reused."
+                );
+            }
+            stamp.check(RTCSdpType::Answer, chrono::Utc::now().timestamp())?;
+            println!("Successfully parsed answer");
+            Ok(blob)
+        }
+        RTCSdpType::Offer => bail!(
+            "This is an offer, but the answer is needed here. Paste the text the other peer \
+             printed after giving your offer to `answer`, not your own offer."
+        ),
+        other => bail!("Expected an answer but this is a {} description", other),
+    }
 }
 
-// Parse SDP answer from input text
-pub fn parse_answer(response: &str) -> Result<RTCSessionDescription> {
-    let answer_line = response
-        .lines()
-        .find(|line| line.starts_with("ANSWER:"))
-        .context("No ANSWER: found in the response")?;
+// Without a stamp there's no telling how old a blob is or which session
+// it belongs to
+fn session_stamp(blob: &SignalingBlob) -> Result<&SessionStamp> {
+    blob.session.as_ref().with_context(|| {
+        format!(
+            "This {} has no session stamp, so it can't be checked for expiry or reuse. \
+             Both peers need to run the same version of modulate-comms.",
+            blob.description.sdp_type
+        )
+    })
+}
 
-    let answer_json = answer_line.trim_start_matches("ANSWER:");
-    let answer = serde_json::from_str::<RTCSessionDescription>(answer_json)
-        .context("Failed to parse answer")?;
+// Parse a blob, saying whether it had a candidates section at all
+fn parse_blob(data: &str) -> Result<(SignalingBlob, bool)> {
+    parse_joined(data, |text| {
+        let (session, text) = session_section(text)?;
+        let (marker, body) = match [OFFER_MARKER, ANSWER_MARKER]
+            .into_iter()
+            .filter_map(|marker| text.find(marker).map(|i| (i, marker)))
+            .min()
+        {
+            Some((i, marker)) => (Some(marker), &text[i + marker.len()..]),
+            // A bare description, as printed by other WebRTC tools
+            None => match text.find('{') {
+                Some(i) => (None, &text[i..]),
+                None => bail!("No offer or answer found in the pasted text"),
+            },
+        };
+
+        let mut values =
+            serde_json::Deserializer::from_str(body).into_iter::<RTCSessionDescription>();
+        let description = match values.next() {
+            Some(Ok(description)) => description,
+            Some(Err(e)) => return Err(e).context("The offer or answer is incomplete or damaged"),
+            None => bail!("The offer or answer is missing"),
+        };
+        let rest = &body[values.byte_offset()..];
+
+        let expected = match marker {
+            Some(OFFER_MARKER) => Some(RTCSdpType::Offer),
+            Some(_) => Some(RTCSdpType::Answer),
+            None => None,
+        };
+        if let Some(expected) = expected {
+            if expected != description.sdp_type {
+                bail!(
+                    "The text is marked as an {} but holds an {} description",
+                    expected,
+                    description.sdp_type
+                );
+            }
+        }
 
-    println!("Successfully parsed answer");
-    Ok(answer)
+        let candidates = candidates_section(rest.trim_start())?;
+        let complete = candidates.is_some();
+        Ok((
+            SignalingBlob {
+                description,
+                candidates: candidates.unwrap_or_default(),
+                session,
+            },
+            complete,
+        ))
+    })
 }
 
-// Process ICE candidates from SDP data
-pub async fn process_ice_candidates(data: &str, pc: &Arc<RTCPeerConnection>) -> Result<()> {
-    // Look for ICE candidates section and parse each line as a candidate
-    let mut in_candidates_section = false;
-    let mut candidates_json = String::new();
+// Run a parser over the pasted lines joined back together, without the copy
+// markers. Our own wrapping never splits at a space, so trimming each line
+// is safe; text wrapped elsewhere may have been split at one, so the lines
+// are also tried joined as they are.
+fn parse_joined<T>(data: &str, parse: impl Fn(&str) -> Result<T>) -> Result<T> {
+    let lines = || data.lines().filter(|line| !is_marker_line(line));
+
+    let trimmed = lines().map(str::trim).collect::<String>();
+    match parse(&trimmed) {
+        Ok(parsed) => Ok(parsed),
+        Err(e) => parse(&lines().collect::<String>()).map_err(|_| e),
+    }
+}
 
-    for line in data.lines() {
-        if line.contains("ICE_CANDIDATES:") {
-            in_candidates_section = true;
-            continue;
-        }
+// The copy markers, however the paste has mangled them. A wrapped line of
+// the description can start with "====" too, so the wording is checked.
+fn is_marker_line(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with("====")
+        && (line.contains("COPY EVERYTHING") || line.contains("END OF SECTION"))
+}
 
-        if in_candidates_section && !line.contains("END OF SECTION") {
-            candidates_json.push_str(line.trim());
-        } else if line.contains("END OF SECTION") {
-            break;
+// Parse the session stamp ahead of the description, if there is one, and
+// return the text after it
+fn session_section(text: &str) -> Result<(Option<SessionStamp>, &str)> {
+    let Some(i) = text.find(SESSION_MARKER) else {
+        return Ok((None, text));
+    };
+    let body = &text[i + SESSION_MARKER.len()..];
+    let mut values = serde_json::Deserializer::from_str(body).into_iter::<SessionStamp>();
+    match values.next() {
+        Some(stamp) => {
+            let stamp = stamp.context("The session stamp is damaged")?;
+            Ok((Some(stamp), &body[values.byte_offset()..]))
         }
+        None => bail!("The session stamp is missing"),
     }
+}
 
-    if !candidates_json.is_empty() {
-        match serde_json::from_str::<Vec<String>>(&candidates_json) {
-            Ok(remote_candidates) => {
-                println!(
-                    "Successfully parsed {} ICE candidates",
-                    remote_candidates.len()
-                );
+// Parse the candidates that follow a description, if there are any
+fn candidates_section(rest: &str) -> Result<Option<Vec<RTCIceCandidateInit>>> {
+    let array = match rest.strip_prefix(CANDIDATES_MARKER) {
+        Some(array) => array.trim_start(),
+        None if rest.starts_with('[') => rest,
+        None => return Ok(None),
+    };
+
+    let entries = match serde_json::Deserializer::from_str(array)
+        .into_iter::<Vec<serde_json::Value>>()
+        .next()
+    {
+        Some(entries) => entries.context("ICE candidates are not a JSON array")?,
+        None => bail!("The ICE candidates are missing"),
+    };
+    validate_candidates(entries).map(Some)
+}
+
+// Add the peer's ICE candidates to its description as a=candidate lines.
+// set_remote_description adds these before ICE starts; candidates added
+// afterwards can race the peer's connectivity checks, which the agent then
+// also learns as duplicate peer-reflexive candidates.
+pub fn attach_candidates(
+    description: RTCSessionDescription,
+    candidates: &[RTCIceCandidateInit],
+) -> Result<RTCSessionDescription> {
+    if candidates.is_empty() {
+        println!("No ICE candidates found in the data");
+        return Ok(description);
+    }
 
-                let mut success_count = 0;
-                let mut error_count = 0;
-
-                // Using &remote_candidates to avoid moving the vector
-                for candidate_str in &remote_candidates {
-                    // Use the conversion function for all candidates
-                    match convert_to_ice_candidate(candidate_str).await {
-                        Ok(candidate) => match pc.add_ice_candidate(candidate).await {
-                            Ok(_) => {
-                                success_count += 1;
-                            }
-                            Err(e) => {
-                                debug!("Warning: Failed to add ICE candidate: {}", e);
-                                error_count += 1;
-                            }
-                        },
-                        Err(e) => {
-                            debug!("Warning: Failed to convert ICE candidate: {}", e);
-                            error_count += 1;
-                        }
-                    }
-                }
-
-                println!(
-                    "Added {}/{} ICE candidates successfully",
-                    success_count,
-                    remote_candidates.len()
+    let mut parsed = description
+        .unmarshal()
+        .context("Failed to parse session description")?;
+    let session_ufrag = parsed.attribute("ice-ufrag").cloned();
+    for (index, candidate) in candidates.iter().enumerate() {
+        let media_index =
+            media_section(&parsed, candidate).with_context(|| format!("candidate {}", index))?;
+        let media = &mut parsed.media_descriptions[media_index];
+
+        // A candidate gathered for some other ICE session would never connect
+        let ufrag = match media.attribute("ice-ufrag") {
+            Some(Some(ufrag)) => Some(ufrag.to_owned()),
+            _ => session_ufrag.clone(),
+        };
+        if let (Some(theirs), Some(ours)) = (&candidate.username_fragment, &ufrag) {
+            if theirs != ours {
+                bail!(
+                    "candidate {}: username fragment {:?} isn't the description's {:?}",
+                    index,
+                    theirs,
+                    ours
                 );
-                if error_count > 0 {
-                    println!(
-                        "({} candidates failed but connection may still work)",
-                        error_count
-                    );
-                }
-            }
-            Err(e) => {
-                warn!("Error parsing ICE candidates: {}", e);
-                println!("Failed to parse ICE candidates, but will continue without them");
-                println!("The connection may still work but might be less reliable");
             }
         }
-    } else {
-        println!("No ICE candidates found in the data");
+
+        let raw = candidate
+            .candidate
+            .strip_prefix("candidate:")
+            .unwrap_or(&candidate.candidate);
+        media
+            .attributes
+            .push(Attribute::new("candidate".to_owned(), Some(raw.to_owned())));
+    }
+
+    let sdp = parsed.marshal();
+    let description = match description.sdp_type {
+        RTCSdpType::Offer => RTCSessionDescription::offer(sdp),
+        RTCSdpType::Answer => RTCSessionDescription::answer(sdp),
+        RTCSdpType::Pranswer => RTCSessionDescription::pranswer(sdp),
+        other => bail!("Can't add ICE candidates to a {} description", other),
     }
+    .context("Failed to rebuild session description")?;
+
+    println!("Successfully parsed {} ICE candidates", candidates.len());
+    Ok(description)
+}
 
-    Ok(())
+// Index of the media section a candidate belongs to, by its mid if it has
+// one and otherwise by its m-line index
+fn media_section(parsed: &SessionDescription, candidate: &RTCIceCandidateInit) -> Result<usize> {
+    match candidate.sdp_mid.as_deref().filter(|mid| !mid.is_empty()) {
+        Some(mid) => parsed
+            .media_descriptions
+            .iter()
+            .position(|media| media.attribute("mid") == Some(Some(mid)))
+            .with_context(|| format!("no media section with mid {:?}", mid)),
+        None => {
+            let index = candidate
+                .sdp_mline_index
+                .context("neither sdp_mid nor sdp_mline_index is set")?
+                as usize;
+            if index >= parsed.media_descriptions.len() {
+                bail!("no media section {}", index);
+            }
+            Ok(index)
+        }
+    }
 }
 
-// Convert JSON string to ICE candidate
-async fn convert_to_ice_candidate(candidate_str: &str) -> Result<RTCIceCandidateInit> {
-    // Parse the raw JSON string to get the fields
-    let raw_json: serde_json::Value =
-        serde_json::from_str(candidate_str).context("Failed to parse ICE candidate JSON")?;
-
-    // Extract the needed fields with better error handling
-    let foundation = raw_json["foundation"].as_str().unwrap_or("").to_string();
-    let component = raw_json["component"].as_u64().unwrap_or(1) as u16;
-    let protocol = raw_json["protocol"].as_str().unwrap_or("udp").to_string();
-    let priority = raw_json["priority"].as_u64().unwrap_or(0) as u32;
-    let address = raw_json["address"].as_str().unwrap_or("").to_string();
-    let port = raw_json["port"].as_u64().unwrap_or(0) as u16;
-    let typ = raw_json["typ"].as_str().unwrap_or("").to_string();
-
-    // Additional fields that might be present
-    let tcp_type = if let Some(tcp_type) = raw_json["tcptype"].as_str() {
-        format!(" tcptype {}", tcp_type)
-    } else {
-        String::new()
+// Fill in the mid and username fragment of our own candidates from the
+// description they were gathered for. RTCIceCandidate::to_json leaves both
+// out, and the peer resolves candidates by them. A description that doesn't
+// parse is left for the peer to reject.
+fn label_candidates(
+    description: &RTCSessionDescription,
+    candidates: &[RTCIceCandidateInit],
+) -> Vec<RTCIceCandidateInit> {
+    let Ok(parsed) = description.unmarshal() else {
+        return candidates.to_vec();
     };
+    let session_ufrag = parsed.attribute("ice-ufrag").cloned();
+    let mut labelled = candidates.to_vec();
+    for candidate in &mut labelled {
+        let index = candidate.sdp_mline_index.unwrap_or(0) as usize;
+        let Some(media) = parsed.media_descriptions.get(index) else {
+            continue;
+        };
+        if candidate.sdp_mid.as_deref().is_none_or(str::is_empty) {
+            candidate.sdp_mid = media.attribute("mid").flatten().map(str::to_owned);
+        }
+        candidate.sdp_mline_index = Some(index as u16);
+        if candidate.username_fragment.is_none() {
+            candidate.username_fragment = match media.attribute("ice-ufrag") {
+                Some(Some(ufrag)) => Some(ufrag.to_owned()),
+                _ => session_ufrag.clone(),
+            };
+        }
+    }
+    labelled
+}
 
-    // Create the candidate string in standard format
-    let candidate_str = format!(
-        "candidate:{} {} {} {} {} {} typ {}{}",
-        foundation, component, protocol, priority, address, port, typ, tcp_type
-    );
+// Extract the ICE candidates section from SDP data
+pub fn parse_candidates(data: &str) -> Result<Vec<RTCIceCandidateInit>> {
+    parse_joined(data, |text| match text.find(CANDIDATES_MARKER) {
+        Some(i) => Ok(candidates_section(&text[i..])?.unwrap_or_default()),
+        None => Ok(Vec::new()),
+    })
+}
 
-    // Create the RTCIceCandidateInit structure
-    let candidate = RTCIceCandidateInit {
-        candidate: candidate_str,
-        ..Default::default()
-    };
+// Every entry must be an RTCIceCandidateInit whose candidate line parses;
+// all the bad entries are reported together, by index, rather than dropped.
+fn validate_candidates(entries: Vec<serde_json::Value>) -> Result<Vec<RTCIceCandidateInit>> {
+    let mut candidates = Vec::with_capacity(entries.len());
+    let mut errors = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        match parse_candidate(entry) {
+            Ok(candidate) => candidates.push(candidate),
+            Err(e) => errors.push(format!("candidate {}: {:#}", index, e)),
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!(
+            "{} of {} ICE candidates are invalid:\n  {}",
+            errors.len(),
+            errors.len() + candidates.len(),
+            errors.join("\n  ")
+        );
+    }
 
+    Ok(candidates)
+}
+
+// Check one candidate the same way the ICE agent will read it
+fn parse_candidate(entry: serde_json::Value) -> Result<RTCIceCandidateInit> {
+    let candidate = serde_json::from_value::<RTCIceCandidateInit>(entry)
+        .context("not an RTCIceCandidateInit")?;
+    let raw = candidate
+        .candidate
+        .strip_prefix("candidate:")
+        .with_context(|| {
+            format!(
+                "{:?} doesn't start with \"candidate:\"",
+                candidate.candidate
+            )
+        })?;
+    unmarshal_candidate(raw).with_context(|| format!("can't parse {:?}", candidate.candidate))?;
+    if candidate.sdp_mid.as_deref().is_none_or(str::is_empty) && candidate.sdp_mline_index.is_none()
+    {
+        bail!("neither sdp_mid nor sdp_mline_index is set");
+    }
     Ok(candidate)
 }
@@ -0,0 +1,130 @@
+use crate::sdp;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+// Carries the offer and answer text between the two peers. The offerer sends
+// its offer and receives the answer; the answerer does the opposite.
+#[async_trait]
+pub trait Signaler: Send {
+    // Deliver our offer or answer to the other peer
+    async fn send(&mut self, blob: &str) -> Result<()>;
+
+    // Wait for the other peer's offer or answer
+    async fn receive(&mut self) -> Result<String>;
+}
+
+// Signaling by hand: print our side for the user to copy, read theirs from stdin
+pub struct ConsoleSignaler;
+
+#[async_trait]
+impl Signaler for ConsoleSignaler {
+    async fn send(&mut self, blob: &str) -> Result<()> {
+        println!("\n{}\n", blob);
+        println!("Send the above text to the other peer");
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<String> {
+        sdp::read_sdp_input().await
+    }
+}
+
+// Signaling over in-process queues, for running both peers in one process.
+// Keeps a copy of everything sent.
+pub struct MemorySignaler {
+    tx: mpsc::UnboundedSender<String>,
+    rx: mpsc::UnboundedReceiver<String>,
+    pub sent: Vec<String>,
+}
+
+impl MemorySignaler {
+    // Two signalers wired to each other
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, b_rx) = mpsc::unbounded_channel();
+        let (b_tx, a_rx) = mpsc::unbounded_channel();
+        (
+            Self {
+                tx: a_tx,
+                rx: a_rx,
+                sent: Vec::new(),
+            },
+            Self {
+                tx: b_tx,
+                rx: b_rx,
+                sent: Vec::new(),
+            },
+        )
+    }
+}
+
+#[async_trait]
+impl Signaler for MemorySignaler {
+    async fn send(&mut self, blob: &str) -> Result<()> {
+        self.sent.push(blob.to_string());
+        self.tx
+            .send(blob.to_string())
+            .map_err(|_| anyhow::anyhow!("Other peer hung up"))
+    }
+
+    async fn receive(&mut self) -> Result<String> {
+        self.rx
+            .recv()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Other peer hung up"))
+    }
+}
+
+// Largest frame accepted from the peer; a blob is a few kilobytes
+const MAX_FRAME_LEN: u32 = 1 << 20;
+
+// Signaling over a TCP connection, each blob in a frame prefixed with its
+// length as a big-endian u32
+pub struct TcpSignaler {
+    stream: TcpStream,
+}
+
+impl TcpSignaler {
+    pub fn new(stream: TcpStream) -> Self {
+        Self { stream }
+    }
+}
+
+#[async_trait]
+impl Signaler for TcpSignaler {
+    async fn send(&mut self, blob: &str) -> Result<()> {
+        let len = u32::try_from(blob.len())
+            .ok()
+            .filter(|&len| len <= MAX_FRAME_LEN)
+            .context("Signaling message is too large")?;
+        self.stream.write_u32(len).await?;
+        self.stream.write_all(blob.as_bytes()).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<String> {
+        let len = match self.stream.read_u32().await {
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => bail!("Other peer hung up"),
+            Err(e) => return Err(e).context("Failed to read from the other peer"),
+        };
+        if len > MAX_FRAME_LEN {
+            bail!(
+                "Other peer sent a {} byte message, too large to be signaling",
+                len
+            );
+        }
+
+        let mut frame = vec![0; len as usize];
+        self.stream
+            .read_exact(&mut frame)
+            .await
+            .context("Other peer hung up mid-message")?;
+        String::from_utf8(frame).context("Other peer sent a message that isn't text")
+    }
+}
@@ -0,0 +1,541 @@
+use crate::app;
+use crate::connection::{
+    self, ChannelSpec, IceSettings, ReliabilityProfile, SelectedCandidatePair, MESSAGING_CHANNEL,
+};
+use crate::protocol::WireMessage;
+use crate::signaling::MemorySignaler;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use log::debug;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::turn;
+use webrtc::util::vnet::nat::{EndpointDependencyType, NatType};
+use webrtc::util::vnet::net::{Net, NetConfig};
+use webrtc::util::vnet::router::{ChunkFilterFn, Nic, Router, RouterConfig};
+
+// Addresses on the simulated internet
+const STUN_SERVER_IP: &str = "1.2.3.4";
+const STUN_SERVER_PORT: u16 = 3478;
+const OFFERER_PUBLIC_IP: &str = "27.1.1.1";
+const OFFERER_LAN: (&str, &str) = ("192.168.0.0/24", "192.168.0.2");
+const ANSWERER_PUBLIC_IP: &str = "28.1.1.1";
+const ANSWERER_LAN: (&str, &str) = ("10.2.0.0/24", "10.2.0.2");
+
+// Credentials of the simulated TURN server
+const TURN_REALM: &str = "modulate-comms";
+const TURN_USERNAME: &str = "sim";
+const TURN_PASSWORD: &str = "sim";
+
+// Smallest burst the bandwidth cap lets through at once
+const MIN_BURST_BYTES: f64 = 8192.0;
+
+// How long to wait for each peer connection to close
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// How long to wait for stragglers once messages stop arriving
+const DELIVERY_GRACE: Duration = Duration::from_secs(3);
+
+// NAT in front of each peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NatKind {
+    // Peers sit directly on the internet
+    None,
+    // Endpoint-independent mapping and filtering
+    FullCone,
+    // Mapping and filtering depend on the remote address and port
+    Symmetric,
+}
+
+impl NatKind {
+    fn nat_type(self) -> Option<NatType> {
+        let behavior = match self {
+            NatKind::None => return None,
+            NatKind::FullCone => EndpointDependencyType::EndpointIndependent,
+            NatKind::Symmetric => EndpointDependencyType::EndpointAddrPortDependent,
+        };
+        Some(NatType {
+            mapping_behavior: behavior,
+            filtering_behavior: behavior,
+            ..Default::default()
+        })
+    }
+}
+
+// Network conditions and traffic for one simulated session
+#[derive(Debug, Clone)]
+pub struct SimulationConfig {
+    // One-way delay added to every packet
+    pub latency: Duration,
+    // Random extra delay on top of the latency, up to this much
+    pub jitter: Duration,
+    // Percentage of packets dropped
+    pub loss_percent: u32,
+    // Link capacity in kbit/s shared by both directions; excess packets are dropped
+    pub bandwidth_kbps: Option<u32>,
+    pub nat: NatKind,
+    // Offer a TURN relay to both peers
+    pub relay: bool,
+    // Chat messages to send once connected
+    pub messages: usize,
+    // Text size of each message in bytes
+    pub message_size: usize,
+    // Profile of the messaging channel
+    pub profile: ReliabilityProfile,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss_percent: 0,
+            bandwidth_kbps: None,
+            nat: NatKind::None,
+            relay: false,
+            messages: 100,
+            message_size: 64,
+            profile: ReliabilityProfile::default(),
+        }
+    }
+}
+
+// What happened in a simulated session
+pub struct SimulationReport {
+    // From creating the peer connections until the chat channel was open on
+    // both sides, or None if that never happened
+    pub connect_time: Option<Duration>,
+    // Pair chosen by each side
+    pub offerer_pair: Option<SelectedCandidatePair>,
+    pub answerer_pair: Option<SelectedCandidatePair>,
+    pub delivery: Option<DeliveryStats>,
+    // Packets the network dropped, to loss or the bandwidth cap
+    pub packets_dropped: u64,
+}
+
+// How the chat messages fared once connected
+pub struct DeliveryStats {
+    pub sent: usize,
+    pub delivered: usize,
+    // Messages that arrived after a later one
+    pub out_of_order: usize,
+    // Delivery latency of the messages that arrived
+    pub latency_min: Option<Duration>,
+    pub latency_avg: Option<Duration>,
+    pub latency_max: Option<Duration>,
+    // From the first send until the last delivery
+    pub delivery_time: Duration,
+}
+
+// Entry point for the hidden `simulate` subcommand
+pub async fn run_simulation(config: SimulationConfig, timeout: Duration) -> Result<()> {
+    println!("Simulating a session with {:?}", config);
+
+    let report = simulate(&config, timeout).await?;
+
+    println!("\n===== SIMULATION REPORT =====");
+    match report.connect_time {
+        Some(connect_time) => println!("Connected in {:.2?}", connect_time),
+        None => println!("Failed to connect within {} seconds", timeout.as_secs()),
+    }
+    for (role, pair) in [
+        ("Offerer", &report.offerer_pair),
+        ("Answerer", &report.answerer_pair),
+    ] {
+        match pair {
+            Some(selected) => println!(
+                "{} candidate pair: {} -> {}",
+                role,
+                connection::describe_candidate(&selected.local),
+                connection::describe_candidate(&selected.remote)
+            ),
+            None => println!("{} candidate pair: none selected", role),
+        }
+    }
+
+    if let Some(ref delivery) = report.delivery {
+        let delivered_percent = if delivery.sent == 0 {
+            100.0
+        } else {
+            delivery.delivered as f64 * 100.0 / delivery.sent as f64
+        };
+        println!(
+            "Messages: {} sent, {} delivered ({:.1}%), {} lost, {} out of order",
+            delivery.sent,
+            delivery.delivered,
+            delivered_percent,
+            delivery.sent - delivery.delivered,
+            delivery.out_of_order
+        );
+        if let (Some(min), Some(avg), Some(max)) = (
+            delivery.latency_min,
+            delivery.latency_avg,
+            delivery.latency_max,
+        ) {
+            println!(
+                "Delivery latency: min {:.2?}, avg {:.2?}, max {:.2?}",
+                min, avg, max
+            );
+        }
+        println!("All deliveries took {:.2?}", delivery.delivery_time);
+    }
+    println!("Packets dropped by the network: {}", report.packets_dropped);
+
+    Ok(())
+}
+
+// Run two peers over a simulated network and measure the session
+pub async fn simulate(config: &SimulationConfig, timeout: Duration) -> Result<SimulationReport> {
+    let network = SimulatedNetwork::build(config).await?;
+
+    let ice = network.ice_settings(config.relay);
+    let start = Instant::now();
+    let offerer =
+        connection::create_peer_connection_with(&ice, network.setting_engine(&network.offerer))
+            .await?;
+    let answerer =
+        connection::create_peer_connection_with(&ice, network.setting_engine(&network.answerer))
+            .await?;
+
+    let result = measure(config, &offerer, &answerer, start, timeout).await;
+    let offerer_pair = connection::selected_candidate_pair(&offerer).await;
+    let answerer_pair = connection::selected_candidate_pair(&answerer).await;
+
+    // Tear everything down however the session went. After a failed
+    // connection, closing the peers or the network can stall on the virtual
+    // network, so don't wait forever.
+    for pc in [&offerer, &answerer] {
+        if tokio::time::timeout(CLOSE_TIMEOUT, pc.close())
+            .await
+            .is_err()
+        {
+            debug!("Gave up waiting for a simulated peer to close");
+        }
+    }
+    if tokio::time::timeout(CLOSE_TIMEOUT, network.close())
+        .await
+        .is_err()
+    {
+        debug!("Gave up waiting for the simulated network to stop");
+    }
+
+    let (connect_time, delivery) = result?;
+    Ok(SimulationReport {
+        connect_time,
+        offerer_pair,
+        answerer_pair,
+        delivery,
+        packets_dropped: network.dropped.load(Ordering::SeqCst),
+    })
+}
+
+// Connect the peers, then send the chat messages if that worked
+async fn measure(
+    config: &SimulationConfig,
+    offerer: &Arc<RTCPeerConnection>,
+    answerer: &Arc<RTCPeerConnection>,
+    start: Instant,
+    timeout: Duration,
+) -> Result<(Option<Duration>, Option<DeliveryStats>)> {
+    // Same signaling code as a real session, over in-process queues
+    let specs = ChannelSpec::with_overrides(&[ChannelSpec::new(MESSAGING_CHANNEL, config.profile)]);
+    let (mut offer_signaler, mut answer_signaler) = MemorySignaler::pair();
+    let (mut offer_channels, mut answer_channels) = tokio::try_join!(
+        app::connect(
+            Arc::clone(offerer),
+            specs.clone(),
+            &mut offer_signaler,
+            true,
+            timeout,
+        ),
+        app::connect(
+            Arc::clone(answerer),
+            specs,
+            &mut answer_signaler,
+            false,
+            timeout,
+        ),
+    )?;
+
+    let sender = offer_channels
+        .remove(MESSAGING_CHANNEL)
+        .context("Messaging channel was not set up")?;
+    let mut receiver = answer_channels
+        .remove(MESSAGING_CHANNEL)
+        .context("Messaging channel was not set up")?;
+
+    // Whatever time is left goes to opening the channel
+    let remaining = timeout.saturating_sub(start.elapsed());
+    let opened = tokio::try_join!(
+        connection::wait_for_open(&sender.channel, remaining),
+        connection::wait_for_open(&receiver.channel, remaining),
+    );
+    let Ok((dc, _)) = opened else {
+        return Ok((None, None));
+    };
+    let connect_time = start.elapsed();
+
+    // Send numbered messages and time each one's delivery
+    let text = "x".repeat(config.message_size);
+    let mut sent_at = Vec::with_capacity(config.messages);
+    let send_start = Instant::now();
+    for i in 0..config.messages {
+        let message = WireMessage::Chat {
+            id: i.to_string(),
+            text: text.clone(),
+            reply_to: None,
+        };
+        sent_at.push(Instant::now());
+        message.send(&dc).await?;
+    }
+
+    let patience = DELIVERY_GRACE + config.latency * 4 + config.jitter * 4;
+    let mut latencies = Vec::new();
+    let mut highest: Option<usize> = None;
+    let mut out_of_order = 0;
+    let mut delivery_time = Duration::ZERO;
+    while latencies.len() < config.messages {
+        let msg = match tokio::time::timeout(patience, receiver.incoming.recv()).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) | Err(_) => break,
+        };
+        let Ok(WireMessage::Chat { id, .. }) = WireMessage::decode(&msg.data) else {
+            continue;
+        };
+        let Some((index, sent)) = id
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| sent_at.get(i).map(|t| (i, t)))
+        else {
+            continue;
+        };
+
+        latencies.push(sent.elapsed());
+        delivery_time = send_start.elapsed();
+        if highest.is_some_and(|h| index < h) {
+            out_of_order += 1;
+        }
+        highest = highest.max(Some(index));
+    }
+
+    let latency_avg = if latencies.is_empty() {
+        None
+    } else {
+        Some(latencies.iter().sum::<Duration>() / latencies.len() as u32)
+    };
+
+    let delivery = DeliveryStats {
+        sent: config.messages,
+        delivered: latencies.len(),
+        out_of_order,
+        latency_min: latencies.iter().min().copied(),
+        latency_avg,
+        latency_max: latencies.iter().max().copied(),
+        delivery_time,
+    };
+    Ok((Some(connect_time), Some(delivery)))
+}
+
+// The simulated internet: a WAN router with the impairments, a STUN/TURN
+// server on it, and each peer either on the WAN or behind its own NAT
+struct SimulatedNetwork {
+    wan: Arc<Mutex<Router>>,
+    offerer: Arc<Net>,
+    answerer: Arc<Net>,
+    server: turn::server::Server,
+    dropped: Arc<AtomicU64>,
+}
+
+impl SimulatedNetwork {
+    async fn build(config: &SimulationConfig) -> Result<Self> {
+        let wan = Arc::new(Mutex::new(Router::new(RouterConfig {
+            cidr: "0.0.0.0/0".to_owned(),
+            min_delay: config.latency,
+            max_jitter: config.jitter,
+            ..Default::default()
+        })?));
+
+        let dropped = Arc::new(AtomicU64::new(0));
+        wan.lock()
+            .await
+            .add_chunk_filter(impairment_filter(config, Arc::clone(&dropped)))
+            .await;
+
+        let server_net = Arc::new(Net::new(Some(NetConfig {
+            static_ips: vec![STUN_SERVER_IP.to_owned()],
+            ..Default::default()
+        })));
+        attach_net(&server_net, &wan).await?;
+
+        let offerer = add_peer(&wan, config.nat, OFFERER_PUBLIC_IP, OFFERER_LAN).await?;
+        let answerer = add_peer(&wan, config.nat, ANSWERER_PUBLIC_IP, ANSWERER_LAN).await?;
+
+        wan.lock().await.start().await?;
+
+        let server = start_turn_server(server_net).await?;
+
+        Ok(Self {
+            wan,
+            offerer,
+            answerer,
+            server,
+            dropped,
+        })
+    }
+
+    // ICE servers pointing at the simulated STUN (and TURN) server
+    fn ice_settings(&self, relay: bool) -> IceSettings {
+        let server = format!("{}:{}", STUN_SERVER_IP, STUN_SERVER_PORT);
+        IceSettings {
+            stun_servers: vec![format!("stun:{}", server)],
+            turn_servers: if relay {
+                vec![format!("turn:{}", server)]
+            } else {
+                Vec::new()
+            },
+            turn_username: TURN_USERNAME.to_string(),
+            turn_credential: TURN_PASSWORD.to_string(),
+            relay_only: false,
+        }
+    }
+
+    fn setting_engine(&self, net: &Arc<Net>) -> SettingEngine {
+        let mut settings = SettingEngine::default();
+        settings.set_vnet(Some(Arc::clone(net)));
+        settings
+    }
+
+    async fn close(&self) {
+        let _ = self.server.close().await;
+        let _ = self.wan.lock().await.stop().await;
+    }
+}
+
+// Drops packets at random and above the bandwidth cap, counting each drop
+fn impairment_filter(config: &SimulationConfig, dropped: Arc<AtomicU64>) -> ChunkFilterFn {
+    let loss_percent = config.loss_percent;
+    // Token bucket holding 100 ms of traffic, but never less than a DTLS
+    // handshake flight so slow links can still connect
+    let bytes_per_sec = config.bandwidth_kbps.map(|kbps| kbps as f64 * 1000.0 / 8.0);
+    let burst = bytes_per_sec.map_or(0.0, |rate| (rate / 10.0).max(MIN_BURST_BYTES));
+    let bucket = std::sync::Mutex::new((burst, Instant::now()));
+
+    Box::new(move |chunk| {
+        let lost = loss_percent > 0 && rand::random_range(0..100) < loss_percent;
+        let over_cap = !lost
+            && bytes_per_sec.is_some_and(|rate| {
+                let mut bucket = bucket.lock().unwrap();
+                let (tokens, last) = &mut *bucket;
+                *tokens = (*tokens + last.elapsed().as_secs_f64() * rate).min(burst);
+                *last = Instant::now();
+
+                let size = chunk.user_data().len() as f64;
+                if *tokens < size {
+                    return true;
+                }
+                *tokens -= size;
+                false
+            });
+
+        if lost || over_cap {
+            dropped.fetch_add(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    })
+}
+
+// A peer's network interface, on the WAN or behind a NAT router of its own
+async fn add_peer(
+    wan: &Arc<Mutex<Router>>,
+    nat: NatKind,
+    public_ip: &str,
+    (lan_cidr, lan_ip): (&str, &str),
+) -> Result<Arc<Net>> {
+    let Some(nat_type) = nat.nat_type() else {
+        let net = Arc::new(Net::new(Some(NetConfig {
+            static_ips: vec![public_ip.to_owned()],
+            ..Default::default()
+        })));
+        attach_net(&net, wan).await?;
+        return Ok(net);
+    };
+
+    let lan = Arc::new(Mutex::new(Router::new(RouterConfig {
+        cidr: lan_cidr.to_owned(),
+        static_ips: vec![public_ip.to_owned()],
+        nat_type: Some(nat_type),
+        ..Default::default()
+    })?));
+    let net = Arc::new(Net::new(Some(NetConfig {
+        static_ips: vec![lan_ip.to_owned()],
+        ..Default::default()
+    })));
+    attach_net(&net, &lan).await?;
+
+    wan.lock().await.add_router(Arc::clone(&lan)).await?;
+    lan.lock().await.set_router(Arc::clone(wan)).await?;
+
+    Ok(net)
+}
+
+async fn attach_net(net: &Arc<Net>, router: &Arc<Mutex<Router>>) -> Result<()> {
+    let nic = net.get_nic()?;
+    router.lock().await.add_net(Arc::clone(&nic)).await?;
+    nic.lock().await.set_router(Arc::clone(router)).await?;
+    Ok(())
+}
+
+// STUN and TURN server on the simulated internet
+async fn start_turn_server(net: Arc<Net>) -> Result<turn::server::Server> {
+    let address = SocketAddr::new(STUN_SERVER_IP.parse()?, STUN_SERVER_PORT);
+    let conn = net.bind(address).await?;
+
+    let server = turn::server::Server::new(turn::server::config::ServerConfig {
+        conn_configs: vec![turn::server::config::ConnConfig {
+            conn,
+            relay_addr_generator: Box::new(
+                turn::relay::relay_static::RelayAddressGeneratorStatic {
+                    relay_address: STUN_SERVER_IP.parse::<IpAddr>()?,
+                    address: "0.0.0.0".to_owned(),
+                    net,
+                },
+            ),
+        }],
+        realm: TURN_REALM.to_owned(),
+        auth_handler: Arc::new(SimulatedAuth),
+        channel_bind_timeout: Duration::from_secs(0),
+        alloc_close_notify: None,
+    })
+    .await?;
+
+    Ok(server)
+}
+
+// Accepts the one simulated TURN user
+struct SimulatedAuth;
+
+impl turn::auth::AuthHandler for SimulatedAuth {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        _src_addr: SocketAddr,
+    ) -> std::result::Result<Vec<u8>, turn::Error> {
+        if username == TURN_USERNAME {
+            Ok(turn::auth::generate_auth_key(
+                username,
+                realm,
+                TURN_PASSWORD,
+            ))
+        } else {
+            Err(turn::Error::Other(format!("Unknown user {}", username)))
+        }
+    }
+}
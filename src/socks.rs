@@ -0,0 +1,112 @@
+use crate::protocol::TunnelError;
+
+use anyhow::{bail, Result};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0x00;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+
+// Reply codes
+pub const SUCCEEDED: u8 = 0x00;
+pub const GENERAL_FAILURE: u8 = 0x01;
+pub const NOT_ALLOWED: u8 = 0x02;
+pub const HOST_UNREACHABLE: u8 = 0x04;
+pub const CONNECTION_REFUSED: u8 = 0x05;
+pub const TTL_EXPIRED: u8 = 0x06;
+pub const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+// Negotiate with a SOCKS5 client (RFC 1928) up to its request, returning the
+// host:port it wants to reach. Only CONNECT without authentication is
+// supported, which is what browsers and curl use on a local proxy; other
+// requests are answered here.
+pub async fn accept<S>(socket: &mut S) -> Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0u8; 2];
+    socket.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        bail!("Not a SOCKS5 client (version {})", header[0]);
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    socket.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTHENTICATION) {
+        socket.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        bail!("SOCKS client wants authentication");
+    }
+    socket.write_all(&[VERSION, NO_AUTHENTICATION]).await?;
+
+    let mut request = [0u8; 4];
+    socket.read_exact(&mut request).await?;
+    if request[0] != VERSION {
+        bail!("Bad SOCKS request version {}", request[0]);
+    }
+    let host = match request[3] {
+        ADDRESS_IPV4 => {
+            let mut ip = [0u8; 4];
+            socket.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        ADDRESS_IPV6 => {
+            let mut ip = [0u8; 16];
+            socket.read_exact(&mut ip).await?;
+            format!("[{}]", Ipv6Addr::from(ip))
+        }
+        ADDRESS_DOMAIN => {
+            let len = socket.read_u8().await?;
+            let mut name = vec![0u8; len as usize];
+            socket.read_exact(&mut name).await?;
+            match String::from_utf8(name) {
+                Ok(name) if !name.is_empty() => name,
+                _ => {
+                    reply(socket, ADDRESS_NOT_SUPPORTED).await?;
+                    bail!("SOCKS client sent a bad domain name");
+                }
+            }
+        }
+        other => {
+            reply(socket, ADDRESS_NOT_SUPPORTED).await?;
+            bail!("SOCKS client sent unknown address type {}", other);
+        }
+    };
+    let port = socket.read_u16().await?;
+
+    if request[1] != CONNECT {
+        reply(socket, COMMAND_NOT_SUPPORTED).await?;
+        bail!(
+            "Only CONNECT is supported, not SOCKS command {}",
+            request[1]
+        );
+    }
+    Ok(format!("{}:{}", host, port))
+}
+
+// Answer the client's request. There's no address to report on our side, the
+// connection is made by the peer.
+pub async fn reply<S>(socket: &mut S, code: u8) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    socket
+        .write_all(&[VERSION, code, 0, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+// The reply for a connection the peer couldn't open
+pub fn reply_code(error: TunnelError) -> u8 {
+    match error {
+        TunnelError::NotAllowed => NOT_ALLOWED,
+        TunnelError::Refused => CONNECTION_REFUSED,
+        TunnelError::Unreachable => HOST_UNREACHABLE,
+        TunnelError::TimedOut => TTL_EXPIRED,
+        TunnelError::Failed => GENERAL_FAILURE,
+    }
+}
@@ -0,0 +1,153 @@
+use crate::connection::{self, SelectedCandidatePair};
+use crate::ping::Pinger;
+use crate::utils::format_bytes;
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::stats::StatsReportType;
+
+// Traffic on one data channel
+#[derive(Debug, Clone)]
+pub struct ChannelStats {
+    pub label: String,
+    pub messages_sent: usize,
+    pub messages_received: usize,
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    // Sent on our side and not yet acknowledged by the peer
+    pub buffered_amount: usize,
+}
+
+// A snapshot of the connection, from the peer connection's stats report
+pub struct ConnectionStats {
+    pub selected: Option<SelectedCandidatePair>,
+    // From the ICE agent if it measured one, else the latest pong
+    pub round_trip_time: Option<Duration>,
+    // Everything that went over the ICE transport, checks and all channels
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    pub channels: Vec<ChannelStats>,
+}
+
+// Collect the stats for the connection and the given channels
+pub async fn collect(
+    pc: &RTCPeerConnection,
+    channels: &[Arc<RTCDataChannel>],
+    pinger: Option<&Pinger>,
+) -> ConnectionStats {
+    let selected = connection::selected_candidate_pair(pc).await;
+    let round_trip_time = selected
+        .as_ref()
+        .map(|selected| selected.pair.current_round_trip_time)
+        .filter(|&rtt| rtt > 0.0)
+        .map(Duration::from_secs_f64)
+        .or_else(|| pinger.and_then(Pinger::last_rtt));
+
+    let reports = pc.get_stats().await.reports;
+    let (bytes_sent, bytes_received) = reports
+        .values()
+        .find_map(|report| match report {
+            StatsReportType::Transport(transport) => {
+                Some((transport.bytes_sent, transport.bytes_received))
+            }
+            _ => None,
+        })
+        .unwrap_or_default();
+
+    let mut channel_stats = Vec::new();
+    for dc in channels {
+        let Some(report) = reports.values().find_map(|report| match report {
+            StatsReportType::DataChannel(stats) if stats.data_channel_identifier == dc.id() => {
+                Some(stats)
+            }
+            _ => None,
+        }) else {
+            continue;
+        };
+        channel_stats.push(ChannelStats {
+            label: report.label.clone(),
+            messages_sent: report.messages_sent,
+            messages_received: report.messages_received,
+            bytes_sent: report.bytes_sent,
+            bytes_received: report.bytes_received,
+            buffered_amount: dc.buffered_amount().await,
+        });
+    }
+
+    ConnectionStats {
+        selected,
+        round_trip_time,
+        bytes_sent,
+        bytes_received,
+        channels: channel_stats,
+    }
+}
+
+impl ConnectionStats {
+    // The report as lines for the console
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        match self.selected {
+            Some(ref selected) => lines.push(format!(
+                "Candidate pair: {} -> {}",
+                connection::describe_candidate(&selected.local),
+                connection::describe_candidate(&selected.remote)
+            )),
+            None => lines.push("Candidate pair: none selected".to_string()),
+        }
+        match self.round_trip_time {
+            Some(rtt) => lines.push(format!(
+                "Round trip time: {:.1} ms",
+                rtt.as_secs_f64() * 1000.0
+            )),
+            None => lines.push("Round trip time: not measured".to_string()),
+        }
+        lines.push(format!(
+            "Transport: sent {}, received {}",
+            format_bytes(self.bytes_sent),
+            format_bytes(self.bytes_received)
+        ));
+        if !self.channels.is_empty() {
+            lines.push("Data channels:".to_string());
+        }
+        for channel in &self.channels {
+            lines.push(format!(
+                "  {} - sent {} messages ({}), received {} messages ({}), {} buffered",
+                channel.label,
+                channel.messages_sent,
+                format_bytes(channel.bytes_sent),
+                channel.messages_received,
+                format_bytes(channel.bytes_received),
+                format_bytes(channel.buffered_amount)
+            ));
+        }
+        lines
+    }
+}
+
+// Print the stats every `interval` until the task is aborted
+pub fn spawn_reporter<F>(
+    pc: Arc<RTCPeerConnection>,
+    channels: Vec<Arc<RTCDataChannel>>,
+    pinger: Option<Arc<Pinger>>,
+    interval: Duration,
+    print: F,
+) -> JoinHandle<()>
+where
+    F: Fn(&str) + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick is immediate, and there's nothing to report yet
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            for line in collect(&pc, &channels, pinger.as_deref()).await.lines() {
+                print(&line);
+            }
+        }
+    })
+}
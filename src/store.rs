@@ -0,0 +1,651 @@
+use crate::connection::ChannelSpec;
+use crate::contacts::Contact;
+use crate::identity::Identity;
+
+use anyhow::{Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use log::{debug, info};
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// Files kept in the data directory
+const VAULT_FILE: &str = "vault.json";
+const CONFIG_FILE: &str = "config.enc";
+const HISTORY_FILE: &str = "history.enc";
+const CONTACTS_FILE: &str = "contacts.enc";
+
+// Known plaintext sealed into the vault to check the passphrase on unlock
+const CHECK_PLAINTEXT: &[u8] = b"modulate-comms vault";
+
+// Environment variable that can supply the passphrase for scripted use
+const PASSPHRASE_ENV: &str = "MODULATE_PASSPHRASE";
+
+// Number of passphrase attempts before giving up
+const UNLOCK_ATTEMPTS: usize = 3;
+
+// Store shared between the chat loop and the message printer
+pub type SharedStore = Arc<Mutex<Store>>;
+
+// User configuration persisted between sessions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // Maximum number of history entries kept on disk
+    pub history_limit: usize,
+    // Secret seed of our long-term identity key, hex encoded
+    pub identity_key: Option<String>,
+    // Name announced to peers
+    pub display_name: Option<String>,
+    // Data channels to open besides the built-in ones, or profiles for them
+    pub channels: Vec<ChannelSpec>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            history_limit: 1000,
+            identity_key: None,
+            display_name: None,
+            channels: Vec::new(),
+        }
+    }
+}
+
+// Which side of the conversation a history entry came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+// The peer and session a message was exchanged in. Edits, deletions and
+// reactions only ever reach messages of the conversation they arrive in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conversation {
+    // Peer's identity key
+    pub peer: String,
+    pub session: String,
+}
+
+impl Conversation {
+    // A new session with the peer of this identity
+    pub fn new(peer: &str) -> Self {
+        let mut session = [0u8; 8];
+        rand::rng().fill_bytes(&mut session);
+        Self {
+            peer: peer.to_string(),
+            session: hex::encode(session),
+        }
+    }
+}
+
+// A single chat message in the history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub timestamp: chrono::DateTime<chrono::Local>,
+    pub direction: Direction,
+    // Who the conversation was with, as shown at the time
+    #[serde(default)]
+    pub peer: String,
+    // The peer's identity key and the session, empty in older histories
+    #[serde(default)]
+    pub peer_identity: String,
+    #[serde(default)]
+    pub session: String,
+    // Message id shared by both peers
+    #[serde(default)]
+    pub id: String,
+    // Text as originally sent
+    pub text: String,
+    // Later versions of the text, oldest first
+    #[serde(default)]
+    pub revisions: Vec<Revision>,
+    #[serde(default)]
+    pub deleted_at: Option<chrono::DateTime<chrono::Local>>,
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    // Message this one replies to
+    #[serde(default)]
+    pub reply_to: Option<String>,
+}
+
+impl HistoryEntry {
+    pub fn belongs_to(&self, conversation: &Conversation) -> bool {
+        !self.session.is_empty()
+            && self.session == conversation.session
+            && self.peer_identity == conversation.peer
+    }
+
+    // Text as it currently reads, after any edits
+    pub fn current_text(&self) -> &str {
+        self.revisions
+            .last()
+            .map(|r| r.text.as_str())
+            .unwrap_or(&self.text)
+    }
+}
+
+// An edit made to a message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revision {
+    pub timestamp: chrono::DateTime<chrono::Local>,
+    pub text: String,
+}
+
+// A reaction left on a message, by us (Sent) or the peer (Received)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reaction {
+    pub by: Direction,
+    pub emoji: String,
+}
+
+// Key derivation parameters and passphrase check, stored in the clear
+#[derive(Serialize, Deserialize)]
+struct VaultHeader {
+    version: u32,
+    salt: String,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    check: Sealed,
+}
+
+// An encrypted blob with the nonce needed to open it
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    nonce: String,
+    ciphertext: String,
+}
+
+// Unlocked on-disk location and the key derived from the passphrase
+struct Vault {
+    dir: PathBuf,
+    cipher: XChaCha20Poly1305,
+}
+
+// Config, identity, contacts and history, encrypted at rest unless running ephemeral
+pub struct Store {
+    vault: Option<Vault>,
+    config: Config,
+    identity: Identity,
+    contacts: Vec<Contact>,
+    history: Vec<HistoryEntry>,
+}
+
+impl Store {
+    // A store that lives only in memory and is never written to disk.
+    // The identity is freshly generated, so peers will not recognise it.
+    pub fn ephemeral() -> Self {
+        Self {
+            vault: None,
+            config: Config::default(),
+            identity: Identity::generate(),
+            contacts: Vec::new(),
+            history: Vec::new(),
+        }
+    }
+
+    // Open the store in the given data directory, prompting for the passphrase
+    pub fn open(data_dir: Option<PathBuf>) -> Result<Self> {
+        let dir = match data_dir {
+            Some(dir) => dir,
+            None => default_data_dir()?,
+        };
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create data directory {}", dir.display()))?;
+        restrict_permissions(&dir)?;
+
+        let vault = if dir.join(VAULT_FILE).exists() {
+            Vault::unlock(dir)?
+        } else {
+            Vault::create(dir)?
+        };
+
+        let mut config: Config = vault.load(CONFIG_FILE)?.unwrap_or_default();
+        let identity = match config.identity_key {
+            Some(ref seed) => Identity::from_seed_hex(seed)?,
+            None => {
+                // First run, create the identity peers will know us by
+                let identity = Identity::generate();
+                config.identity_key = Some(identity.seed_hex());
+                vault.save(CONFIG_FILE, &config)?;
+                println!("Generated new identity {}", identity.fingerprint());
+                identity
+            }
+        };
+        let contacts = vault.load(CONTACTS_FILE)?.unwrap_or_default();
+        let history = vault.load(HISTORY_FILE)?.unwrap_or_default();
+
+        Ok(Self {
+            vault: Some(vault),
+            config,
+            identity,
+            contacts,
+            history,
+        })
+    }
+
+    // Whether changes are written to disk
+    pub fn is_persistent(&self) -> bool {
+        self.vault.is_some()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // Update the configuration and persist it
+    pub fn update_config(&mut self, update: impl FnOnce(&mut Config)) -> Result<()> {
+        update(&mut self.config);
+        if let Some(ref vault) = self.vault {
+            vault.save(CONFIG_FILE, &self.config)?;
+        }
+        Ok(())
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    pub fn contacts(&self) -> &[Contact] {
+        &self.contacts
+    }
+
+    // Find a contact by name
+    pub fn contact(&self, name: &str) -> Option<&Contact> {
+        self.contacts.iter().find(|c| c.name == name)
+    }
+
+    // Find a contact by identity key
+    pub fn contact_by_identity(&self, identity: &str) -> Option<&Contact> {
+        self.contacts.iter().find(|c| c.identity == identity)
+    }
+
+    // Modify the contact list and persist it
+    pub fn update_contacts<T>(&mut self, update: impl FnOnce(&mut Vec<Contact>) -> T) -> Result<T> {
+        let result = update(&mut self.contacts);
+        if let Some(ref vault) = self.vault {
+            vault.save(CONTACTS_FILE, &self.contacts)?;
+        }
+        Ok(result)
+    }
+
+    pub fn history(&self) -> &[HistoryEntry] {
+        &self.history
+    }
+
+    // Append a message to the history and persist it
+    pub fn record(
+        &mut self,
+        conversation: &Conversation,
+        direction: Direction,
+        peer: &str,
+        id: &str,
+        text: &str,
+        reply_to: Option<&str>,
+    ) -> Result<()> {
+        self.history.push(HistoryEntry {
+            timestamp: chrono::Local::now(),
+            direction,
+            peer: peer.to_string(),
+            peer_identity: conversation.peer.clone(),
+            session: conversation.session.clone(),
+            id: id.to_string(),
+            text: text.to_string(),
+            revisions: Vec::new(),
+            deleted_at: None,
+            reactions: Vec::new(),
+            reply_to: reply_to.map(|id| id.to_string()),
+        });
+
+        let limit = self.config.history_limit;
+        if self.history.len() > limit {
+            let excess = self.history.len() - limit;
+            self.history.drain(..excess);
+        }
+
+        if let Some(ref vault) = self.vault {
+            vault.save(HISTORY_FILE, &self.history)?;
+        }
+        Ok(())
+    }
+
+    // Most recent message of the conversation with this id
+    pub fn message(&self, conversation: &Conversation, id: &str) -> Option<&HistoryEntry> {
+        self.history
+            .iter()
+            .rev()
+            .find(|e| e.id == id && e.belongs_to(conversation))
+    }
+
+    // The conversation's messages by id, the latest one for repeated ids
+    fn messages_by_id(&self, conversation: &Conversation) -> HashMap<&str, &HistoryEntry> {
+        self.history
+            .iter()
+            .filter(|e| !e.id.is_empty() && e.belongs_to(conversation))
+            .map(|e| (e.id.as_str(), e))
+            .collect()
+    }
+
+    // Id of the message that started the thread containing this message
+    pub fn thread_root<'a>(&'a self, conversation: &Conversation, id: &'a str) -> &'a str {
+        let messages = self.messages_by_id(conversation);
+        let mut root = id;
+        // Bounded walk, in case of a reply cycle from a misbehaving peer
+        for _ in 0..messages.len() {
+            match messages.get(root).and_then(|e| e.reply_to.as_deref()) {
+                Some(parent) if messages.contains_key(parent) => root = parent,
+                _ => break,
+            }
+        }
+        root
+    }
+
+    // The thread starting at root, each message after the one it replies to
+    // with its depth below the root
+    pub fn thread(&self, conversation: &Conversation, root: &str) -> Vec<(usize, &HistoryEntry)> {
+        let messages = self.messages_by_id(conversation);
+        let mut children: HashMap<&str, Vec<&HistoryEntry>> = HashMap::new();
+        for entry in self.history.iter().filter(|e| e.belongs_to(conversation)) {
+            if let Some(ref parent) = entry.reply_to {
+                children.entry(parent.as_str()).or_default().push(entry);
+            }
+        }
+
+        let mut thread = Vec::new();
+        let mut seen = HashSet::new();
+        let mut stack: Vec<(usize, &HistoryEntry)> =
+            messages.get(root).map(|&e| (0, e)).into_iter().collect();
+        while let Some((depth, entry)) = stack.pop() {
+            // A reply cycle would otherwise go round forever
+            if !seen.insert(entry.id.as_str()) {
+                continue;
+            }
+            thread.push((depth, entry));
+            if let Some(replies) = children.get(entry.id.as_str()) {
+                stack.extend(replies.iter().rev().map(|&reply| (depth + 1, reply)));
+            }
+        }
+        thread
+    }
+
+    // Record an edit to a message of the conversation from the given side.
+    // Returns false if there is no such message, or it was deleted.
+    pub fn edit_message(
+        &mut self,
+        conversation: &Conversation,
+        id: &str,
+        direction: Direction,
+        text: &str,
+    ) -> Result<bool> {
+        self.update_message(conversation, id, Some(direction), |entry| {
+            entry.revisions.push(Revision {
+                timestamp: chrono::Local::now(),
+                text: text.to_string(),
+            });
+        })
+    }
+
+    // Mark a message from the given side as deleted, keeping it for the audit trail
+    pub fn delete_message(
+        &mut self,
+        conversation: &Conversation,
+        id: &str,
+        direction: Direction,
+    ) -> Result<bool> {
+        self.update_message(conversation, id, Some(direction), |entry| {
+            entry.deleted_at = Some(chrono::Local::now());
+        })
+    }
+
+    // Add a reaction to a message of the conversation from either side,
+    // ignoring duplicates
+    pub fn react_to_message(
+        &mut self,
+        conversation: &Conversation,
+        id: &str,
+        by: Direction,
+        emoji: &str,
+    ) -> Result<bool> {
+        self.update_message(conversation, id, None, |entry| {
+            let reaction = Reaction {
+                by,
+                emoji: emoji.to_string(),
+            };
+            if !entry.reactions.contains(&reaction) {
+                entry.reactions.push(reaction);
+            }
+        })
+    }
+
+    // Apply a change to a live message and persist the history
+    fn update_message(
+        &mut self,
+        conversation: &Conversation,
+        id: &str,
+        direction: Option<Direction>,
+        update: impl FnOnce(&mut HistoryEntry),
+    ) -> Result<bool> {
+        let entry = self.history.iter_mut().rev().find(|e| {
+            e.id == id
+                && e.belongs_to(conversation)
+                && e.deleted_at.is_none()
+                && direction.is_none_or(|d| e.direction == d)
+        });
+        match entry {
+            Some(entry) => update(entry),
+            None => return Ok(false),
+        }
+
+        if let Some(ref vault) = self.vault {
+            vault.save(HISTORY_FILE, &self.history)?;
+        }
+        Ok(true)
+    }
+}
+
+impl Vault {
+    // Set up a new vault, asking the user to choose a passphrase
+    fn create(dir: PathBuf) -> Result<Self> {
+        println!("Creating encrypted store in {}", dir.display());
+        let passphrase = match std::env::var(PASSPHRASE_ENV) {
+            Ok(passphrase) => passphrase,
+            Err(_) => loop {
+                let first = rpassword::prompt_password("Choose a passphrase: ")?;
+                let second = rpassword::prompt_password("Repeat passphrase: ")?;
+                if first.is_empty() {
+                    println!("Passphrase cannot be empty (use --no-persist to skip storage)");
+                } else if first != second {
+                    println!("Passphrases do not match, try again");
+                } else {
+                    break first;
+                }
+            },
+        };
+
+        let mut salt = [0u8; 16];
+        rand::rng().fill_bytes(&mut salt);
+        let params = Params::default();
+        let cipher = derive_cipher(
+            &passphrase,
+            &salt,
+            params.m_cost(),
+            params.t_cost(),
+            params.p_cost(),
+        )?;
+
+        let header = VaultHeader {
+            version: 1,
+            salt: hex::encode(salt),
+            m_cost: params.m_cost(),
+            t_cost: params.t_cost(),
+            p_cost: params.p_cost(),
+            check: seal(&cipher, VAULT_FILE, CHECK_PLAINTEXT)?,
+        };
+        write_atomic(
+            &dir.join(VAULT_FILE),
+            serde_json::to_string_pretty(&header)?.as_bytes(),
+        )?;
+
+        info!("Created encrypted store in {}", dir.display());
+        Ok(Self { dir, cipher })
+    }
+
+    // Unlock an existing vault with the user's passphrase
+    fn unlock(dir: PathBuf) -> Result<Self> {
+        let raw = fs::read_to_string(dir.join(VAULT_FILE)).context("Failed to read vault")?;
+        let header: VaultHeader = serde_json::from_str(&raw).context("Corrupt vault header")?;
+        let salt = hex::decode(&header.salt).context("Corrupt vault salt")?;
+
+        let from_env = std::env::var(PASSPHRASE_ENV).ok();
+        let attempts = if from_env.is_some() {
+            1
+        } else {
+            UNLOCK_ATTEMPTS
+        };
+
+        for _ in 0..attempts {
+            let passphrase = match from_env {
+                Some(ref passphrase) => passphrase.clone(),
+                None => rpassword::prompt_password("Passphrase to unlock store: ")?,
+            };
+
+            let cipher = derive_cipher(
+                &passphrase,
+                &salt,
+                header.m_cost,
+                header.t_cost,
+                header.p_cost,
+            )?;
+            if open(&cipher, VAULT_FILE, &header.check).is_ok() {
+                debug!("Unlocked store in {}", dir.display());
+                return Ok(Self { dir, cipher });
+            }
+            println!("Incorrect passphrase");
+        }
+
+        Err(anyhow::anyhow!(
+            "Could not unlock the store in {} (use --no-persist to run without it)",
+            dir.display()
+        ))
+    }
+
+    // Load and decrypt a file, returning None if it has not been written yet
+    fn load<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>> {
+        let path = self.dir.join(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let raw = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let sealed: Sealed = serde_json::from_str(&raw)
+            .with_context(|| format!("Corrupt encrypted file {}", path.display()))?;
+        let plaintext = open(&self.cipher, name, &sealed)?;
+        let value = serde_json::from_slice(&plaintext)
+            .with_context(|| format!("Corrupt contents in {}", path.display()))?;
+        Ok(Some(value))
+    }
+
+    // Encrypt and write a file
+    fn save<T: Serialize>(&self, name: &str, value: &T) -> Result<()> {
+        let plaintext = serde_json::to_vec(value)?;
+        let sealed = seal(&self.cipher, name, &plaintext)?;
+        write_atomic(
+            &self.dir.join(name),
+            serde_json::to_string(&sealed)?.as_bytes(),
+        )
+    }
+}
+
+// Default location of the data directory
+fn default_data_dir() -> Result<PathBuf> {
+    dirs::data_local_dir()
+        .map(|dir| dir.join("modulate-comms"))
+        .context("Could not determine a data directory, pass --data-dir")
+}
+
+// Derive the store key from the passphrase with Argon2id
+fn derive_cipher(
+    passphrase: &str,
+    salt: &[u8],
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+) -> Result<XChaCha20Poly1305> {
+    let params = Params::new(m_cost, t_cost, p_cost, Some(32))
+        .map_err(|e| anyhow::anyhow!("Invalid key derivation parameters: {}", e))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Key derivation failed: {}", e))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+// Encrypt data, binding it to the file name so files can't be swapped
+fn seal(cipher: &XChaCha20Poly1305, name: &str, plaintext: &[u8]) -> Result<Sealed> {
+    let mut nonce = [0u8; 24];
+    rand::rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: name.as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt {}", name))?;
+
+    Ok(Sealed {
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    })
+}
+
+// Decrypt data sealed for the given file name
+fn open(cipher: &XChaCha20Poly1305, name: &str, sealed: &Sealed) -> Result<Vec<u8>> {
+    let nonce = hex::decode(&sealed.nonce).context("Corrupt nonce")?;
+    if nonce.len() != 24 {
+        return Err(anyhow::anyhow!("Corrupt nonce in {}", name));
+    }
+    let ciphertext = hex::decode(&sealed.ciphertext).context("Corrupt ciphertext")?;
+    cipher
+        .decrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: &ciphertext,
+                aad: name.as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Failed to decrypt {}", name))
+}
+
+// Keep the data directory private to the current user
+#[cfg(unix)]
+fn restrict_permissions(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+        .with_context(|| format!("Failed to set permissions on {}", dir.display()))
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+// Write through a temporary file so a crash never leaves a half-written file
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
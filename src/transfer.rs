@@ -0,0 +1,327 @@
+use crate::protocol::{self, WireMessage};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use webrtc::data_channel::RTCDataChannel;
+
+// File data in each binary frame, well under what every SCTP stack accepts
+const CHUNK_LEN: usize = 16 * 1024;
+
+// Stop queueing frames while this much is still waiting to go out, so a big
+// file doesn't end up in memory
+const MAX_BUFFERED: usize = 1024 * 1024;
+
+// Frames start with the transfer id, a message id as made by new_message_id
+const ID_LEN: usize = 16;
+
+// Files one peer can be offering or sending at once
+const MAX_INCOMING: usize = 16;
+
+// Largest file the daemon takes unless told otherwise, in MiB
+pub const DEFAULT_MAX_FILE_MIB: u64 = 1024;
+
+// A file to send over the messaging channel: a FileStart message offers it,
+// and once the peer accepts the contents go in binary frames tagged with the
+// transfer id, then a FileEnd with the hash
+pub struct OutgoingFile {
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    path: PathBuf,
+    file: File,
+}
+
+impl OutgoingFile {
+    pub async fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .await
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let size = file.metadata().await?.len();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("{} has no usable file name", path.display()))?
+            .to_string();
+        Ok(Self {
+            id: protocol::new_message_id(),
+            name,
+            size,
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    // Ask the peer whether it wants the file
+    pub async fn offer(&self, dc: &RTCDataChannel) -> Result<()> {
+        WireMessage::FileStart {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            size: self.size,
+        }
+        .send(dc)
+        .await
+    }
+
+    // Send the contents the peer accepted, returning the number of bytes sent
+    pub async fn send(mut self, dc: &RTCDataChannel) -> Result<u64> {
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; CHUNK_LEN];
+        let mut sent = 0u64;
+        loop {
+            let n = self.file.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            while dc.buffered_amount().await > MAX_BUFFERED {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            let mut frame = Vec::with_capacity(ID_LEN + n);
+            frame.extend_from_slice(self.id.as_bytes());
+            frame.extend_from_slice(&buffer[..n]);
+            dc.send(&Bytes::from(frame))
+                .await
+                .context("Failed to send file data")?;
+            hasher.update(&buffer[..n]);
+            sent += n as u64;
+        }
+
+        // Always finish, so the peer drops the transfer even if it went wrong
+        WireMessage::FileEnd {
+            id: self.id.clone(),
+            sha256: hex::encode(hasher.finalize()),
+        }
+        .send(dc)
+        .await?;
+        if sent != self.size {
+            bail!("{} changed while it was being sent", self.path.display());
+        }
+        Ok(sent)
+    }
+}
+
+// A file received in full and checked against its hash
+#[derive(Debug, Clone)]
+pub struct ReceivedFile {
+    pub id: String,
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+struct Partial {
+    name: String,
+    path: PathBuf,
+    file: File,
+    size: u64,
+    received: u64,
+    hasher: Sha256,
+}
+
+// A file the peer offered that hasn't been accepted or declined yet
+#[derive(Debug, Clone)]
+pub struct OfferedFile {
+    pub name: String,
+    pub size: u64,
+}
+
+// Files the peer is sending. Nothing is written until a file is accepted,
+// then it goes to a hidden file in `dir` as it arrives and is given its real
+// name once complete.
+pub struct IncomingFiles {
+    dir: PathBuf,
+    max_size: u64,
+    offered: HashMap<String, OfferedFile>,
+    partial: HashMap<String, Partial>,
+}
+
+impl IncomingFiles {
+    // Files larger than `max_size` bytes are refused when offered
+    pub fn new(dir: PathBuf, max_size: u64) -> Self {
+        Self {
+            dir,
+            max_size,
+            offered: HashMap::new(),
+            partial: HashMap::new(),
+        }
+    }
+
+    // The peer offered a file, which waits for accept or decline
+    pub fn offer(&mut self, id: &str, name: &str, size: u64) -> Result<OfferedFile> {
+        if id.len() != ID_LEN || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("Invalid transfer id {:?}", id);
+        }
+        if self.offered.contains_key(id) || self.partial.contains_key(id) {
+            bail!("Transfer {} was started twice", id);
+        }
+        if self.offered.len() + self.partial.len() >= MAX_INCOMING {
+            bail!("Peer is sending more than {} files at once", MAX_INCOMING);
+        }
+        if size > self.max_size {
+            bail!(
+                "{} bytes is more than the {} bytes accepted",
+                size,
+                self.max_size
+            );
+        }
+        let offered = OfferedFile {
+            name: safe_name(name)?,
+            size,
+        };
+        self.offered.insert(id.to_string(), offered.clone());
+        Ok(offered)
+    }
+
+    // Turn down an offered file
+    pub fn decline(&mut self, id: &str) -> Result<OfferedFile> {
+        self.offered
+            .remove(id)
+            .with_context(|| format!("No file {} waiting to be accepted", id))
+    }
+
+    // Take an offered file, ready for its data
+    pub async fn accept(&mut self, id: &str) -> Result<OfferedFile> {
+        let offered = self.decline(id)?;
+        let name = offered.name.clone();
+        let size = offered.size;
+
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let path = self.dir.join(format!(".{}.{}.part", name, id));
+        let file = File::create(&path)
+            .await
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+
+        self.partial.insert(
+            id.to_string(),
+            Partial {
+                name,
+                path,
+                file,
+                size,
+                received: 0,
+                hasher: Sha256::new(),
+            },
+        );
+        Ok(offered)
+    }
+
+    // A binary frame of file data arrived
+    pub async fn chunk(&mut self, frame: &[u8]) -> Result<()> {
+        if frame.len() < ID_LEN {
+            bail!("File data frame is too short");
+        }
+        let (id, data) = frame.split_at(ID_LEN);
+        let id = String::from_utf8_lossy(id).to_string();
+        let Some(partial) = self.partial.get_mut(&id) else {
+            bail!("Data for unknown file transfer {}", id);
+        };
+
+        if partial.received + data.len() as u64 > partial.size {
+            let size = partial.size;
+            self.abandon(&id).await;
+            bail!(
+                "Transfer {} sent more than the {} bytes it announced",
+                id,
+                size
+            );
+        }
+        if let Err(e) = partial.file.write_all(data).await {
+            self.abandon(&id).await;
+            return Err(e).context("Failed to write received file");
+        }
+        partial.hasher.update(data);
+        partial.received += data.len() as u64;
+        Ok(())
+    }
+
+    // The peer finished sending, so check the file and move it into place
+    pub async fn finish(&mut self, id: &str, sha256: &str) -> Result<ReceivedFile> {
+        let Some(mut partial) = self.partial.remove(id) else {
+            bail!("No transfer {} in progress", id);
+        };
+        let complete = partial.received == partial.size;
+        let hash = hex::encode(std::mem::take(&mut partial.hasher).finalize());
+        let flushed = partial.file.flush().await;
+        drop(partial.file);
+
+        if !complete || !hash.eq_ignore_ascii_case(sha256) || flushed.is_err() {
+            let _ = tokio::fs::remove_file(&partial.path).await;
+            if !complete {
+                bail!(
+                    "{} ended after {} of {} bytes",
+                    partial.name,
+                    partial.received,
+                    partial.size
+                );
+            }
+            flushed.context("Failed to write received file")?;
+            bail!("{} arrived damaged, its hash doesn't match", partial.name);
+        }
+
+        let path = unused_path(&self.dir, &partial.name);
+        tokio::fs::rename(&partial.path, &path)
+            .await
+            .with_context(|| format!("Failed to save {}", path.display()))?;
+        Ok(ReceivedFile {
+            id: id.to_string(),
+            name: partial.name,
+            path,
+            size: partial.size,
+        })
+    }
+
+    async fn abandon(&mut self, id: &str) {
+        if let Some(partial) = self.partial.remove(id) {
+            drop(partial.file);
+            let _ = tokio::fs::remove_file(&partial.path).await;
+        }
+    }
+}
+
+// Don't leave half-received files behind when the session ends
+impl Drop for IncomingFiles {
+    fn drop(&mut self) {
+        for partial in self.partial.values() {
+            let _ = std::fs::remove_file(&partial.path);
+        }
+    }
+}
+
+// The last component of a name the peer gave, so it can't point elsewhere
+fn safe_name(name: &str) -> Result<String> {
+    let base = Path::new(name)
+        .file_name()
+        .and_then(|base| base.to_str())
+        .unwrap_or("");
+    let base: String = base.chars().filter(|c| !c.is_control()).collect();
+    let base = base.trim();
+    if base.is_empty() || base.starts_with('.') {
+        bail!("Refusing a file named {:?}", name);
+    }
+    Ok(base.to_string())
+}
+
+// `dir/name`, or `dir/name (1)` and so on if that's taken
+fn unused_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
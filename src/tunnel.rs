@@ -0,0 +1,1094 @@
+use crate::app::{self, SessionOptions};
+use crate::connection::{self, ChannelSpec, IncomingMessages, ReliabilityProfile};
+use crate::pipe;
+use crate::protocol::{TunnelError, TunnelProtocol, WireMessage};
+use crate::socks;
+use crate::stats;
+use crate::store::SharedStore;
+
+use anyhow::{bail, Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use log::{debug, info, warn};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::RTCPeerConnection;
+
+// Stream ids for tunnelled connections start here, well clear of the ids the
+// session's own channels are given during setup
+const FIRST_STREAM_ID: u16 = 1024;
+
+// Label of every tunnelled connection's channel
+pub const TUNNEL_CHANNEL: &str = "tunnel";
+
+// How long the exit side tries to reach a target
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long the entry side waits to hear whether the peer connected
+const OPEN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How often to check the peer is still there while nothing is arriving
+const STATE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Bytes of flow id in front of each datagram on a UDP tunnel's channel
+const FLOW_HEADER_LEN: usize = 4;
+
+// Largest datagram read from a UDP socket
+const MAX_DATAGRAM: usize = 65535;
+
+// A UDP flow with nothing going either way for this long is forgotten, as a
+// NAT would
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Drop datagrams instead of queueing them once this much is waiting to go out
+const MAX_UDP_BUFFERED: usize = 256 * 1024;
+
+// What this side of a tunnel does
+pub enum TunnelRole {
+    // Accept connections here and have the peer connect where `forward` says
+    Entry {
+        local: SocketAddr,
+        forward: Forward,
+    },
+    // Carry datagrams sent here to `remote` on the peer's side, on a channel
+    // with this profile
+    UdpEntry {
+        local: SocketAddr,
+        remote: String,
+        profile: ReliabilityProfile,
+    },
+    // Connect to the targets the peer asks for, if they're allowed
+    Exit {
+        allow: Allowlist,
+    },
+}
+
+// Where the entry side has the peer connect each accepted connection to
+#[derive(Debug, Clone)]
+pub enum Forward {
+    // The same host:port for every connection
+    To(String),
+    // Wherever each SOCKS5 client asks for
+    Socks,
+}
+
+// Connect to the peer, then carry connections through it until the session
+// ends
+pub async fn run_tunnel(
+    options: SessionOptions,
+    store: SharedStore,
+    is_offerer: bool,
+    role: TunnelRole,
+) -> Result<()> {
+    // Bind first, so a port that's taken fails before the peer is involved
+    let listener = match role {
+        TunnelRole::Entry { local, .. } => Some(
+            TcpListener::bind(local)
+                .await
+                .with_context(|| format!("Failed to listen on {}", local))?,
+        ),
+        TunnelRole::UdpEntry { .. } | TunnelRole::Exit { .. } => None,
+    };
+    let udp_socket = match role {
+        TunnelRole::UdpEntry { local, .. } => Some(
+            UdpSocket::bind(local)
+                .await
+                .with_context(|| format!("Failed to bind UDP {}", local))?,
+        ),
+        TunnelRole::Entry { .. } | TunnelRole::Exit { .. } => None,
+    };
+
+    let mut signaler = app::session_signaler(&options, is_offerer)?;
+    let app::Session {
+        pc, dc, incoming, ..
+    } = app::open_session(&options, &store, signaler.as_mut(), is_offerer).await?;
+    // Tunnelled connections are counted in the transport totals
+    let reporter = options.stats_interval.map(|interval| {
+        stats::spawn_reporter(
+            Arc::clone(&pc),
+            vec![Arc::clone(&dc)],
+            None,
+            interval,
+            |line| println!("{}", line),
+        )
+    });
+
+    let result = match (role, listener, udp_socket) {
+        (TunnelRole::Entry { local, forward }, Some(listener), _) => {
+            match forward {
+                Forward::To(ref remote) => println!(
+                    "Forwarding connections to {} through the peer to {}",
+                    local, remote
+                ),
+                Forward::Socks => println!(
+                    "SOCKS5 proxy on {}, connections are made from the peer's side",
+                    local
+                ),
+            }
+            serve_entry(&pc, &dc, incoming, listener, forward).await
+        }
+        (
+            TunnelRole::UdpEntry {
+                local,
+                remote,
+                profile,
+            },
+            _,
+            Some(socket),
+        ) => {
+            println!(
+                "Forwarding UDP datagrams to {} through the peer to {} ({})",
+                local, remote, profile
+            );
+            serve_udp_entry(&pc, &dc, incoming, socket, remote, profile).await
+        }
+        (TunnelRole::Exit { allow }, _, _) => {
+            println!("Letting the peer connect to {} (Ctrl+C to stop)", allow);
+            serve_exit(&pc, &dc, incoming, allow).await
+        }
+        _ => unreachable!("entry sides are always bound"),
+    };
+    if let Some(reporter) = reporter {
+        reporter.abort();
+    }
+    let _ = pc.close().await;
+    result
+}
+
+// Accept connections on `listener` and carry each one to where `forward`
+// says on the peer's side, over a channel of its own, until the peer goes
+// away
+pub async fn serve_entry(
+    pc: &Arc<RTCPeerConnection>,
+    dc: &Arc<RTCDataChannel>,
+    mut incoming: IncomingMessages,
+    listener: TcpListener,
+    forward: Forward,
+) -> Result<()> {
+    let opener = Arc::new(Opener::new(Arc::clone(pc), Arc::clone(dc)));
+    let mut check = tokio::time::interval(STATE_CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, from) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept a connection: {}", e);
+                        continue;
+                    }
+                };
+                let opener = Arc::clone(&opener);
+                let forward = forward.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = forward_connection(&opener, forward, socket, from).await {
+                        debug!("Connection from {}: {:#}", from, e);
+                    }
+                });
+            }
+            msg = incoming.recv() => {
+                let Some(msg) = msg else {
+                    bail!("The connection to the peer closed");
+                };
+                route_answer(&opener, msg);
+            }
+            _ = check.tick() => {
+                if dc.ready_state() != RTCDataChannelState::Open {
+                    println!("The peer closed the session");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// Pass the peer's answer in a message on the messaging channel to whoever is
+// waiting for it
+fn route_answer(opener: &Opener, msg: DataChannelMessage) {
+    if !msg.is_string {
+        return;
+    }
+    match WireMessage::decode(&msg.data) {
+        Ok(WireMessage::TunnelOpened { stream }) => opener.answered(stream, Ok(())),
+        Ok(WireMessage::TunnelFailed {
+            stream,
+            error,
+            reason,
+        }) => opener.answered(stream, Err(TunnelFailure { error, reason })),
+        Ok(WireMessage::Nick { .. }) => {}
+        Ok(other) => warn!("Ignoring unexpected message from peer: {:?}", other),
+        Err(e) => warn!("{}", e),
+    }
+}
+
+// Have the peer connect one accepted connection to its target, then splice
+async fn forward_connection(
+    opener: &Opener,
+    forward: Forward,
+    mut socket: TcpStream,
+    from: SocketAddr,
+) -> Result<()> {
+    let target = match forward {
+        Forward::To(ref remote) => remote.clone(),
+        Forward::Socks => socks::accept(&mut socket).await?,
+    };
+    info!("Connection from {} to {}", from, target);
+
+    let opened = opener
+        .open(
+            &target,
+            TunnelProtocol::Tcp,
+            ReliabilityProfile::ReliableOrdered,
+        )
+        .await;
+    match opened {
+        Ok((stream, channel, incoming)) => {
+            if let Forward::Socks = forward {
+                socks::reply(&mut socket, socks::SUCCEEDED).await?;
+            }
+            splice(stream, socket, channel, incoming).await;
+        }
+        Err(failure) => {
+            println!("Couldn't forward the connection from {}: {}", from, failure);
+            if let Forward::Socks = forward {
+                socks::reply(&mut socket, socks::reply_code(failure.error)).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Why a tunnelled connection couldn't be opened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelFailure {
+    pub error: TunnelError,
+    pub reason: String,
+}
+
+impl TunnelFailure {
+    fn new(error: TunnelError, reason: impl Into<String>) -> Self {
+        Self {
+            error,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for TunnelFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+type OpenResult = std::result::Result<(), TunnelFailure>;
+
+// Opens tunnelled connections on the entry side, matching the peer's answers
+// to the connections waiting for them
+pub struct Opener {
+    pc: Arc<RTCPeerConnection>,
+    dc: Arc<RTCDataChannel>,
+    next_stream: Mutex<u16>,
+    pending: Mutex<HashMap<u16, oneshot::Sender<OpenResult>>>,
+}
+
+impl Opener {
+    pub fn new(pc: Arc<RTCPeerConnection>, dc: Arc<RTCDataChannel>) -> Self {
+        Self {
+            pc,
+            dc,
+            next_stream: Mutex::new(FIRST_STREAM_ID),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Ask the peer to connect to `target`, returning the stream's channel
+    // once it has
+    pub async fn open(
+        &self,
+        target: &str,
+        protocol: TunnelProtocol,
+        profile: ReliabilityProfile,
+    ) -> std::result::Result<(u16, Arc<RTCDataChannel>, IncomingMessages), TunnelFailure> {
+        let stream = {
+            let mut next = self.next_stream.lock().unwrap();
+            let stream = *next;
+            *next = next.checked_add(1).ok_or_else(|| {
+                TunnelFailure::new(
+                    TunnelError::Failed,
+                    "out of stream ids for this session, reconnect to carry on",
+                )
+            })?;
+            stream
+        };
+
+        let spec = ChannelSpec::new(TUNNEL_CHANNEL, profile);
+        let (channel, incoming) = connection::open_negotiated_channel(&self.pc, &spec, stream)
+            .await
+            .map_err(|e| TunnelFailure::new(TunnelError::Failed, format!("{:#}", e)))?;
+        let (opened_tx, opened_rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(stream, opened_tx);
+
+        let request = WireMessage::TunnelOpen {
+            stream,
+            target: target.to_string(),
+            protocol,
+            profile,
+        };
+        let opened = match request.send(&self.dc).await {
+            Ok(()) => match tokio::time::timeout(OPEN_TIMEOUT, opened_rx).await {
+                Ok(Ok(opened)) => opened,
+                Ok(Err(_)) => Err(TunnelFailure::new(TunnelError::Failed, "the session ended")),
+                Err(_) => Err(TunnelFailure::new(
+                    TunnelError::TimedOut,
+                    "the peer didn't answer",
+                )),
+            },
+            Err(e) => Err(TunnelFailure::new(TunnelError::Failed, format!("{:#}", e))),
+        };
+        self.pending.lock().unwrap().remove(&stream);
+
+        match opened {
+            Ok(()) => Ok((stream, channel, incoming)),
+            Err(failure) => {
+                let _ = channel.close().await;
+                Err(failure)
+            }
+        }
+    }
+
+    // The peer's answer to one of our requests
+    pub fn answered(&self, stream: u16, result: OpenResult) {
+        match self.pending.lock().unwrap().remove(&stream) {
+            Some(opened) => {
+                let _ = opened.send(result);
+            }
+            None => debug!("Answer for stream {}, which isn't waiting", stream),
+        }
+    }
+}
+
+// Connect to the targets the peer asks for, as long as `allow` lets it,
+// until the peer goes away
+pub async fn serve_exit(
+    pc: &Arc<RTCPeerConnection>,
+    dc: &Arc<RTCDataChannel>,
+    mut incoming: IncomingMessages,
+    allow: Allowlist,
+) -> Result<()> {
+    let allow = Arc::new(allow);
+    // Streams with a connection open or being opened
+    let streams = Arc::new(Mutex::new(HashSet::new()));
+    let mut check = tokio::time::interval(STATE_CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            msg = incoming.recv() => {
+                let Some(msg) = msg else {
+                    bail!("The connection to the peer closed");
+                };
+                if !msg.is_string {
+                    continue;
+                }
+                match WireMessage::decode(&msg.data) {
+                    Ok(WireMessage::TunnelOpen { stream, target, protocol, profile }) => {
+                        // Ids below FIRST_STREAM_ID belong to the session's own
+                        // channels, and a second channel can't share an id
+                        if stream < FIRST_STREAM_ID || !streams.lock().unwrap().insert(stream) {
+                            let failure = TunnelFailure::new(
+                                TunnelError::Failed,
+                                format!("stream id {} isn't free", stream),
+                            );
+                            if let Err(e) = refuse(dc, stream, &target, failure).await {
+                                warn!("Connection {} to {}: {:#}", stream, target, e);
+                            }
+                            continue;
+                        }
+                        let pc = Arc::clone(pc);
+                        let dc = Arc::clone(dc);
+                        let allow = Arc::clone(&allow);
+                        let streams = Arc::clone(&streams);
+                        tokio::spawn(async move {
+                            let result = match protocol {
+                                TunnelProtocol::Tcp => {
+                                    open_target(&pc, &dc, &allow, stream, &target).await
+                                }
+                                TunnelProtocol::Udp => {
+                                    open_udp_target(&pc, &dc, &allow, stream, &target, profile)
+                                        .await
+                                }
+                            };
+                            if let Err(e) = result {
+                                warn!("Connection {} to {}: {:#}", stream, target, e);
+                            }
+                            streams.lock().unwrap().remove(&stream);
+                        });
+                    }
+                    Ok(WireMessage::Nick { .. }) => {}
+                    Ok(other) => warn!("Ignoring unexpected message from peer: {:?}", other),
+                    Err(e) => warn!("{}", e),
+                }
+            }
+            _ = check.tick() => {
+                if dc.ready_state() != RTCDataChannelState::Open {
+                    println!("The peer closed the session");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// Connect to `target` for the peer's stream if it's allowed and splice the
+// two together, telling the peer whether it worked
+async fn open_target(
+    pc: &RTCPeerConnection,
+    dc: &RTCDataChannel,
+    allow: &Allowlist,
+    stream: u16,
+    target: &str,
+) -> Result<()> {
+    let (channel, channel_incoming) = match open_stream(pc, stream, Default::default()).await {
+        Ok(opened) => opened,
+        Err(failure) => return refuse(dc, stream, target, failure).await,
+    };
+    let socket = match connect_allowed(allow, target).await {
+        Ok(socket) => socket,
+        Err(failure) => {
+            let _ = channel.close().await;
+            return refuse(dc, stream, target, failure).await;
+        }
+    };
+    confirm(dc, &channel, stream, target).await?;
+    println!("Connection {} to {}", stream, target);
+
+    splice(stream, socket, channel, channel_incoming).await;
+    Ok(())
+}
+
+// Our end of the peer's stream. It has to exist before the peer hears it can
+// send, and is opened before the target is touched so a clash fails early.
+async fn open_stream(
+    pc: &RTCPeerConnection,
+    stream: u16,
+    profile: ReliabilityProfile,
+) -> std::result::Result<(Arc<RTCDataChannel>, IncomingMessages), TunnelFailure> {
+    let spec = ChannelSpec::new(TUNNEL_CHANNEL, profile);
+    connection::open_negotiated_channel(pc, &spec, stream)
+        .await
+        .map_err(|e| TunnelFailure::new(TunnelError::Failed, format!("{:#}", e)))
+}
+
+// Tell the peer its stream is open. If that fails, close our end and still
+// try to tell it the stream failed, rather than leave it waiting.
+async fn confirm(
+    dc: &RTCDataChannel,
+    channel: &RTCDataChannel,
+    stream: u16,
+    target: &str,
+) -> Result<()> {
+    let opened = WireMessage::TunnelOpened { stream };
+    if let Err(e) = opened.send(dc).await {
+        let _ = channel.close().await;
+        let failure = TunnelFailure::new(TunnelError::Failed, format!("{:#}", e));
+        let _ = refuse(dc, stream, target, failure).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Tell the peer we didn't open its stream
+async fn refuse(
+    dc: &RTCDataChannel,
+    stream: u16,
+    target: &str,
+    failure: TunnelFailure,
+) -> Result<()> {
+    if failure.error == TunnelError::NotAllowed {
+        println!("Refused a connection to {}, it isn't allowed", target);
+    }
+    WireMessage::TunnelFailed {
+        stream,
+        error: failure.error,
+        reason: failure.reason,
+    }
+    .send(dc)
+    .await
+}
+
+// Resolve `target` and connect to the first of its addresses the allowlist
+// lets us reach
+async fn connect_allowed(
+    allow: &Allowlist,
+    target: &str,
+) -> std::result::Result<TcpStream, TunnelFailure> {
+    let addrs = resolve_allowed(allow, target).await?;
+    match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&addrs[..])).await {
+        Ok(Ok(socket)) => Ok(socket),
+        Ok(Err(e)) => {
+            let error = match e.kind() {
+                io::ErrorKind::ConnectionRefused => TunnelError::Refused,
+                io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => {
+                    TunnelError::Unreachable
+                }
+                _ => TunnelError::Failed,
+            };
+            Err(TunnelFailure::new(
+                error,
+                format!("couldn't connect to {}: {}", target, e),
+            ))
+        }
+        Err(_) => Err(TunnelFailure::new(
+            TunnelError::TimedOut,
+            format!("connecting to {} timed out", target),
+        )),
+    }
+}
+
+// The addresses of `target` the allowlist lets us reach, if there are any
+async fn resolve_allowed(
+    allow: &Allowlist,
+    target: &str,
+) -> std::result::Result<Vec<SocketAddr>, TunnelFailure> {
+    let not_allowed = || {
+        TunnelFailure::new(
+            TunnelError::NotAllowed,
+            format!("{} isn't allowed by the peer", target),
+        )
+    };
+    let (host, port) = split_host_port(target)
+        .ok_or_else(|| TunnelFailure::new(TunnelError::Failed, format!("bad target {}", target)))?;
+    let by_name = allow.allows_name(host, port);
+    if !by_name && !allow.has_address_rules(port) {
+        return Err(not_allowed());
+    }
+
+    let resolved =
+        match tokio::time::timeout(CONNECT_TIMEOUT, tokio::net::lookup_host(target)).await {
+            Ok(Ok(addrs)) => addrs.collect::<Vec<_>>(),
+            Ok(Err(e)) => {
+                return Err(TunnelFailure::new(
+                    TunnelError::Unreachable,
+                    format!("couldn't resolve {}: {}", host, e),
+                ))
+            }
+            Err(_) => {
+                return Err(TunnelFailure::new(
+                    TunnelError::TimedOut,
+                    format!("resolving {} timed out", host),
+                ))
+            }
+        };
+    // Names are checked before resolving, addresses after, so a name can't
+    // be pointed at an address that isn't allowed
+    let addrs: Vec<SocketAddr> = resolved
+        .into_iter()
+        .filter(|addr| by_name || allow.allows_address(addr))
+        .collect();
+    if addrs.is_empty() {
+        return Err(not_allowed());
+    }
+    Ok(addrs)
+}
+
+// `host:port`, with the brackets taken off an IPv6 host
+fn split_host_port(s: &str) -> Option<(&str, &str)> {
+    let (host, port) = s.rsplit_once(':')?;
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.strip_suffix(']')?,
+        None => host,
+    };
+    Some((host, port))
+}
+
+// Hosts an allowlist rule matches
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostPattern {
+    Any,
+    // A name, matched without regard to case
+    Name(String),
+    // Any name under this domain, not the domain itself
+    Subdomains(String),
+    // Addresses in a network, a single address being a network of one
+    Network { ip: IpAddr, prefix: u8 },
+}
+
+// One destination the exit side lets the peer reach: `host:port`, where the
+// host is a name, `*.domain`, an address, a network such as 10.0.0.0/8 or
+// [fd00::/8], or `*`, and the port is a number, a range such as 8000-8100,
+// or `*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowRule {
+    host: HostPattern,
+    ports: (u16, u16),
+    text: String,
+}
+
+impl AllowRule {
+    fn allows_port(&self, port: u16) -> bool {
+        self.ports.0 <= port && port <= self.ports.1
+    }
+
+    fn allows_name(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_lowercase();
+        match self.host {
+            HostPattern::Any => true,
+            HostPattern::Name(ref expected) => name == *expected,
+            // The domain is kept with its leading dot, so what's left is a
+            // label of the subdomain
+            HostPattern::Subdomains(ref domain) => name
+                .strip_suffix(domain.as_str())
+                .is_some_and(|rest| !rest.is_empty()),
+            HostPattern::Network { .. } => false,
+        }
+    }
+
+    fn allows_ip(&self, ip: IpAddr) -> bool {
+        match self.host {
+            HostPattern::Any => true,
+            HostPattern::Network {
+                ip: network,
+                prefix,
+            } => match (network, ip) {
+                (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                    let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                    u32::from(network) & mask == u32::from(ip) & mask
+                }
+                (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                    let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                    u128::from(network) & mask == u128::from(ip) & mask
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for AllowRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let text = s.trim();
+        let (host, port) =
+            split_host_port(text).with_context(|| format!("Expected host:port in '{}'", text))?;
+
+        let parse_port = |p: &str| {
+            p.parse::<u16>()
+                .map_err(|_| anyhow::anyhow!("Invalid port '{}' in '{}'", p, text))
+        };
+        let ports = match port {
+            "*" => (1, u16::MAX),
+            _ => match port.split_once('-') {
+                Some((low, high)) => (parse_port(low)?, parse_port(high)?),
+                None => (parse_port(port)?, parse_port(port)?),
+            },
+        };
+        if ports.0 > ports.1 {
+            bail!("Port range '{}' is backwards", port);
+        }
+
+        let host = if host == "*" {
+            HostPattern::Any
+        } else if let Some(domain) = host.strip_prefix("*.") {
+            HostPattern::Subdomains(format!(".{}", domain.to_lowercase()))
+        } else if let Some((ip, prefix)) = host.split_once('/') {
+            let ip: IpAddr = ip
+                .parse()
+                .with_context(|| format!("Invalid network address '{}'", ip))?;
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let prefix = prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .with_context(|| format!("Invalid prefix length '{}'", prefix))?;
+            HostPattern::Network { ip, prefix }
+        } else if let Ok(ip) = host.parse::<IpAddr>() {
+            let prefix = if ip.is_ipv4() { 32 } else { 128 };
+            HostPattern::Network { ip, prefix }
+        } else if !host.is_empty() && !host.contains('*') {
+            HostPattern::Name(host.trim_end_matches('.').to_lowercase())
+        } else {
+            bail!(
+                "Invalid host '{}', wildcards only go at the start as in *.example.com",
+                host
+            );
+        };
+
+        Ok(Self {
+            host,
+            ports,
+            text: text.to_string(),
+        })
+    }
+}
+
+// The destinations the exit side lets the peer reach. Nothing is allowed
+// unless a rule says so.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Allowlist(pub Vec<AllowRule>);
+
+impl Allowlist {
+    // Whether a rule allows `host` by name. Addresses never match here.
+    pub fn allows_name(&self, host: &str, port: &str) -> bool {
+        let Ok(port) = port.parse::<u16>() else {
+            return false;
+        };
+        host.parse::<IpAddr>().is_err()
+            && self
+                .0
+                .iter()
+                .any(|rule| rule.allows_port(port) && rule.allows_name(host))
+    }
+
+    // Whether any rule could allow some address on `port`
+    fn has_address_rules(&self, port: &str) -> bool {
+        let Ok(port) = port.parse::<u16>() else {
+            return false;
+        };
+        self.0.iter().any(|rule| {
+            rule.allows_port(port)
+                && matches!(rule.host, HostPattern::Any | HostPattern::Network { .. })
+        })
+    }
+
+    // Whether a rule allows connecting to `addr`
+    pub fn allows_address(&self, addr: &SocketAddr) -> bool {
+        self.0
+            .iter()
+            .any(|rule| rule.allows_port(addr.port()) && rule.allows_ip(addr.ip()))
+    }
+}
+
+impl fmt::Display for Allowlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rules: Vec<&str> = self.0.iter().map(|rule| rule.text.as_str()).collect();
+        write!(f, "{}", rules.join(", "))
+    }
+}
+
+// Carry a TCP connection over its channel in both directions, then close the
+// channel
+async fn splice(
+    stream: u16,
+    socket: TcpStream,
+    channel: Arc<RTCDataChannel>,
+    incoming: IncomingMessages,
+) {
+    let (reader, writer) = socket.into_split();
+    match pipe::pump(&channel, incoming, reader, writer).await {
+        Ok(totals) => debug!(
+            "Connection {} done: sent {} bytes, received {} bytes",
+            stream, totals.sent, totals.received
+        ),
+        Err(e) => debug!("Connection {} ended: {:#}", stream, e),
+    }
+    let _ = channel.close().await;
+}
+
+// Carry datagrams between local UDP clients and `remote` on the peer's side,
+// all on one channel. Each client is a flow of its own, so the peer gives it
+// a socket of its own and replies go back to the client they're for.
+pub async fn serve_udp_entry(
+    pc: &Arc<RTCPeerConnection>,
+    dc: &Arc<RTCDataChannel>,
+    mut incoming: IncomingMessages,
+    socket: UdpSocket,
+    remote: String,
+    profile: ReliabilityProfile,
+) -> Result<()> {
+    let opener = Arc::new(Opener::new(Arc::clone(pc), Arc::clone(dc)));
+    let mut opening = {
+        let opener = Arc::clone(&opener);
+        tokio::spawn(async move { opener.open(&remote, TunnelProtocol::Udp, profile).await })
+    };
+    let mut tunnel: Option<(Arc<RTCDataChannel>, IncomingMessages)> = None;
+    let mut flows = EntryFlows::default();
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    let mut check = tokio::time::interval(STATE_CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            opened = &mut opening, if tunnel.is_none() => {
+                match opened? {
+                    Ok((_, channel, channel_incoming)) => {
+                        println!("The peer opened the UDP tunnel");
+                        tunnel = Some((channel, channel_incoming));
+                    }
+                    Err(failure) => bail!("Couldn't open the UDP tunnel: {}", failure),
+                }
+            }
+            received = socket.recv_from(&mut buffer) => {
+                let (n, from) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        debug!("Failed to receive a datagram: {}", e);
+                        continue;
+                    }
+                };
+                // Nowhere to send it until the peer has opened the tunnel,
+                // and a UDP client has to cope with loss anyway
+                let Some((ref channel, _)) = tunnel else {
+                    continue;
+                };
+                let flow = flows.flow_for(from);
+                send_datagram(channel, flow, &buffer[..n]).await;
+            }
+            msg = recv_tunnel(&mut tunnel) => {
+                let Some(msg) = msg else {
+                    bail!("The UDP tunnel closed");
+                };
+                let Some((flow, payload)) = parse_udp_frame(&msg.data) else {
+                    debug!("Ignoring a malformed datagram frame");
+                    continue;
+                };
+                match flows.address_of(flow) {
+                    Some(client) => {
+                        if let Err(e) = socket.send_to(payload, client).await {
+                            debug!("Failed to send a datagram to {}: {}", client, e);
+                        }
+                    }
+                    None => debug!("Dropping a datagram for forgotten flow {}", flow),
+                }
+            }
+            msg = incoming.recv() => {
+                let Some(msg) = msg else {
+                    bail!("The connection to the peer closed");
+                };
+                route_answer(&opener, msg);
+            }
+            _ = check.tick() => {
+                if dc.ready_state() != RTCDataChannelState::Open {
+                    println!("The peer closed the session");
+                    return Ok(());
+                }
+                if let Some((ref channel, _)) = tunnel {
+                    if channel.ready_state() != RTCDataChannelState::Open {
+                        bail!("The peer closed the UDP tunnel");
+                    }
+                }
+                flows.expire();
+            }
+        }
+    }
+}
+
+// The next message on the UDP tunnel's channel, waiting forever until there
+// is one
+async fn recv_tunnel(
+    tunnel: &mut Option<(Arc<RTCDataChannel>, IncomingMessages)>,
+) -> Option<DataChannelMessage> {
+    match tunnel {
+        Some((_, incoming)) => incoming.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Local UDP clients and the flow ids their datagrams are tagged with. Ids
+// aren't reused, so a late reply for a forgotten client can't reach another.
+#[derive(Default)]
+struct EntryFlows {
+    next: u32,
+    by_client: HashMap<SocketAddr, u32>,
+    clients: HashMap<u32, (SocketAddr, Instant)>,
+}
+
+impl EntryFlows {
+    fn flow_for(&mut self, client: SocketAddr) -> u32 {
+        let flow = *self.by_client.entry(client).or_insert_with(|| {
+            let flow = self.next;
+            self.next = self.next.wrapping_add(1);
+            debug!("New UDP flow {} from {}", flow, client);
+            flow
+        });
+        self.clients.insert(flow, (client, Instant::now()));
+        flow
+    }
+
+    fn address_of(&mut self, flow: u32) -> Option<SocketAddr> {
+        let (client, last_seen) = self.clients.get_mut(&flow)?;
+        *last_seen = Instant::now();
+        Some(*client)
+    }
+
+    fn expire(&mut self) {
+        let by_client = &mut self.by_client;
+        self.clients.retain(|_, (client, last_seen)| {
+            let live = last_seen.elapsed() < FLOW_IDLE_TIMEOUT;
+            if !live {
+                by_client.remove(client);
+            }
+            live
+        });
+    }
+}
+
+// Reach `target` with UDP for the peer's stream if it's allowed, relaying
+// each of the peer's flows through a socket of its own
+async fn open_udp_target(
+    pc: &RTCPeerConnection,
+    dc: &RTCDataChannel,
+    allow: &Allowlist,
+    stream: u16,
+    target: &str,
+    profile: ReliabilityProfile,
+) -> Result<()> {
+    let (channel, incoming) = match open_stream(pc, stream, profile).await {
+        Ok(opened) => opened,
+        Err(failure) => return refuse(dc, stream, target, failure).await,
+    };
+    let target_addr = match resolve_allowed(allow, target).await {
+        Ok(addrs) => addrs[0],
+        Err(failure) => {
+            let _ = channel.close().await;
+            return refuse(dc, stream, target, failure).await;
+        }
+    };
+    confirm(dc, &channel, stream, target).await?;
+    println!("UDP tunnel {} to {} ({})", stream, target, profile);
+
+    relay_udp_exit(&channel, incoming, target_addr).await;
+    let _ = channel.close().await;
+    Ok(())
+}
+
+// One of the peer's UDP flows on the exit side, with the task passing replies
+// back to the peer
+struct ExitFlow {
+    socket: Arc<UdpSocket>,
+    last_seen: Arc<Mutex<Instant>>,
+    replies: JoinHandle<()>,
+}
+
+impl Drop for ExitFlow {
+    fn drop(&mut self) {
+        self.replies.abort();
+    }
+}
+
+// Send each flow's datagrams to `target` from the flow's own socket until the
+// tunnel's channel closes
+async fn relay_udp_exit(
+    channel: &Arc<RTCDataChannel>,
+    mut incoming: IncomingMessages,
+    target: SocketAddr,
+) {
+    let mut flows: HashMap<u32, ExitFlow> = HashMap::new();
+    let mut check = tokio::time::interval(STATE_CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            msg = incoming.recv() => {
+                let Some(msg) = msg else {
+                    return;
+                };
+                let Some((flow, payload)) = parse_udp_frame(&msg.data) else {
+                    debug!("Ignoring a malformed datagram frame");
+                    continue;
+                };
+                let exit_flow = match flows.entry(flow) {
+                    Entry::Occupied(existing) => existing.into_mut(),
+                    Entry::Vacant(vacant) => match open_exit_flow(channel, flow, target).await {
+                        Ok(opened) => vacant.insert(opened),
+                        Err(e) => {
+                            warn!("UDP flow {} to {}: {:#}", flow, target, e);
+                            continue;
+                        }
+                    },
+                };
+                *exit_flow.last_seen.lock().unwrap() = Instant::now();
+                if let Err(e) = exit_flow.socket.send(payload).await {
+                    debug!("Failed to send a datagram to {}: {}", target, e);
+                }
+            }
+            _ = check.tick() => {
+                if channel.ready_state() != RTCDataChannelState::Open {
+                    return;
+                }
+                flows.retain(|_, flow| {
+                    flow.last_seen.lock().unwrap().elapsed() < FLOW_IDLE_TIMEOUT
+                });
+            }
+        }
+    }
+}
+
+// A socket for a new flow, connected to `target` so only its replies come in
+async fn open_exit_flow(
+    channel: &Arc<RTCDataChannel>,
+    flow: u32,
+    target: SocketAddr,
+) -> Result<ExitFlow> {
+    let any: IpAddr = if target.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(any, 0)).await?;
+    socket.connect(target).await?;
+    let socket = Arc::new(socket);
+    let last_seen = Arc::new(Mutex::new(Instant::now()));
+    debug!("New UDP flow {} to {}", flow, target);
+
+    let replies = {
+        let socket = Arc::clone(&socket);
+        let last_seen = Arc::clone(&last_seen);
+        let channel = Arc::clone(channel);
+        tokio::spawn(async move {
+            let mut buffer = vec![0u8; MAX_DATAGRAM];
+            loop {
+                match socket.recv(&mut buffer).await {
+                    Ok(n) => {
+                        *last_seen.lock().unwrap() = Instant::now();
+                        send_datagram(&channel, flow, &buffer[..n]).await;
+                    }
+                    // ICMP errors for earlier datagrams show up here, the
+                    // socket itself is fine
+                    Err(e) => debug!("UDP flow {}: {}", flow, e),
+                }
+            }
+        })
+    };
+    Ok(ExitFlow {
+        socket,
+        last_seen,
+        replies,
+    })
+}
+
+// Send a datagram of `flow` on the tunnel's channel, dropping it if the
+// channel is backed up as a congested link would
+async fn send_datagram(channel: &RTCDataChannel, flow: u32, payload: &[u8]) {
+    if channel.buffered_amount().await > MAX_UDP_BUFFERED {
+        debug!(
+            "Dropping a datagram of flow {}, the tunnel is backed up",
+            flow
+        );
+        return;
+    }
+    let mut frame = BytesMut::with_capacity(FLOW_HEADER_LEN + payload.len());
+    frame.put_u32(flow);
+    frame.put_slice(payload);
+    if let Err(e) = channel.send(&frame.freeze()).await {
+        debug!("Failed to send a datagram of flow {}: {}", flow, e);
+    }
+}
+
+// The flow id and datagram in a frame from the tunnel's channel
+fn parse_udp_frame(data: &Bytes) -> Option<(u32, &[u8])> {
+    if data.len() < FLOW_HEADER_LEN {
+        return None;
+    }
+    let (header, payload) = data.split_at(FLOW_HEADER_LEN);
+    Some((u32::from_be_bytes(header.try_into().ok()?), payload))
+}
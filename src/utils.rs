@@ -15,7 +15,7 @@ pub fn print_progress(message: &str, progress_counter: usize) -> io::Result<()>
 // Wait for a specified time with a spinner animation
 pub async fn animated_wait(message: &str, duration: Duration) -> io::Result<()> {
     let steps = (duration.as_millis() / 100) as usize;
-    let spinner = vec!["|", "/", "-", "\\"];
+    let spinner = ["|", "/", "-", "\\"];
 
     for i in 0..steps {
         print!("\r{} {} ", message, spinner[i % spinner.len()]);
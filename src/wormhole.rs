@@ -0,0 +1,497 @@
+use crate::rendezvous::{Mailbox, Side};
+use crate::signaling::Signaler;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::fmt;
+
+// Identity both peers bind into the symmetric exchange
+const PAKE_IDENTITY: &[u8] = b"modulate-comms/wormhole";
+
+// Two of these follow the nameplate in a code, so someone who doesn't know
+// the code gets one guess in 65536 before the mailbox is used up
+const WORDS: [&str; 256] = [
+    "acorn",
+    "alpine",
+    "amber",
+    "anchor",
+    "apple",
+    "arcade",
+    "armor",
+    "arrow",
+    "atlas",
+    "attic",
+    "autumn",
+    "avocado",
+    "badge",
+    "bagel",
+    "bamboo",
+    "banana",
+    "banjo",
+    "barn",
+    "barrel",
+    "basket",
+    "beacon",
+    "beetle",
+    "bell",
+    "berry",
+    "bicycle",
+    "bison",
+    "blanket",
+    "blossom",
+    "boat",
+    "bonnet",
+    "bottle",
+    "boulder",
+    "bracket",
+    "breeze",
+    "brick",
+    "bridge",
+    "broom",
+    "bubble",
+    "bucket",
+    "buffalo",
+    "bunny",
+    "butter",
+    "button",
+    "cabin",
+    "cactus",
+    "camel",
+    "candle",
+    "canoe",
+    "canyon",
+    "carpet",
+    "carrot",
+    "castle",
+    "cattle",
+    "cedar",
+    "cello",
+    "cherry",
+    "chess",
+    "chimney",
+    "circus",
+    "clover",
+    "cobra",
+    "coconut",
+    "comet",
+    "compass",
+    "copper",
+    "coral",
+    "cotton",
+    "coyote",
+    "crayon",
+    "cricket",
+    "crossword",
+    "crystal",
+    "cupcake",
+    "curtain",
+    "cushion",
+    "daisy",
+    "denim",
+    "desert",
+    "diamond",
+    "dolphin",
+    "donkey",
+    "dragon",
+    "drum",
+    "eagle",
+    "elephant",
+    "ember",
+    "falcon",
+    "feather",
+    "fern",
+    "fiddle",
+    "finch",
+    "flannel",
+    "flute",
+    "forest",
+    "fossil",
+    "fountain",
+    "fox",
+    "galaxy",
+    "garden",
+    "garlic",
+    "gazelle",
+    "geyser",
+    "ginger",
+    "giraffe",
+    "glacier",
+    "globe",
+    "goblet",
+    "gondola",
+    "gorilla",
+    "granite",
+    "grape",
+    "gravel",
+    "guitar",
+    "hammock",
+    "harbor",
+    "harp",
+    "hazel",
+    "helmet",
+    "heron",
+    "hickory",
+    "honey",
+    "hornet",
+    "igloo",
+    "iris",
+    "island",
+    "ivory",
+    "jacket",
+    "jaguar",
+    "jasmine",
+    "jelly",
+    "jigsaw",
+    "jungle",
+    "kayak",
+    "kettle",
+    "kiwi",
+    "koala",
+    "ladder",
+    "lagoon",
+    "lantern",
+    "lemon",
+    "leopard",
+    "lettuce",
+    "lily",
+    "lizard",
+    "lobster",
+    "locket",
+    "lotus",
+    "magnet",
+    "mango",
+    "maple",
+    "marble",
+    "meadow",
+    "melon",
+    "meteor",
+    "mitten",
+    "monkey",
+    "moose",
+    "mosaic",
+    "muffin",
+    "nectar",
+    "needle",
+    "nickel",
+    "noodle",
+    "nutmeg",
+    "oasis",
+    "ocean",
+    "olive",
+    "onion",
+    "orange",
+    "orchid",
+    "otter",
+    "oyster",
+    "paddle",
+    "panda",
+    "papaya",
+    "parrot",
+    "peach",
+    "peanut",
+    "pebble",
+    "pelican",
+    "pepper",
+    "piano",
+    "pickle",
+    "pillow",
+    "pine",
+    "planet",
+    "plum",
+    "pocket",
+    "pony",
+    "poppy",
+    "potato",
+    "pretzel",
+    "puffin",
+    "pumpkin",
+    "puzzle",
+    "quartz",
+    "quilt",
+    "rabbit",
+    "raccoon",
+    "radish",
+    "rainbow",
+    "raven",
+    "reef",
+    "ribbon",
+    "river",
+    "robin",
+    "rocket",
+    "saddle",
+    "salmon",
+    "sandal",
+    "satin",
+    "scarf",
+    "seal",
+    "shovel",
+    "silver",
+    "sled",
+    "snail",
+    "sparrow",
+    "spider",
+    "spinach",
+    "sponge",
+    "squirrel",
+    "statue",
+    "stone",
+    "sugar",
+    "summit",
+    "sunset",
+    "swan",
+    "tablet",
+    "tango",
+    "teapot",
+    "thistle",
+    "thunder",
+    "tiger",
+    "tomato",
+    "topaz",
+    "tortoise",
+    "trumpet",
+    "tulip",
+    "tunnel",
+    "turtle",
+    "umbrella",
+    "valley",
+    "velvet",
+    "violin",
+    "volcano",
+    "waffle",
+    "walnut",
+    "walrus",
+    "wagon",
+    "whale",
+    "willow",
+    "window",
+    "wizard",
+    "yogurt",
+    "zebra",
+];
+
+// A code such as 7-crossword-banana: the nameplate of a mailbox on the
+// rendezvous server, then two words the server never sees
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Code {
+    pub nameplate: String,
+    pub words: [String; 2],
+}
+
+impl Code {
+    // A code for the mailbox with `nameplate`, with two random words
+    pub fn generate(nameplate: &str) -> Self {
+        let word = || WORDS[rand::random_range(0..WORDS.len())].to_string();
+        Self {
+            nameplate: nameplate.to_string(),
+            words: [word(), word()],
+        }
+    }
+
+    // Read a code as the user typed it
+    pub fn parse(code: &str) -> Result<Self> {
+        let code = code.trim().to_lowercase();
+        let parts = code.split('-').collect::<Vec<_>>();
+        let [nameplate, first, second] = parts.as_slice() else {
+            bail!("A code looks like 7-crossword-banana: a number and two words");
+        };
+        if nameplate.is_empty() || !nameplate.chars().all(|c| c.is_ascii_digit()) {
+            bail!("A code starts with a number, like the 7 in 7-crossword-banana");
+        }
+        for word in [first, second] {
+            if !WORDS.contains(word) {
+                bail!(
+                    "\"{}\" isn't one of the code words, check the spelling",
+                    word
+                );
+            }
+        }
+        Ok(Self {
+            nameplate: nameplate.to_string(),
+            words: [first.to_string(), second.to_string()],
+        })
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}-{}", self.nameplate, self.words[0], self.words[1])
+    }
+}
+
+// Signaling through a rendezvous mailbox, authenticated by a short code.
+// Both peers run SPAKE2 over the whole code, then seal the offer and answer
+// with the key it gives them, so a server that doesn't know the words can
+// neither read them nor substitute its own. The mailbox holds, in order:
+//   offerer 0: SPAKE2 message    answerer 0: SPAKE2 message
+//   offerer 1: sealed offer      answerer 1: sealed answer
+pub struct WormholeSignaler {
+    server: String,
+    // Set once we know it: from the start when answering, after the offer
+    // is sent when offering
+    code: Option<Code>,
+    mailbox: Option<Mailbox>,
+    cipher: Option<XChaCha20Poly1305>,
+    offering: bool,
+}
+
+impl WormholeSignaler {
+    // Offer through `server`; the code is printed once the mailbox is open
+    pub fn offering(server: &str) -> Self {
+        Self {
+            server: server.to_string(),
+            code: None,
+            mailbox: None,
+            cipher: None,
+            offering: true,
+        }
+    }
+
+    // Answer the offer left at `server` under `code`
+    pub fn answering(server: &str, code: Code) -> Self {
+        Self {
+            server: server.to_string(),
+            mailbox: Some(Mailbox::open(server, &code.nameplate)),
+            code: Some(code),
+            cipher: None,
+            offering: false,
+        }
+    }
+
+    pub fn code(&self) -> Option<&Code> {
+        self.code.as_ref()
+    }
+
+    // Open a mailbox and pick the code for it, if that isn't done yet.
+    // Sending the offer does this itself.
+    pub async fn open(&mut self) -> Result<Code> {
+        if let Some(ref code) = self.code {
+            return Ok(code.clone());
+        }
+
+        let mailbox = Mailbox::allocate(&self.server).await?;
+        let code = Code::generate(mailbox.nameplate());
+        println!("\nWormhole code: {}", code);
+        println!(
+            "Give it to the other peer, who runs `answer --rendezvous {} {}`",
+            mailbox.server(),
+            code
+        );
+        self.code = Some(code.clone());
+        self.mailbox = Some(mailbox);
+        Ok(code)
+    }
+
+    fn mailbox(&self) -> Result<&Mailbox> {
+        self.mailbox.as_ref().context("No mailbox is open yet")
+    }
+
+    fn cipher(&self) -> Result<&XChaCha20Poly1305> {
+        self.cipher.as_ref().context("No key has been agreed yet")
+    }
+
+    // Swap SPAKE2 messages with the other side and derive the shared key
+    async fn exchange_keys(&mut self) -> Result<()> {
+        let code = self.code.as_ref().context("No code yet")?.to_string();
+        let (ours, theirs) = self.sides();
+        let mailbox = self.mailbox()?;
+
+        let (state, outbound) = Spake2::<Ed25519Group>::start_symmetric(
+            &Password::new(code.as_bytes()),
+            &Identity::new(PAKE_IDENTITY),
+        );
+        mailbox.put(ours, 0, &hex::encode(outbound)).await?;
+        let inbound = hex::decode(mailbox.wait(theirs, 0).await?.trim())
+            .context("The other side's key exchange message is corrupt")?;
+        let key = state
+            .finish(&inbound)
+            .map_err(|e| anyhow::anyhow!("Invalid key exchange message: {}", e))?;
+
+        self.cipher = Some(XChaCha20Poly1305::new_from_slice(&key)?);
+        Ok(())
+    }
+
+    fn sides(&self) -> (Side, Side) {
+        if self.offering {
+            (Side::Offerer, Side::Answerer)
+        } else {
+            (Side::Answerer, Side::Offerer)
+        }
+    }
+}
+
+#[async_trait]
+impl Signaler for WormholeSignaler {
+    async fn send(&mut self, blob: &str) -> Result<()> {
+        if self.offering {
+            self.open().await?;
+            println!("(Waiting for the other peer to enter the code...)");
+            self.exchange_keys().await?;
+        }
+
+        let (ours, _) = self.sides();
+        let sealed = seal(self.cipher()?, ours, blob)?;
+        self.mailbox()?.put(ours, 1, &sealed).await
+    }
+
+    async fn receive(&mut self) -> Result<String> {
+        if !self.offering {
+            self.exchange_keys().await?;
+        }
+
+        let (_, theirs) = self.sides();
+        let mailbox = self.mailbox()?;
+        let sealed = mailbox.wait(theirs, 1).await?;
+        let blob = open(self.cipher()?, theirs, &sealed).with_context(|| {
+            format!(
+                "The {} couldn't be authenticated. Either the code was typed wrong on one \
+                 side, or someone tampered with the exchange at the rendezvous server.",
+                theirs.as_str()
+            )
+        })?;
+
+        // The offerer collects last, and frees the nameplate
+        if self.offering {
+            let _ = mailbox.close().await;
+        }
+        Ok(blob)
+    }
+}
+
+// Encrypt a blob, binding it to the side that sent it so the other side's
+// message can't be reflected back
+fn seal(cipher: &XChaCha20Poly1305, side: Side, blob: &str) -> Result<String> {
+    let mut nonce = [0u8; 24];
+    rand::rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: blob.as_bytes(),
+                aad: side.as_str().as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt the {}", side.as_str()))?;
+    Ok(format!("{}{}", hex::encode(nonce), hex::encode(ciphertext)))
+}
+
+fn open(cipher: &XChaCha20Poly1305, side: Side, sealed: &str) -> Result<String> {
+    let sealed = hex::decode(sealed.trim()).context("Not hex encoded")?;
+    if sealed.len() < 24 {
+        bail!("Too short");
+    }
+    let (nonce, ciphertext) = sealed.split_at(24);
+    let blob = cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: side.as_str().as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Decryption failed"))?;
+    String::from_utf8(blob).context("Not text")
+}
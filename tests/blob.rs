@@ -0,0 +1,353 @@
+// The signaling blob goes through terminals, chat apps and email on its way
+// to the peer. These fuzz the parser with the damage that picks up on the
+// way: indentation, rewrapping, CRLFs, lost markers and truncation.
+
+use modulate_comms::sdp::{self, SessionStamp, SignalingBlob};
+use proptest::prelude::*;
+use std::io::{BufRead, Cursor};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+fn description(sdp_type: RTCSdpType, sdp: &str) -> RTCSessionDescription {
+    serde_json::from_value(serde_json::json!({ "type": sdp_type.to_string(), "sdp": sdp })).unwrap()
+}
+
+// Lowercase only, so the text can never contain an OFFER:/ANSWER: marker
+fn sdp_text() -> impl Strategy<Value = String> {
+    "[a-z0-9 =:/.\r\n-]{0,3000}"
+}
+
+fn candidates() -> impl Strategy<Value = Vec<RTCIceCandidateInit>> {
+    prop::collection::vec(
+        (1..=u32::MAX, any::<[u8; 4]>(), 1..=u16::MAX).prop_map(|(priority, ip, port)| {
+            RTCIceCandidateInit {
+                candidate: format!(
+                    "candidate:1 1 udp {} {}.{}.{}.{} {} typ host",
+                    priority, ip[0], ip[1], ip[2], ip[3], port
+                ),
+                sdp_mid: Some(String::new()),
+                sdp_mline_index: Some(0),
+                username_fragment: None,
+            }
+        }),
+        0..6,
+    )
+}
+
+fn stamp() -> impl Strategy<Value = SessionStamp> {
+    (any::<i64>(), "[0-9a-f]{32}", any::<u64>()).prop_map(|(created, nonce, valid_for)| {
+        SessionStamp {
+            created,
+            nonce,
+            valid_for,
+        }
+    })
+}
+
+// A blob made `age` seconds ago
+fn stamped(sdp_type: RTCSdpType, nonce: &str, age: i64) -> String {
+    let stamp = SessionStamp {
+        created: chrono::Utc::now().timestamp() - age,
+        nonce: nonce.to_owned(),
+        valid_for: sdp::BLOB_VALIDITY_SECS,
+    };
+    sdp::encode_blob(&description(sdp_type, "v=0"), &[], &stamp).unwrap()
+}
+
+fn sdp_type() -> impl Strategy<Value = RTCSdpType> {
+    prop_oneof![Just(RTCSdpType::Offer), Just(RTCSdpType::Answer)]
+}
+
+// Split a line at most `width` characters long, never next to a space, the
+// way a wrapping editor or mail client would
+fn rewrap(line: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![String::new()];
+    let chars = line.chars().collect::<Vec<_>>();
+    for (i, &c) in chars.iter().enumerate() {
+        let current = lines.last_mut().unwrap();
+        current.push(c);
+        let clean = !c.is_whitespace() && chars.get(i + 1).is_some_and(|n| !n.is_whitespace());
+        if current.chars().count() >= width && clean {
+            lines.push(String::new());
+        }
+    }
+    lines
+}
+
+#[derive(Debug, Clone)]
+struct Damage {
+    width: usize,
+    indent: usize,
+    trailing: usize,
+    crlf: bool,
+    markers: bool,
+    prefix: bool,
+    label: bool,
+    stamp: bool,
+}
+
+fn damage() -> impl Strategy<Value = Damage> {
+    (
+        8..400usize,
+        0..5usize,
+        0..3usize,
+        any::<bool>(),
+        any::<bool>(),
+        any::<bool>(),
+        any::<bool>(),
+        any::<bool>(),
+    )
+        .prop_map(
+            |(width, indent, trailing, crlf, markers, prefix, label, stamp)| Damage {
+                width,
+                indent,
+                trailing,
+                crlf,
+                markers,
+                prefix,
+                label,
+                stamp,
+            },
+        )
+}
+
+// A blob as it might arrive after being pasted through something lossy
+fn damaged_blob(
+    description: &RTCSessionDescription,
+    candidates: &[RTCIceCandidateInit],
+    stamp: &SessionStamp,
+    damage: &Damage,
+) -> String {
+    let marker = if description.sdp_type == RTCSdpType::Offer {
+        "OFFER:"
+    } else {
+        "ANSWER:"
+    };
+    let mut lines = Vec::new();
+    if damage.markers {
+        lines.push("==== COPY EVERYTHING BETWEEN THESE LINES ====".to_owned());
+    }
+    if damage.stamp {
+        let stamp_line = format!("SESSION:{}", serde_json::to_string(stamp).unwrap());
+        lines.extend(rewrap(&stamp_line, damage.width));
+    }
+    let description_json = serde_json::to_string(description).unwrap();
+    let description_line = if damage.prefix {
+        format!("{}{}", marker, description_json)
+    } else {
+        description_json
+    };
+    lines.extend(rewrap(&description_line, damage.width));
+    if damage.label {
+        lines.push("ICE_CANDIDATES:".to_owned());
+    }
+    lines.extend(rewrap(
+        &sdp::encode_candidates(candidates).unwrap(),
+        damage.width,
+    ));
+    if damage.markers {
+        lines.push("==== END OF SECTION TO COPY ====".to_owned());
+    }
+
+    let newline = if damage.crlf { "\r\n" } else { "\n" };
+    lines
+        .iter()
+        .map(|line| {
+            format!(
+                "{}{}{}{}",
+                " ".repeat(damage.indent),
+                line,
+                " ".repeat(damage.trailing),
+                newline
+            )
+        })
+        .collect()
+}
+
+proptest! {
+    #[test]
+    fn encoded_blobs_round_trip(
+        sdp_type in sdp_type(),
+        sdp in sdp_text(),
+        candidates in candidates(),
+        stamp in stamp(),
+    ) {
+        let description = description(sdp_type, &sdp);
+        let text = sdp::encode_blob(&description, &candidates, &stamp).unwrap();
+
+        let blob = SignalingBlob::parse(&text).unwrap();
+        prop_assert_eq!(blob.description.sdp_type, sdp_type);
+        prop_assert_eq!(blob.description.sdp, sdp);
+        prop_assert_eq!(blob.candidates, candidates);
+        prop_assert_eq!(blob.session.as_ref(), Some(&stamp));
+
+        // Whatever the peer put in the stamp, checking it is safe
+        let _ = stamp.check(sdp_type, chrono::Utc::now().timestamp());
+
+        // Wrapped to fit a terminal's line buffer, with room to spare
+        for line in text.lines() {
+            prop_assert!(line.chars().count() <= 80, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn damaged_blobs_still_parse(
+        sdp_type in sdp_type(),
+        sdp in sdp_text(),
+        candidates in candidates(),
+        stamp in stamp(),
+        damage in damage(),
+    ) {
+        let description = description(sdp_type, &sdp);
+        let text = damaged_blob(&description, &candidates, &stamp, &damage);
+
+        let blob = SignalingBlob::parse(&text).unwrap();
+        prop_assert_eq!(blob.description.sdp_type, sdp_type);
+        prop_assert_eq!(blob.description.sdp, sdp);
+        prop_assert_eq!(blob.candidates, candidates);
+        prop_assert_eq!(blob.session, damage.stamp.then_some(stamp));
+
+        // Reading stops once the paste is complete, whether or not it has markers
+        let mut reader = Cursor::new(format!("{}chat message\n", text));
+        let read = sdp::read_blob(&mut reader).unwrap();
+        prop_assert_eq!(SignalingBlob::parse(&read).unwrap().description.sdp, description.sdp);
+        let mut rest = String::new();
+        reader.read_line(&mut rest).unwrap();
+        prop_assert_eq!(rest, "chat message\n");
+    }
+
+    #[test]
+    fn truncated_blobs_are_never_misread(
+        sdp in sdp_text(),
+        candidates in candidates(),
+        stamp in stamp(),
+        cut in any::<prop::sample::Index>(),
+    ) {
+        let description = description(RTCSdpType::Offer, &sdp);
+        let text = sdp::encode_blob(&description, &candidates, &stamp).unwrap();
+        let end = text.find("==== END").unwrap();
+        let cut = cut.index(end);
+
+        // Reading hits EOF rather than waiting for more, and whatever parses
+        // is what was sent
+        let read = sdp::read_blob(&mut Cursor::new(&text[..cut]));
+        if let Ok(blob) = read.and_then(|read| SignalingBlob::parse(&read)) {
+            prop_assert_eq!(blob.description.sdp, sdp);
+            prop_assert!(candidates.starts_with(&blob.candidates));
+        }
+    }
+
+    #[test]
+    fn arbitrary_text_never_panics(text in "\\PC*") {
+        let _ = SignalingBlob::parse(&text);
+        let _ = sdp::parse_candidates(&text);
+        let _ = sdp::read_blob(&mut Cursor::new(&text));
+    }
+
+    #[test]
+    fn stamps_are_accepted_only_within_their_window(
+        age in -1000..5000i64,
+        valid_for in 0..3600u64,
+        now in 0..i64::MAX / 2,
+    ) {
+        let stamp = SessionStamp {
+            created: now - age,
+            nonce: String::new(),
+            valid_for,
+        };
+        // Two minutes of clock skew are allowed either way
+        let within = (-120..=valid_for as i64 + 120).contains(&age);
+        prop_assert_eq!(stamp.check(RTCSdpType::Offer, now).is_ok(), within);
+    }
+}
+
+#[test]
+fn reading_stops_at_eof() {
+    let err = sdp::read_blob(&mut Cursor::new("")).unwrap_err();
+    assert!(err.to_string().contains("ended"), "{}", err);
+    assert!(sdp::read_blob(&mut Cursor::new("\n  \n")).is_err());
+
+    // A partial paste comes back as it is and fails to parse
+    let partial = sdp::read_blob(&mut Cursor::new("OFFER:{\"type\":\"off")).unwrap();
+    let err = SignalingBlob::parse(&partial).unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("incomplete or damaged"),
+        "{:#}",
+        err
+    );
+}
+
+#[test]
+fn blobs_of_the_wrong_kind_are_explained() {
+    let offer = stamped(RTCSdpType::Offer, "ab12", 0);
+    let answer = stamped(RTCSdpType::Answer, "ab12", 0);
+
+    let err = sdp::parse_answer(&offer, "ab12").unwrap_err().to_string();
+    assert!(err.contains("This is an offer"), "{}", err);
+    assert!(err.contains("not your own offer"), "{}", err);
+
+    let err = sdp::parse_offer(&answer).unwrap_err().to_string();
+    assert!(err.contains("This is an answer"), "{}", err);
+
+    // A marker that disagrees with the description inside it
+    let mislabelled = answer.replace("ANSWER:", "OFFER:");
+    let err = SignalingBlob::parse(&mislabelled).unwrap_err().to_string();
+    assert!(err.contains("marked as an offer"), "{}", err);
+
+    let err = SignalingBlob::parse("hello there").unwrap_err().to_string();
+    assert!(err.contains("No offer or answer"), "{}", err);
+}
+
+#[test]
+fn stale_and_foreign_blobs_are_rejected() {
+    assert!(sdp::parse_offer(&stamped(RTCSdpType::Offer, "ab12", 60)).is_ok());
+    assert!(sdp::parse_answer(&stamped(RTCSdpType::Answer, "ab12", 60), "ab12").is_ok());
+
+    // An offer pasted again long after it was made
+    let err = sdp::parse_offer(&stamped(RTCSdpType::Offer, "ab12", 3600))
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("expired 48 minutes ago"), "{}", err);
+    assert!(err.contains("fresh one"), "{}", err);
+
+    let err = sdp::parse_answer(&stamped(RTCSdpType::Answer, "ab12", 3600), "ab12")
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("expired"), "{}", err);
+
+    let err = sdp::parse_offer(&stamped(RTCSdpType::Offer, "ab12", -3600))
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("in the future"), "{}", err);
+
+    // An answer to some other offer
+    let err = sdp::parse_answer(&stamped(RTCSdpType::Answer, "ab12", 0), "cd34")
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("different offer"), "{}", err);
+
+    let err = SignalingBlob::parse("SESSION:{\"created\":1}\nOFFER:{}")
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("session stamp is damaged"), "{}", err);
+}
+
+#[test]
+fn unstamped_blobs_are_refused() {
+    // A bare description, as other WebRTC tools print it, still parses
+    for sdp_type in [RTCSdpType::Offer, RTCSdpType::Answer] {
+        let bare = serde_json::to_string(&description(sdp_type, "v=0")).unwrap();
+        let blob = SignalingBlob::parse(&bare).unwrap();
+        assert_eq!(blob.description.sdp_type, sdp_type);
+        assert!(blob.session.is_none());
+    }
+
+    // but without a stamp neither an offer nor an answer can be checked
+    let bare = serde_json::to_string(&description(RTCSdpType::Offer, "v=0")).unwrap();
+    let err = sdp::parse_offer(&bare).unwrap_err().to_string();
+    assert!(err.contains("no session stamp"), "{}", err);
+    let bare = serde_json::to_string(&description(RTCSdpType::Answer, "v=0")).unwrap();
+    let err = sdp::parse_answer(&bare, "ab12").unwrap_err().to_string();
+    assert!(err.contains("no session stamp"), "{}", err);
+}
@@ -0,0 +1,301 @@
+// ICE candidates travel in the blob as the RTCIceCandidateInit values that
+// RTCIceCandidate::to_json produces, so nothing is lost on the way to the
+// peer's ICE agent, and anything malformed is rejected by index. Candidates
+// are attached to the media section they name, and the peer connection takes
+// the result; candidates naming no section, the wrong one or another ICE
+// session are rejected.
+
+use modulate_comms::connection::{self, IceSettings};
+use modulate_comms::sdp;
+use proptest::prelude::*;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, LazyLock};
+use tokio::runtime::Runtime;
+use webrtc::ice::candidate::candidate_base::unmarshal_candidate;
+use webrtc::ice::candidate::Candidate;
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
+use webrtc::ice_transport::ice_protocol::RTCIceProtocol;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+static RUNTIME: LazyLock<Runtime> = LazyLock::new(|| Runtime::new().unwrap());
+
+// No servers, so nothing is gathered beyond what the tests attach
+fn ice() -> IceSettings {
+    IceSettings {
+        stun_servers: Vec::new(),
+        ..Default::default()
+    }
+}
+
+// An offer as the peer makes it, with its one data channel section and
+// the username fragment candidates are checked against
+static OFFER: LazyLock<(RTCSessionDescription, String)> = LazyLock::new(|| {
+    RUNTIME.block_on(async {
+        let pc = connection::create_peer_connection(&ice()).await.unwrap();
+        pc.create_data_channel("messaging", None).await.unwrap();
+        let offer = pc.create_offer(None).await.unwrap();
+        pc.close().await.unwrap();
+        let ufrag = offer
+            .sdp
+            .lines()
+            .find_map(|line| line.strip_prefix("a=ice-ufrag:"))
+            .unwrap()
+            .to_string();
+        (offer, ufrag)
+    })
+});
+
+// Attach the candidates to the offer and hand it to a fresh peer connection
+fn set_remote(candidates: &[RTCIceCandidateInit]) -> anyhow::Result<String> {
+    let attached = sdp::attach_candidates(OFFER.0.clone(), candidates)?;
+    RUNTIME.block_on(async {
+        let pc = connection::create_peer_connection(&ice()).await?;
+        let result = pc.set_remote_description(attached).await;
+        let remote = pc.remote_description().await;
+        pc.close().await?;
+        result?;
+        Ok(remote
+            .map(|description| description.sdp)
+            .unwrap_or_default())
+    })
+}
+
+// Wrap candidates the way generate_offer/generate_answer do
+fn blob(candidates: &[RTCIceCandidateInit]) -> String {
+    format!(
+        "==== COPY EVERYTHING BETWEEN THESE LINES ====\nOFFER:{{}}\nICE_CANDIDATES:\n{}\n==== END OF SECTION TO COPY ====",
+        sdp::encode_candidates(candidates).unwrap()
+    )
+}
+
+fn address() -> impl Strategy<Value = String> {
+    prop_oneof![
+        any::<Ipv4Addr>().prop_map(|ip| ip.to_string()),
+        any::<Ipv6Addr>().prop_map(|ip| ip.to_string()),
+    ]
+}
+
+// Candidates as webrtc gathers them. Zero priorities and empty foundations
+// are left out because the ICE agent replaces them with computed values.
+fn candidate() -> impl Strategy<Value = RTCIceCandidate> {
+    let typ = prop_oneof![
+        Just(RTCIceCandidateType::Host),
+        Just(RTCIceCandidateType::Srflx),
+        Just(RTCIceCandidateType::Prflx),
+        Just(RTCIceCandidateType::Relay),
+    ];
+    (
+        "[a-zA-Z0-9+/]{1,32}",
+        1..=u32::MAX,
+        address(),
+        1..=u16::MAX,
+        typ,
+        1..=2u16,
+        address(),
+        1..=u16::MAX,
+    )
+        .prop_map(
+            |(foundation, priority, address, port, typ, component, raddr, rport)| {
+                let (related_address, related_port) = if typ == RTCIceCandidateType::Host {
+                    (String::new(), 0)
+                } else {
+                    (raddr, rport)
+                };
+                RTCIceCandidate {
+                    foundation,
+                    priority,
+                    address,
+                    protocol: RTCIceProtocol::Udp,
+                    port,
+                    typ,
+                    component,
+                    related_address,
+                    related_port,
+                    ..Default::default()
+                }
+            },
+        )
+}
+
+proptest! {
+    #[test]
+    fn gathered_candidates_survive_the_blob(candidates in prop::collection::vec(candidate(), 0..8)) {
+        let inits = candidates
+            .iter()
+            .map(|c| c.to_json().unwrap())
+            .collect::<Vec<_>>();
+
+        let parsed = sdp::parse_candidates(&blob(&inits)).unwrap();
+        prop_assert_eq!(&parsed, &inits);
+
+        // What the peer's ICE agent reads back is the candidate that was gathered
+        for (original, init) in candidates.iter().zip(&parsed) {
+            let raw = init.candidate.strip_prefix("candidate:").unwrap();
+            let ice: Arc<dyn Candidate + Send + Sync> = Arc::new(unmarshal_candidate(raw).unwrap());
+            let received = RTCIceCandidate::from(&ice);
+            prop_assert_eq!(&received.foundation, &original.foundation);
+            prop_assert_eq!(received.priority, original.priority);
+            prop_assert_eq!(&received.address, &original.address);
+            prop_assert_eq!(received.protocol, original.protocol);
+            prop_assert_eq!(received.port, original.port);
+            prop_assert_eq!(received.typ, original.typ);
+            prop_assert_eq!(received.component, original.component);
+            prop_assert_eq!(&received.related_address, &original.related_address);
+            prop_assert_eq!(received.related_port, original.related_port);
+        }
+    }
+
+    #[test]
+    fn init_fields_are_kept(
+        candidate in candidate(),
+        sdp_mid in proptest::option::of("[a-z0-9]{0,8}"),
+        sdp_mline_index in proptest::option::of(any::<u16>()),
+        username_fragment in proptest::option::of("[a-zA-Z0-9+/]{4,32}"),
+    ) {
+        prop_assume!(sdp_mid.as_deref().is_some_and(|mid| !mid.is_empty()) || sdp_mline_index.is_some());
+        let init = RTCIceCandidateInit {
+            candidate: candidate.to_json().unwrap().candidate,
+            sdp_mid,
+            sdp_mline_index,
+            username_fragment,
+        };
+        prop_assert_eq!(sdp::parse_candidates(&blob(std::slice::from_ref(&init))).unwrap(), vec![init]);
+    }
+
+    #[test]
+    fn every_bad_candidate_is_named(
+        candidates in prop::collection::vec(candidate(), 1..8),
+        bad in prop::collection::btree_set(0..8usize, 1..4),
+    ) {
+        let mut inits = candidates
+            .iter()
+            .map(|c| c.to_json().unwrap())
+            .collect::<Vec<_>>();
+        let bad = bad.into_iter().filter(|&i| i < inits.len()).collect::<Vec<_>>();
+        prop_assume!(!bad.is_empty());
+        for &i in &bad {
+            // Drop the port and everything after it
+            let fields = inits[i].candidate.split(' ').take(5).collect::<Vec<_>>();
+            inits[i].candidate = fields.join(" ");
+        }
+
+        let err = format!("{:#}", sdp::parse_candidates(&blob(&inits)).unwrap_err());
+        for i in 0..inits.len() {
+            prop_assert_eq!(
+                err.contains(&format!("candidate {}:", i)),
+                bad.contains(&i),
+                "{}",
+                err
+            );
+        }
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn candidates_reach_the_peer_connection(
+        candidates in prop::collection::vec(candidate(), 1..8),
+        by_mid in any::<bool>(),
+        with_ufrag in any::<bool>(),
+    ) {
+        let inits = candidates
+            .iter()
+            .map(|c| RTCIceCandidateInit {
+                candidate: c.to_json().unwrap().candidate,
+                sdp_mid: by_mid.then(|| "0".to_string()),
+                sdp_mline_index: (!by_mid).then_some(0),
+                username_fragment: with_ufrag.then(|| OFFER.1.clone()),
+            })
+            .collect::<Vec<_>>();
+        let parsed = sdp::parse_candidates(&blob(&inits)).unwrap();
+
+        let remote = set_remote(&parsed).unwrap();
+        for init in &inits {
+            prop_assert!(remote.contains(&format!("a={}", init.candidate)), "{}", remote);
+        }
+    }
+
+    #[test]
+    fn misplaced_candidates_are_rejected(
+        candidates in prop::collection::vec(candidate(), 2..6),
+        bad in 0..6usize,
+        wrong_mid in "[1-9a-z][a-z0-9]{0,3}",
+        wrong_index in 1..u16::MAX,
+        wrong_ufrag in "[a-zA-Z0-9+/]{4,32}",
+        fault in 0..3u8,
+    ) {
+        prop_assume!(wrong_ufrag != OFFER.1);
+        let bad = bad % candidates.len();
+        let mut inits = candidates
+            .iter()
+            .map(|c| RTCIceCandidateInit {
+                candidate: c.to_json().unwrap().candidate,
+                sdp_mid: Some("0".to_string()),
+                sdp_mline_index: Some(0),
+                username_fragment: Some(OFFER.1.clone()),
+            })
+            .collect::<Vec<_>>();
+        let reason = match fault {
+            0 => {
+                inits[bad].sdp_mid = Some(wrong_mid);
+                "no media section with mid"
+            }
+            1 => {
+                inits[bad].sdp_mid = None;
+                inits[bad].sdp_mline_index = Some(wrong_index);
+                "no media section"
+            }
+            _ => {
+                inits[bad].username_fragment = Some(wrong_ufrag);
+                "username fragment"
+            }
+        };
+
+        let err = format!("{:#}", set_remote(&inits).unwrap_err());
+        prop_assert!(err.contains(&format!("candidate {}: {}", bad, reason)), "{}", err);
+    }
+}
+
+#[test]
+fn malformed_entries_are_rejected() {
+    for (entries, reason) in [
+        (
+            r#"["candidate:1 1 udp 1 1.2.3.4 5 typ host"]"#,
+            "not an RTCIceCandidateInit",
+        ),
+        (
+            r#"[{"candidate":"1 1 udp 1 1.2.3.4 5 typ host"}]"#,
+            "doesn't start with",
+        ),
+        (
+            r#"[{"candidate":"candidate:1 1 udp 1 1.2.3.4 port typ host"}]"#,
+            "can't parse",
+        ),
+        (
+            r#"[{"candidate":"candidate:1 1 udp 1 1.2.3.4 5 typ nonsense"}]"#,
+            "can't parse",
+        ),
+        (r#"[{"candidate":""}]"#, "doesn't start with"),
+        (
+            r#"[{"candidate":"candidate:1 1 udp 1 1.2.3.4 5 typ host","sdpMid":""}]"#,
+            "neither sdp_mid nor sdp_mline_index",
+        ),
+    ] {
+        let data = format!(
+            "ICE_CANDIDATES:\n{}\n==== END OF SECTION TO COPY ====",
+            entries
+        );
+        let err = format!("{:#}", sdp::parse_candidates(&data).unwrap_err());
+        assert!(err.contains("candidate 0:"), "{}", err);
+        assert!(err.contains(reason), "{}", err);
+    }
+
+    let err = sdp::parse_candidates("ICE_CANDIDATES:\n{\"candidate\":\"x\"}\n").unwrap_err();
+    assert!(format!("{:#}", err).contains("not a JSON array"));
+
+    // No candidates at all is not an error; the peer may have sent none
+    assert!(sdp::parse_candidates("OFFER:{}").unwrap().is_empty());
+}
@@ -0,0 +1,232 @@
+// Two peers in one process on webrtc-rs's virtual network, so tests need no
+// internet access or real STUN servers, and the same peers connected through
+// the real signaling code.
+#![allow(dead_code)]
+
+use anyhow::Result;
+use modulate_comms::app;
+use modulate_comms::connection::{
+    self, ChannelSpec, DataChannelHandle, IceSettings, IncomingMessages, MESSAGING_CHANNEL,
+};
+use modulate_comms::signaling::MemorySignaler;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::util::vnet::net::{Net, NetConfig};
+use webrtc::util::vnet::router::{Router, RouterConfig};
+
+// How long tests wait for anything to happen on the virtual network
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct VnetPair {
+    pub offerer: Arc<RTCPeerConnection>,
+    pub answerer: Arc<RTCPeerConnection>,
+    pub wan: Arc<Mutex<Router>>,
+    // Percentage of packets the router drops, changeable at any time
+    pub loss_percent: Arc<AtomicU32>,
+}
+
+impl VnetPair {
+    pub fn set_loss(&self, percent: u32) {
+        self.loss_percent.store(percent, Ordering::SeqCst);
+    }
+
+    pub async fn close(&self) {
+        let _ = self.offerer.close().await;
+        let _ = self.answerer.close().await;
+        let _ = self.wan.lock().await.stop().await;
+    }
+}
+
+// Offerer at 1.2.3.4 and answerer at 1.2.3.5 behind one lossy router
+pub async fn vnet_pair() -> Result<VnetPair> {
+    let wan = Arc::new(Mutex::new(Router::new(RouterConfig {
+        cidr: "1.2.3.0/24".to_owned(),
+        ..Default::default()
+    })?));
+
+    let loss_percent = Arc::new(AtomicU32::new(0));
+    let loss = Arc::clone(&loss_percent);
+    wan.lock()
+        .await
+        .add_chunk_filter(Box::new(move |_chunk| {
+            rand::random_range(0..100) >= loss.load(Ordering::SeqCst)
+        }))
+        .await;
+
+    let offerer = vnet_peer(&wan, "1.2.3.4").await?;
+    let answerer = vnet_peer(&wan, "1.2.3.5").await?;
+
+    wan.lock().await.start().await?;
+
+    Ok(VnetPair {
+        offerer,
+        answerer,
+        wan,
+        loss_percent,
+    })
+}
+
+// A peer connection on its own virtual interface attached to the router
+async fn vnet_peer(wan: &Arc<Mutex<Router>>, ip: &str) -> Result<Arc<RTCPeerConnection>> {
+    let net = Arc::new(Net::new(Some(NetConfig {
+        static_ips: vec![ip.to_owned()],
+        ..Default::default()
+    })));
+
+    let nic = net.get_nic()?;
+    wan.lock().await.add_net(Arc::clone(&nic)).await?;
+    nic.lock().await.set_router(Arc::clone(wan)).await?;
+
+    let mut settings = SettingEngine::default();
+    settings.set_vnet(Some(net));
+
+    // Host candidates are all there is on the virtual network
+    let ice = IceSettings {
+        stun_servers: Vec::new(),
+        ..Default::default()
+    };
+    connection::create_peer_connection_with(&ice, settings).await
+}
+
+// Both ends of `app::connect` run against each other over an in-memory
+// signaler, with the default channels
+pub struct ConnectedPair {
+    pub pair: VnetPair,
+    pub offer_channels: HashMap<String, DataChannelHandle>,
+    pub answer_channels: HashMap<String, DataChannelHandle>,
+    // What each side put on the wire
+    pub offer_blob: String,
+    pub answer_blob: String,
+}
+
+pub async fn connected_pair() -> Result<ConnectedPair> {
+    let pair = vnet_pair().await?;
+    let (mut offer_signaler, mut answer_signaler) = MemorySignaler::pair();
+
+    let specs = ChannelSpec::with_overrides(&[]);
+    let (offer_channels, answer_channels) = tokio::try_join!(
+        app::connect(
+            Arc::clone(&pair.offerer),
+            specs.clone(),
+            &mut offer_signaler,
+            true,
+            TIMEOUT,
+        ),
+        app::connect(
+            Arc::clone(&pair.answerer),
+            specs,
+            &mut answer_signaler,
+            false,
+            TIMEOUT,
+        ),
+    )?;
+
+    Ok(ConnectedPair {
+        pair,
+        offer_channels,
+        answer_channels,
+        offer_blob: offer_signaler.sent.remove(0),
+        answer_blob: answer_signaler.sent.remove(0),
+    })
+}
+
+// The messaging channel at both ends of a connected pair, offerer first
+pub async fn connected_messaging_pair() -> Result<(VnetPair, DataChannelHandle, DataChannelHandle)>
+{
+    let mut connected = connected_pair().await?;
+    let offerer = connected
+        .offer_channels
+        .remove(MESSAGING_CHANNEL)
+        .expect("offerer has a messaging channel");
+    let answerer = connected
+        .answer_channels
+        .remove(MESSAGING_CHANNEL)
+        .expect("answerer has a messaging channel");
+    Ok((connected.pair, offerer, answerer))
+}
+
+// An open messaging channel and what arrives on it
+pub type OpenChannel = (Arc<RTCDataChannel>, IncomingMessages);
+
+// The same, once both channels are open
+pub async fn open_messaging_pair() -> Result<(VnetPair, OpenChannel, OpenChannel)> {
+    let (pair, offerer, answerer) = connected_messaging_pair().await?;
+    let offerer_dc = connection::wait_for_open(&offerer.channel, TIMEOUT).await?;
+    let answerer_dc = connection::wait_for_open(&answerer.channel, TIMEOUT).await?;
+    Ok((
+        pair,
+        (offerer_dc, offerer.incoming),
+        (answerer_dc, answerer.incoming),
+    ))
+}
+
+// Exchange descriptions directly, with every candidate gathered up front
+pub async fn signal(offerer: &RTCPeerConnection, answerer: &RTCPeerConnection) -> Result<()> {
+    let offer = offerer.create_offer(None).await?;
+    let mut gathered = offerer.gathering_complete_promise().await;
+    offerer.set_local_description(offer).await?;
+    let _ = gathered.recv().await;
+    let offer = offerer
+        .local_description()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("Offerer has no local description"))?;
+
+    answerer.set_remote_description(offer).await?;
+    let answer = answerer.create_answer(None).await?;
+    let mut gathered = answerer.gathering_complete_promise().await;
+    answerer.set_local_description(answer).await?;
+    let _ = gathered.recv().await;
+    let answer = answerer
+        .local_description()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("Answerer has no local description"))?;
+
+    offerer.set_remote_description(answer).await?;
+    Ok(())
+}
+
+// Wait for a peer connection to reach the connected state
+pub async fn wait_connected(pc: &RTCPeerConnection) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        match pc.connection_state() {
+            RTCPeerConnectionState::Connected => return Ok(()),
+            RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => {
+                return Err(anyhow::anyhow!("Peer connection {}", pc.connection_state()));
+            }
+            _ if start.elapsed() > TIMEOUT => {
+                return Err(anyhow::anyhow!("Timed out waiting to connect"));
+            }
+            _ => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    }
+}
+
+// A directory of its own under the system temp dir, removed on drop
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new(prefix: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "modulate-{}-{}",
+            prefix,
+            rand::random_range(0..u64::MAX)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
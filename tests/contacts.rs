@@ -0,0 +1,108 @@
+// The contact book: identity keys are checked and kept unique, names can be
+// changed, and ICE preferences are only saved when given.
+
+use anyhow::Result;
+use modulate_comms::cli::ContactsAction;
+use modulate_comms::contacts;
+use modulate_comms::identity::Identity;
+use modulate_comms::store::Store;
+
+fn add(name: &str, identity: &str) -> ContactsAction {
+    ContactsAction::Add {
+        name: name.to_string(),
+        identity: identity.to_string(),
+        display_name: None,
+        stun: Vec::new(),
+        turn: Vec::new(),
+        turn_username: None,
+        turn_credential: None,
+        relay_only: false,
+    }
+}
+
+#[test]
+fn contacts_are_added_renamed_and_removed() -> Result<()> {
+    let mut store = Store::ephemeral();
+    let bob = Identity::generate().fingerprint();
+    // Keys are saved the way fingerprints are compared, trimmed and lowercase
+    contacts::run_contacts(add("bob", &format!(" {} ", bob.to_uppercase())), &mut store)?;
+    assert_eq!(store.contact("bob").unwrap().identity, bob);
+    assert_eq!(store.contact_by_identity(&bob).unwrap().name, "bob");
+    assert!(store.contact("bob").unwrap().ice.is_none());
+
+    contacts::run_contacts(
+        ContactsAction::Rename {
+            name: "bob".to_string(),
+            new_name: "robert".to_string(),
+        },
+        &mut store,
+    )?;
+    assert!(store.contact("bob").is_none());
+    assert_eq!(store.contact("robert").unwrap().label(), "robert");
+
+    contacts::run_contacts(
+        ContactsAction::Remove {
+            name: "robert".to_string(),
+        },
+        &mut store,
+    )?;
+    assert!(store.contacts().is_empty());
+    assert!(contacts::run_contacts(
+        ContactsAction::Remove {
+            name: "robert".to_string()
+        },
+        &mut store
+    )
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn bad_and_duplicate_identities_are_refused() -> Result<()> {
+    let mut store = Store::ephemeral();
+    let bob = Identity::generate().fingerprint();
+    contacts::run_contacts(add("bob", &bob), &mut store)?;
+
+    for (action, expected) in [
+        (
+            add("bob", &Identity::generate().fingerprint()),
+            "already exists",
+        ),
+        (add("bobby", &bob.to_uppercase()), "already saved as 'bob'"),
+        (add("carol", "not hex"), "hex encoded"),
+        (add("carol", &bob[..62]), "32 bytes"),
+    ] {
+        let err = contacts::run_contacts(action, &mut store).unwrap_err();
+        assert!(err.to_string().contains(expected), "{:#}", err);
+    }
+    assert_eq!(store.contacts().len(), 1);
+    Ok(())
+}
+
+#[test]
+fn ice_preferences_are_kept_per_contact() -> Result<()> {
+    let mut store = Store::ephemeral();
+    contacts::run_contacts(
+        ContactsAction::Add {
+            name: "bob".to_string(),
+            identity: Identity::generate().fingerprint(),
+            display_name: Some("Bob".to_string()),
+            stun: Vec::new(),
+            turn: vec!["turn:turn.example.com:3478".to_string()],
+            turn_username: Some("alice".to_string()),
+            turn_credential: Some("secret".to_string()),
+            relay_only: true,
+        },
+        &mut store,
+    )?;
+
+    let bob = store.contact("bob").unwrap();
+    assert_eq!(bob.label(), "Bob");
+    let ice = bob.ice.as_ref().expect("ICE settings");
+    assert_eq!(ice.turn_servers, ["turn:turn.example.com:3478"]);
+    assert_eq!(ice.turn_username, "alice");
+    assert!(ice.relay_only);
+    // No STUN servers given, so the defaults stay
+    assert!(!ice.stun_servers.is_empty());
+    Ok(())
+}
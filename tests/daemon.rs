@@ -0,0 +1,406 @@
+// Two daemons, each holding one end of a session on the virtual network,
+// driven through their control sockets as a script would, and the control
+// socket itself, which only its owner can reach.
+#![cfg(unix)]
+
+mod common;
+
+use anyhow::{Context, Result};
+use common::{TempDir, TIMEOUT};
+use modulate_comms::app::SessionOptions;
+use modulate_comms::daemon::{self, Daemon, Event, SessionInfo, SessionState};
+use modulate_comms::signaling::MemorySignaler;
+use modulate_comms::store::{self, Store};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+use tokio::sync::Mutex;
+
+// Largest file the daemons take
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+// A daemon on a socket in `dir`, with received files going to dir/downloads
+fn start_daemon(dir: &TempDir, name: &str) -> Result<(Arc<Daemon>, PathBuf)> {
+    let store: store::SharedStore = Arc::new(Mutex::new(Store::ephemeral()));
+    let daemon = Daemon::new(
+        store,
+        dir.0.join(name).join("downloads"),
+        MAX_FILE_SIZE,
+        TIMEOUT,
+    );
+    let socket = dir.0.join(name).join("control.sock");
+    tokio::spawn(Arc::clone(&daemon).serve(daemon::bind(&socket)?));
+    Ok((daemon, socket))
+}
+
+fn options() -> SessionOptions {
+    SessionOptions {
+        timeout: TIMEOUT,
+        password: None,
+        to: None,
+        name: None,
+        channels: Vec::new(),
+        rendezvous: None,
+        code: None,
+        stats_interval: None,
+        announced: None,
+    }
+}
+
+// A JSON-RPC client on a control socket, keeping events that arrive while
+// it waits for a response
+struct Client {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+    events: VecDeque<Event>,
+}
+
+impl Client {
+    async fn connect(socket: &PathBuf) -> Result<Self> {
+        let (reader, writer) = UnixStream::connect(socket).await?.into_split();
+        Ok(Self {
+            lines: BufReader::new(reader).lines(),
+            writer,
+            next_id: 1,
+            events: VecDeque::new(),
+        })
+    }
+
+    async fn send_line(&mut self, line: &str) -> Result<()> {
+        self.writer
+            .write_all(format!("{}\n", line).as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    async fn read(&mut self) -> Result<Value> {
+        let line = tokio::time::timeout(TIMEOUT, self.lines.next_line())
+            .await
+            .context("Nothing from the daemon")??
+            .context("Daemon hung up")?;
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    // The response to the next request, stashing events on the way
+    async fn response(&mut self) -> Result<Value> {
+        loop {
+            let message = self.read().await?;
+            if message["method"] == "event" {
+                self.events
+                    .push_back(serde_json::from_value(message["params"].clone())?);
+            } else {
+                return Ok(message);
+            }
+        }
+    }
+
+    // The whole response to a request
+    async fn request(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        self.send_line(&request.to_string()).await?;
+        let response = self.response().await?;
+        assert_eq!(response["id"], id, "{}", response);
+        Ok(response)
+    }
+
+    // The result of a request that has to succeed
+    async fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        let response = self.request(method, params).await?;
+        anyhow::ensure!(response["error"].is_null(), "{}", response["error"]);
+        Ok(response["result"].clone())
+    }
+
+    async fn event(&mut self) -> Result<Event> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            let message = self.read().await?;
+            assert_eq!(message["method"], "event", "{}", message);
+            self.events
+                .push_back(serde_json::from_value(message["params"].clone())?);
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn daemons_share_sessions_over_their_sockets() -> Result<()> {
+    let dir = TempDir::new("daemon");
+    let (alice, alice_socket) = start_daemon(&dir, "alice")?;
+    let (bob, bob_socket) = start_daemon(&dir, "bob")?;
+    let mut alice_client = Client::connect(&alice_socket).await?;
+    let mut bob_client = Client::connect(&bob_socket).await?;
+    alice_client.call("subscribe", Value::Null).await?;
+    bob_client.call("subscribe", Value::Null).await?;
+
+    let pair = common::vnet_pair().await?;
+    let (alice_signaler, bob_signaler) = MemorySignaler::pair();
+    let alice_session = alice
+        .attach(
+            Arc::clone(&pair.offerer),
+            Box::new(alice_signaler),
+            options(),
+            true,
+        )
+        .await;
+    let bob_session = bob
+        .attach(
+            Arc::clone(&pair.answerer),
+            Box::new(bob_signaler),
+            options(),
+            false,
+        )
+        .await;
+
+    // Both ends report the peer once the identities have been checked
+    let Event::Connected {
+        session,
+        fingerprint,
+        ..
+    } = alice_client.event().await?
+    else {
+        panic!("alice didn't connect");
+    };
+    assert_eq!(session, alice_session);
+    let Event::Connected { session, .. } = bob_client.event().await? else {
+        panic!("bob didn't connect");
+    };
+    assert_eq!(session, bob_session);
+    let listed: Vec<SessionInfo> =
+        serde_json::from_value(alice_client.call("list_sessions", Value::Null).await?)?;
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].state, SessionState::Connected);
+    assert_eq!(listed[0].fingerprint, Some(fingerprint));
+
+    // A second client shares the same sessions
+    let mut script = Client::connect(&alice_socket).await?;
+    let sent = script
+        .call(
+            "send_message",
+            json!({ "session": alice_session, "text": "hello bob" }),
+        )
+        .await?;
+    let Event::Message {
+        session, id, text, ..
+    } = bob_client.event().await?
+    else {
+        panic!("no message for bob");
+    };
+    assert_eq!((session, text.as_str()), (bob_session, "hello bob"));
+    assert_eq!(sent["id"], id.as_str());
+
+    // Files are offered, and only sent once accepted
+    let contents: Vec<u8> = (0..200_000).map(|_| rand::random::<u8>()).collect();
+    let path = dir.0.join("notes.bin");
+    std::fs::write(&path, &contents)?;
+    let downloads = dir.0.join("bob").join("downloads");
+    let accept = async {
+        let Event::FileOffered {
+            session,
+            id,
+            name,
+            size,
+        } = bob_client.event().await?
+        else {
+            panic!("no file offered to bob");
+        };
+        assert_eq!((name.as_str(), size), ("notes.bin", contents.len() as u64));
+        assert!(!downloads.exists(), "written before it was accepted");
+        bob_client
+            .call("accept_file", json!({ "session": session, "id": id }))
+            .await
+    };
+    let (sent, _) = tokio::try_join!(
+        script.call(
+            "send_file",
+            json!({ "session": alice_session, "path": path }),
+        ),
+        accept
+    )?;
+    assert_eq!(sent["size"], contents.len());
+    let Event::FileReceived { name, path, .. } = bob_client.event().await? else {
+        panic!("no file for bob");
+    };
+    assert_eq!(name, "notes.bin");
+    assert!(path.starts_with(&downloads));
+    assert_eq!(std::fs::read(&path)?, contents);
+
+    // A declined file is never sent
+    let decline = async {
+        let Event::FileOffered { session, id, .. } = bob_client.event().await? else {
+            panic!("no file offered to bob");
+        };
+        bob_client
+            .call("decline_file", json!({ "session": session, "id": id }))
+            .await
+    };
+    let (declined, _) = tokio::join!(
+        script.call(
+            "send_file",
+            json!({ "session": alice_session, "path": dir.0.join("notes.bin") }),
+        ),
+        decline
+    );
+    let err = declined.expect_err("sent anyway").to_string();
+    assert!(err.contains("declined notes.bin"), "{}", err);
+
+    // Nor is one over the limit, which isn't even offered to bob
+    let big = dir.0.join("big.bin");
+    std::fs::File::create(&big)?.set_len(MAX_FILE_SIZE + 1)?;
+    let err = script
+        .call(
+            "send_file",
+            json!({ "session": alice_session, "path": big }),
+        )
+        .await
+        .expect_err("sent anyway")
+        .to_string();
+    assert!(
+        err.contains("more than the 1048576 bytes accepted"),
+        "{}",
+        err
+    );
+    let Event::FileFailed { reason, .. } = bob_client.event().await? else {
+        panic!("bob wasn't told of the refused file");
+    };
+    assert!(reason.contains("more than"), "{}", reason);
+    assert_eq!(std::fs::read_dir(&downloads)?.count(), 1);
+
+    // Closing one end is seen by both
+    script
+        .call("close", json!({ "session": alice_session }))
+        .await?;
+    assert!(matches!(alice_client.event().await?, Event::Closed { .. }));
+    assert!(
+        matches!(bob_client.event().await?, Event::Closed { session, .. } if session == bob_session)
+    );
+    assert_eq!(script.call("list_sessions", Value::Null).await?, json!([]));
+
+    pair.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn bad_requests_get_json_rpc_errors() -> Result<()> {
+    let dir = TempDir::new("daemon");
+    let (_daemon, socket) = start_daemon(&dir, "alice")?;
+    let mut client = Client::connect(&socket).await?;
+
+    client.send_line("{not json").await?;
+    assert_eq!(client.response().await?["error"]["code"], -32700);
+    client
+        .send_line(r#"{"jsonrpc":"1.0","id":7,"method":"list_sessions"}"#)
+        .await?;
+    let response = client.response().await?;
+    assert_eq!(response["error"]["code"], -32600);
+    assert_eq!(response["id"], 7);
+
+    let response = client.request("fly", Value::Null).await?;
+    assert_eq!(response["error"]["code"], -32601);
+    let response = client
+        .request("connect", json!({ "role": "sideways", "rendezvous": "x" }))
+        .await?;
+    assert_eq!(response["error"]["code"], -32602);
+
+    // Failures carry the reason
+    let response = client
+        .request("send_message", json!({ "session": 42, "text": "hi" }))
+        .await?;
+    assert_eq!(response["error"]["code"], -32000);
+    assert_eq!(response["error"]["message"], "No session 42");
+    let response = client
+        .request(
+            "connect",
+            json!({ "role": "answer", "rendezvous": "http://127.0.0.1:1" }),
+        )
+        .await?;
+    let message = response["error"]["message"].as_str().unwrap_or_default();
+    assert!(message.contains("code"), "{}", message);
+
+    // Notifications are carried out without a response
+    client
+        .send_line(r#"{"jsonrpc":"2.0","method":"list_sessions"}"#)
+        .await?;
+    assert_eq!(client.call("list_sessions", Value::Null).await?, json!([]));
+
+    // And a second daemon can't take over the socket
+    let err = daemon::bind(&socket).unwrap_err();
+    assert!(err.to_string().contains("already listening"), "{}", err);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sessions_that_never_connect_are_dropped() -> Result<()> {
+    let dir = TempDir::new("daemon");
+    let (daemon, _socket) = start_daemon(&dir, "alice")?;
+    let mut events = daemon.subscribe();
+
+    // An answerer whose offer never comes, as with a wormhole code nobody uses
+    let pair = common::vnet_pair().await?;
+    let (signaler, _silent) = MemorySignaler::pair();
+    let mut options = options();
+    options.timeout = Duration::from_secs(1);
+    let session = daemon
+        .attach(
+            Arc::clone(&pair.answerer),
+            Box::new(signaler),
+            options,
+            false,
+        )
+        .await;
+
+    let Event::Closed {
+        session: closed,
+        reason,
+    } = tokio::time::timeout(TIMEOUT, events.recv()).await??
+    else {
+        panic!("expected the session to close");
+    };
+    assert_eq!(closed, session);
+    assert!(reason.contains("within 1 seconds"), "{}", reason);
+    assert!(daemon.sessions().await.is_empty());
+
+    pair.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn sockets_are_kept_private() -> Result<()> {
+    let dir = TempDir::new("daemon");
+
+    // A missing directory is created for this user alone
+    let socket = dir.0.join("run").join("control.sock");
+    let listener = daemon::bind(&socket)?;
+    let mode = std::fs::metadata(dir.0.join("run"))?.permissions().mode();
+    assert_eq!(mode & 0o777, 0o700);
+    assert_eq!(
+        std::fs::metadata(&socket)?.permissions().mode() & 0o777,
+        0o600
+    );
+    drop(listener);
+
+    // A socket left behind is replaced, anything else at the path is not
+    drop(daemon::bind(&socket)?);
+    let file = dir.0.join("run").join("notes.txt");
+    std::fs::write(&file, "keep me")?;
+    let err = daemon::bind(&file).expect_err("bound over a file");
+    assert!(err.to_string().contains("isn't a socket"), "{}", err);
+    assert_eq!(std::fs::read_to_string(&file)?, "keep me");
+
+    // Nor does the socket go where others could swap it out
+    let shared = dir.0.join("shared");
+    std::fs::create_dir(&shared)?;
+    std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o777))?;
+    let err = daemon::bind(&shared.join("control.sock")).expect_err("bound in a shared directory");
+    assert!(err.to_string().contains("other users"), "{}", err);
+    Ok(())
+}
@@ -0,0 +1,242 @@
+// LAN discovery: peers on this machine find each other by broadcast on
+// loopback, then run the offer/answer exchange over TCP. Announcements are
+// unsigned, so the identity a peer announced is checked once connected.
+
+mod common;
+
+use anyhow::Result;
+use common::TIMEOUT;
+use modulate_comms::app::{self, SessionOptions};
+use modulate_comms::connection::ChannelSpec;
+use modulate_comms::discovery::{self, Announcement, DiscoveredPeer, Discovery};
+use modulate_comms::identity::Identity;
+use modulate_comms::signaling::{Signaler, TcpSignaler};
+use modulate_comms::store::{SharedStore, Store};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+
+// A UDP port nothing else is using, so parallel tests don't hear each other
+fn free_port() -> u16 {
+    std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+// On every interface, as in `discover`, since the peer may be heard on any
+async fn listener() -> Result<(TcpListener, u16)> {
+    let listener = TcpListener::bind("0.0.0.0:0").await?;
+    let port = listener.local_addr()?.port();
+    Ok((listener, port))
+}
+
+// Wait until `discovery` has heard the peer announcing as `them`
+async fn find(discovery: &Discovery, them: &Announcement) -> Result<DiscoveredPeer> {
+    let start = Instant::now();
+    loop {
+        let peers = discovery.peers().await;
+        if let Some(peer) = peers.iter().find(|p| p.announcement == *them) {
+            assert_eq!(peers.len(), 1, "{:?}", peers);
+            return Ok(peer.clone());
+        }
+        anyhow::ensure!(
+            start.elapsed() < TIMEOUT,
+            "{} was never heard",
+            them.label()
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_find_each_other_and_signal_over_tcp() -> Result<()> {
+    let port = free_port();
+    let (_alice_listener, alice_port) = listener().await?;
+    let (bob_listener, bob_port) = listener().await?;
+    let alice = Announcement::new(
+        "alice".into(),
+        Identity::generate().fingerprint(),
+        alice_port,
+    );
+    let bob = Announcement::new("bob".into(), Identity::generate().fingerprint(), bob_port);
+
+    // Both are listed on the other side, neither sees itself
+    let alice_discovery = Discovery::start(alice.clone(), port).await?;
+    let bob_discovery = Discovery::start(bob.clone(), port).await?;
+    let bob_seen = find(&alice_discovery, &bob).await?;
+    find(&bob_discovery, &alice).await?;
+    assert_eq!(bob_seen.address.port(), bob_port);
+
+    // Alice picks bob, who hears who is asking and accepts
+    let accept = async {
+        let (stream, _) = bob_listener.accept().await?;
+        let mut signaler = TcpSignaler::new(stream);
+        assert_eq!(discovery::receive_hello(&mut signaler).await?, alice);
+        signaler.send(&bob.encode()?).await?;
+        anyhow::Ok(signaler)
+    };
+    let (mut alice_signaler, mut bob_signaler) =
+        tokio::try_join!(discovery::connect_to(&bob_seen, &alice, TIMEOUT), accept)?;
+
+    // The offer and answer cross the TCP connection as they would in a session
+    let pair = common::vnet_pair().await?;
+    let specs = ChannelSpec::with_overrides(&[]);
+    tokio::try_join!(
+        app::connect(
+            Arc::clone(&pair.offerer),
+            specs.clone(),
+            &mut alice_signaler,
+            true,
+            TIMEOUT,
+        ),
+        app::connect(
+            Arc::clone(&pair.answerer),
+            specs,
+            &mut bob_signaler,
+            false,
+            TIMEOUT,
+        ),
+    )?;
+    assert_eq!(
+        pair.offerer.connection_state(),
+        RTCPeerConnectionState::Connected
+    );
+
+    pair.close().await;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn declined_connections_are_reported() -> Result<()> {
+    let (bob_listener, bob_port) = listener().await?;
+    let alice = Announcement::new("alice".into(), Identity::generate().fingerprint(), 1);
+    let bob = Announcement::new("bob".into(), Identity::generate().fingerprint(), bob_port);
+
+    let port = free_port();
+    let _bob_discovery = Discovery::start(bob.clone(), port).await?;
+    let alice_discovery = Discovery::start(alice.clone(), port).await?;
+    let bob_seen = find(&alice_discovery, &bob).await?;
+
+    // Bob hears who is asking, then hangs up instead of accepting
+    let decline = async {
+        let (stream, _) = bob_listener.accept().await?;
+        let mut signaler = TcpSignaler::new(stream);
+        discovery::receive_hello(&mut signaler).await?;
+        anyhow::Ok(())
+    };
+
+    let (connected, declined) = tokio::time::timeout(TIMEOUT, async {
+        tokio::join!(discovery::connect_to(&bob_seen, &alice, TIMEOUT), decline)
+    })
+    .await?;
+    declined?;
+    let err = format!("{:#}", connected.err().expect("connected anyway"));
+    assert!(err.contains("declined"), "{}", err);
+    Ok(())
+}
+
+fn announced(fingerprint: Option<String>) -> SessionOptions {
+    SessionOptions {
+        timeout: TIMEOUT,
+        password: None,
+        to: None,
+        name: None,
+        channels: Vec::new(),
+        rendezvous: None,
+        code: None,
+        stats_interval: None,
+        announced: fingerprint,
+    }
+}
+
+// The handshake on both ends, the offerer expecting the answerer to be
+// `expected`. Returns the offerer's result.
+async fn handshake_expecting(answerer_store: &SharedStore, expected: String) -> Result<()> {
+    let (pair, mut offerer, mut answerer) = common::connected_messaging_pair().await?;
+    let offerer_store: SharedStore = Arc::new(Mutex::new(Store::ephemeral()));
+    let (expecting, not_expecting) = (announced(Some(expected)), announced(None));
+    let (offered, _) = tokio::join!(
+        app::handshake(
+            &pair.offerer,
+            &offerer.channel,
+            &mut offerer.incoming,
+            &expecting,
+            true,
+            &offerer_store,
+        ),
+        // Ends with the connection when the offerer hangs up
+        tokio::time::timeout(
+            TIMEOUT,
+            app::handshake(
+                &pair.answerer,
+                &answerer.channel,
+                &mut answerer.incoming,
+                &not_expecting,
+                false,
+                answerer_store,
+            )
+        ),
+    );
+    pair.close().await;
+    offered.map(|_| ())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn announced_identities_are_checked_once_connected() -> Result<()> {
+    let bob: SharedStore = Arc::new(Mutex::new(Store::ephemeral()));
+    let bob_fingerprint = bob.lock().await.identity().fingerprint();
+    handshake_expecting(&bob, bob_fingerprint.clone()).await?;
+
+    // Mallory announcing Bob's identity is found out
+    let mallory: SharedStore = Arc::new(Mutex::new(Store::ephemeral()));
+    let err = handshake_expecting(&mallory, bob_fingerprint)
+        .await
+        .expect_err("Mallory passed as Bob");
+    assert!(err.to_string().contains("it announced"), "{}", err);
+    Ok(())
+}
+
+#[test]
+fn only_our_announcements_are_accepted() {
+    let alice = Announcement::new("alice".into(), Identity::generate().fingerprint(), 4000);
+    let encoded = alice.encode().unwrap();
+    assert_eq!(Announcement::decode(encoded.as_bytes()).unwrap(), alice);
+
+    assert!(Announcement::decode(b"M-SEARCH * HTTP/1.1").is_err());
+    let other_app = encoded.replace("modulate-comms", "something-else");
+    assert!(Announcement::decode(other_app.as_bytes()).is_err());
+    let bad_identity = encoded.replace(&alice.fingerprint, "abcd");
+    assert!(Announcement::decode(bad_identity.as_bytes()).is_err());
+}
+
+#[tokio::test]
+async fn tcp_signaling_frames_survive_and_are_bounded() -> Result<()> {
+    let (listener, port) = listener().await?;
+    let (client, server) = tokio::join!(TcpStream::connect(("127.0.0.1", port)), listener.accept());
+    let mut client = TcpSignaler::new(client?);
+    let mut server = TcpSignaler::new(server?.0);
+
+    // Multi-line blobs arrive whole
+    let blob = "line one\nline two\n".repeat(1000);
+    client.send(&blob).await?;
+    assert_eq!(server.receive().await?, blob);
+
+    // A peer claiming an enormous frame is cut off before it is read
+    let (listener, port) = self::listener().await?;
+    let (raw, accepted) = tokio::join!(TcpStream::connect(("127.0.0.1", port)), listener.accept());
+    let mut raw = raw?;
+    raw.write_u32(u32::MAX).await?;
+    let err = TcpSignaler::new(accepted?.0).receive().await.unwrap_err();
+    assert!(err.to_string().contains("too large"), "{}", err);
+
+    // Hanging up is reported as such
+    drop(client);
+    let err = server.receive().await.unwrap_err();
+    assert!(err.to_string().contains("hung up"), "{}", err);
+    Ok(())
+}
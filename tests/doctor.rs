@@ -0,0 +1,219 @@
+// `doctor` against a local STUN stand-in that plays a NAT: it reports a
+// made-up public address, mapped per destination or not, and only lets an
+// answer through if the NAT's filtering would, given which of the stand-in's
+// addresses the client has sent to.
+
+use anyhow::Result;
+use modulate_comms::connection::IceSettings;
+use modulate_comms::doctor::{
+    self, DoctorReport, Filtering, LocalAddress, Mapping, Outlook, ServerCheck, ServerOutcome,
+};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use webrtc::stun::addr::MappedAddress;
+use webrtc::stun::attributes::{ATTR_CHANGE_REQUEST, ATTR_OTHER_ADDRESS};
+use webrtc::stun::message::{Message, BINDING_REQUEST, BINDING_SUCCESS};
+use webrtc::stun::xoraddr::XorMappedAddress;
+
+const PUBLIC_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+
+#[derive(Clone, Copy)]
+struct StandIn {
+    // Report the client's real address, as if there were no NAT
+    no_nat: bool,
+    // A new public port for each of the stand-in's addresses
+    symmetric: bool,
+    filtering: Filtering,
+}
+
+// Start the stand-in on 127.0.0.1 and 127.0.0.2, two ports each, and return
+// the primary address. Sockets 0 and 1 share an address, as do 2 and 3.
+async fn spawn_stand_in(behaviour: StandIn) -> Result<SocketAddr> {
+    let primary = UdpSocket::bind("127.0.0.1:0").await?;
+    let alternate_port = UdpSocket::bind("127.0.0.1:0").await?;
+    let (port, other_port) = (
+        primary.local_addr()?.port(),
+        alternate_port.local_addr()?.port(),
+    );
+    let sockets = Arc::new([
+        primary,
+        alternate_port,
+        UdpSocket::bind(("127.0.0.2", port)).await?,
+        UdpSocket::bind(("127.0.0.2", other_port)).await?,
+    ]);
+    let other_address: SocketAddr = ([127, 0, 0, 2], other_port).into();
+    // The sockets each client has sent to, which the NAT has opened up to
+    let sent_to: Arc<Mutex<HashMap<SocketAddr, HashSet<usize>>>> = Arc::default();
+
+    for index in 0..sockets.len() {
+        let sockets = Arc::clone(&sockets);
+        let sent_to = Arc::clone(&sent_to);
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1500];
+            while let Ok((n, from)) = sockets[index].recv_from(&mut buffer).await {
+                let mut request = Message::new();
+                request.raw = buffer[..n].to_vec();
+                if request.decode().is_err() || request.typ != BINDING_REQUEST {
+                    continue;
+                }
+                let change = request
+                    .get(ATTR_CHANGE_REQUEST)
+                    .map(|value| value[3])
+                    .unwrap_or(0);
+                let (change_ip, change_port) = (change & 0x04 != 0, change & 0x02 != 0);
+                // Answer from the address the client asked for
+                let from_index = index ^ (change_ip as usize * 2) ^ (change_port as usize);
+                let let_through = {
+                    let mut sent_to = sent_to.lock().unwrap();
+                    let sent_to = sent_to.entry(from).or_default();
+                    sent_to.insert(index);
+                    match behaviour.filtering {
+                        Filtering::EndpointIndependent => true,
+                        Filtering::AddressDependent => {
+                            sent_to.iter().any(|&i| i / 2 == from_index / 2)
+                        }
+                        _ => sent_to.contains(&from_index),
+                    }
+                };
+                if !let_through {
+                    continue;
+                }
+
+                let mapped = if behaviour.no_nat {
+                    from
+                } else if behaviour.symmetric {
+                    SocketAddr::new(PUBLIC_IP, 40000 + index as u16)
+                } else {
+                    SocketAddr::new(PUBLIC_IP, 40000)
+                };
+                let mut response = Message::new();
+                response
+                    .build(&[
+                        Box::new(request.transaction_id),
+                        Box::new(BINDING_SUCCESS),
+                        Box::new(XorMappedAddress {
+                            ip: mapped.ip(),
+                            port: mapped.port(),
+                        }),
+                    ])
+                    .unwrap();
+                MappedAddress {
+                    ip: other_address.ip(),
+                    port: other_address.port(),
+                }
+                .add_to_as(&mut response, ATTR_OTHER_ADDRESS)
+                .unwrap();
+                let _ = sockets[from_index].send_to(&response.raw, from).await;
+            }
+        });
+    }
+    Ok(sockets[0].local_addr()?)
+}
+
+async fn probe(behaviour: StandIn) -> Result<doctor::NatReport> {
+    let server = spawn_stand_in(behaviour).await?;
+    let local = doctor::local_addresses();
+    Ok(doctor::probe_nat(&[server], &local)
+        .await?
+        .expect("an answer"))
+}
+
+#[tokio::test]
+async fn nat_mapping_and_filtering_are_inferred() -> Result<()> {
+    let full_cone = probe(StandIn {
+        no_nat: false,
+        symmetric: false,
+        filtering: Filtering::EndpointIndependent,
+    })
+    .await?;
+    assert_eq!(full_cone.public, [SocketAddr::new(PUBLIC_IP, 40000)]);
+    assert_eq!(full_cone.mapping, Mapping::EndpointIndependent);
+    assert_eq!(full_cone.filtering, Filtering::EndpointIndependent);
+    assert_eq!(full_cone.outlook(), Outlook::Direct);
+
+    let restricted = probe(StandIn {
+        no_nat: false,
+        symmetric: false,
+        filtering: Filtering::AddressDependent,
+    })
+    .await?;
+    assert_eq!(restricted.mapping, Mapping::EndpointIndependent);
+    assert_eq!(restricted.filtering, Filtering::AddressDependent);
+    assert_eq!(restricted.outlook(), Outlook::MostPeers);
+
+    let symmetric = probe(StandIn {
+        no_nat: false,
+        symmetric: true,
+        filtering: Filtering::AddressAndPortDependent,
+    })
+    .await?;
+    assert_eq!(symmetric.mapping, Mapping::EndpointDependent);
+    assert_eq!(symmetric.filtering, Filtering::AddressAndPortDependent);
+    assert_eq!(symmetric.outlook(), Outlook::Relay);
+
+    // Loopback is one of our own addresses, so the stand-in echoing it back
+    // means there's no NAT at all
+    let open = probe(StandIn {
+        no_nat: true,
+        symmetric: false,
+        filtering: Filtering::AddressAndPortDependent,
+    })
+    .await?;
+    assert_eq!(open.mapping, Mapping::NoNat);
+    assert_eq!(open.outlook(), Outlook::Direct);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn servers_are_checked_by_gathering_candidates() -> Result<()> {
+    let server = spawn_stand_in(StandIn {
+        no_nat: false,
+        symmetric: false,
+        filtering: Filtering::EndpointIndependent,
+    })
+    .await?;
+    let url = format!("stun:{}", server);
+    let check = doctor::check_server(&url, &IceSettings::default()).await;
+    assert_eq!(check.address, Some(server));
+    let ServerOutcome::Candidates(ref candidates) = check.outcome else {
+        panic!("no candidates from {}: {:?}", url, check.outcome);
+    };
+    assert_eq!(candidates[0].0, "203.0.113.7:40000");
+
+    let check = doctor::check_server("stun:", &IceSettings::default()).await;
+    assert!(
+        matches!(check.outcome, ServerOutcome::Unresolved(_)),
+        "{:?}",
+        check.outcome
+    );
+    Ok(())
+}
+
+#[test]
+fn advice_asks_for_turn_when_nothing_answers() {
+    let report = DoctorReport {
+        local: vec![LocalAddress {
+            interface: "eth0".to_string(),
+            ip: [192, 168, 1, 20].into(),
+        }],
+        servers: vec![ServerCheck {
+            url: "turn:turn.example.com:3478".to_string(),
+            is_turn: true,
+            address: None,
+            outcome: ServerOutcome::NoResponse,
+        }],
+        nat: None,
+    };
+    let advice = report.advice();
+    assert!(
+        advice[0].starts_with("No STUN server resolved"),
+        "{:?}",
+        advice
+    );
+    assert!(advice
+        .iter()
+        .any(|line| line.starts_with("TURN server turn:turn.example.com:3478 gave no relay")));
+    assert!(advice.last().unwrap().contains("contacts add"));
+}
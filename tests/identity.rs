@@ -0,0 +1,55 @@
+// Identity announcements are signed over both DTLS fingerprints of the
+// session, so a Hello captured in one session is refused in another.
+
+mod common;
+
+use anyhow::Result;
+use common::TIMEOUT;
+use modulate_comms::connection;
+use modulate_comms::identity::{self, Identity};
+use std::sync::Arc;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn hello_from_another_session_is_refused() -> Result<()> {
+    let alice = Identity::generate();
+
+    // Capture Alice's signed Hello in a first session
+    let (first, mut offerer, mut answerer) = common::connected_messaging_pair().await?;
+    let offerer_pc = Arc::clone(&first.offerer);
+    let exchange = tokio::spawn(async move {
+        identity::exchange(&offerer_pc, &offerer.channel, &mut offerer.incoming, &alice).await
+    });
+    let captured = tokio::time::timeout(TIMEOUT, answerer.incoming.recv())
+        .await?
+        .expect("answerer channel closed");
+    exchange.abort();
+    first.close().await;
+
+    // Mallory replays it to Bob in a session of her own
+    let (second, mut bob_side, mut mallory) = common::connected_messaging_pair().await?;
+    let bob = Identity::generate();
+    let mallory_dc = connection::wait_for_open(&mallory.channel, TIMEOUT).await?;
+    mallory_dc
+        .send_text(String::from_utf8(captured.data.to_vec())?)
+        .await?;
+    let err = identity::exchange(
+        &second.offerer,
+        &bob_side.channel,
+        &mut bob_side.incoming,
+        &bob,
+    )
+    .await
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("does not match this session"),
+        "{:#}",
+        err
+    );
+    // Bob's own Hello went out before the check, and that's all Mallory got
+    tokio::time::timeout(TIMEOUT, mallory.incoming.recv())
+        .await?
+        .expect("no Hello from Bob");
+
+    second.close().await;
+    Ok(())
+}
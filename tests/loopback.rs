@@ -0,0 +1,163 @@
+// Offerer and answerer in one process, connected through the real signaling
+// code over an in-memory signaler and the virtual network.
+
+mod common;
+
+use anyhow::Result;
+use common::TIMEOUT;
+use modulate_comms::connection::{self, ChannelSpec, MESSAGING_CHANNEL};
+use modulate_comms::identity::{self, Identity};
+use modulate_comms::protocol::WireMessage;
+use modulate_comms::sdp;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_connect_through_signaler() -> Result<()> {
+    let session = common::connected_pair().await?;
+
+    assert_eq!(
+        session.pair.offerer.connection_state(),
+        RTCPeerConnectionState::Connected
+    );
+    assert_eq!(
+        session.pair.answerer.connection_state(),
+        RTCPeerConnectionState::Connected
+    );
+
+    // Every channel opens on both sides
+    for channels in [&session.offer_channels, &session.answer_channels] {
+        for spec in ChannelSpec::with_overrides(&[]) {
+            connection::wait_for_open(&channels[&spec.label].channel, TIMEOUT).await?;
+        }
+    }
+
+    session.pair.close().await;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn blobs_parse_as_the_right_description() -> Result<()> {
+    let session = common::connected_pair().await?;
+
+    let offer = sdp::parse_offer(&session.offer_blob)?;
+    assert_eq!(offer.description.sdp_type, RTCSdpType::Offer);
+    let nonce = &offer.session.as_ref().unwrap().nonce;
+    let answer = sdp::parse_answer(&session.answer_blob, nonce)?;
+    assert_eq!(answer.description.sdp_type, RTCSdpType::Answer);
+
+    // The answer carries the offer's nonce back, and is only accepted with it
+    assert_eq!(&answer.session.as_ref().unwrap().nonce, nonce);
+    let err = sdp::parse_answer(&session.answer_blob, "0123").unwrap_err();
+    assert!(err.to_string().contains("different offer"), "{}", err);
+
+    // Blobs are wrapped to fit through a terminal's line buffer
+    for blob in [&session.offer_blob, &session.answer_blob] {
+        assert!(blob.lines().count() > 3);
+        assert!(blob.lines().all(|line| line.len() <= 80), "{}", blob);
+    }
+
+    // Each parser only accepts its own kind of blob
+    assert!(sdp::parse_answer(&session.offer_blob, nonce).is_err());
+    assert!(sdp::parse_offer(&session.answer_blob).is_err());
+    assert!(sdp::parse_offer("").is_err());
+    assert!(sdp::parse_offer("OFFER:{not json").is_err());
+
+    session.pair.close().await;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn candidates_travel_outside_the_description() -> Result<()> {
+    let session = common::connected_pair().await?;
+
+    // The descriptions are created before gathering, so the connection can
+    // only have come up through the candidates sent alongside them
+    for blob in [
+        sdp::SignalingBlob::parse(&session.offer_blob)?,
+        sdp::SignalingBlob::parse(&session.answer_blob)?,
+    ] {
+        assert!(!blob.description.sdp.contains("a=candidate"));
+        assert!(
+            blob.candidates
+                .iter()
+                .any(|c| c.candidate.contains(" 1.2.3.")),
+            "no vnet candidate in {:?}",
+            blob.candidates
+        );
+    }
+    assert_eq!(
+        session.pair.offerer.connection_state(),
+        RTCPeerConnectionState::Connected
+    );
+
+    // Every candidate is attached to the description it arrived with
+    let offer = sdp::parse_offer(&session.offer_blob)?;
+    let attached = sdp::attach_candidates(offer.description, &offer.candidates)?;
+    for candidate in &offer.candidates {
+        assert!(attached.sdp.contains(&format!("a={}", candidate.candidate)));
+    }
+
+    // Unreadable candidates are rejected, naming each bad entry
+    let garbage = "ICE_CANDIDATES:\n[\"{broken\"]\n==== END OF SECTION TO COPY ====";
+    let err = sdp::parse_candidates(garbage).unwrap_err();
+    assert!(format!("{:#}", err).contains("candidate 0:"), "{:#}", err);
+
+    session.pair.close().await;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chat_is_delivered_end_to_end() -> Result<()> {
+    let mut session = common::connected_pair().await?;
+    let mut offer_chat = session.offer_channels.remove(MESSAGING_CHANNEL).unwrap();
+    let mut answer_chat = session.answer_channels.remove(MESSAGING_CHANNEL).unwrap();
+
+    // Identities are exchanged first, as in a real session
+    let offer_identity = Identity::generate();
+    let answer_identity = Identity::generate();
+    let (seen_by_offerer, seen_by_answerer) = tokio::try_join!(
+        identity::exchange(
+            &session.pair.offerer,
+            &offer_chat.channel,
+            &mut offer_chat.incoming,
+            &offer_identity,
+        ),
+        identity::exchange(
+            &session.pair.answerer,
+            &answer_chat.channel,
+            &mut answer_chat.incoming,
+            &answer_identity,
+        ),
+    )?;
+    assert_eq!(seen_by_offerer, answer_identity.fingerprint());
+    assert_eq!(seen_by_answerer, offer_identity.fingerprint());
+
+    // A message one way and a reply the other
+    let hello = WireMessage::Chat {
+        id: "a1b2c3d4e5f60718".to_string(),
+        text: "hello over the loopback".to_string(),
+        reply_to: None,
+    };
+    let dc = connection::wait_for_open(&offer_chat.channel, TIMEOUT).await?;
+    hello.send(&dc).await?;
+    let msg = tokio::time::timeout(TIMEOUT, answer_chat.incoming.recv())
+        .await?
+        .expect("answerer channel closed");
+    assert_eq!(WireMessage::decode(&msg.data)?, hello);
+
+    let reply = WireMessage::Chat {
+        id: "d4e5f60718293a4b".to_string(),
+        text: "hello back".to_string(),
+        reply_to: Some("a1b2c3d4e5f60718".to_string()),
+    };
+    let dc = connection::wait_for_open(&answer_chat.channel, TIMEOUT).await?;
+    reply.send(&dc).await?;
+    let msg = tokio::time::timeout(TIMEOUT, offer_chat.incoming.recv())
+        .await?
+        .expect("offerer channel closed");
+    assert_eq!(WireMessage::decode(&msg.data)?, reply);
+
+    session.pair.close().await;
+    Ok(())
+}
@@ -0,0 +1,159 @@
+// Pings over the typing channel: round trip times, the ping-style summary,
+// pongs getting past a backlog on the messaging channel, and the keepalive
+// noticing a peer that went quiet, on the virtual network.
+
+mod common;
+
+use anyhow::Result;
+use bytes::Bytes;
+use common::TIMEOUT;
+use modulate_comms::connection::{self, DataChannelHandle, MESSAGING_CHANNEL, TYPING_CHANNEL};
+use modulate_comms::ping::{self, PingSummary, Pinger};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+
+// Connected peers where the offerer pings on the typing channel and the
+// answerer only answers, with the offerer's messaging channel left for
+// filling up
+async fn ping_pair() -> Result<(common::VnetPair, Arc<Pinger>, Arc<RTCDataChannel>)> {
+    let mut connected = common::connected_pair().await?;
+    let open = |channels: &mut HashMap<String, DataChannelHandle>, label: &str| {
+        let handle = channels.remove(label).expect("channel was set up");
+        async move {
+            let dc = connection::wait_for_open(&handle.channel, TIMEOUT).await?;
+            anyhow::Ok((dc, handle.incoming))
+        }
+    };
+    let (offerer_dc, offerer_incoming) =
+        open(&mut connected.offer_channels, TYPING_CHANNEL).await?;
+    let (answerer_dc, answerer_incoming) =
+        open(&mut connected.answer_channels, TYPING_CHANNEL).await?;
+    let (messaging, _) = open(&mut connected.offer_channels, MESSAGING_CHANNEL).await?;
+
+    let pinger = Arc::new(Pinger::new(Arc::clone(&offerer_dc)));
+    // Only pings and pongs are sent, so nothing is left over for the queues
+    // the interceptors return
+    ping::intercept(offerer_incoming, offerer_dc, Some(Arc::clone(&pinger)));
+    ping::intercept(answerer_incoming, answerer_dc, None);
+    Ok((connected.pair, pinger, messaging))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pings_are_answered_and_summed_up() -> Result<()> {
+    let (pair, pinger, _messaging) = ping_pair().await?;
+
+    let printed = Arc::new(Mutex::new(Vec::new()));
+    let lines = Arc::clone(&printed);
+    let summary = tokio::time::timeout(
+        TIMEOUT,
+        ping::run(&pinger, 3, "bob", move |line| {
+            lines.lock().unwrap().push(line.to_string())
+        }),
+    )
+    .await?;
+
+    assert_eq!(summary.sent, 3);
+    assert_eq!(summary.received, 3);
+    assert!(summary.min <= summary.avg && summary.avg <= summary.max);
+    assert_eq!(pinger.last_rtt().map(|rtt| rtt <= summary.max), Some(true));
+
+    let printed = printed.lock().unwrap().clone();
+    assert_eq!(printed[0], "PING bob: 3 pings on the typing channel");
+    assert!(printed[1].starts_with("pong from bob: seq=1 time="));
+    assert_eq!(printed[4], "--- bob ping statistics ---");
+    assert_eq!(printed[5], "3 pings sent, 3 pongs received, 0% loss");
+    assert!(printed[6].starts_with("rtt min/avg/max/jitter = "));
+
+    pair.close().await;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pongs_get_past_a_backlog_on_the_messaging_channel() -> Result<()> {
+    let (pair, pinger, messaging) = ping_pair().await?;
+
+    // Far more than the messaging channel gets through in a round trip
+    let chunk = Bytes::from(vec![0u8; 60 * 1024]);
+    for _ in 0..256 {
+        messaging.send(&chunk).await?;
+    }
+    assert!(messaging.buffered_amount().await > 0);
+
+    let rtt = tokio::time::timeout(TIMEOUT, pinger.ping(ping::PONG_TIMEOUT)).await??;
+    assert!(rtt.is_some(), "the pong was held up");
+    // The pong overtook data still waiting to go out
+    assert!(messaging.buffered_amount().await > 0);
+
+    pair.close().await;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keepalive_notices_a_silent_peer_before_ice_does() -> Result<()> {
+    let (pair, pinger, _messaging) = ping_pair().await?;
+    let (tx, mut notices) = mpsc::unbounded_channel();
+    let keepalive = ping::spawn_keepalive(Arc::clone(&pinger), move |line| {
+        let _ = tx.send(line.to_string());
+    });
+
+    // Once pongs are coming back, cut the link
+    tokio::time::timeout(TIMEOUT, async {
+        while pinger.silence().is_none() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+    pair.set_loss(100);
+    let cut = Instant::now();
+
+    let notice = tokio::time::timeout(TIMEOUT, notices.recv())
+        .await?
+        .unwrap();
+    assert!(notice.contains("hasn't answered"), "{}", notice);
+    // Well before ICE gives up on the connection
+    assert!(cut.elapsed() < ping::PEER_TIMEOUT + Duration::from_secs(2));
+    assert_ne!(
+        pair.offerer.connection_state(),
+        RTCPeerConnectionState::Failed
+    );
+
+    // And the peer is back once the link is
+    pair.set_loss(0);
+    let notice = tokio::time::timeout(TIMEOUT, notices.recv())
+        .await?
+        .unwrap();
+    assert_eq!(notice, "* The peer is answering again");
+
+    keepalive.abort();
+    pair.close().await;
+    Ok(())
+}
+
+#[test]
+fn summaries_report_loss_and_jitter() {
+    let ms = Duration::from_millis;
+    let summary = PingSummary::new(4, &[ms(10), ms(20), ms(15)]);
+    assert_eq!(summary.received, 3);
+    assert_eq!(summary.min, ms(10));
+    assert_eq!(summary.avg, ms(15));
+    assert_eq!(summary.max, ms(20));
+    // Consecutive differences of 10 and 5
+    assert_eq!(summary.jitter, Duration::from_micros(7500));
+    assert_eq!(
+        summary.lines(),
+        [
+            "4 pings sent, 3 pongs received, 25% loss",
+            "rtt min/avg/max/jitter = 10.000/15.000/20.000/7.500 ms",
+        ]
+    );
+
+    // Nothing came back, so there are no times to show
+    assert_eq!(
+        PingSummary::new(2, &[]).lines(),
+        ["2 pings sent, 0 pongs received, 100% loss"]
+    );
+}
@@ -0,0 +1,109 @@
+// Pipe mode: bytes in on one side come out on the other, with each side's
+// end of input passed on, over the virtual network.
+#![cfg(unix)]
+
+mod common;
+
+use anyhow::Result;
+use common::TIMEOUT;
+use modulate_comms::pipe::{self, PipeTotals};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn data_and_end_of_input_cross_both_ways() -> Result<()> {
+    let (pair, (offerer_dc, offerer_incoming), (answerer_dc, answerer_incoming)) =
+        common::open_messaging_pair().await?;
+
+    // Far more than fits in one frame one way, a short reply the other
+    let upload: Vec<u8> = (0..500_000).map(|_| rand::random::<u8>()).collect();
+    let reply = b"got it\n".to_vec();
+    let mut downloaded = Vec::new();
+    let mut replied = Vec::new();
+
+    let (offer_totals, answer_totals) = tokio::time::timeout(TIMEOUT, async {
+        tokio::try_join!(
+            pipe::pump(
+                &offerer_dc,
+                offerer_incoming,
+                upload.as_slice(),
+                &mut replied
+            ),
+            pipe::pump(
+                &answerer_dc,
+                answerer_incoming,
+                reply.as_slice(),
+                &mut downloaded
+            ),
+        )
+    })
+    .await??;
+
+    assert_eq!(downloaded, upload);
+    assert_eq!(replied, reply);
+    assert_eq!(
+        offer_totals,
+        PipeTotals {
+            sent: upload.len() as u64,
+            received: reply.len() as u64,
+        }
+    );
+    assert_eq!(answer_totals.received, upload.len() as u64);
+
+    pair.close().await;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn empty_input_still_ends_the_session() -> Result<()> {
+    let (pair, (offerer_dc, offerer_incoming), (answerer_dc, answerer_incoming)) =
+        common::open_messaging_pair().await?;
+
+    let mut offer_output = Vec::new();
+    let mut answer_output = Vec::new();
+    let (offer_totals, answer_totals) = tokio::time::timeout(TIMEOUT, async {
+        tokio::try_join!(
+            pipe::pump(
+                &offerer_dc,
+                offerer_incoming,
+                tokio::io::empty(),
+                &mut offer_output
+            ),
+            pipe::pump(
+                &answerer_dc,
+                answerer_incoming,
+                tokio::io::empty(),
+                &mut answer_output
+            ),
+        )
+    })
+    .await??;
+    assert_eq!(offer_totals, PipeTotals::default());
+    assert_eq!(answer_totals, PipeTotals::default());
+    assert!(offer_output.is_empty() && answer_output.is_empty());
+
+    pair.close().await;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_peer_hanging_up_early_is_an_error() -> Result<()> {
+    let (pair, (offerer_dc, offerer_incoming), _answerer) = common::open_messaging_pair().await?;
+
+    // Our input ends, but the peer goes away without ever ending theirs
+    let pumping = tokio::spawn(async move {
+        pipe::pump(
+            &offerer_dc,
+            offerer_incoming,
+            tokio::io::empty(),
+            tokio::io::sink(),
+        )
+        .await
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    pair.answerer.close().await?;
+
+    let err = tokio::time::timeout(TIMEOUT, pumping).await??.unwrap_err();
+    assert!(err.to_string().contains("Lost the connection"), "{}", err);
+
+    pair.close().await;
+    Ok(())
+}
@@ -0,0 +1,136 @@
+// Chat must survive packet loss: the messaging channel is fully reliable, so
+// every message arrives, in order, however many packets the network drops.
+
+mod common;
+
+use anyhow::Result;
+use common::TIMEOUT;
+use modulate_comms::connection::{
+    self, ChannelSpec, DataChannelHandle, ReliabilityProfile, MESSAGING_CHANNEL,
+};
+use modulate_comms::protocol::WireMessage;
+use std::sync::Arc;
+use std::time::Duration;
+
+const MESSAGES: usize = 200;
+
+// Open one channel on a fresh pair and return both ends once it is open
+async fn open_channel(
+    spec: ChannelSpec,
+) -> Result<(common::VnetPair, DataChannelHandle, DataChannelHandle)> {
+    let pair = common::vnet_pair().await?;
+    let label = spec.label.clone();
+
+    let mut offer_channels =
+        connection::setup_data_channels(Arc::clone(&pair.offerer), vec![spec.clone()], true)
+            .await?;
+    let mut answer_channels =
+        connection::setup_data_channels(Arc::clone(&pair.answerer), vec![spec], false).await?;
+
+    common::signal(&pair.offerer, &pair.answerer).await?;
+    common::wait_connected(&pair.offerer).await?;
+
+    let sender = offer_channels.remove(&label).unwrap();
+    let receiver = answer_channels.remove(&label).unwrap();
+    connection::wait_for_open(&sender.channel, TIMEOUT).await?;
+    connection::wait_for_open(&receiver.channel, TIMEOUT).await?;
+
+    Ok((pair, sender, receiver))
+}
+
+// Send numbered chat messages and collect the numbers that arrive before
+// `patience` passes without anything new
+async fn send_and_collect(
+    sender: &DataChannelHandle,
+    receiver: &mut DataChannelHandle,
+    patience: Duration,
+) -> Result<Vec<usize>> {
+    let dc = connection::wait_for_open(&sender.channel, TIMEOUT).await?;
+    for i in 0..MESSAGES {
+        let message = WireMessage::Chat {
+            id: format!("{:06}", i),
+            text: format!("message {}", i),
+            reply_to: None,
+        };
+        message.send(&dc).await?;
+    }
+
+    let mut received = Vec::new();
+    while received.len() < MESSAGES {
+        match tokio::time::timeout(patience, receiver.incoming.recv()).await {
+            Ok(Some(msg)) => match WireMessage::decode(&msg.data)? {
+                WireMessage::Chat { id, .. } => received.push(id.parse()?),
+                other => panic!("Unexpected message {:?}", other),
+            },
+            Ok(None) | Err(_) => break,
+        }
+    }
+    Ok(received)
+}
+
+async fn assert_no_loss(loss_percent: u32) -> Result<()> {
+    let (pair, sender, mut receiver) = open_channel(ChannelSpec::messaging()).await?;
+    pair.set_loss(loss_percent);
+
+    let received = send_and_collect(&sender, &mut receiver, TIMEOUT).await?;
+    pair.close().await;
+
+    let expected: Vec<usize> = (0..MESSAGES).collect();
+    assert_eq!(
+        received, expected,
+        "chat messages lost or reordered at {}% packet loss",
+        loss_percent
+    );
+    Ok(())
+}
+
+#[test]
+fn chat_channel_is_fully_reliable_by_default() {
+    let spec = ChannelSpec::messaging();
+    assert_eq!(spec.label, MESSAGING_CHANNEL);
+    assert_eq!(spec.profile, ReliabilityProfile::ReliableOrdered);
+
+    let init = spec.profile.init();
+    assert_eq!(init.ordered, Some(true));
+    assert_eq!(init.max_retransmits, None);
+    assert_eq!(init.max_packet_life_time, None);
+
+    // Channels without an explicit profile are reliable too
+    let extra: ChannelSpec = "files".parse().unwrap();
+    assert_eq!(extra.profile, ReliabilityProfile::ReliableOrdered);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chat_survives_10_percent_loss() -> Result<()> {
+    assert_no_loss(10).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chat_survives_25_percent_loss() -> Result<()> {
+    assert_no_loss(25).await
+}
+
+// The same network with an opted-in partial profile does drop messages,
+// which shows the loss above is real
+#[tokio::test(flavor = "multi_thread")]
+async fn partial_profile_drops_messages_under_loss() -> Result<()> {
+    let spec = ChannelSpec::new(
+        MESSAGING_CHANNEL,
+        ReliabilityProfile::MaxRetransmits {
+            retransmits: 0,
+            ordered: false,
+        },
+    );
+    let (pair, sender, mut receiver) = open_channel(spec).await?;
+    pair.set_loss(25);
+
+    let received = send_and_collect(&sender, &mut receiver, Duration::from_secs(3)).await?;
+    pair.close().await;
+
+    assert!(
+        received.len() < MESSAGES,
+        "expected losses without retransmissions, got all {} messages",
+        MESSAGES
+    );
+    Ok(())
+}
@@ -0,0 +1,134 @@
+// The bundled rendezvous server, run on localhost.
+
+use anyhow::Result;
+use modulate_comms::rendezvous::{self, Mailbox, Side, MAX_MAILBOXES};
+use reqwest::StatusCode;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+// A rendezvous server on a free local port, returning its URL
+pub async fn server(ttl: Duration) -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(rendezvous::serve(listener, ttl));
+    Ok(url)
+}
+
+#[tokio::test]
+async fn mailboxes_pass_messages_once() -> Result<()> {
+    let url = server(Duration::from_secs(60)).await?;
+
+    // Nameplates are the smallest free numbers
+    let offerer = Mailbox::allocate(&url).await?;
+    assert_eq!(offerer.nameplate(), "1");
+    let other = Mailbox::allocate(&url).await?;
+    assert_eq!(other.nameplate(), "2");
+
+    // Nothing until it's left, then the same message every time
+    let answerer = Mailbox::open(&format!("{}/", url), "1");
+    assert_eq!(answerer.get(Side::Offerer, 0).await?, None);
+    offerer.put(Side::Offerer, 0, "hello").await?;
+    assert_eq!(answerer.wait(Side::Offerer, 0).await?, "hello");
+    assert_eq!(
+        answerer.get(Side::Offerer, 0).await?.as_deref(),
+        Some("hello")
+    );
+
+    // Each side has its own messages, and none can be replaced
+    assert_eq!(offerer.get(Side::Answerer, 0).await?, None);
+    answerer.put(Side::Answerer, 0, "hi").await?;
+    let err = answerer
+        .put(Side::Answerer, 0, "replaced")
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("already used"), "{}", err);
+    assert_eq!(offerer.get(Side::Answerer, 0).await?.as_deref(), Some("hi"));
+
+    // Closing frees the nameplate
+    offerer.close().await?;
+    let err = answerer.get(Side::Offerer, 0).await.unwrap_err();
+    assert!(err.to_string().contains("may have expired"), "{}", err);
+    assert_eq!(Mailbox::allocate(&url).await?.nameplate(), "1");
+    Ok(())
+}
+
+#[tokio::test]
+async fn mailboxes_expire() -> Result<()> {
+    let url = server(Duration::from_millis(500)).await?;
+    let mailbox = Mailbox::allocate(&url).await?;
+    mailbox.put(Side::Offerer, 0, "stale").await?;
+
+    tokio::time::sleep(Duration::from_millis(700)).await;
+    let err = mailbox.wait(Side::Answerer, 0).await.unwrap_err();
+    assert!(err.to_string().contains("may have expired"), "{}", err);
+    let err = mailbox.put(Side::Answerer, 0, "late").await.unwrap_err();
+    assert!(err.to_string().contains("may have expired"), "{}", err);
+    Ok(())
+}
+
+#[tokio::test]
+async fn requests_are_bounded() -> Result<()> {
+    let url = server(Duration::from_secs(60)).await?;
+    let client = reqwest::Client::new();
+    let mailbox = Mailbox::allocate(&url).await?;
+    let message_url = |side: &str, index: usize| format!("{}/mailboxes/1/{}/{}", url, side, index);
+
+    // Bodies are capped, as are the messages per side
+    let huge = "x".repeat(1024 * 1024);
+    let response = client
+        .post(message_url("offer", 0))
+        .body(huge)
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let response = client
+        .post(message_url("offer", 8))
+        .body("x")
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Unknown sides, mailboxes and routes are rejected
+    let response = client
+        .post(message_url("server", 0))
+        .body("x")
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = client
+        .get(format!("{}/mailboxes/99/offer/0", url))
+        .send()
+        .await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = client.get(format!("{}/mailboxes", url)).send().await?;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    assert_eq!(mailbox.get(Side::Offerer, 0).await?, None);
+
+    // So are open mailboxes, until one closes
+    for _ in 1..MAX_MAILBOXES {
+        let response = client.post(format!("{}/mailboxes", url)).send().await?;
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+    let err = Mailbox::allocate(&url)
+        .await
+        .err()
+        .expect("too many mailboxes");
+    assert!(
+        err.to_string().contains("too many open mailboxes"),
+        "{}",
+        err
+    );
+    mailbox.close().await?;
+    assert_eq!(Mailbox::allocate(&url).await?.nameplate(), "1");
+    Ok(())
+}
+
+#[tokio::test]
+async fn unreachable_servers_are_named() {
+    let err = Mailbox::allocate("http://127.0.0.1:1/")
+        .await
+        .err()
+        .expect("no server there");
+    assert!(err.to_string().contains("http://127.0.0.1:1"), "{}", err);
+}
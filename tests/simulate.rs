@@ -0,0 +1,71 @@
+// The network simulator connects (or fails to) the way the topology says it
+// should, and reports what it saw.
+
+use anyhow::Result;
+use modulate_comms::simulate::{self, NatKind, SimulationConfig};
+use std::time::Duration;
+use webrtc::ice::candidate::CandidateType;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[tokio::test(flavor = "multi_thread")]
+async fn direct_peers_use_host_candidates() -> Result<()> {
+    let config = SimulationConfig {
+        latency: Duration::from_millis(20),
+        messages: 20,
+        ..Default::default()
+    };
+    let report = simulate::simulate(&config, TIMEOUT).await?;
+
+    assert!(report.connect_time.is_some());
+    let pair = report.offerer_pair.expect("no candidate pair selected");
+    assert_eq!(pair.local.candidate_type, CandidateType::Host);
+
+    let delivery = report.delivery.expect("no delivery stats");
+    assert_eq!(delivery.delivered, 20);
+    assert!(delivery.latency_min.unwrap() >= Duration::from_millis(20));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn full_cone_nat_connects_through_mapped_addresses() -> Result<()> {
+    let config = SimulationConfig {
+        nat: NatKind::FullCone,
+        messages: 20,
+        ..Default::default()
+    };
+    let report = simulate::simulate(&config, TIMEOUT).await?;
+
+    assert!(report.connect_time.is_some());
+    // The peer is only reachable at its NAT's public address
+    let pair = report.offerer_pair.expect("no candidate pair selected");
+    assert_eq!(pair.remote.ip, "28.1.1.1");
+    assert_eq!(report.delivery.unwrap().delivered, 20);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn symmetric_nat_needs_a_relay() -> Result<()> {
+    let config = SimulationConfig {
+        nat: NatKind::Symmetric,
+        messages: 10,
+        ..Default::default()
+    };
+    let report = simulate::simulate(&config, Duration::from_secs(10)).await?;
+    assert!(report.connect_time.is_none());
+    assert!(report.delivery.is_none());
+
+    let config = SimulationConfig {
+        relay: true,
+        ..config
+    };
+    let report = simulate::simulate(&config, TIMEOUT).await?;
+    assert!(report.connect_time.is_some());
+    let pair = report.offerer_pair.expect("no candidate pair selected");
+    assert!(
+        pair.local.candidate_type == CandidateType::Relay
+            || pair.remote.candidate_type == CandidateType::Relay
+    );
+    assert_eq!(report.delivery.unwrap().delivered, 10);
+    Ok(())
+}
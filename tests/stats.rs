@@ -0,0 +1,60 @@
+// Connection stats: the selected candidate pair and per-channel traffic, as
+// /stats and --stats-interval report them, on the virtual network.
+
+mod common;
+
+use anyhow::Result;
+use common::TIMEOUT;
+use modulate_comms::connection::{self, MESSAGING_CHANNEL};
+use modulate_comms::stats;
+use modulate_comms::utils::format_bytes;
+use std::sync::Arc;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stats_report_the_candidate_pair_and_channel_traffic() -> Result<()> {
+    let (pair, (dc, _), (_, mut answer_incoming)) = common::open_messaging_pair().await?;
+
+    for text in ["one", "two", "three"] {
+        dc.send_text(text.to_string()).await?;
+    }
+    for _ in 0..3 {
+        tokio::time::timeout(TIMEOUT, answer_incoming.recv()).await?;
+    }
+
+    let report = stats::collect(&pair.offerer, &[Arc::clone(&dc)], None).await;
+    let selected = report.selected.as_ref().expect("a selected candidate pair");
+    assert_eq!(
+        connection::describe_candidate(&selected.local),
+        format!("host 1.2.3.4:{}", selected.local.port)
+    );
+    assert_eq!(selected.remote.ip, "1.2.3.5");
+    assert!(report.bytes_sent > 0 && report.bytes_received > 0);
+
+    let [ref channel] = report.channels[..] else {
+        panic!("expected one channel, got {:?}", report.channels);
+    };
+    assert_eq!(channel.label, MESSAGING_CHANNEL);
+    assert_eq!(channel.messages_sent, 3);
+    assert_eq!(channel.bytes_sent, "onetwothree".len());
+    assert_eq!(channel.messages_received, 0);
+
+    let lines = report.lines();
+    assert!(lines[0].starts_with("Candidate pair: host 1.2.3.4:"));
+    assert!(
+        lines.iter().any(|line| line
+            .contains("messaging - sent 3 messages (11.00 B), received 0 messages (0 B),")),
+        "{:?}",
+        lines
+    );
+
+    pair.close().await;
+    Ok(())
+}
+
+#[test]
+fn byte_counts_are_formatted_in_binary_units() {
+    assert_eq!(format_bytes(0), "0 B");
+    assert_eq!(format_bytes(512), "512.00 B");
+    assert_eq!(format_bytes(1536), "1.50 KB");
+    assert_eq!(format_bytes(5 * 1024 * 1024), "5.00 MB");
+}
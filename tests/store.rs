@@ -0,0 +1,223 @@
+// The encrypted store on disk: what's sealed comes back with the right
+// passphrase, and a wrong passphrase, a damaged header or swapped files are
+// refused rather than read. The history keeps an audit trail of edits, and
+// changes, replies and threads only reach messages of the conversation they
+// arrive in.
+
+mod common;
+
+use anyhow::Result;
+use common::TempDir;
+use modulate_comms::store::{Conversation, Direction, Store};
+use std::fs;
+use std::sync::Mutex;
+
+// The passphrase comes from the environment, which every test shares
+static PASSPHRASE: Mutex<()> = Mutex::new(());
+
+fn open_with(dir: &TempDir, passphrase: &str) -> Result<Store> {
+    let _guard = PASSPHRASE.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("MODULATE_PASSPHRASE", passphrase);
+    Store::open(Some(dir.0.clone()))
+}
+
+#[test]
+fn store_is_sealed_and_unlocks_with_the_passphrase() -> Result<()> {
+    let dir = TempDir::new("store");
+    let mut store = open_with(&dir, "correct horse")?;
+    assert!(store.is_persistent());
+    let identity = store.identity().fingerprint();
+    store.update_config(|config| config.display_name = Some("Alice".to_string()))?;
+    store.record(
+        &Conversation::new("b0b"),
+        Direction::Sent,
+        "bob",
+        "0011223344556677",
+        "meet at the usual place",
+        None,
+    )?;
+    drop(store);
+
+    // Nothing readable is left on disk
+    for entry in fs::read_dir(&dir.0)? {
+        let contents = fs::read_to_string(entry?.path())?;
+        assert!(!contents.contains("usual place"));
+        assert!(!contents.contains("Alice"));
+    }
+
+    let store = open_with(&dir, "correct horse")?;
+    assert_eq!(store.identity().fingerprint(), identity);
+    assert_eq!(store.config().display_name.as_deref(), Some("Alice"));
+    let [ref entry] = store.history()[..] else {
+        panic!("expected one message, got {:?}", store.history());
+    };
+    assert_eq!(entry.text, "meet at the usual place");
+    assert_eq!(entry.peer, "bob");
+    Ok(())
+}
+
+#[test]
+fn wrong_passphrase_is_refused() -> Result<()> {
+    let dir = TempDir::new("store");
+    open_with(&dir, "correct horse")?;
+
+    let err = open_with(&dir, "battery staple").err().expect("unlocked");
+    assert!(err.to_string().contains("Could not unlock"), "{:#}", err);
+    Ok(())
+}
+
+#[test]
+fn damaged_or_swapped_files_are_refused() -> Result<()> {
+    let dir = TempDir::new("store");
+    let mut store = open_with(&dir, "correct horse")?;
+    store.record(
+        &Conversation::new("b0b"),
+        Direction::Received,
+        "bob",
+        "8899aabbccddeeff",
+        "hi",
+        None,
+    )?;
+    store.update_contacts(|_| ())?;
+    drop(store);
+
+    // Files are bound to their names, so one can't stand in for another
+    fs::copy(dir.0.join("history.enc"), dir.0.join("contacts.enc"))?;
+    let err = open_with(&dir, "correct horse").err().expect("opened");
+    assert!(
+        format!("{:#}", err).contains("Failed to decrypt contacts.enc"),
+        "{:#}",
+        err
+    );
+
+    fs::write(dir.0.join("vault.json"), "{\"version\": 1, \"salt\":")?;
+    let err = open_with(&dir, "correct horse").err().expect("opened");
+    assert!(
+        err.to_string().contains("Corrupt vault header"),
+        "{:#}",
+        err
+    );
+    Ok(())
+}
+
+#[test]
+fn edits_deletions_and_reactions_keep_a_trail() -> Result<()> {
+    let mut store = Store::ephemeral();
+    let bob = Conversation::new("b0b");
+    store.record(
+        &bob,
+        Direction::Sent,
+        "bob",
+        "0000000000000001",
+        "helo",
+        None,
+    )?;
+    store.record(
+        &bob,
+        Direction::Received,
+        "bob",
+        "0000000000000002",
+        "hi",
+        None,
+    )?;
+
+    assert!(store.edit_message(&bob, "0000000000000001", Direction::Sent, "hello")?);
+    assert!(store.edit_message(&bob, "0000000000000001", Direction::Sent, "hello!")?);
+    // Only the side that sent a message can change it
+    assert!(!store.edit_message(&bob, "0000000000000001", Direction::Received, "bye")?);
+    assert!(!store.delete_message(&bob, "0000000000000002", Direction::Sent)?);
+    // Reactions go on messages from either side, once each
+    assert!(store.react_to_message(&bob, "0000000000000002", Direction::Sent, "👍")?);
+    assert!(store.react_to_message(&bob, "0000000000000002", Direction::Sent, "👍")?);
+    assert!(store.react_to_message(&bob, "0000000000000001", Direction::Received, "🎉")?);
+
+    assert!(store.delete_message(&bob, "0000000000000002", Direction::Received)?);
+    // Deleted messages stay for the trail but can't be changed any more
+    assert!(!store.react_to_message(&bob, "0000000000000002", Direction::Sent, "😮")?);
+
+    let [ref sent, ref received] = store.history()[..] else {
+        panic!("expected two messages, got {:?}", store.history());
+    };
+    assert_eq!(sent.text, "helo");
+    let revisions: Vec<&str> = sent.revisions.iter().map(|r| r.text.as_str()).collect();
+    assert_eq!(revisions, ["hello", "hello!"]);
+    assert_eq!(sent.current_text(), "hello!");
+    assert_eq!(sent.reactions.len(), 1);
+    assert!(received.deleted_at.is_some());
+    assert_eq!(received.reactions.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn changes_stay_in_their_conversation() -> Result<()> {
+    let mut store = Store::ephemeral();
+    let bob = Conversation::new("b0b");
+    let bob_again = Conversation::new("b0b");
+    let carol = Conversation {
+        peer: "ca401".to_string(),
+        session: bob.session.clone(),
+    };
+    store.record(
+        &bob,
+        Direction::Received,
+        "bob",
+        "0000000000000001",
+        "hi",
+        None,
+    )?;
+    store.record(
+        &bob,
+        Direction::Sent,
+        "bob",
+        "0000000000000002",
+        "hey",
+        None,
+    )?;
+
+    // Another peer, even one naming the same session, or Bob in a later
+    // session, can't reach these messages
+    for other in [&carol, &bob_again] {
+        assert!(!store.edit_message(other, "0000000000000001", Direction::Received, "x")?);
+        assert!(!store.delete_message(other, "0000000000000001", Direction::Received)?);
+        assert!(!store.react_to_message(other, "0000000000000002", Direction::Received, "x")?);
+    }
+    let entry = &store.history()[0];
+    assert!(entry.belongs_to(&bob) && !entry.belongs_to(&carol));
+    assert!(entry.revisions.is_empty() && entry.deleted_at.is_none());
+    assert!(store.history()[1].reactions.is_empty());
+    Ok(())
+}
+
+#[test]
+fn threads_are_built_within_their_conversation() -> Result<()> {
+    let mut store = Store::ephemeral();
+    let bob = Conversation::new("b0b");
+    let earlier = Conversation::new("b0b");
+    let record = |store: &mut Store, conversation, id: &str, reply_to: Option<&str>| {
+        store.record(conversation, Direction::Received, "bob", id, id, reply_to)
+    };
+    // The same id in an earlier session, which must not be pulled in
+    record(&mut store, &earlier, "root", None)?;
+    record(&mut store, &earlier, "old reply", Some("root"))?;
+    record(&mut store, &bob, "root", None)?;
+    record(&mut store, &bob, "a", Some("root"))?;
+    record(&mut store, &bob, "other", None)?;
+    record(&mut store, &bob, "b", Some("root"))?;
+    record(&mut store, &bob, "a1", Some("a"))?;
+
+    assert_eq!(store.thread_root(&bob, "a1"), "root");
+    assert!(store.message(&bob, "old reply").is_none());
+    let thread: Vec<(usize, &str)> = store
+        .thread(&bob, "root")
+        .into_iter()
+        .map(|(depth, e)| (depth, e.id.as_str()))
+        .collect();
+    assert_eq!(thread, [(0, "root"), (1, "a"), (2, "a1"), (1, "b")]);
+
+    // A peer replying in a circle doesn't hang the lookups
+    record(&mut store, &bob, "x", Some("y"))?;
+    record(&mut store, &bob, "y", Some("x"))?;
+    let root = store.thread_root(&bob, "x");
+    assert_eq!(store.thread(&bob, root).len(), 2);
+    Ok(())
+}
@@ -0,0 +1,389 @@
+// TCP and UDP tunnels and the SOCKS proxy: connections to the entry side reach a
+// service next to the exit side, each over a data channel of its own, on the
+// virtual network, and stream ids the exit side refuses because they aren't free.
+
+mod common;
+
+use anyhow::{Context, Result};
+use common::TIMEOUT;
+use modulate_comms::connection::{self, ChannelSpec, IncomingMessages, ReliabilityProfile};
+use modulate_comms::protocol::{TunnelError, TunnelProtocol, WireMessage};
+use modulate_comms::socks;
+use modulate_comms::tunnel::{self, AllowRule, Allowlist, Forward};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+// A TCP service that sends back whatever it reads, prefixed with a greeting
+async fn echo_service() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = socket.split();
+                writer.write_all(b"hello ").await?;
+                tokio::io::copy(&mut reader, &mut writer).await?;
+                writer.shutdown().await
+            });
+        }
+    });
+    Ok(addr)
+}
+
+fn allowlist(rules: &[String]) -> Result<Allowlist> {
+    Ok(Allowlist(
+        rules
+            .iter()
+            .map(|rule| rule.parse())
+            .collect::<Result<_>>()?,
+    ))
+}
+
+// Both ends of a tunnel over the virtual network, the entry side listening on
+// the returned address and the exit side allowing `allow`
+async fn tunnel_pair(forward: Forward, allow: &[String]) -> Result<(common::VnetPair, SocketAddr)> {
+    let allow = allowlist(allow)?;
+    let (pair, (entry_dc, entry_incoming), (exit_dc, exit_incoming)) =
+        common::open_messaging_pair().await?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let local = listener.local_addr()?;
+    let entry_pc = Arc::clone(&pair.offerer);
+    tokio::spawn(async move {
+        tunnel::serve_entry(&entry_pc, &entry_dc, entry_incoming, listener, forward).await
+    });
+    let exit_pc = Arc::clone(&pair.answerer);
+    tokio::spawn(async move { tunnel::serve_exit(&exit_pc, &exit_dc, exit_incoming, allow).await });
+    Ok((pair, local))
+}
+
+// Send `data` through the tunnel, end our side, and read everything back
+async fn round_trip(local: SocketAddr, data: Vec<u8>) -> Result<Vec<u8>> {
+    let mut socket = TcpStream::connect(local).await?;
+    let (mut reader, mut writer) = socket.split();
+    let mut received = Vec::new();
+    tokio::try_join!(
+        async {
+            writer.write_all(&data).await?;
+            writer.shutdown().await
+        },
+        reader.read_to_end(&mut received),
+    )?;
+    Ok(received)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connections_reach_the_service_on_the_peers_side() -> Result<()> {
+    let service = echo_service().await?;
+    let (pair, local) =
+        tunnel_pair(Forward::To(service.to_string()), &[service.to_string()]).await?;
+
+    // Several at once, each on its own channel, one well past a single frame
+    let big: Vec<u8> = (0..300_000).map(|_| rand::random::<u8>()).collect();
+    let (first, second, third) = tokio::time::timeout(TIMEOUT, async {
+        tokio::try_join!(
+            round_trip(local, b"first".to_vec()),
+            round_trip(local, big.clone()),
+            round_trip(local, Vec::new()),
+        )
+    })
+    .await??;
+    assert_eq!(first, b"hello first");
+    assert_eq!(&second[..6], b"hello ");
+    assert_eq!(second[6..], big[..]);
+    assert_eq!(third, b"hello ");
+
+    // And the tunnel keeps taking connections after those have closed
+    let again = tokio::time::timeout(TIMEOUT, round_trip(local, b"again".to_vec())).await??;
+    assert_eq!(again, b"hello again");
+
+    pair.close().await;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn targets_the_exit_side_doesnt_allow_are_refused() -> Result<()> {
+    let service = echo_service().await?;
+    let (pair, local) = tunnel_pair(
+        Forward::To(service.to_string()),
+        &["127.0.0.1:1".to_string()],
+    )
+    .await?;
+
+    // The connection is closed without anything from the service
+    let received = tokio::time::timeout(TIMEOUT, round_trip(local, b"let me in".to_vec())).await?;
+    assert!(received.map_or(true, |r| r.is_empty()));
+
+    pair.close().await;
+    Ok(())
+}
+
+// The exit side's answer to a stream we asked it to open
+async fn answer(incoming: &mut IncomingMessages) -> Result<WireMessage> {
+    let msg = incoming.recv().await.context("the session ended")?;
+    WireMessage::decode(&msg.data)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stream_ids_that_arent_free_are_refused() -> Result<()> {
+    let service = echo_service().await?;
+    let (pair, (dc, mut incoming), (exit_dc, exit_incoming)) =
+        common::open_messaging_pair().await?;
+    let exit_pc = Arc::clone(&pair.answerer);
+    let allow = allowlist(&[service.to_string()])?;
+    tokio::spawn(async move { tunnel::serve_exit(&exit_pc, &exit_dc, exit_incoming, allow).await });
+
+    let open = |stream| WireMessage::TunnelOpen {
+        stream,
+        target: service.to_string(),
+        protocol: TunnelProtocol::Tcp,
+        profile: ReliabilityProfile::default(),
+    };
+    let spec = ChannelSpec::new(tunnel::TUNNEL_CHANNEL, ReliabilityProfile::default());
+    let (_channel, _channel_incoming) =
+        connection::open_negotiated_channel(&pair.offerer, &spec, 2000).await?;
+
+    tokio::time::timeout(TIMEOUT, async {
+        open(2000).send(&dc).await?;
+        assert!(matches!(
+            answer(&mut incoming).await?,
+            WireMessage::TunnelOpened { stream: 2000 }
+        ));
+
+        // The same id again while the first connection is open, and one of
+        // the ids the session's own channels use, are refused straight away
+        for stream in [2000, 1] {
+            open(stream).send(&dc).await?;
+            match answer(&mut incoming).await? {
+                WireMessage::TunnelFailed {
+                    stream: failed,
+                    error,
+                    ..
+                } => {
+                    assert_eq!(failed, stream);
+                    assert_eq!(error, TunnelError::Failed);
+                }
+                other => panic!("stream {} was answered with {:?}", stream, other),
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+
+    pair.close().await;
+    Ok(())
+}
+
+// Ask a SOCKS proxy for `host`, an IPv4 address or a name, returning the reply
+// code and the connection
+async fn socks_connect(proxy: SocketAddr, host: &str, port: u16) -> Result<(u8, TcpStream)> {
+    let mut socket = TcpStream::connect(proxy).await?;
+    socket.write_all(&[5, 1, 0]).await?;
+    let mut chosen = [0u8; 2];
+    socket.read_exact(&mut chosen).await?;
+    assert_eq!(chosen, [5, 0]);
+
+    let mut request = vec![5, 1, 0];
+    match host.parse::<std::net::Ipv4Addr>() {
+        Ok(ip) => {
+            request.push(1);
+            request.extend(ip.octets());
+        }
+        Err(_) => {
+            request.extend([3, host.len() as u8]);
+            request.extend(host.as_bytes());
+        }
+    }
+    request.extend(port.to_be_bytes());
+    socket.write_all(&request).await?;
+
+    let mut reply = [0u8; 10];
+    socket.read_exact(&mut reply).await?;
+    Ok((reply[1], socket))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn socks_connections_follow_the_exit_sides_allowlist() -> Result<()> {
+    let service = echo_service().await?;
+    let port = service.port();
+    let allow = [
+        format!("localhost:{}", port),
+        "127.0.0.0/8:1-1000".to_string(),
+    ];
+    let (pair, proxy) = tunnel_pair(Forward::Socks, &allow).await?;
+
+    tokio::time::timeout(TIMEOUT, async {
+        // Allowed by name, and carried through once the reply is in
+        let (reply, mut socket) = socks_connect(proxy, "localhost", port).await?;
+        assert_eq!(reply, socks::SUCCEEDED);
+        socket.write_all(b"via socks").await?;
+        socket.shutdown().await?;
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await?;
+        assert_eq!(received, b"hello via socks");
+
+        // A name rule doesn't cover the address it resolves to
+        let (reply, _) = socks_connect(proxy, "127.0.0.1", port).await?;
+        assert_eq!(reply, socks::NOT_ALLOWED);
+        let (reply, _) = socks_connect(proxy, "elsewhere.invalid", port).await?;
+        assert_eq!(reply, socks::NOT_ALLOWED);
+
+        // Allowed by network, but nothing is listening there
+        let (reply, _) = socks_connect(proxy, "127.0.0.1", 1).await?;
+        assert_eq!(reply, socks::CONNECTION_REFUSED);
+        anyhow::Ok(())
+    })
+    .await??;
+
+    pair.close().await;
+    Ok(())
+}
+
+// A UDP service that answers each datagram with the sender's address
+async fn udp_echo_service() -> Result<SocketAddr> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let addr = socket.local_addr()?;
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 1500];
+        while let Ok((n, from)) = socket.recv_from(&mut buffer).await {
+            let reply = format!("{} from {}", String::from_utf8_lossy(&buffer[..n]), from);
+            let _ = socket.send_to(reply.as_bytes(), from).await;
+        }
+    });
+    Ok(addr)
+}
+
+// Send `message` until a reply comes back, as a UDP client would
+async fn ask(socket: &UdpSocket, message: &str) -> Result<String> {
+    let mut buffer = [0u8; 1500];
+    loop {
+        socket.send(message.as_bytes()).await?;
+        if let Ok(n) =
+            tokio::time::timeout(Duration::from_millis(200), socket.recv(&mut buffer)).await
+        {
+            return Ok(String::from_utf8_lossy(&buffer[..n?]).into_owned());
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn udp_replies_go_back_to_the_client_that_sent_each_flow() -> Result<()> {
+    let service = udp_echo_service().await?;
+    let (pair, (entry_dc, entry_incoming), (exit_dc, exit_incoming)) =
+        common::open_messaging_pair().await?;
+
+    let local_socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let local = local_socket.local_addr()?;
+    let entry_pc = Arc::clone(&pair.offerer);
+    let profile = ReliabilityProfile::MaxRetransmits {
+        retransmits: 0,
+        ordered: false,
+    };
+    let remote = service.to_string();
+    tokio::spawn(async move {
+        tunnel::serve_udp_entry(
+            &entry_pc,
+            &entry_dc,
+            entry_incoming,
+            local_socket,
+            remote,
+            profile,
+        )
+        .await
+    });
+    let exit_pc = Arc::clone(&pair.answerer);
+    let allow = allowlist(&[service.to_string()])?;
+    tokio::spawn(async move { tunnel::serve_exit(&exit_pc, &exit_dc, exit_incoming, allow).await });
+
+    let alice = UdpSocket::bind("127.0.0.1:0").await?;
+    alice.connect(local).await?;
+    let bob = UdpSocket::bind("127.0.0.1:0").await?;
+    bob.connect(local).await?;
+
+    tokio::time::timeout(TIMEOUT, async {
+        let to_alice = ask(&alice, "alice").await?;
+        let to_bob = ask(&bob, "bob").await?;
+        assert!(
+            to_alice.starts_with("alice from 127.0.0.1:"),
+            "{}",
+            to_alice
+        );
+        assert!(to_bob.starts_with("bob from 127.0.0.1:"), "{}", to_bob);
+
+        // Each client is a flow with a source port of its own on the far
+        // side, which stays the same for later datagrams
+        let alice_source = to_alice.rsplit(' ').next().unwrap().to_string();
+        let bob_source = to_bob.rsplit(' ').next().unwrap().to_string();
+        assert_ne!(alice_source, bob_source);
+        let again = ask(&alice, "alice").await?;
+        assert!(again.ends_with(&alice_source), "{}", again);
+        anyhow::Ok(())
+    })
+    .await??;
+
+    pair.close().await;
+    Ok(())
+}
+
+#[test]
+fn allow_rules_parse_hosts_networks_and_port_ranges() {
+    for rule in [
+        "intranet.example:443",
+        "*.corp.example:*",
+        "10.0.0.0/8:22",
+        "[fd00::/8]:8000-8100",
+        "[::1]:80",
+        "*:443",
+    ] {
+        assert!(rule.parse::<AllowRule>().is_ok(), "{}", rule);
+    }
+    for rule in [
+        "intranet.example",
+        "10.0.0.0/33:22",
+        "host:99999",
+        "host:9000-80",
+        "in*ranet:80",
+        ":80",
+    ] {
+        assert!(rule.parse::<AllowRule>().is_err(), "{}", rule);
+    }
+}
+
+#[test]
+fn allowlists_match_names_addresses_and_ports() -> Result<()> {
+    let allow = allowlist(&[
+        "intranet.example:443".to_string(),
+        "*.corp.example:8000-8100".to_string(),
+        "10.0.0.0/8:22".to_string(),
+        "[fd00::/8]:*".to_string(),
+    ])?;
+
+    // Names match without regard to case or a trailing dot
+    assert!(allow.allows_name("intranet.example", "443"));
+    assert!(allow.allows_name("Intranet.Example.", "443"));
+    assert!(!allow.allows_name("intranet.example", "80"));
+    assert!(!allow.allows_name("www.intranet.example", "443"));
+
+    // A wildcard covers subdomains at any depth, but not the domain itself
+    // or names that merely end the same way
+    assert!(allow.allows_name("a.corp.example", "8000"));
+    assert!(allow.allows_name("build.eu.CORP.example", "8100"));
+    assert!(!allow.allows_name("corp.example", "8000"));
+    assert!(!allow.allows_name("evilcorp.example", "8000"));
+    assert!(!allow.allows_name("a.corp.example", "8101"));
+    assert!(!allow.allows_name("a.corp.example", "http"));
+
+    // Addresses only match network rules, never names
+    assert!(!allow.allows_name("10.1.2.3", "22"));
+    assert!(allow.allows_address(&"10.1.2.3:22".parse()?));
+    assert!(!allow.allows_address(&"11.1.2.3:22".parse()?));
+    assert!(!allow.allows_address(&"10.1.2.3:23".parse()?));
+    assert!(allow.allows_address(&"[fd12::1]:9".parse()?));
+    assert!(!allow.allows_address(&"[fe80::1]:9".parse()?));
+
+    assert!(!Allowlist::default().allows_name("intranet.example", "443"));
+    Ok(())
+}
@@ -0,0 +1,161 @@
+// Signaling with short wormhole codes through the bundled rendezvous server,
+// including a server that tries to swap in its own offer.
+
+mod common;
+
+use anyhow::Result;
+use common::TIMEOUT;
+use modulate_comms::app;
+use modulate_comms::connection::ChannelSpec;
+use modulate_comms::rendezvous::{self, Mailbox, Side};
+use modulate_comms::signaling::Signaler;
+use modulate_comms::wormhole::{Code, WormholeSignaler};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+
+async fn server() -> Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(rendezvous::serve(listener, Duration::from_secs(60)));
+    Ok(url)
+}
+
+// Whatever the answerer was told, with the last word changed
+fn mistyped(code: &Code) -> Code {
+    let mut wrong = code.clone();
+    wrong.words[1] = if code.words[1] == "zebra" {
+        "yogurt".to_string()
+    } else {
+        "zebra".to_string()
+    };
+    wrong
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_connect_with_a_wormhole_code() -> Result<()> {
+    let url = server().await?;
+    let pair = common::vnet_pair().await?;
+    let specs = ChannelSpec::with_overrides(&[]);
+
+    // The offerer reads the code out before its offer is even ready
+    let mut offerer = WormholeSignaler::offering(&url);
+    let code = offerer.open().await?;
+    assert_eq!(code.nameplate, "1");
+    let mut answerer = WormholeSignaler::answering(&url, Code::parse(&code.to_string())?);
+
+    tokio::try_join!(
+        app::connect(
+            Arc::clone(&pair.offerer),
+            specs.clone(),
+            &mut offerer,
+            true,
+            TIMEOUT,
+        ),
+        app::connect(
+            Arc::clone(&pair.answerer),
+            specs,
+            &mut answerer,
+            false,
+            TIMEOUT,
+        ),
+    )?;
+    assert_eq!(
+        pair.offerer.connection_state(),
+        RTCPeerConnectionState::Connected
+    );
+
+    // The offerer closes the mailbox once it has the answer
+    let err = Mailbox::open(&url, "1")
+        .get(Side::Offerer, 0)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("may have expired"), "{}", err);
+
+    pair.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn a_wrong_code_uses_up_the_mailbox() -> Result<()> {
+    let url = server().await?;
+    let mut offerer = WormholeSignaler::offering(&url);
+    let code = offerer.open().await?;
+    let offering = tokio::spawn(async move {
+        offerer.send("the offer").await?;
+        offerer.receive().await
+    });
+
+    // A mistyped code can't read the offer
+    let mut answerer = WormholeSignaler::answering(&url, mistyped(&code));
+    let err = answerer.receive().await.unwrap_err();
+    assert!(
+        err.to_string().contains("offer couldn't be authenticated"),
+        "{}",
+        err
+    );
+
+    // And whoever comes next with the right code is told to start again
+    let mut answerer = WormholeSignaler::answering(&url, code);
+    let err = answerer.receive().await.unwrap_err();
+    assert!(err.to_string().contains("start again"), "{}", err);
+
+    offering.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn the_server_cannot_substitute_an_offer() -> Result<()> {
+    let url = server().await?;
+
+    // The server knows the nameplate but not the words, so its key exchange
+    // runs with a guess, and its offer is sealed with the wrong key if at all
+    let mailbox = Mailbox::allocate(&url).await?;
+    let code = Code::generate(mailbox.nameplate());
+    let (_, outbound) = Spake2::<Ed25519Group>::start_symmetric(
+        &Password::new(mistyped(&code).to_string().as_bytes()),
+        &Identity::new(b"modulate-comms/wormhole"),
+    );
+    mailbox
+        .put(Side::Offerer, 0, &hex::encode(outbound))
+        .await?;
+    let forged = format!("{}{}", "00".repeat(24), hex::encode("OFFER:{}"));
+    mailbox.put(Side::Offerer, 1, &forged).await?;
+
+    let mut answerer = WormholeSignaler::answering(&url, code);
+    let err = answerer.receive().await.unwrap_err();
+    assert!(
+        err.to_string().contains("offer couldn't be authenticated"),
+        "{}",
+        err
+    );
+    Ok(())
+}
+
+#[test]
+fn codes_are_checked_as_typed() {
+    let code = Code::parse(" 7-Crossword-BANANA\n").unwrap();
+    assert_eq!(code.nameplate, "7");
+    assert_eq!(code.to_string(), "7-crossword-banana");
+
+    for code in [
+        "17",
+        "7-crossword",
+        "crossword-banana-7",
+        "x-crossword-banana",
+        "-crossword-banana",
+        "7-crossword-banana-zebra",
+    ] {
+        assert!(Code::parse(code).is_err(), "{}", code);
+    }
+    let err = Code::parse("7-crossword-bananna").unwrap_err();
+    assert!(err.to_string().contains("\"bananna\""), "{}", err);
+
+    // Generated codes are made of words from the list
+    for _ in 0..100 {
+        let code = Code::generate("12");
+        assert_eq!(Code::parse(&code.to_string()).unwrap(), code);
+    }
+}